@@ -0,0 +1,56 @@
+/*
+ * CPU benchmarks, run with `cargo bench`.
+ *
+ * clear_loop clears WRAM with the LD (HL+),A loop of game startup code,
+ * 0x2000 iterations of six instructions, without any fast path.
+ * traced_clear_loop runs it through Vm with --trace writing to nowhere,
+ * the cost of formatting the trace line of every instruction.
+ */
+use std::io;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use rugameboy::cpu::Cpu;
+use rugameboy::vm::Vm;
+
+/// address after the clear loop
+const CLEAR_LOOP_END: u16 = 0x10d;
+
+// LD HL, 0xc000; LD BC, 0x2000; loop: XOR A; LD (HL+), A; DEC BC; LD A, B; OR C; JR NZ loop
+fn clear_loop_rom() -> Vec<u8> {
+    let program = [0x21, 0x00, 0xc0, 0x01, 0x00, 0x20,
+                   0xaf, 0x22, 0x0b, 0x78, 0xb1, 0x20, 0xf9];
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+    rom
+}
+
+fn clear_loop_cpu() -> Cpu {
+    Cpu::new(clear_loop_rom())
+}
+
+fn traced_clear_loop_vm() -> Vm {
+    let mut vm = Vm::new(clear_loop_rom());
+    vm.set_trace_log(Box::new(io::sink()));
+    vm
+}
+
+fn clear_loop(c: &mut Criterion) {
+    c.bench_function("clear_loop", |b| b.iter_batched(clear_loop_cpu, |mut cpu| {
+        while cpu.pc != CLEAR_LOOP_END {
+            cpu.step().unwrap();
+        }
+        cpu
+    }, BatchSize::SmallInput));
+}
+
+fn traced_clear_loop(c: &mut Criterion) {
+    c.bench_function("traced_clear_loop", |b| b.iter_batched(traced_clear_loop_vm, |mut vm| {
+        while vm.cpu.pc != CLEAR_LOOP_END {
+            vm.step().unwrap();
+        }
+        vm
+    }, BatchSize::SmallInput));
+}
+
+criterion_group!(benches, clear_loop, traced_clear_loop);
+criterion_main!(benches);
@@ -0,0 +1,859 @@
+use crate::bus::Device;
+use crate::vm::CLOCK_RATE;
+use crate::resampler::Resampler;
+
+/*
+ * Sound registers from 0xff10 to 0xff3f
+ *
+ * 0xff10-0xff14 channel 1, square wave with frequency sweep
+ * 0xff16-0xff19 channel 2, square wave
+ * 0xff1a-0xff1e channel 3, wave from wave RAM
+ * 0xff20-0xff23 channel 4, noise
+ * 0xff24-0xff26 master volume, panning and power
+ * 0xff30-0xff3f wave RAM, 32 4-bit samples
+ */
+pub const APU_START:  u16 = 0xff10;
+pub const APU_END:    u16 = 0xff3f;
+const NR10:           u16 = 0xff10;
+const NR11:           u16 = 0xff11;
+const NR12:           u16 = 0xff12;
+const NR13:           u16 = 0xff13;
+const NR14:           u16 = 0xff14;
+const NR21:           u16 = 0xff16;
+const NR22:           u16 = 0xff17;
+const NR23:           u16 = 0xff18;
+const NR24:           u16 = 0xff19;
+const NR30:           u16 = 0xff1a;
+const NR31:           u16 = 0xff1b;
+const NR32:           u16 = 0xff1c;
+const NR33:           u16 = 0xff1d;
+const NR34:           u16 = 0xff1e;
+const NR41:           u16 = 0xff20;
+const NR42:           u16 = 0xff21;
+const NR43:           u16 = 0xff22;
+const NR44:           u16 = 0xff23;
+const NR50:           u16 = 0xff24;
+const NR51:           u16 = 0xff25;
+const NR52:           u16 = 0xff26;
+const WAVE_START:     u16 = 0xff30;
+
+/// output sample rate in Hz
+pub const SAMPLE_RATE: u64 = 44100;
+/// frame sequencer clocks length, sweep and envelope at 512 Hz
+const FRAME_SEQUENCER_CLOCK: u64 = 8192;
+
+/// bits always read as 1 in register 0xff10-0xff2f
+const READ_MASK: [u8; 0x20] = [
+    0x80, 0x3f, 0x00, 0xff, 0xbf, // NR10-NR14
+    0xff, 0x3f, 0x00, 0xff, 0xbf, // unused, NR21-NR24
+    0x7f, 0xff, 0x9f, 0xff, 0xbf, // NR30-NR34
+    0xff, 0xff, 0x00, 0x00, 0xbf, // unused, NR41-NR44
+    0x00, 0x00, 0x70,             // NR50-NR52
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, // unused
+];
+
+/// waveform of 8 steps for each duty, 12.5%, 25%, 50% and 75%
+const DUTY: [u8; 4] = [0b00000001, 0b10000001, 0b10000111, 0b01111110];
+
+/// DMG only allows wave RAM access by CPU this many clocks
+/// after channel 3 read its sample
+const WAVE_ACCESS_WINDOW: u64 = 2;
+
+/// clock divisor of noise channel
+const NOISE_DIVISOR: [u64; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+fn to_i16(sample: f32) -> i16 {
+    (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+}
+
+/// Length counter shared by all channels, disable channel when it reaches 0
+#[derive(Default,Clone)]
+struct LengthCounter<const MAX: u16> {
+    counter: u16,
+    enabled: bool,
+}
+
+impl<const MAX: u16> LengthCounter<MAX> {
+    /// load length from NRx1
+    fn load(&mut self, length: u16) {
+        self.counter = MAX - length;
+    }
+
+    /// clock by frame sequencer, return true if channel should be disabled
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            self.counter == 0
+        } else {
+            false
+        }
+    }
+
+    /// Write enable and trigger bits of NRx4, return true if channel should be disabled.
+    /// When next step of frame sequencer does not clock length (extra_clock):
+    /// enabling length clocks it once immediately, and trigger reloads MAX - 1 instead of MAX.
+    fn write(&mut self, enable: bool, trigger: bool, extra_clock: bool) -> bool {
+        let was_enabled = self.enabled;
+        self.enabled = enable;
+        let mut disable = false;
+        if !was_enabled && enable && extra_clock {
+            disable = self.clock();
+        }
+        if trigger && self.counter == 0 {
+            self.counter = MAX;
+            if enable && extra_clock {
+                self.counter -= 1;
+            }
+        }
+        disable && !trigger
+    }
+}
+
+#[derive(Default,Clone)]
+struct Envelope {
+    volume: u8,
+    increase: bool,
+    period: u8,
+    timer: u8,
+    /// volume reached 0 or 15, no more automatic update
+    stopped: bool,
+}
+
+impl Envelope {
+    fn trigger(&mut self, nrx2: u8) {
+        self.volume = nrx2 >> 4;
+        self.increase = nrx2 & 0x08 != 0;
+        self.period = nrx2 & 0x07;
+        self.timer = self.period;
+        self.stopped = false;
+    }
+
+    fn clock(&mut self) {
+        if self.period == 0 || self.stopped {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            } else {
+                self.stopped = true;
+            }
+        }
+    }
+
+    /// "zombie mode", writing NRx2 while channel is on changes volume:
+    /// +1 if old period is 0 and envelope is still running, else +2 in subtract mode,
+    /// then volume becomes 16 - volume if direction changed, keeping low 4 bits.
+    fn zombie(&mut self, old: u8, new: u8) {
+        let old_increase = old & 0x08 != 0;
+        let new_increase = new & 0x08 != 0;
+        if old & 0x07 == 0 && !self.stopped {
+            self.volume += 1;
+        } else if !old_increase {
+            self.volume += 2;
+        }
+        if old_increase != new_increase {
+            self.volume = 16u8.wrapping_sub(self.volume);
+            self.increase = new_increase;
+        }
+        self.volume &= 0x0f;
+    }
+}
+
+/// square channel, sweep is only used by channel 1
+#[derive(Default,Clone)]
+struct Square {
+    enabled: bool,
+    dac: bool,
+    duty: u8,
+    step: u8,
+    frequency: u16,
+    timer: u64,
+    length: LengthCounter<64>,
+    envelope: Envelope,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_timer: u8,
+    sweep_enabled: bool,
+    shadow: u16,
+}
+
+impl Square {
+    fn update(&mut self, clock: u64) {
+        let period = (2048 - self.frequency as u64) * 4;
+        self.timer += clock;
+        while self.timer >= period {
+            self.timer -= period;
+            self.step = (self.step + 1) % 8;
+        }
+    }
+
+    fn trigger(&mut self, nrx2: u8) {
+        self.enabled = self.dac;
+        self.timer = 0;
+        self.envelope.trigger(nrx2);
+        self.shadow = self.frequency;
+        self.sweep_timer = if self.sweep_period == 0 { 8 } else { self.sweep_period };
+        self.sweep_enabled = self.sweep_period != 0 || self.sweep_shift != 0;
+        if self.sweep_shift != 0 {
+            self.sweep_frequency();
+        }
+    }
+
+    // next frequency of sweep, disable channel on overflow
+    fn sweep_frequency(&mut self) -> u16 {
+        let delta = self.shadow >> self.sweep_shift;
+        let frequency = if self.sweep_negate {
+            self.shadow.wrapping_sub(delta)
+        } else {
+            self.shadow + delta
+        };
+        if frequency > 2047 {
+            self.enabled = false;
+        }
+        frequency
+    }
+
+    fn clock_sweep(&mut self) {
+        self.sweep_timer = self.sweep_timer.saturating_sub(1);
+        if self.sweep_timer != 0 {
+            return;
+        }
+        self.sweep_timer = if self.sweep_period == 0 { 8 } else { self.sweep_period };
+        if self.sweep_enabled && self.sweep_period != 0 {
+            let frequency = self.sweep_frequency();
+            if frequency <= 2047 && self.sweep_shift != 0 {
+                self.shadow = frequency;
+                self.frequency = frequency;
+                self.sweep_frequency();
+            }
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && DUTY[self.duty as usize] & (1 << self.step) != 0 {
+            self.envelope.volume
+        } else {
+            0
+        }
+    }
+}
+
+#[derive(Default,Clone)]
+struct Wave {
+    enabled: bool,
+    dac: bool,
+    volume_code: u8,
+    frequency: u16,
+    timer: u64,
+    length: LengthCounter<256>,
+    /// index of 4-bit sample playing
+    position: usize,
+    ram: [u8; 16],
+}
+
+impl Wave {
+    fn update(&mut self, clock: u64) {
+        let period = (2048 - self.frequency as u64) * 2;
+        self.timer += clock;
+        while self.timer >= period {
+            self.timer -= period;
+            self.position = (self.position + 1) % 32;
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac;
+        self.timer = 0;
+        self.position = 0;
+    }
+
+    fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    // While playing, CPU accesses the byte being played instead of index.
+    // CGB always allows it, DMG only right after the channel read the byte.
+    fn ram_index(&self, index: usize, cgb_mode: bool) -> Option<usize> {
+        if !self.enabled {
+            Some(index)
+        } else if cgb_mode || self.timer < WAVE_ACCESS_WINDOW {
+            Some(self.position / 2)
+        } else {
+            None
+        }
+    }
+
+    fn load_ram(&self, index: usize, cgb_mode: bool) -> u8 {
+        self.ram_index(index, cgb_mode).map_or(0xff, |index| self.ram[index])
+    }
+
+    fn store_ram(&mut self, index: usize, value: u8, cgb_mode: bool) {
+        if let Some(index) = self.ram_index(index, cgb_mode) {
+            self.ram[index] = value;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled || self.volume_code == 0 {
+            return 0;
+        }
+        let byte = self.ram[self.position / 2];
+        let sample = if self.position.is_multiple_of(2) { byte >> 4 } else { byte & 0x0f };
+        sample >> (self.volume_code - 1)
+    }
+}
+
+#[derive(Default,Clone)]
+struct Noise {
+    enabled: bool,
+    dac: bool,
+    clock_shift: u8,
+    width7: bool,
+    divisor_code: u8,
+    lfsr: u16,
+    timer: u64,
+    length: LengthCounter<64>,
+    envelope: Envelope,
+}
+
+impl Noise {
+    fn update(&mut self, clock: u64) {
+        let period = NOISE_DIVISOR[self.divisor_code as usize] << self.clock_shift;
+        self.timer += clock;
+        while self.timer >= period {
+            self.timer -= period;
+            let bit = (self.lfsr & 0x1) ^ ((self.lfsr >> 1) & 0x1);
+            self.lfsr = (self.lfsr >> 1) | (bit << 14);
+            if self.width7 {
+                self.lfsr = (self.lfsr & !0x40) | (bit << 6);
+            }
+        }
+    }
+
+    fn trigger(&mut self, nrx2: u8) {
+        self.enabled = self.dac;
+        self.timer = 0;
+        self.lfsr = 0x7fff;
+        self.envelope.trigger(nrx2);
+    }
+
+    fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && self.lfsr & 0x1 == 0 {
+            self.envelope.volume
+        } else {
+            0
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Apu {
+    /// NR52 bit 7, all sound off when false
+    power: bool,
+    /// CGB behavior of wave RAM access
+    cgb_mode: bool,
+    /// register values written by CPU, 0xff10-0xff2f
+    regs: [u8; 0x20],
+    square1: Square,
+    square2: Square,
+    wave: Wave,
+    noise: Noise,
+    /// step of frame sequencer, 0 to 7
+    sequencer_step: u8,
+    sequencer_clock: u64,
+    /// mixed output of left and right, and each channel
+    output: [f32; 6],
+    left: Resampler,
+    right: Resampler,
+    /// each channel before mixing, captured only when enabled
+    channels: Option<Vec<Resampler>>,
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        Self {
+            power: true,
+            cgb_mode: false,
+            regs: [0; 0x20],
+            square1: Default::default(),
+            square2: Default::default(),
+            wave: Default::default(),
+            noise: Default::default(),
+            sequencer_step: 0,
+            sequencer_clock: 0,
+            output: [0.0; 6],
+            left: Resampler::new(CLOCK_RATE, SAMPLE_RATE),
+            right: Resampler::new(CLOCK_RATE, SAMPLE_RATE),
+            channels: None,
+        }
+    }
+
+    pub fn set_cgb_mode(&mut self, enable: bool) {
+        self.cgb_mode = enable;
+    }
+
+    /// Channel 1 is left on by the ding of boot ROM, its envelope faded out
+    pub fn finish_boot_sound(&mut self) {
+        self.square1.enabled = self.square1.dac;
+        self.square1.envelope.volume = 0;
+        self.square1.envelope.stopped = true;
+    }
+
+    pub fn update(&mut self, clock: u64) {
+        if self.power {
+            self.square1.update(clock);
+            self.square2.update(clock);
+            self.wave.update(clock);
+            self.noise.update(clock);
+
+            self.sequencer_clock += clock;
+            while self.sequencer_clock >= FRAME_SEQUENCER_CLOCK {
+                self.sequencer_clock -= FRAME_SEQUENCER_CLOCK;
+                self.clock_sequencer();
+            }
+        }
+
+        let output = self.mix();
+        let resamplers = std::iter::once(&mut self.left)
+                            .chain(std::iter::once(&mut self.right))
+                            .chain(self.channels.iter_mut().flatten());
+        for (i, resampler) in resamplers.enumerate() {
+            resampler.advance(clock);
+            if output[i] != self.output[i] {
+                resampler.add_delta(output[i] - self.output[i]);
+            }
+        }
+        self.output = output;
+    }
+
+    // length on step 0, 2, 4, 6, sweep on step 2, 6, envelope on step 7
+    fn clock_sequencer(&mut self) {
+        if self.sequencer_step.is_multiple_of(2) {
+            self.square1.clock_length();
+            self.square2.clock_length();
+            self.wave.clock_length();
+            self.noise.clock_length();
+        }
+        if self.sequencer_step == 2 || self.sequencer_step == 6 {
+            self.square1.clock_sweep();
+        }
+        if self.sequencer_step == 7 {
+            self.square1.envelope.clock();
+            self.square2.envelope.clock();
+            self.noise.envelope.clock();
+        }
+        self.sequencer_step = (self.sequencer_step + 1) % 8;
+    }
+
+    // mixed output of left, right then channel 1 to 4, in range 0 to 1
+    fn mix(&self) -> [f32; 6] {
+        let outputs = [
+            (self.square1.dac, self.square1.output()),
+            (self.square2.dac, self.square2.output()),
+            (self.wave.dac, self.wave.output()),
+            (self.noise.dac, self.noise.output()),
+        ];
+        let panning = self.regs[(NR51 - APU_START) as usize];
+        let volume = self.regs[(NR50 - APU_START) as usize];
+        let mut mixed = [0.0; 6];
+        for (ch, &(dac, output)) in outputs.iter().enumerate() {
+            let analog = if dac && self.power { output as f32 / 15.0 } else { 0.0 };
+            if panning & (0x10 << ch) != 0 {
+                mixed[0] += analog;
+            }
+            if panning & (0x01 << ch) != 0 {
+                mixed[1] += analog;
+            }
+            mixed[ch + 2] = analog;
+        }
+        mixed[0] *= (((volume >> 4) & 0x7) + 1) as f32 / 32.0;
+        mixed[1] *= ((volume & 0x7) + 1) as f32 / 32.0;
+        mixed
+    }
+
+    /// Take mixed stereo samples at SAMPLE_RATE, interleaved left and right
+    pub fn take_samples(&mut self) -> Vec<i16> {
+        let left = self.left.read();
+        let right = self.right.read();
+        left.iter()
+            .zip(right.iter())
+            .flat_map(|(&left, &right)| [to_i16(left), to_i16(right)])
+            .collect()
+    }
+
+    /// Scale output sample rate to keep audio buffer from underrun or overrun,
+    /// see resampler::rate_adjust
+    pub fn set_rate_adjust(&mut self, adjust: f64) {
+        let resamplers = std::iter::once(&mut self.left)
+                            .chain(std::iter::once(&mut self.right))
+                            .chain(self.channels.iter_mut().flatten());
+        resamplers.for_each(|resampler| resampler.set_rate_adjust(adjust));
+    }
+
+    /// Capture mono samples of each channel before mixing, for debugging
+    pub fn set_channel_capture(&mut self, enable: bool) {
+        self.channels = if enable {
+            Some((0..4).map(|_| Resampler::new(CLOCK_RATE, SAMPLE_RATE)).collect())
+        } else {
+            None
+        };
+    }
+
+    /// Take captured samples of channel 1 to 4
+    pub fn take_channel_samples(&mut self) -> Option<Vec<Vec<i16>>> {
+        self.channels.as_mut().map(|channels| {
+            channels.iter_mut()
+                    .map(|resampler| resampler.read().into_iter().map(to_i16).collect())
+                    .collect()
+        })
+    }
+
+    fn channel_status(&self) -> u8 {
+        (self.square1.enabled as u8) |
+            (self.square2.enabled as u8) << 1 |
+            (self.wave.enabled as u8) << 2 |
+            (self.noise.enabled as u8) << 3
+    }
+
+    fn power_off(&mut self) {
+        self.power = false;
+        self.regs = [0; 0x20];
+        let ram = self.wave.ram;
+        self.square1 = Default::default();
+        self.square2 = Default::default();
+        self.wave = Wave { ram, ..Default::default() };
+        self.noise = Default::default();
+        self.sequencer_step = 0;
+    }
+
+    fn store_register(&mut self, addr: u16, value: u8) {
+        let old = self.regs[(addr - APU_START) as usize];
+        self.regs[(addr - APU_START) as usize] = value;
+        let nr12 = self.regs[(NR12 - APU_START) as usize];
+        let nr22 = self.regs[(NR22 - APU_START) as usize];
+        let nr42 = self.regs[(NR42 - APU_START) as usize];
+        // next step of frame sequencer does not clock length
+        let extra_clock = self.sequencer_step % 2 == 1;
+        match addr {
+            NR10 => {
+                self.square1.sweep_period = (value >> 4) & 0x7;
+                self.square1.sweep_negate = value & 0x08 != 0;
+                self.square1.sweep_shift = value & 0x7;
+            }
+            NR11 => {
+                self.square1.duty = value >> 6;
+                self.square1.length.load((value & 0x3f) as u16);
+            }
+            NR12 => {
+                if self.square1.enabled {
+                    self.square1.envelope.zombie(old, value);
+                }
+                self.square1.dac = value & 0xf8 != 0;
+                self.square1.enabled &= self.square1.dac;
+            }
+            NR13 => self.square1.frequency = (self.square1.frequency & 0x700) | value as u16,
+            NR14 => {
+                self.square1.frequency = (self.square1.frequency & 0xff) | ((value & 0x7) as u16) << 8;
+                let trigger = value & 0x80 != 0;
+                if self.square1.length.write(value & 0x40 != 0, trigger, extra_clock) {
+                    self.square1.enabled = false;
+                }
+                if trigger {
+                    self.square1.trigger(nr12);
+                }
+            }
+            NR21 => {
+                self.square2.duty = value >> 6;
+                self.square2.length.load((value & 0x3f) as u16);
+            }
+            NR22 => {
+                if self.square2.enabled {
+                    self.square2.envelope.zombie(old, value);
+                }
+                self.square2.dac = value & 0xf8 != 0;
+                self.square2.enabled &= self.square2.dac;
+            }
+            NR23 => self.square2.frequency = (self.square2.frequency & 0x700) | value as u16,
+            NR24 => {
+                self.square2.frequency = (self.square2.frequency & 0xff) | ((value & 0x7) as u16) << 8;
+                let trigger = value & 0x80 != 0;
+                if self.square2.length.write(value & 0x40 != 0, trigger, extra_clock) {
+                    self.square2.enabled = false;
+                }
+                if trigger {
+                    self.square2.trigger(nr22);
+                }
+            }
+            NR30 => {
+                self.wave.dac = value & 0x80 != 0;
+                self.wave.enabled &= self.wave.dac;
+            }
+            NR31 => self.wave.length.load(value as u16),
+            NR32 => self.wave.volume_code = (value >> 5) & 0x3,
+            NR33 => self.wave.frequency = (self.wave.frequency & 0x700) | value as u16,
+            NR34 => {
+                self.wave.frequency = (self.wave.frequency & 0xff) | ((value & 0x7) as u16) << 8;
+                let trigger = value & 0x80 != 0;
+                if self.wave.length.write(value & 0x40 != 0, trigger, extra_clock) {
+                    self.wave.enabled = false;
+                }
+                if trigger {
+                    self.wave.trigger();
+                }
+            }
+            NR41 => self.noise.length.load((value & 0x3f) as u16),
+            NR42 => {
+                if self.noise.enabled {
+                    self.noise.envelope.zombie(old, value);
+                }
+                self.noise.dac = value & 0xf8 != 0;
+                self.noise.enabled &= self.noise.dac;
+            }
+            NR43 => {
+                self.noise.clock_shift = value >> 4;
+                self.noise.width7 = value & 0x08 != 0;
+                self.noise.divisor_code = value & 0x7;
+            }
+            NR44 => {
+                let trigger = value & 0x80 != 0;
+                if self.noise.length.write(value & 0x40 != 0, trigger, extra_clock) {
+                    self.noise.enabled = false;
+                }
+                if trigger {
+                    self.noise.trigger(nr42);
+                }
+            }
+            _ => {},
+        }
+    }
+}
+
+impl Device for Apu {
+    fn load(&self, addr: u16) -> Result<u8, ()> {
+        match addr {
+            WAVE_START ..= APU_END => Ok(self.wave.load_ram((addr - WAVE_START) as usize, self.cgb_mode)),
+            NR52 => Ok(0x70 | (self.power as u8) << 7 | self.channel_status()),
+            APU_START ..= APU_END => {
+                let idx = (addr - APU_START) as usize;
+                Ok(self.regs[idx] | READ_MASK[idx])
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn store(&mut self, addr: u16, value: u8) -> Result<(), ()> {
+        match addr {
+            WAVE_START ..= APU_END => self.wave.store_ram((addr - WAVE_START) as usize, value, self.cgb_mode),
+            NR52 => {
+                if value & 0x80 == 0 {
+                    self.power_off();
+                } else if !self.power {
+                    self.power = true;
+                }
+            }
+            // registers are not writable while powered off,
+            // except length counters on DMG
+            APU_START ..= APU_END if !self.power => if !self.cgb_mode {
+                match addr {
+                    NR11 => self.square1.length.load((value & 0x3f) as u16),
+                    NR21 => self.square2.length.load((value & 0x3f) as u16),
+                    NR31 => self.wave.length.load(value as u16),
+                    NR41 => self.noise.length.load((value & 0x3f) as u16),
+                    _ => {},
+                }
+            },
+            APU_START ..= APU_END => self.store_register(addr, value),
+            _ => return Err(()),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // wave RAM filled with index * 0x11, channel 3 playing byte 2 at 512 clocks per sample
+    fn playing_wave(cgb_mode: bool) -> Apu {
+        let mut apu = Apu::new();
+        apu.set_cgb_mode(cgb_mode);
+        apu.store(NR52, 0x80).unwrap();
+        for i in 0..16 {
+            apu.store(WAVE_START + i, i as u8 * 0x11).unwrap();
+        }
+        apu.store(NR30, 0x80).unwrap();
+        apu.store(NR33, 0x00).unwrap();
+        apu.store(NR34, 0x87).unwrap();
+        apu.update(512 * 4);
+        apu
+    }
+
+    #[test]
+    fn wave_ram_off_is_plain_memory() {
+        let mut apu = Apu::new();
+        apu.store(WAVE_START + 5, 0x5a).unwrap();
+        assert_eq!(apu.load(WAVE_START + 5), Ok(0x5a));
+    }
+
+    #[test]
+    fn wave_ram_playing_on_dmg() {
+        let mut apu = playing_wave(false);
+        // just read by the channel, any address gets the playing byte
+        assert_eq!(apu.load(WAVE_START + 15), Ok(0x22));
+        apu.update(100);
+        assert_eq!(apu.load(WAVE_START + 15), Ok(0xff));
+        apu.store(WAVE_START + 15, 0x00).unwrap();
+        apu.store(NR30, 0x00).unwrap();
+        assert_eq!(apu.load(WAVE_START + 2), Ok(0x22));
+        assert_eq!(apu.load(WAVE_START + 15), Ok(0xff));
+    }
+
+    #[test]
+    fn wave_ram_playing_on_cgb() {
+        let mut apu = playing_wave(true);
+        apu.update(100);
+        assert_eq!(apu.load(WAVE_START + 15), Ok(0x22));
+        apu.store(WAVE_START + 15, 0x99).unwrap();
+        apu.store(NR30, 0x00).unwrap();
+        assert_eq!(apu.load(WAVE_START + 2), Ok(0x99));
+        assert_eq!(apu.load(WAVE_START + 15), Ok(0xff));
+    }
+
+    fn envelope(nrx2: u8) -> Envelope {
+        let mut envelope = Envelope::default();
+        envelope.trigger(nrx2);
+        envelope
+    }
+
+    #[test]
+    fn zombie_write_slides_volume() {
+        // period 0 adds 1 on every write, used for volume slides
+        let mut env = envelope(0x80);
+        let volumes: Vec<u8> = (0..3).map(|_| { env.zombie(0x80, 0x80); env.volume }).collect();
+        assert_eq!(volumes, [9, 10, 11]);
+        // period not 0 in subtract mode adds 2
+        let mut env = envelope(0x81);
+        env.zombie(0x81, 0x81);
+        assert_eq!(env.volume, 10);
+        // adding past 15 wraps
+        let mut env = envelope(0xf0);
+        env.zombie(0xf0, 0xf0);
+        assert_eq!(env.volume, 0);
+    }
+
+    #[test]
+    fn zombie_direction_change_inverts_volume() {
+        let mut env = envelope(0x88);
+        env.zombie(0x88, 0x80);
+        // 8 + 1, then 16 - 9
+        assert_eq!(env.volume, 7);
+        assert!(!env.increase);
+    }
+
+    #[test]
+    fn dac_off_disables_channel() {
+        let mut apu = Apu::new();
+        apu.store(NR52, 0x80).unwrap();
+        apu.store(NR12, 0x08).unwrap();
+        apu.store(NR14, 0x80).unwrap();
+        assert_eq!(apu.load(NR52).unwrap() & 0x01, 0x01);
+        // volume 0 in subtract mode turns DAC off
+        apu.store(NR12, 0x00).unwrap();
+        assert_eq!(apu.load(NR52).unwrap() & 0x01, 0x00);
+        apu.store(NR14, 0x80).unwrap();
+        assert_eq!(apu.load(NR52).unwrap() & 0x01, 0x00);
+    }
+
+    #[test]
+    fn length_counts_only_when_enabled() {
+        let mut length = LengthCounter::<64>::default();
+        length.load(62);
+        assert!(!length.clock());
+        assert_eq!(length.counter, 2);
+        length.write(true, false, false);
+        assert!(!length.clock());
+        assert!(length.clock());
+        // stays at 0, channel is disabled only once
+        assert!(!length.clock());
+    }
+
+    #[test]
+    fn length_trigger_reloads_zero_to_max() {
+        let mut length = LengthCounter::<64>::default();
+        assert!(!length.write(false, true, false));
+        assert_eq!(length.counter, 64);
+        // non zero length is kept
+        length.load(60);
+        length.write(false, true, false);
+        assert_eq!(length.counter, 4);
+    }
+
+    #[test]
+    fn length_enable_in_first_half_clocks_once() {
+        let mut length = LengthCounter::<256>::default();
+        length.load(254);
+        assert!(!length.write(true, false, true));
+        assert_eq!(length.counter, 1);
+        // enabled again, no extra clock
+        assert!(!length.write(true, false, true));
+        assert_eq!(length.counter, 1);
+        // reaching 0 by the extra clock disables channel
+        length.write(false, false, false);
+        assert!(length.write(true, false, true));
+        assert_eq!(length.counter, 0);
+    }
+
+    #[test]
+    fn length_trigger_with_enable_in_first_half_reloads_max_minus_one() {
+        let mut length = LengthCounter::<64>::default();
+        length.load(63);
+        // extra clock reaches 0, trigger keeps channel on with 63
+        assert!(!length.write(true, true, true));
+        assert_eq!(length.counter, 63);
+        let mut length = LengthCounter::<64>::default();
+        assert!(!length.write(false, true, true));
+        assert_eq!(length.counter, 64);
+    }
+
+    #[test]
+    fn registers_ignored_while_off() {
+        let mut apu = Apu::new();
+        apu.store(NR52, 0x80).unwrap();
+        apu.store(NR12, 0xf3).unwrap();
+        assert_eq!(apu.load(NR12), Ok(0xf3));
+        // power off clears registers and ignores writes
+        apu.store(NR52, 0x00).unwrap();
+        assert_eq!(apu.load(NR12), Ok(0x00));
+        apu.store(NR12, 0xf3).unwrap();
+        assert_eq!(apu.load(NR12), Ok(0x00));
+        apu.store(NR52, 0x80).unwrap();
+        assert_eq!(apu.load(NR12), Ok(0x00));
+        assert_eq!(apu.load(NR52), Ok(0xf0));
+    }
+}
@@ -0,0 +1,329 @@
+/*
+ * Tiny assembler to write test programs readable, e.g.
+ *
+ * let program = asm![LD B, 0x10; INC B; LD (HL), B; JR NZ, -2];
+ * let program = Asm::new().ld_a_d8(0x42).call(0x1234).halt().build();
+ *
+ * Only common instructions are supported, unsupported one panics.
+ */
+use crate::instruction::{encode as encode_inst, encode_cb, CBInstruction, Condition, Instruction, Target};
+
+#[derive(Debug,Clone,Copy)]
+pub enum Operand {
+    /// register or condition, like B, HL, NZ
+    Reg(&'static str),
+    /// memory pointed by register, like (HL)
+    Ind(&'static str),
+    /// immediate value, address or relative jump offset
+    Imm(i64),
+}
+
+use Operand::*;
+
+fn r8(operand: &Operand) -> Option<u8> {
+    match operand {
+        Reg("B") => Some(0),
+        Reg("C") => Some(1),
+        Reg("D") => Some(2),
+        Reg("E") => Some(3),
+        Reg("H") => Some(4),
+        Reg("L") => Some(5),
+        Ind("HL") => Some(6),
+        Reg("A") => Some(7),
+        _ => None,
+    }
+}
+
+fn r16(operand: &Operand) -> Option<u8> {
+    match operand {
+        Reg("BC") => Some(0),
+        Reg("DE") => Some(1),
+        Reg("HL") => Some(2),
+        Reg("SP") => Some(3),
+        _ => None,
+    }
+}
+
+fn condition(operand: &Operand) -> Option<u8> {
+    match operand {
+        Reg("NZ") => Some(0),
+        Reg("Z") => Some(1),
+        Reg("NC") => Some(2),
+        Reg("C") => Some(3),
+        _ => None,
+    }
+}
+
+fn alu(mnemonic: &str) -> Option<u8> {
+    ["ADD", "ADC", "SUB", "SBC", "AND", "XOR", "OR", "CP"].iter()
+        .position(|&op| op == mnemonic)
+        .map(|op| op as u8)
+}
+
+fn word(opcode: u8, value: i64) -> Vec<u8> {
+    vec![opcode, value as u8, (value >> 8) as u8]
+}
+
+/// Encode one instruction into bytes
+pub fn encode(mnemonic: &str, operands: &[Operand]) -> Vec<u8> {
+    let implied = match mnemonic {
+        "NOP"  => Some(0x00),
+        "RLCA" => Some(0x07),
+        "RRCA" => Some(0x0f),
+        "RLA"  => Some(0x17),
+        "RRA"  => Some(0x1f),
+        "DAA"  => Some(0x27),
+        "CPL"  => Some(0x2f),
+        "SCF"  => Some(0x37),
+        "CCF"  => Some(0x3f),
+        "HALT" => Some(0x76),
+        "RET"  => Some(0xc9),
+        "RETI" => Some(0xd9),
+        "DI"   => Some(0xf3),
+        "EI"   => Some(0xfb),
+        _ => None,
+    };
+    if let (Some(opcode), []) = (implied, operands) {
+        return vec![opcode];
+    }
+
+    // ALU accepts both ADD A, B and ADD B
+    if let Some(op) = alu(mnemonic) {
+        let source = match operands {
+            [Reg("A"), source] | [source] => Some(source),
+            _ => None,
+        };
+        match source {
+            Some(&Imm(n)) => return vec![0xc6 | op << 3, n as u8],
+            Some(source) => if let Some(s) = r8(source) {
+                return vec![0x80 | op << 3 | s];
+            },
+            None => {},
+        }
+    }
+
+    let bytes = match (mnemonic, operands) {
+        ("LD", [dst, Imm(n)]) => match (r8(dst), r16(dst)) {
+            (Some(d), _) => Some(vec![0x06 | d << 3, *n as u8]),
+            (_, Some(rr)) => Some(word(0x01 | rr << 4, *n)),
+            _ => None,
+        },
+        ("LD", [dst, src]) => match (r8(dst), r8(src)) {
+            (Some(6), Some(6)) => None,
+            (Some(d), Some(s)) => Some(vec![0x40 | d << 3 | s]),
+            _ => None,
+        },
+        ("INC", [r]) | ("DEC", [r]) => {
+            let dec = mnemonic == "DEC";
+            match (r8(r), r16(r)) {
+                (Some(r), _) => Some(vec![0x04 | r << 3 | dec as u8]),
+                (_, Some(rr)) => Some(vec![0x03 | rr << 4 | (dec as u8) << 3]),
+                _ => None,
+            }
+        },
+        ("ADD", [Reg("HL"), rr]) => r16(rr).map(|rr| vec![0x09 | rr << 4]),
+        ("JR", [Imm(e)]) => Some(vec![0x18, *e as u8]),
+        ("JR", [cc, Imm(e)]) => condition(cc).map(|cc| vec![0x20 | cc << 3, *e as u8]),
+        ("JP", [Reg("HL")]) => Some(vec![0xe9]),
+        ("JP", [Imm(nn)]) => Some(word(0xc3, *nn)),
+        ("JP", [cc, Imm(nn)]) => condition(cc).map(|cc| word(0xc2 | cc << 3, *nn)),
+        ("CALL", [Imm(nn)]) => Some(word(0xcd, *nn)),
+        ("CALL", [cc, Imm(nn)]) => condition(cc).map(|cc| word(0xc4 | cc << 3, *nn)),
+        ("RET", [cc]) => condition(cc).map(|cc| vec![0xc0 | cc << 3]),
+        ("RST", [Imm(n)]) if n % 8 == 0 && *n < 0x40 => Some(vec![0xc7 | *n as u8]),
+        ("PUSH", [Reg("AF")]) => Some(vec![0xf5]),
+        ("POP", [Reg("AF")]) => Some(vec![0xf1]),
+        ("PUSH", [rr]) => r16(rr).filter(|&rr| rr != 3).map(|rr| vec![0xc5 | rr << 4]),
+        ("POP", [rr]) => r16(rr).filter(|&rr| rr != 3).map(|rr| vec![0xc1 | rr << 4]),
+        _ => None,
+    };
+    bytes.unwrap_or_else(|| panic!("asm: unsupported instruction {} {:?}", mnemonic, operands))
+}
+
+/// Builder of test program from decoded instructions
+#[derive(Debug,Default,Clone)]
+pub struct Asm {
+    bytes: Vec<u8>,
+}
+
+impl Asm {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append any instruction with its operand bytes
+    pub fn inst(mut self, inst: Instruction, operands: &[u8]) -> Self {
+        self.bytes.extend(encode_inst(&inst, operands));
+        self
+    }
+
+    pub fn cb(mut self, inst: CBInstruction) -> Self {
+        self.bytes.extend(encode_cb(&inst));
+        self
+    }
+
+    /// Append raw bytes, like data or invalid opcodes
+    pub fn bytes(mut self, bytes: &[u8]) -> Self {
+        self.bytes.extend_from_slice(bytes);
+        self
+    }
+
+    pub fn nop(self) -> Self {
+        self.inst(Instruction::NOP, &[])
+    }
+
+    pub fn halt(self) -> Self {
+        self.inst(Instruction::HALT, &[])
+    }
+
+    pub fn di(self) -> Self {
+        self.inst(Instruction::DI, &[])
+    }
+
+    pub fn ei(self) -> Self {
+        self.inst(Instruction::EI, &[])
+    }
+
+    /// LD r,d8, (HL) is Target::HL
+    pub fn ld_d8(self, target: Target, value: u8) -> Self {
+        self.inst(Instruction::LDIMM8(target), &[value])
+    }
+
+    pub fn ld_a_d8(self, value: u8) -> Self {
+        self.ld_d8(Target::A, value)
+    }
+
+    /// LD rr,d16
+    pub fn ld_d16(self, target: Target, value: u16) -> Self {
+        self.inst(Instruction::LDIMM16(target), &value.to_le_bytes())
+    }
+
+    /// LDH (a8),A
+    pub fn ldh_a8_a(self, offset: u8) -> Self {
+        self.inst(Instruction::LD8A, &[offset])
+    }
+
+    /// LDH A,(a8)
+    pub fn ldh_a_a8(self, offset: u8) -> Self {
+        self.inst(Instruction::LDA8, &[offset])
+    }
+
+    pub fn jp(self, addr: u16) -> Self {
+        self.inst(Instruction::JP(Condition::Always), &addr.to_le_bytes())
+    }
+
+    /// JR with offset from the next instruction
+    pub fn jr(self, offset: i8) -> Self {
+        self.inst(Instruction::JR(Condition::Always), &[offset as u8])
+    }
+
+    pub fn jr_if(self, condition: Condition, offset: i8) -> Self {
+        self.inst(Instruction::JR(condition), &[offset as u8])
+    }
+
+    pub fn call(self, addr: u16) -> Self {
+        self.inst(Instruction::CALL(Condition::Always), &addr.to_le_bytes())
+    }
+
+    pub fn ret(self) -> Self {
+        self.inst(Instruction::RET(Condition::Always), &[])
+    }
+
+    /// Current length, address of next instruction relative to program start
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn build(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+/// Assemble instructions separated by ; into Vec<u8>
+#[macro_export]
+macro_rules! asm {
+    ($($body:tt)*) => {{
+        let mut bytes: Vec<u8> = Vec::new();
+        $crate::asm_inner!(bytes; $($body)* ;);
+        bytes
+    }};
+}
+
+/// Consume one instruction at a time, used by asm!
+#[doc(hidden)]
+#[macro_export]
+macro_rules! asm_inner {
+    ($b:ident;) => {};
+    ($b:ident; ; $($rest:tt)*) => {
+        $crate::asm_inner!($b; $($rest)*);
+    };
+    ($b:ident; $m:ident ; $($rest:tt)*) => {
+        $b.extend($crate::asm::encode(stringify!($m), &[]));
+        $crate::asm_inner!($b; $($rest)*);
+    };
+    ($b:ident; $m:ident $o:ident ; $($rest:tt)*) => {
+        $b.extend($crate::asm::encode(stringify!($m), &[$crate::asm_operand!($o)]));
+        $crate::asm_inner!($b; $($rest)*);
+    };
+    ($b:ident; $m:ident $o1:tt , $o2:tt ; $($rest:tt)*) => {
+        $b.extend($crate::asm::encode(stringify!($m),
+                  &[$crate::asm_operand!($o1), $crate::asm_operand!($o2)]));
+        $crate::asm_inner!($b; $($rest)*);
+    };
+    ($b:ident; $m:ident $o1:tt , $o2:expr ; $($rest:tt)*) => {
+        $b.extend($crate::asm::encode(stringify!($m),
+                  &[$crate::asm_operand!($o1), $crate::asm::Operand::Imm($o2)]));
+        $crate::asm_inner!($b; $($rest)*);
+    };
+    ($b:ident; $m:ident $o:expr ; $($rest:tt)*) => {
+        $b.extend($crate::asm::encode(stringify!($m), &[$crate::asm::Operand::Imm($o)]));
+        $crate::asm_inner!($b; $($rest)*);
+    };
+}
+
+/// Convert single token into operand, used by asm!
+#[doc(hidden)]
+#[macro_export]
+macro_rules! asm_operand {
+    (($r:ident)) => { $crate::asm::Operand::Ind(stringify!($r)) };
+    ($r:ident) => { $crate::asm::Operand::Reg(stringify!($r)) };
+    ($n:tt) => { $crate::asm::Operand::Imm($n) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu;
+
+    // run program at 0x100 until HALT
+    fn run(program: &[u8]) -> Cpu {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + program.len()].copy_from_slice(program);
+        let mut cpu = Cpu::new(rom);
+        while !cpu.is_halted() {
+            cpu.step().unwrap();
+        }
+        cpu
+    }
+
+    #[test]
+    fn macro_program_sums_counter() {
+        let cpu = run(&crate::asm![LD B, 0x10; LD A, 0; ADD A, B; DEC B; JR NZ, -4; HALT]);
+        assert_eq!(cpu.trace_entry().af >> 8, 136);
+    }
+
+    #[test]
+    fn builder_program_calls_subroutine() {
+        // 0x100: CALL 0x0106; LDH (0x80), A; HALT; 0x106: LD A, 0x42; RET
+        let program = Asm::new().call(0x0106).ldh_a8_a(0x80).halt()
+                                .ld_a_d8(0x42).ret()
+                                .build();
+        assert_eq!(program.len(), 9);
+        let cpu = run(&program);
+        assert_eq!(cpu.bus.load8(0xff80), Ok(0x42));
+    }
+}
@@ -0,0 +1,156 @@
+/*
+ * Queue of samples between emulation and the audio device.
+ *
+ * Emulation pushes samples once per frame, paced by the display clock, and
+ * the device pops them at its own sample rate. The two clocks drift apart, so
+ * the fill level is fed back to the resampler of APU, see rate_adjust, to
+ * keep the queue about half full without underrun or overrun.
+ */
+use crate::apu::Apu;
+use crate::resampler::rate_adjust;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+#[derive(Default)]
+struct Queue {
+    samples: VecDeque<i16>,
+    /// device plays, set when half full and cleared on underrun
+    playing: bool,
+    underruns: u64,
+}
+
+/// Interleaved stereo samples, cloned to share between emulation and device
+#[derive(Clone)]
+pub struct AudioQueue {
+    queue: Arc<Mutex<Queue>>,
+    capacity: usize,
+}
+
+impl AudioQueue {
+    /// Queue holding at most capacity samples
+    pub fn new(capacity: usize) -> Self {
+        Self { queue: Arc::new(Mutex::new(Queue::default())), capacity }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Samples waiting to be played
+    pub fn len(&self) -> usize {
+        self.queue.lock().unwrap().samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Times the device found the queue empty while playing
+    pub fn underruns(&self) -> u64 {
+        self.queue.lock().unwrap().underruns
+    }
+
+    /// Add samples, the ones not fitting in capacity are dropped
+    pub fn push(&self, samples: &[i16]) {
+        let mut queue = self.queue.lock().unwrap();
+        let room = self.capacity - queue.samples.len();
+        queue.samples.extend(samples.iter().take(room));
+    }
+
+    /// Fill output from the queue, silence if not enough samples.
+    /// After an underrun nothing is played until the queue is half full again.
+    pub fn pop(&self, output: &mut [i16]) {
+        let mut queue = self.queue.lock().unwrap();
+        if !queue.playing && queue.samples.len() >= self.capacity / 2 {
+            queue.playing = true;
+        }
+        let count = if queue.playing { output.len().min(queue.samples.len()) } else { 0 };
+        for (out, sample) in output.iter_mut().zip(queue.samples.drain(..count)) {
+            *out = sample;
+        }
+        output[count..].iter_mut().for_each(|out| *out = 0);
+        if queue.playing && count < output.len() {
+            queue.playing = false;
+            queue.underruns += 1;
+        }
+    }
+
+    /// Move samples of APU to the queue, then adjust output rate of APU by fill level
+    pub fn feed(&self, apu: &mut Apu) {
+        self.push(&apu.take_samples());
+        apu.set_rate_adjust(rate_adjust(self.len(), self.capacity));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apu::SAMPLE_RATE;
+    use crate::bus::Device;
+    use crate::vm::{CLOCK_RATE, CYCLES_PER_FRAME};
+
+    #[test]
+    fn pop_waits_for_half_full() {
+        let queue = AudioQueue::new(8);
+        let mut output = [1; 4];
+        queue.push(&[5, 6, 7]);
+        queue.pop(&mut output);
+        assert_eq!(output, [0; 4]);
+        queue.push(&[8, 9, 10, 11, 12, 13, 14]);
+        assert_eq!(queue.len(), 8);
+        queue.pop(&mut output);
+        assert_eq!(output, [5, 6, 7, 8]);
+        queue.pop(&mut output[..2]);
+        queue.pop(&mut output);
+        assert_eq!(output, [11, 12, 0, 0]);
+        assert_eq!(queue.underruns(), 1);
+    }
+
+    // emulate APU frames while a device clocked by a display of display_rate Hz
+    // plays each frame, return the queue and its fill level before each play
+    fn simulate(display_rate: f64, frames: usize, feedback: bool) -> (AudioQueue, Vec<usize>) {
+        let mut apu = Apu::new();
+        // silent, channels need not be clocked
+        apu.store(0xff26, 0x00).unwrap();
+        let frame_samples = SAMPLE_RATE * CYCLES_PER_FRAME / CLOCK_RATE;
+        // 8 frames of stereo samples
+        let queue = AudioQueue::new(frame_samples as usize * 2 * 8);
+        let mut played = 0.0;
+        let mut fills = Vec::new();
+        for _ in 0..frames {
+            apu.update(CYCLES_PER_FRAME);
+            if feedback {
+                queue.feed(&mut apu);
+            } else {
+                queue.push(&apu.take_samples());
+            }
+            fills.push(queue.len());
+            played += SAMPLE_RATE as f64 / display_rate;
+            let mut output = vec![0; played as usize * 2];
+            queue.pop(&mut output);
+            played -= (output.len() / 2) as f64;
+        }
+        (queue, fills)
+    }
+
+    #[test]
+    fn fill_stays_in_bounds_with_drifting_device() {
+        // device clock 0.2% faster and slower than the 59.73 Hz of emulation,
+        // 3 minutes are many times the time the feedback takes to settle
+        for &rate in &[59.61, 59.85] {
+            let (queue, fills) = simulate(rate, 60 * 60 * 3, true);
+            assert_eq!(queue.underruns(), 0, "display at {}", rate);
+            let (low, high) = (fills[10..].iter().min().unwrap(), fills.iter().max().unwrap());
+            assert!(*low > queue.capacity() / 10 && *high < queue.capacity() * 9 / 10,
+                    "fill {}-{} of {} at {}", low, high, queue.capacity(), rate);
+        }
+    }
+
+    #[test]
+    fn fixed_rate_drifts_out_of_bounds() {
+        let (queue, _) = simulate(59.61, 60 * 60, false);
+        assert!(queue.underruns() > 0);
+        let (queue, fills) = simulate(59.85, 60 * 60, false);
+        assert_eq!(fills.last(), Some(&queue.capacity()));
+    }
+}
@@ -0,0 +1,77 @@
+use std::io::{self, Write};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use log::{error, info};
+use rugameboy::apu::SAMPLE_RATE;
+use rugameboy::audio::AudioQueue;
+
+/// stereo frames written to the command at once, about 12ms
+const CHUNK_FRAMES: usize = 512;
+/// samples queued for the command, 100ms of stereo
+const QUEUE_SIZE: usize = SAMPLE_RATE as usize / 10 * 2;
+
+/*
+ * Play audio by piping raw samples to a command like `aplay -f cd`,
+ * 16-bit little endian stereo at SAMPLE_RATE. A thread writes the queue to
+ * the command in small chunks, the pipe blocks at the pace of the sound
+ * device, so the fill level of the queue follows the device clock and the
+ * emulation keeps it half full by the rate adjust of APU, see AudioQueue.
+ */
+pub struct AudioPipe {
+    queue: AudioQueue,
+    child: Child,
+    stop: Arc<AtomicBool>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl AudioPipe {
+    pub fn spawn(command: &str) -> io::Result<Self> {
+        let mut args = command.split_whitespace();
+        let program = args.next().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty command"))?;
+        let mut child = Command::new(program).args(args).stdin(Stdio::piped()).spawn()?;
+        let mut stdin = child.stdin.take().unwrap();
+        let queue = AudioQueue::new(QUEUE_SIZE);
+        let stop = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let (queue, stop) = (queue.clone(), stop.clone());
+            thread::spawn(move || {
+                let mut chunk = vec![0; CHUNK_FRAMES * 2];
+                let mut bytes = Vec::with_capacity(chunk.len() * 2);
+                while !stop.load(Ordering::Relaxed) {
+                    queue.pop(&mut chunk);
+                    bytes.clear();
+                    bytes.extend(chunk.iter().flat_map(|sample| sample.to_le_bytes()));
+                    if let Err(e) = stdin.write_all(&bytes) {
+                        error!("audio-cmd: {}", e);
+                        break;
+                    }
+                }
+            })
+        };
+        info!("audio-cmd: playing through {}", program);
+        Ok(Self { queue, child, stop, writer: Some(writer) })
+    }
+
+    /// Queue to feed APU samples to, once per frame
+    pub fn queue(&self) -> &AudioQueue {
+        &self.queue
+    }
+}
+
+impl Drop for AudioPipe {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        // writer may be blocked on a full pipe, killing the command wakes it
+        self.child.kill().unwrap_or(());
+        if let Some(writer) = self.writer.take() {
+            writer.join().unwrap_or(());
+        }
+        self.child.wait().map(drop).unwrap_or(());
+        if self.queue.underruns() > 0 {
+            info!("audio-cmd: {} underruns", self.queue.underruns());
+        }
+    }
+}
@@ -0,0 +1,107 @@
+/*
+ * High level emulation of DMG boot ROM, for users without a boot ROM dump.
+ * Logo in cartridge header is expanded to VRAM tiles like the boot ROM does,
+ * then scrolled down one line per frame, with the ding at the end.
+ * CPU does not run during the animation, registers are already in the state
+ * boot ROM leaves, so the cartridge starts the same as without animation.
+ */
+use crate::vm::{Vm, CYCLES_PER_FRAME};
+
+const LOGO_START: u16 = 0x0104;
+const LOGO_SIZE:  u16 = 48;
+/// logo tiles start from tile 1, tile 0 is blank
+const LOGO_TILES: u16 = 0x8010;
+/// (R) mark drawn right to logo, one byte per line
+const REGISTERED_MARK: [u8; 8] = [0x3c, 0x42, 0xb9, 0xa5, 0xb9, 0xa5, 0x42, 0x3c];
+const REGISTERED_TILE: u8 = 0x19;
+/// tile map of upper and lower half of logo, 12 tiles each
+const LOGO_MAP_UPPER: u16 = 0x9904;
+const LOGO_MAP_LOWER: u16 = 0x9924;
+const LOGO_WIDTH:     u16 = 12;
+/// SCY at start, decreased by one every frame
+const SCROLL_START: u8 = 0x64;
+/// frames logo is kept still after scrolling
+const HOLD_FRAMES: u32 = 0x20;
+/// NR13 of the two notes, played at the last frames of scrolling
+const NOTES: [(u32, u8); 2] = [(0x62, 0x83), (0x64, 0xc1)];
+/// clocks sound is run at a time during animation
+const SOUND_STEP: u64 = 16;
+
+const NR11: u16 = 0xff11;
+const NR12: u16 = 0xff12;
+const NR13: u16 = 0xff13;
+const NR14: u16 = 0xff14;
+const NR50: u16 = 0xff24;
+const NR51: u16 = 0xff25;
+const NR52: u16 = 0xff26;
+const SCY:  u16 = 0xff42;
+
+pub struct HleBoot {
+    frame: u32,
+}
+
+impl HleBoot {
+    /// Clear VRAM, draw logo of cartridge and set up sound like boot ROM
+    pub fn start(vm: &mut Vm) -> Result<Self, ()> {
+        let bus = &mut vm.cpu.bus;
+        for addr in 0x8000..=0x9fff {
+            bus.store8(addr, 0)?;
+        }
+
+        // every nibble of logo is 4 pixels of 1 line, doubled in width and height
+        let double = |nibble: u8| (0..4).fold(0u8, |byte, bit| byte | (((nibble >> bit) & 1) * (0b11 << (bit * 2))));
+        let mut addr = LOGO_TILES;
+        for offset in 0..LOGO_SIZE {
+            let byte = bus.load8(LOGO_START + offset)?;
+            for &nibble in [byte >> 4, byte & 0xf].iter() {
+                for _ in 0..2 {
+                    bus.store8(addr, double(nibble))?;
+                    addr += 2;
+                }
+            }
+        }
+        for &line in REGISTERED_MARK.iter() {
+            bus.store8(addr, line)?;
+            addr += 2;
+        }
+
+        for tile in 0..LOGO_WIDTH {
+            bus.store8(LOGO_MAP_UPPER + tile, (tile + 1) as u8)?;
+            bus.store8(LOGO_MAP_LOWER + tile, (tile + LOGO_WIDTH + 1) as u8)?;
+        }
+        bus.store8(LOGO_MAP_UPPER + LOGO_WIDTH, REGISTERED_TILE)?;
+
+        for &(addr, value) in [(NR52, 0x80), (NR11, 0x80), (NR12, 0xf3), (NR51, 0xf3), (NR50, 0x77),
+                               (SCY, SCROLL_START)].iter() {
+            bus.store8(addr, value)?;
+        }
+        Ok(Self { frame: 0 })
+    }
+
+    /// Build next frame of animation to vm.buffer and run sound for a frame,
+    /// return false when animation is over
+    pub fn run_frame(&mut self, vm: &mut Vm) -> Result<bool, ()> {
+        if self.frame >= SCROLL_START as u32 + HOLD_FRAMES {
+            return Ok(false);
+        }
+        self.frame += 1;
+        let bus = &mut vm.cpu.bus;
+        if let Some(&(_, note)) = NOTES.iter().find(|&&(frame, _)| frame == self.frame) {
+            bus.store8(NR13, note)?;
+            bus.store8(NR14, 0x87)?;
+        }
+        bus.gpu.build_screen(&mut vm.buffer);
+        for _ in 0..CYCLES_PER_FRAME / SOUND_STEP {
+            bus.apu.update(SOUND_STEP);
+        }
+        if self.frame <= SCROLL_START as u32 {
+            bus.store8(SCY, SCROLL_START - self.frame as u8)?;
+        }
+        Ok(true)
+    }
+
+    /// Hand off to cartridge, also used when animation is skipped
+    pub fn finish(self, vm: &mut Vm) -> Result<(), ()> {
+        vm.cpu.bus.store8(SCY, 0)
+    }
+}
@@ -1,15 +1,19 @@
-use crate::memory::{Memory, Permission};
-use crate::gpu::{Gpu, LCDC, VRAM_START, VRAM_END, OAM_START, OAM_END};
+use crate::memory::{Memory, Permission, RamInit};
+use crate::gpu::{Gpu, GpuMode, LCDC, Palette, VRAM_START, VRAM_END, OAM_START, OAM_END};
 use crate::timer::{Timer, TIMER_START, TIMER_END};
+use crate::apu::{Apu, APU_START, APU_END};
 use crate::joypad::{Joypad, JOYPAD_ADDR};
+use crate::cartridge::{CartridgeHeader, Mapper};
+use crate::mbc::{BankSwitch, Mbc1};
 
-use num_traits::FromPrimitive;
-use num_derive::FromPrimitive;
 use log::{error, info};
+use std::any::Any;
 
 /// memory map of LR35902, xxx_START to xxx_END inclusive
 const CATRIDGE_START: u16 = 0x0000;
 const CATRIDGE_END:   u16 = 0x7fff;
+const EXTERNAL_RAM_START: u16 = 0xa000;
+const EXTERNAL_RAM_END:   u16 = 0xbfff;
 const RAM_START:      u16 = 0xc000;
 const RAM_END:        u16 = 0xdfff;
 const UNUSABLE_START: u16 = 0xfea0;
@@ -18,17 +22,50 @@ const HRAM_START:     u16 = 0xff80;
 const HRAM_END:       u16 = 0xfffe;
 const INT:            u16 = 0xff0f;
 const INTENB:         u16 = 0xffff;
-const DUMMYIO_START:  u16 = 0xFF4C;
-const DUMMYIO_END:    u16 = 0xFF7F;
+const IO_START:       u16 = 0xff00;
+const IO_END:         u16 = 0xff7f;
 
-/// Bit offset of interrupt register
-const VBLANK_SHIFT: u8 = 0;
-const LCDC_SHIFT: u8 = 1;
-const TIMER_SHIFT: u8 = 2;
-const SERIAL_SHIFT: u8 = 3;
-const JOYPAD_SHIFT: u8 = 4;
+/// upper 3 bits are not used by interrupt, but readable and writable as RAM
+const UNUSED_MASK: u8 = 0xe0;
 
-#[derive(Debug,Default)]
+/// Interrupt source, in priority order
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Interrupt {
+    VBlank,
+    Stat,
+    Timer,
+    Serial,
+    Joypad,
+}
+
+impl Interrupt {
+    /// all sources, highest priority first
+    pub const ALL: [Interrupt; 5] = [
+        Interrupt::VBlank, Interrupt::Stat, Interrupt::Timer, Interrupt::Serial, Interrupt::Joypad,
+    ];
+
+    /// bit offset of the source in IF and IE
+    pub fn bit(self) -> u8 {
+        match self {
+            Interrupt::VBlank => 0,
+            Interrupt::Stat   => 1,
+            Interrupt::Timer  => 2,
+            Interrupt::Serial => 3,
+            Interrupt::Joypad => 4,
+        }
+    }
+
+    pub fn mask(self) -> u8 {
+        1 << self.bit()
+    }
+
+    /// address of the handler
+    pub fn vector(self) -> u16 {
+        0x40 + 8 * self.bit() as u16
+    }
+}
+
+#[derive(Debug,Default,Clone)]
 pub struct InterruptFlag {
     // vblank on/off
     pub vblank: bool,
@@ -40,140 +77,468 @@ pub struct InterruptFlag {
     pub serial: bool,
     // serial on/off
     pub joypad: bool,
+    // unused upper bits, stored as is
+    unused: u8,
 }
 
 impl std::convert::From<&InterruptFlag> for u8 {
     fn from(flag: &InterruptFlag) -> Self {
-        ( if flag.vblank { 1 << VBLANK_SHIFT } else { 0 } ) |
-        ( if flag.lcdc   { 1 << LCDC_SHIFT   } else { 0 } ) |
-        ( if flag.timer  { 1 << TIMER_SHIFT  } else { 0 } ) |
-        ( if flag.serial { 1 << SERIAL_SHIFT } else { 0 } ) |
-        ( if flag.joypad { 1 << JOYPAD_SHIFT } else { 0 } )
+        ( if flag.vblank { Interrupt::VBlank.mask() } else { 0 } ) |
+        ( if flag.lcdc   { Interrupt::Stat.mask()   } else { 0 } ) |
+        ( if flag.timer  { Interrupt::Timer.mask()  } else { 0 } ) |
+        ( if flag.serial { Interrupt::Serial.mask() } else { 0 } ) |
+        ( if flag.joypad { Interrupt::Joypad.mask() } else { 0 } ) |
+        flag.unused
     }
 }
 
 impl std::convert::From<u8> for InterruptFlag {
     fn from(byte: u8) -> Self {
         InterruptFlag {
-            vblank: ((byte >> VBLANK_SHIFT) & 0b1) != 0,
-            lcdc:   ((byte >> LCDC_SHIFT  ) & 0b1) != 0,
-            timer:  ((byte >> TIMER_SHIFT ) & 0b1) != 0,
-            serial: ((byte >> SERIAL_SHIFT) & 0b1) != 0,
-            joypad: ((byte >> JOYPAD_SHIFT) & 0b1) != 0,
+            vblank: byte & Interrupt::VBlank.mask() != 0,
+            lcdc:   byte & Interrupt::Stat.mask()   != 0,
+            timer:  byte & Interrupt::Timer.mask()  != 0,
+            serial: byte & Interrupt::Serial.mask() != 0,
+            joypad: byte & Interrupt::Joypad.mask() != 0,
+            unused: byte & UNUSED_MASK,
+        }
+    }
+}
+
+/// Width of memory access
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum DataSize {
+    Byte,
+    Word,
+}
+
+impl DataSize {
+    pub fn bytes(self) -> u16 {
+        match self {
+            DataSize::Byte => 1,
+            DataSize::Word => 2,
         }
     }
 }
 
 /// IO line, 0xff00 - 0xff7f
-#[derive(FromPrimitive)]
-enum IO {
-    SB      = 0xff01,
-    SC      = 0xff02,
-    //TODO move all NR line from 0xff10 to 0xff3f one module
-    NR10    = 0xff10,
-    NR11    = 0xff11,
-    NR12    = 0xff12,
-    NR13    = 0xff13,
-    NR14    = 0xff14,
-    NR21    = 0xff16,
-    NR22    = 0xff17,
-    NR23    = 0xff18,
-    NR24    = 0xff19,
-    NR30    = 0xff1a,
-    NR31    = 0xff1b,
-    NR32    = 0xff1c,
-    NR33    = 0xff1d,
-    NR34    = 0xff1e,
-    NR41    = 0xff20,
-    NR42    = 0xff21,
-    NR43    = 0xff22,
-    NR44    = 0xff23,
-    NR50    = 0xff24,
-    NR51    = 0xff25,
-    NR52    = 0xff26,
-    WAV0    = 0xff30,
-    WAV1    = 0xff31,
-    WAV2    = 0xff32,
-    WAV3    = 0xff33,
-    WAV4    = 0xff34,
-    WAV5    = 0xff35,
-    WAV6    = 0xff36,
-    WAV7    = 0xff37,
-    WAV8    = 0xff38,
-    WAV9    = 0xff39,
-    WAVa    = 0xff3a,
-    WAVb    = 0xff3b,
-    WAVc    = 0xff3c,
-    WAVd    = 0xff3d,
-    WAVe    = 0xff3e,
-    WAVf    = 0xff3f,
-    LCDC    = 0xff40,
-    STAT    = 0xff41,
-    SCY     = 0xff42,
-    SCX     = 0xff43,
-    LY      = 0xff44,
-    DMA     = 0xff46,
-    BGP     = 0xff47,
-    OBP0    = 0xff48,
-    OBP1    = 0xff49,
-    WINY    = 0xff4a,
-    WINX    = 0xff4b,
+const SB:   u16 = 0xff01;
+const SC:   u16 = 0xff02;
+const LCDC: u16 = 0xff40;
+const STAT: u16 = 0xff41;
+const SCY:  u16 = 0xff42;
+const SCX:  u16 = 0xff43;
+const LY:   u16 = 0xff44;
+const LYC:  u16 = 0xff45;
+const DMA:  u16 = 0xff46;
+/// bytes copied by OAM DMA
+const DMA_LENGTH: u16 = 40 * 4;
+/// clocks to copy one byte by OAM DMA
+const DMA_BYTE_CLOCK: u64 = 4;
+const BGP:  u16 = 0xff47;
+const OBP0: u16 = 0xff48;
+const OBP1: u16 = 0xff49;
+const WY:   u16 = 0xff4a;
+const WX:   u16 = 0xff4b;
+const VBK:  u16 = 0xff4f;
+const OPRI: u16 = 0xff6c;
+
+/// IO registers after DMG boot ROM, written in order through store.
+/// Not listed: P1, DIV and LY are set by their devices, DMA write starts
+/// a transfer and is stored directly. NRx4 are written without trigger bit,
+/// which reads 1 anyway, so the ding of boot ROM is not played again.
+const POWER_ON_IO: [(u16, u8); 36] = [
+    (SB, 0x00), (SC, 0x7e),
+    (0xff05, 0x00), (0xff06, 0x00), (0xff07, 0xf8), // TIMA, TMA, TAC
+    // NR52 first, registers are not writable when sound is off
+    (0xff26, 0xf1),
+    (0xff10, 0x80), (0xff11, 0xbf), (0xff12, 0xf3), (0xff13, 0xff), (0xff14, 0x3f),
+    (0xff16, 0x3f), (0xff17, 0x00), (0xff18, 0xff), (0xff19, 0x3f),
+    (0xff1a, 0x7f), (0xff1b, 0xff), (0xff1c, 0x9f), (0xff1d, 0xff), (0xff1e, 0x3f),
+    (0xff20, 0xff), (0xff21, 0x00), (0xff22, 0x00), (0xff23, 0x3f),
+    (0xff24, 0x77), (0xff25, 0xf3),
+    (LCDC, 0x91), (STAT, 0x85), (SCY, 0x00), (SCX, 0x00), (LYC, 0x00),
+    (BGP, 0xfc), (WY, 0x00), (WX, 0x00),
+    // after STAT, which requests STAT interrupt by DMG write bug
+    (INT, 0xe1), (INTENB, 0x00),
+];
+
+/// read behavior of IO register
+#[derive(Clone,Copy,PartialEq)]
+enum IoRead {
+    /// read from device
+    Device,
+    /// stored value, unused bits or-ed by mask read as 1
+    Stored(u8),
+    /// constant value, 0xff for unmapped register
+    Const(u8),
+}
+
+/// write behavior of IO register
+#[derive(Clone,Copy,PartialEq)]
+enum IoWrite {
+    /// write to device, value is also stored
+    Device,
+    Stored,
+    Ignored,
+}
+
+/// Behavior of IO register not handled by attached device, 0xff00 - 0xff7f.
+/// Joypad, timer, sound and interrupt flag are devices found before this table.
+fn io_register(addr: u16, cgb_mode: bool) -> (IoRead, IoWrite) {
+    match addr {
+        SB   => (IoRead::Stored(0x00), IoWrite::Stored),
+        SC   => (IoRead::Stored(0x7e), IoWrite::Device),
+        LCDC => (IoRead::Device, IoWrite::Device),
+        STAT => (IoRead::Device, IoWrite::Device),
+        SCY  => (IoRead::Device, IoWrite::Device),
+        SCX  => (IoRead::Device, IoWrite::Device),
+        // read only, counted by GPU
+        LY   => (IoRead::Device, IoWrite::Ignored),
+        LYC  => (IoRead::Stored(0x00), IoWrite::Stored),
+        DMA  => (IoRead::Stored(0x00), IoWrite::Device),
+        BGP  => (IoRead::Device, IoWrite::Device),
+        OBP0 => (IoRead::Device, IoWrite::Device),
+        OBP1 => (IoRead::Device, IoWrite::Device),
+        WY   => (IoRead::Stored(0x00), IoWrite::Stored),
+        WX   => (IoRead::Stored(0x00), IoWrite::Stored),
+        VBK if cgb_mode => (IoRead::Device, IoWrite::Device),
+        OPRI if cgb_mode => (IoRead::Device, IoWrite::Device),
+        _ => (IoRead::Const(0xff), IoWrite::Ignored),
+    }
 }
 
-pub trait Device {
+pub trait Device: DeviceClone {
     fn load(&self, addr: u16) -> Result<u8, ()>;
     fn store(&mut self, addr: u16, value: u8) -> Result<(), ()>;
 }
 
+/// Clone boxed device, so Bus with attached devices can be cloned
+pub trait DeviceClone {
+    fn box_clone(&self) -> Box<dyn Device>;
+}
+
+impl<T: Device + Clone + 'static> DeviceClone for T {
+    fn box_clone(&self) -> Box<dyn Device> {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for Box<dyn Device> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+/// Device on the other end of link cable, game is the clock master
+pub trait SerialLink: SerialLinkBox {
+    /// Exchange byte sent by game with byte sent back, one transfer
+    fn transfer(&mut self, byte: u8) -> u8;
+}
+
+/// Clone boxed link with Bus, and get the concrete device back
+pub trait SerialLinkBox {
+    fn box_clone(&self) -> Box<dyn SerialLink>;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<T: SerialLink + Clone + 'static> SerialLinkBox for T {
+    fn box_clone(&self) -> Box<dyn SerialLink> {
+        Box::new(self.clone())
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl Clone for Box<dyn SerialLink> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+/// CPU reads and writes of each 256 bytes page, for memory heat map
+#[derive(Clone)]
+pub struct AccessCount {
+    pub reads: [u32; 256],
+    pub writes: [u32; 256],
+}
+
+impl Default for AccessCount {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AccessCount {
+    pub fn new() -> Self {
+        Self { reads: [0; 256], writes: [0; 256] }
+    }
+
+    /// Count access of size bytes from addr
+    pub fn count(&mut self, addr: u16, size: &DataSize, write: bool) {
+        let pages = if write { &mut self.writes } else { &mut self.reads };
+        for i in 0..size.bytes() {
+            let page = (addr.wrapping_add(i) >> 8) as usize;
+            pages[page] = pages[page].saturating_add(1);
+        }
+    }
+}
+
+/// Device attached by user at address start to end inclusive
+#[derive(Clone)]
+struct AttachedDevice {
+    start: u16,
+    end: u16,
+    device: Box<dyn Device>,
+}
+
+#[derive(Clone)]
 pub struct Bus {
     catridge: Memory,
+    /// RAM on cartridge without MBC, None if cartridge has none
+    external_ram: Option<Memory>,
+    /// MBC mapping banks of cartridge ROM and RAM, None if cartridge has none
+    mbc: Option<Mbc1>,
+    /// bank changes by writes to MBC, taken by Vm after each instruction
+    bank_switches: Vec<BankSwitch>,
     pub gpu: Gpu,
     pub timer: Timer,
+    pub apu: Apu,
     ram: Memory,
     hram: Memory,
     unusable: Memory,
     pub interruptenb: InterruptFlag,
     pub joypad: Joypad,
+    /// devices attached at runtime on address not used by bus
+    devices: Vec<AttachedDevice>,
+    /// CGB features enabled by header of the cartridge
+    pub cgb_mode: bool,
+    /// value of IO registers stored by bus, 0xff00 - 0xff7f
+    io: [u8; 0x80],
+    /// IF register, lower 5 bits
+    interrupt_flag: u8,
+    /// copy OAM DMA one byte per machine cycle instead of at once
+    pub timed_dma: bool,
+    /// source address and bytes copied of OAM DMA in progress
+    dma_transfer: Option<(u16, u16)>,
+    dma_clock: u64,
+    /// keep bytes sent through serial port, read by test ROMs
+    pub capture_serial: bool,
+    /// count CPU access per page if set
+    pub access_count: Option<Box<AccessCount>>,
+    serial_output: Vec<u8>,
+    /// device connected to serial port, None receives 0xff
+    pub serial_link: Option<Box<dyn SerialLink>>,
+    /// STAT interrupt line, interrupt is requested when it goes high
+    stat_line: bool,
 }
 
 impl Bus {
     pub fn new(binary: Vec<u8>) -> Self {
+        let header = CartridgeHeader::new(&binary);
+        let cgb_mode = header.as_ref().is_ok_and(|header| header.cgb_mode());
+        // ROM without header is run as ROM only, like homebrew test programs
+        let mapper = header.map_or(Mapper::RomOnly, |header| header.mapper());
         let catridge = Memory::new(0, binary, Permission::ReadOnly);
-        Self {
-            catridge: catridge,
+        let mut bus = Self {
+            catridge,
+            external_ram: None,
+            mbc: None,
+            bank_switches: Vec::new(),
             gpu: Gpu::new(),
             timer: Timer::new(),
+            apu: Apu::new(),
             ram: Memory::new_empty(RAM_START as usize, (RAM_END - RAM_START + 1) as usize, Permission::Normal),
             hram: Memory::new_empty(HRAM_START as usize, (HRAM_END - HRAM_START + 1) as usize, Permission::Normal),
             unusable: Memory::new_empty(UNUSABLE_START as usize, (UNUSABLE_END - UNUSABLE_START + 1) as usize, Permission::Invalid),
             joypad: Joypad::new(),
             interruptenb: Default::default(),
+            devices: Vec::new(),
+            cgb_mode: false,
+            io: [0; 0x80],
+            interrupt_flag: 0,
+            timed_dma: false,
+            dma_transfer: None,
+            dma_clock: 0,
+            capture_serial: false,
+            access_count: None,
+            serial_output: Vec::new(),
+            serial_link: None,
+            stat_line: false,
+        };
+        bus.set_mapper(mapper);
+        bus.set_cgb_mode(cgb_mode);
+        bus.init_io();
+        bus
+    }
+
+    /// Map cartridge by mapper, like when the header gives a wrong one
+    pub fn set_mapper(&mut self, mapper: Mapper) {
+        self.mbc = None;
+        self.external_ram = match mapper {
+            Mapper::RomOnly => None,
+            Mapper::RomRam => Some(Memory::new_empty(EXTERNAL_RAM_START as usize,
+                                                     (EXTERNAL_RAM_END - EXTERNAL_RAM_START + 1) as usize,
+                                                     Permission::Normal)),
+            Mapper::Mbc(cartridge_type @ 0x01 ..= 0x03) => {
+                self.mbc = Some(Mbc1::new(self.catridge.bytes().to_vec(), cartridge_type != 0x01));
+                None
+            },
+            Mapper::Mbc(cartridge_type) => {
+                info!("Cartridge type {:#04x} uses MBC, not supported, mapped as ROM only", cartridge_type);
+                None
+            },
+        };
+    }
+
+    /// RAM on cartridge, all banks of MBC RAM, None if cartridge has none
+    pub fn cartridge_ram(&self) -> Option<&[u8]> {
+        match (self.mbc.as_ref(), self.external_ram.as_ref()) {
+            (Some(mbc), _) if !mbc.ram().is_empty() => Some(mbc.ram()),
+            (_, Some(ram)) => Some(ram.bytes()),
+            _ => None,
+        }
+    }
+
+    pub fn cartridge_ram_mut(&mut self) -> Option<&mut [u8]> {
+        match (self.mbc.as_mut(), self.external_ram.as_mut()) {
+            (Some(mbc), _) if !mbc.ram().is_empty() => Some(mbc.ram_mut()),
+            (_, Some(ram)) => Some(ram.bytes_mut()),
+            _ => None,
+        }
+    }
+
+    /// Bank changes by writes to MBC since last call
+    pub fn take_bank_switches(&mut self) -> Vec<BankSwitch> {
+        std::mem::take(&mut self.bank_switches)
+    }
+
+    /// Set IO registers to their value after boot ROM
+    fn init_io(&mut self) {
+        for &(addr, value) in POWER_ON_IO.iter() {
+            self.store(addr, value).unwrap();
+        }
+        self.apu.finish_boot_sound();
+        self.io[(DMA - IO_START) as usize] = 0xff;
+    }
+
+    /// Switch CGB features of all devices
+    pub fn set_cgb_mode(&mut self, enable: bool) {
+        self.cgb_mode = enable;
+        self.gpu.set_cgb_mode(enable);
+        self.apu.set_cgb_mode(enable);
+    }
+
+    /// Fill WRAM, HRAM, VRAM and OAM with power-on content
+    pub fn init_ram(&mut self, init: RamInit) {
+        let mut next = init.generator();
+        self.ram.fill_with(&mut next);
+        self.hram.fill_with(&mut next);
+        self.gpu.fill_with(&mut next);
+    }
+
+    /// Attach custom device on address start to end inclusive.
+    /// Return error if the range overlaps built-in or attached devices.
+    pub fn attach_device(&mut self, start: u16, end: u16, device: Box<dyn Device>) -> Result<(), ()> {
+        if start > end {
+            return Err(());
+        }
+        let overlap = (start..=end).any(|addr| {
+            self.find_device(addr).is_some() ||
+            addr == INT || addr == INTENB ||
+            ((IO_START..=IO_END).contains(&addr) &&
+             io_register(addr, self.cgb_mode).0 != IoRead::Const(0xff))
+        });
+        if overlap {
+            error!("Device attached on {:#X}-{:#X} overlaps other device", start, end);
+            return Err(());
+        }
+        self.devices.push(AttachedDevice { start, end, device });
+        Ok(())
+    }
+
+    /// IF register, interrupts raised by devices
+    pub fn pending_interrupts(&self) -> InterruptFlag {
+        InterruptFlag::from(self.interrupt_flag)
+    }
+
+    /// Interrupts both raised and enabled, as IF & IE
+    pub fn requested_interrupts(&self) -> u8 {
+        self.interrupt_flag & u8::from(&self.interruptenb) & !UNUSED_MASK
+    }
+
+    /// Set bit of source in IF
+    pub fn request_interrupt(&mut self, source: Interrupt) {
+        self.interrupt_flag |= source.mask();
+    }
+
+    /// Clear bit of source in IF, done when it is serviced
+    pub fn clear_interrupt(&mut self, source: Interrupt) {
+        self.interrupt_flag &= !source.mask();
+    }
+
+    /// Move interrupts raised by devices since the last call to IF
+    pub fn collect_interrupts(&mut self) {
+        let raised = [
+            (Interrupt::VBlank, &mut self.gpu.is_interrupt),
+            (Interrupt::Timer,  &mut self.timer.is_interrupt),
+            (Interrupt::Joypad, &mut self.joypad.is_interrupt),
+        ];
+        for (source, raised) in raised {
+            if std::mem::take(raised) {
+                self.interrupt_flag |= source.mask();
+            }
         }
+        self.update_stat_line();
     }
 
-    fn load_interrupt(&self) -> u8 {
-       ( if self.gpu.is_interrupt    { 1 << VBLANK_SHIFT } else { 0 } ) |
-       ( if self.timer.is_interrupt  { 1 << TIMER_SHIFT  } else { 0 } ) |
-       ( if self.joypad.is_interrupt { 1 << JOYPAD_SHIFT } else { 0 } )
+    // Sources enabled in STAT are ORed into one line, a source rising while
+    // another is high requests nothing. LY wraps to 0 during line 153, so
+    // LYC=0 fires there and stays high through line 0.
+    fn update_stat_line(&mut self) {
+        let stat = self.io[(STAT - IO_START) as usize];
+        let mode = match self.gpu.mode {
+            GpuMode::HBlank => 0x08,
+            GpuMode::VBlank => 0x10,
+            GpuMode::ScanlineOAM => 0x20,
+            GpuMode::ScanlineVRAM => 0,
+        };
+        let coincidence = if self.coincidence() { 0x40 } else { 0 };
+        let line = stat & (mode | coincidence) != 0;
+        if line && !self.stat_line {
+            self.request_interrupt(Interrupt::Stat);
+        }
+        self.stat_line = line;
+    }
+
+    fn coincidence(&self) -> bool {
+        self.gpu.ly() == self.io[(LYC - IO_START) as usize]
     }
 
     fn store_interrupt(&mut self, value: u8) {
-        self.gpu.is_interrupt    = (value >> VBLANK_SHIFT) & 0x1 != 0;
-        self.timer.is_interrupt  = (value >> TIMER_SHIFT)  & 0x1 != 0;
-        self.joypad.is_interrupt = (value >> JOYPAD_SHIFT) & 0x1 != 0;
+        self.interrupt_flag = value & !UNUSED_MASK;
     }
 
     fn find_device(&self, addr: u16) -> Option<&dyn Device> {
         match addr {
+            CATRIDGE_START ..= CATRIDGE_END | EXTERNAL_RAM_START ..= EXTERNAL_RAM_END if self.mbc.is_some() =>
+                self.mbc.as_ref().map(|mbc| mbc as &dyn Device),
             CATRIDGE_START ..= CATRIDGE_END => Some(&self.catridge),
+            EXTERNAL_RAM_START ..= EXTERNAL_RAM_END if self.external_ram.is_some() =>
+                self.external_ram.as_ref().map(|ram| ram as &dyn Device),
             VRAM_START ..= VRAM_END => Some(&self.gpu),
             RAM_START ..= RAM_END => Some(&self.ram),
             OAM_START ..= OAM_END => Some(&self.gpu),
             HRAM_START ..= HRAM_END => Some(&self.hram),
             TIMER_START ..= TIMER_END => Some(&self.timer),
+            APU_START ..= APU_END => Some(&self.apu),
             JOYPAD_ADDR => Some(&self.joypad),
             UNUSABLE_START ..= UNUSABLE_END => Some(&self.unusable),
-            _ => return None,
+            _ => self.devices.iter()
+                             .find(|dev| dev.start <= addr && addr <= dev.end)
+                             .map(|dev| dev.device.as_ref()),
         }
     }
 
@@ -181,77 +546,157 @@ impl Bus {
         match self.find_device(addr) {
             Some(dev) => dev.load(addr),
             None => match addr {
-                INT => Ok(self.load_interrupt()),
+                // upper 3 bits of IF are unused and read 1
+                INT => Ok(UNUSED_MASK | self.interrupt_flag),
                 INTENB => Ok(u8::from(&self.interruptenb)),
-                DUMMYIO_START ..= DUMMYIO_END => Ok(0), // dummy hardware IO
+                IO_START ..= IO_END => Ok(self.load_io(addr)),
                 _ => {
-                    // match IO line
-                    match FromPrimitive::from_u16(addr) {
-                        Some(IO::LCDC) => Ok(self.gpu.lcdc.to_u8()),
-                        Some(IO::SCY) => Ok(self.gpu.scy),
-                        Some(IO::SCX) => Ok(self.gpu.scx),
-                        Some(IO::LY) => Ok(self.gpu.line),
-                        Some(IO::BGP) => Ok(self.gpu.bg_palette),
-                        Some(IO::OBP0) => Ok(self.gpu.ob0_palette),
-                        Some(IO::OBP1) => Ok(self.gpu.ob1_palette),
-                        Some(_) => {
-                            info!("Unimplemented load on address {:#X}", addr);
-                            Ok(0)
-                        },
-                        None => {
-                            error!("Invalid load on address {:#X}", addr);
-                            Err(())
-                        }
-                    }
+                    error!("Invalid load on address {:#X}", addr);
+                    Err(())
                 }
             }
         }
     }
 
+    fn load_io(&self, addr: u16) -> u8 {
+        match io_register(addr, self.cgb_mode).0 {
+            IoRead::Const(value) => value,
+            IoRead::Stored(mask) => self.io[(addr - IO_START) as usize] | mask,
+            IoRead::Device => match addr {
+                LCDC => self.gpu.lcdc.to_u8(),
+                STAT => self.load_stat(),
+                SCY => self.gpu.scy,
+                SCX => self.gpu.scx,
+                LY => self.gpu.ly(),
+                BGP => self.gpu.palette(Palette::Bg),
+                OBP0 => self.gpu.palette(Palette::Obj0),
+                OBP1 => self.gpu.palette(Palette::Obj1),
+                VBK => self.gpu.load_vram_bank(),
+                OPRI => self.gpu.load_object_priority(),
+                _ => 0xff,
+            },
+        }
+    }
+
+    // interrupt select bits are stored, LYC coincidence and mode come from gpu
+    fn load_stat(&self) -> u8 {
+        let mode = match self.gpu.mode {
+            GpuMode::HBlank => 0,
+            GpuMode::VBlank => 1,
+            GpuMode::ScanlineOAM => 2,
+            GpuMode::ScanlineVRAM => 3,
+        };
+        0x80 | (self.io[(STAT - IO_START) as usize] & 0x78) | (self.coincidence() as u8) << 2 | mode
+    }
+
     fn find_device_mut(&mut self, addr: u16) -> Option<&mut dyn Device> {
         match addr {
+            CATRIDGE_START ..= CATRIDGE_END | EXTERNAL_RAM_START ..= EXTERNAL_RAM_END if self.mbc.is_some() =>
+                self.mbc.as_mut().map(|mbc| mbc as &mut dyn Device),
             VRAM_START ..= VRAM_END => Some(&mut self.gpu),
             RAM_START ..= RAM_END => Some(&mut self.ram),
             OAM_START ..= OAM_END => Some(&mut self.gpu),
             HRAM_START ..= HRAM_END => Some(&mut self.hram),
             TIMER_START ..= TIMER_END => Some(&mut self.timer),
+            APU_START ..= APU_END => Some(&mut self.apu),
             JOYPAD_ADDR => Some(&mut self.joypad),
             CATRIDGE_START ..= CATRIDGE_END => Some(&mut self.catridge),
+            EXTERNAL_RAM_START ..= EXTERNAL_RAM_END if self.external_ram.is_some() =>
+                self.external_ram.as_mut().map(|ram| ram as &mut dyn Device),
             UNUSABLE_START ..= UNUSABLE_END => Some(&mut self.unusable),
-            _ => return None,
+            _ => self.devices.iter_mut()
+                             .find(|dev| dev.start <= addr && addr <= dev.end)
+                             .map(|dev| dev.device.as_mut() as &mut dyn Device),
         }
     }
 
     fn store(&mut self, addr: u16, value: u8) -> Result<(), ()> {
+        if let (Some(mbc), CATRIDGE_START ..= CATRIDGE_END) = (self.mbc.as_mut(), addr) {
+            let old = mbc.banks();
+            mbc.store(addr, value)?;
+            let new = mbc.banks();
+            if new != old {
+                self.bank_switches.push(BankSwitch { old, new });
+            }
+            return Ok(());
+        }
         match self.find_device_mut(addr) {
             Some(dev) => dev.store(addr, value),
             None => match addr {
-                INT => Ok(self.store_interrupt(value)),
-                INTENB => Ok(self.interruptenb = InterruptFlag::from(value)),
-                DUMMYIO_START ..= DUMMYIO_END => Ok(()), // dummy hardware IO
-                _ => {
-                    // match IO line
-                    match FromPrimitive::from_u16(addr) {
-                        Some(IO::LCDC) => self.gpu.lcdc = LCDC::from_u8(value),
-                        Some(IO::SCY) => self.gpu.scy = value,
-                        Some(IO::SCX) => self.gpu.scx = value,
-                        Some(IO::LY) => self.gpu.line = 0,
-                        Some(IO::DMA) => self.dma(value),
-                        Some(IO::BGP) => self.gpu.bg_palette = value,
-                        Some(IO::OBP0) => self.gpu.ob0_palette = value,
-                        Some(IO::OBP1) => self.gpu.ob1_palette = value,
-                        Some(_) => {},
-                        None => {
-                            error!("Invalid store to address {:#X}", addr);
-                            return Err(())
-                        }
-                    }
+                INT => {
+                    self.store_interrupt(value);
+                    Ok(())
+                },
+                INTENB => {
+                    self.interruptenb = InterruptFlag::from(value);
                     Ok(())
+                },
+                IO_START ..= IO_END => {
+                    self.store_io(addr, value);
+                    Ok(())
+                },
+                _ => {
+                    error!("Invalid store to address {:#X}", addr);
+                    Err(())
                 }
             }
         }
     }
 
+    fn store_io(&mut self, addr: u16, value: u8) {
+        let write = io_register(addr, self.cgb_mode).1;
+        if write != IoWrite::Ignored {
+            self.io[(addr - IO_START) as usize] = value;
+        }
+        if write == IoWrite::Device {
+            match addr {
+                LCDC => self.gpu.lcdc = LCDC::from_u8(value),
+                STAT => self.stat_write_bug(),
+                SCY => self.gpu.scy = value,
+                SCX => self.gpu.scx = value,
+                DMA => self.dma(value),
+                SC => self.serial_transfer(value),
+                BGP => self.gpu.set_palette(Palette::Bg, value),
+                OBP0 => self.gpu.set_palette(Palette::Obj0, value),
+                OBP1 => self.gpu.set_palette(Palette::Obj1, value),
+                VBK => self.gpu.store_vram_bank(value),
+                OPRI => self.gpu.store_object_priority(value),
+                _ => {},
+            }
+        }
+    }
+
+    // On DMG, writing STAT enables all sources for one cycle, which raises STAT
+    // interrupt in HBlank, VBlank or when LY = LYC. Road Rash depends on it.
+    fn stat_write_bug(&mut self) {
+        if self.cgb_mode {
+            return;
+        }
+        if self.coincidence() || matches!(self.gpu.mode, GpuMode::HBlank | GpuMode::VBlank) {
+            self.request_interrupt(Interrupt::Stat);
+        }
+    }
+
+    // Transfer with internal clock finishes at once, receives 0xff without
+    // link device. With external clock it waits forever like hardware.
+    fn serial_transfer(&mut self, value: u8) {
+        if value & 0x81 != 0x81 {
+            return;
+        }
+        let sb = &mut self.io[(SB - IO_START) as usize];
+        if self.capture_serial {
+            self.serial_output.push(*sb);
+        }
+        *sb = self.serial_link.as_mut().map_or(0xff, |link| link.transfer(*sb));
+        self.io[(SC - IO_START) as usize] = value & 0x7f;
+        self.request_interrupt(Interrupt::Serial);
+    }
+
+    /// Bytes sent through serial port since last call, kept if capture_serial is set
+    pub fn take_serial_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.serial_output)
+    }
+
     fn dma(&mut self, value: u8) {
         /* dma copy 40 * 28 bits data to OAM zone 0xFE00-0xFE9F
          * each sprite takes 28 bits space (note that 4 bits are not used in each sprite)
@@ -261,22 +706,50 @@ impl Bus {
          * 0x01 -> 0x0100
          * ...
          */
-        let addr = (value as u16) << 8;
-        // copy memory to OAM
-        for i in 0..(40 * 4) {
-            let byte = self.load(addr + i).unwrap();
-            self.store(OAM_START + i, byte).unwrap();
+        // source 0xE000 and above reads work RAM, the same as echo RAM
+        let addr = match value {
+            0xe0 ..= 0xff => (value as u16 - 0x20) << 8,
+            _ => (value as u16) << 8,
+        };
+        if self.timed_dma {
+            self.dma_transfer = Some((addr, 0));
+            self.dma_clock = 0;
+        } else {
+            for i in 0..DMA_LENGTH {
+                self.dma_copy(addr, i);
+            }
         }
     }
 
+    // copy memory to OAM through the same path as CPU, unmapped source reads 0xff
+    fn dma_copy(&mut self, source: u16, offset: u16) {
+        let byte = self.load(source + offset).unwrap_or(0xff);
+        if self.store(OAM_START + offset, byte).is_err() {
+            error!("DMA failed to write OAM {:#X}", OAM_START + offset);
+        }
+    }
+
+    /// Copy bytes of timed OAM DMA in progress for clocks passed
+    pub fn update_dma(&mut self, clock: u64) {
+        let (source, mut copied) = match self.dma_transfer {
+            Some(transfer) => transfer,
+            None => return,
+        };
+        self.dma_clock += clock;
+        while self.dma_clock >= DMA_BYTE_CLOCK && copied < DMA_LENGTH {
+            self.dma_clock -= DMA_BYTE_CLOCK;
+            self.dma_copy(source, copied);
+            copied += 1;
+        }
+        self.dma_transfer = if copied < DMA_LENGTH { Some((source, copied)) } else { None };
+    }
+
     pub fn load8(&self, addr: u16) -> Result<u8, ()> {
         self.load(addr)
     }
 
     pub fn load16(&self, addr: u16) -> Result<u16, ()> {
-        let msb = self.load(addr+1)?;
-        let lsb = self.load(addr)?;
-        Ok(((msb as u16) << 8) | (lsb as u16))
+        self.read(addr, DataSize::Word)
     }
 
     pub fn store8(&mut self, addr: u16, value: u8) -> Result<(), ()> {
@@ -284,8 +757,332 @@ impl Bus {
     }
 
     pub fn store16(&mut self, addr: u16, value: u16) -> Result<(), ()> {
-        self.store(addr, (value & 0xff) as u8)?;
-        self.store(addr+1, ((value >> 8) & 0xff) as u8)?;
+        self.write(addr, DataSize::Word, value)
+    }
+
+    /// Read little endian value of size at addr, address wraps after 0xFFFF
+    pub fn read(&self, addr: u16, size: DataSize) -> Result<u16, ()> {
+        let mut value = 0;
+        for i in (0..size.bytes()).rev() {
+            value = (value << 8) | self.load(addr.wrapping_add(i))? as u16;
+        }
+        Ok(value)
+    }
+
+    /// Write little endian value of size at addr, address wraps after 0xFFFF
+    pub fn write(&mut self, addr: u16, size: DataSize, value: u16) -> Result<(), ()> {
+        for i in 0..size.bytes() {
+            self.store(addr.wrapping_add(i), (value >> (8 * i)) as u8)?;
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ie_keeps_unused_bits() {
+        let mut bus = Bus::new(vec![0; 0x8000]);
+        bus.store8(INTENB, 0xff).unwrap();
+        assert_eq!(bus.load8(INTENB), Ok(0xff));
+        // upper bits of IF read as 1 at power on, but never request interrupt
+        bus.store8(INT, 0xe0).unwrap();
+        assert_eq!(bus.requested_interrupts(), 0);
+        bus.store8(INT, 0xe4).unwrap();
+        assert_eq!(bus.requested_interrupts(), 0x04);
+    }
+
+    // register reading back the complement of the last write
+    #[derive(Clone)]
+    struct Latch(u8);
+
+    impl Device for Latch {
+        fn load(&self, _addr: u16) -> Result<u8, ()> {
+            Ok(!self.0)
+        }
+
+        fn store(&mut self, _addr: u16, value: u8) -> Result<(), ()> {
+            self.0 = value;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn attached_device_gets_unused_io() {
+        let mut bus = Bus::new(vec![0; 0x8000]);
+        assert_eq!(bus.load8(0xff03), Ok(0xff));
+        bus.attach_device(0xff03, 0xff03, Box::new(Latch(0))).unwrap();
+        bus.store8(0xff03, 0x5a).unwrap();
+        assert_eq!(bus.load8(0xff03), Ok(0xa5));
+        // clone keeps its own device
+        let copy = bus.clone();
+        bus.store8(0xff03, 0x00).unwrap();
+        assert_eq!(copy.load8(0xff03), Ok(0xa5));
+        // used addresses are refused
+        assert!(bus.attach_device(0xff03, 0xff03, Box::new(Latch(0))).is_err());
+        assert!(bus.attach_device(0xff40, 0xff44, Box::new(Latch(0))).is_err());
+        assert_eq!(bus.load8(0xff44), Ok(0));
+    }
+
+    #[test]
+    fn cgb_only_rom_enables_cgb_registers() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0143] = 0xc0;
+        let mut bus = Bus::new(rom);
+        assert!(bus.cgb_mode);
+        bus.store8(0xff4f, 0x01).unwrap();
+        assert_eq!(bus.load8(0xff4f), Ok(0xff));
+        bus.store8(0xff4f, 0x00).unwrap();
+        assert_eq!(bus.load8(0xff4f), Ok(0xfe));
+        // DMG ROM has no VBK
+        let mut bus = Bus::new(vec![0; 0x8000]);
+        assert!(!bus.cgb_mode);
+        bus.store8(0xff4f, 0x00).unwrap();
+        assert_eq!(bus.load8(0xff4f), Ok(0xff));
+    }
+
+    #[test]
+    fn io_sweep_reads_documented_values() {
+        let mut bus = Bus::new(vec![0; 0x8000]);
+        for addr in IO_START..=IO_END {
+            assert!(bus.load8(addr).is_ok(), "read {:#x}", addr);
+        }
+        let unmapped = [0xff03].iter().cloned()
+            .chain(0xff08..=0xff0e)
+            .chain([0xff15, 0xff1f].iter().cloned())
+            .chain(0xff27..=0xff2f)
+            .chain(0xff4c..=0xff7f);
+        for addr in unmapped {
+            bus.store8(addr, 0x00).unwrap();
+            assert_eq!(bus.load8(addr), Ok(0xff), "unmapped {:#x}", addr);
+        }
+        // unused bits read as 1
+        for &(addr, value) in &[(SC, 0x7e), (INT, 0xe0), (0xff07, 0xf8), (STAT, 0x80)] {
+            bus.store8(addr, 0x00).unwrap();
+            assert_eq!(bus.load8(addr).unwrap() & value, value, "register {:#x}", addr);
+        }
+    }
+
+    #[test]
+    fn power_on_io_values() {
+        let bus = Bus::new(vec![0; 0x8000]);
+        let expected = [
+            (SC, 0x7e), (0xff07, 0xf8), (INT, 0xe1),
+            (0xff10, 0x80), (0xff11, 0xbf), (0xff12, 0xf3), (0xff24, 0x77),
+            (0xff25, 0xf3), (0xff26, 0xf1),
+            (LCDC, 0x91), (SCY, 0x00), (BGP, 0xfc), (INTENB, 0x00),
+        ];
+        for &(addr, value) in &expected {
+            assert_eq!(bus.load8(addr), Ok(value), "register {:#x}", addr);
+        }
+    }
+
+    #[test]
+    fn word_access_wraps_at_ffff() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0000] = 0x12;
+        let mut bus = Bus::new(rom);
+        bus.store8(INTENB, 0x1f).unwrap();
+        assert_eq!(bus.load16(0xffff), Ok(0x121f));
+        // high byte goes to read only ROM
+        bus.store16(0xffff, 0x3405).unwrap();
+        assert_eq!(bus.load8(INTENB), Ok(0x05));
+        assert_eq!(bus.load8(0x0000), Ok(0x12));
+        assert_eq!(bus.load16(0xc000), Ok(0x0000));
+        bus.store16(0xc000, 0xbeef).unwrap();
+        assert_eq!((bus.load8(0xc000), bus.load8(0xc001)), (Ok(0xef), Ok(0xbe)));
+    }
+
+    #[test]
+    fn palette_reads_stored_byte() {
+        use crate::gpu::{Palette, GRAYSCALE};
+        let mut bus = Bus::new(vec![0; 0x8000]);
+        bus.store8(BGP, 0xe4).unwrap();
+        assert_eq!(bus.load8(BGP), Ok(0xe4));
+        assert_eq!(bus.gpu.palette_colors(Palette::Bg), GRAYSCALE);
+        bus.store8(OBP1, 0x1b).unwrap();
+        assert_eq!(bus.load8(OBP1), Ok(0x1b));
+        assert_eq!(bus.gpu.palette_colors(Palette::Obj1),
+                   [GRAYSCALE[3], GRAYSCALE[2], GRAYSCALE[1], GRAYSCALE[0]]);
+    }
+
+    #[test]
+    fn timed_dma_is_seen_by_oam_scan() {
+        use crate::gpu::{GpuMode, Palette};
+        use crate::vm::{WIDTH, HEIGHT};
+        let mut bus = Bus::new(vec![0; 0x8000]);
+        bus.timed_dma = true;
+        // 40 sprites with solid tile 1 on line 0-7, side by side in two rounds
+        for i in 0..40 {
+            for (j, byte) in [16, 8 + 8 * (i % 20) as u8, 1, 0].iter().enumerate() {
+                bus.store8(0xc000 + i * 4 + j as u16, *byte).unwrap();
+            }
+        }
+        for line in 0..8 {
+            bus.store8(0x8010 + line * 2, 0xff).unwrap();
+        }
+        bus.store8(LCDC, 0x93).unwrap();
+        bus.store8(OBP0, 0xe4).unwrap();
+        bus.gpu.force_position(0, GpuMode::ScanlineOAM, 0);
+        bus.store8(DMA, 0xc0).unwrap();
+        while bus.gpu.mode != GpuMode::VBlank {
+            bus.gpu.update(4);
+            bus.update_dma(4);
+        }
+        let mut buffer = vec![0; WIDTH * HEIGHT];
+        bus.gpu.build_screen(&mut buffer);
+        let sprite = bus.gpu.palette_colors(Palette::Obj0)[1];
+        let drawn = |line: usize| buffer[line * WIDTH..(line + 1) * WIDTH]
+            .iter().take_while(|&&p| p == sprite).count();
+        // scan of line 0 ends before DMA copies sprite 5
+        assert_eq!(drawn(0), 5 * 8);
+        // line 1 sees the 10 sprites limit
+        assert_eq!(drawn(1), 10 * 8);
+        assert_eq!(drawn(8), 0);
+    }
+
+    #[test]
+    fn interrupt_vectors_and_bits() {
+        let expected = [(0x40, 0), (0x48, 1), (0x50, 2), (0x58, 3), (0x60, 4)];
+        for (source, &(vector, bit)) in Interrupt::ALL.iter().zip(expected.iter()) {
+            assert_eq!(source.vector(), vector, "{:?}", source);
+            assert_eq!(source.bit(), bit, "{:?}", source);
+            assert_eq!(source.mask(), 1 << bit, "{:?}", source);
+        }
+        // requested source sets only its own IF bit
+        let mut bus = Bus::new(vec![0; 0x8000]);
+        bus.store8(INTENB, 0x1f).unwrap();
+        for source in Interrupt::ALL.iter() {
+            bus.store8(INT, 0x00).unwrap();
+            bus.request_interrupt(*source);
+            assert_eq!(bus.requested_interrupts(), source.mask(), "{:?}", source);
+        }
+    }
+
+    #[test]
+    fn stat_write_in_vblank_requests_stat_on_dmg() {
+        use crate::gpu::GpuMode;
+        for &(cgb_mode, expected) in &[(false, 0x02), (true, 0x00)] {
+            let mut bus = Bus::new(vec![0; 0x8000]);
+            bus.set_cgb_mode(cgb_mode);
+            bus.store8(LYC, 0x00).unwrap();
+            bus.gpu.force_position(144, GpuMode::VBlank, 0);
+            bus.store8(INT, 0x00).unwrap();
+            bus.store8(STAT, 0x00).unwrap();
+            assert_eq!(bus.load8(INT).unwrap() & 0x02, expected, "cgb {}", cgb_mode);
+        }
+    }
+
+    #[test]
+    fn lyc_0_requests_stat_at_frame_start() {
+        use crate::gpu::GpuMode;
+        let mut bus = Bus::new(vec![0; 0x8000]);
+        bus.store8(LYC, 0x00).unwrap();
+        bus.gpu.force_position(152, GpuMode::VBlank, 0);
+        // LYC interrupt only, STAT write in VBlank requests one by itself
+        bus.store8(STAT, 0x40).unwrap();
+        bus.collect_interrupts();
+        bus.store8(INT, 0x00).unwrap();
+        let mut clock = 0;
+        while bus.load8(INT).unwrap() & 0x02 == 0 {
+            assert!(clock < 2 * 456, "no STAT interrupt");
+            bus.gpu.update(4);
+            bus.collect_interrupts();
+            clock += 4;
+        }
+        // LY reads 0 early in line 153
+        assert_eq!(bus.load8(LY), Ok(0));
+        assert_eq!(bus.gpu.line, 153);
+        assert!(clock > 456);
+    }
+
+    #[test]
+    fn rom_only_types_map_flat() {
+        for &(cartridge_type, has_ram) in &[(0x00, false), (0x08, true), (0x09, true)] {
+            let mut rom: Vec<u8> = (0..0x8000).map(|i| (i >> 8) as u8).collect();
+            rom[0x0147] = cartridge_type;
+            let mut bus = Bus::new(rom);
+            assert_eq!(bus.load8(0x4000), Ok(0x40));
+            assert_eq!(bus.load8(0x7f00), Ok(0x7f));
+            // ROM is read only, and writes are not taken as bank switch
+            bus.store8(0x2000, 0x05).unwrap();
+            assert_eq!(bus.load8(0x2000), Ok(0x20));
+            assert_eq!(bus.load8(0x4000), Ok(0x40));
+            if has_ram {
+                bus.store8(0xbfff, 0x12).unwrap();
+                assert_eq!(bus.load8(0xbfff), Ok(0x12), "type {:#04x}", cartridge_type);
+            }
+        }
+    }
+
+    #[test]
+    fn cartridge_ram_has_every_bank() {
+        let mut rom = vec![0; 0x10000];
+        rom[0x0147] = 0x03;
+        let mut bus = Bus::new(rom);
+        bus.store8(0x0000, 0x0a).unwrap();
+        bus.store8(0x6000, 0x01).unwrap();
+        bus.store8(0x4000, 0x01).unwrap();
+        bus.store8(0xa001, 0x12).unwrap();
+        let ram = bus.cartridge_ram().unwrap();
+        assert_eq!(ram.len(), 0x8000);
+        assert_eq!(ram[0x2001], 0x12);
+
+        bus.cartridge_ram_mut().unwrap()[0x2002] = 0x34;
+        assert_eq!(bus.load8(0xa002), Ok(0x34));
+        assert!(Bus::new(vec![0; 0x8000]).cartridge_ram().is_none());
+    }
+
+    // pattern of the 160 bytes copied by DMA, differs for each seed
+    fn dma_marker(seed: u8) -> Vec<u8> {
+        (0..DMA_LENGTH as u8).map(|i| i.wrapping_mul(3) ^ seed).collect()
+    }
+
+    #[test]
+    fn dma_reads_selected_rom_bank() {
+        // MBC1 with 4 banks, bank 2 holds the marker
+        let mut rom = vec![0; 0x10000];
+        rom[0x0147] = 0x01;
+        rom[0x8000..0x8000 + DMA_LENGTH as usize].copy_from_slice(&dma_marker(0x5a));
+        let mut bus = Bus::new(rom);
+        bus.store8(0x2000, 0x02).unwrap();
+        bus.store8(DMA, 0x40).unwrap();
+        assert_eq!(bus.gpu.oam()[..DMA_LENGTH as usize], dma_marker(0x5a)[..]);
+        // bank 1 is all zero
+        bus.store8(0x2000, 0x01).unwrap();
+        bus.store8(DMA, 0x40).unwrap();
+        assert!(bus.gpu.oam()[..DMA_LENGTH as usize].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn dma_above_e000_mirrors_work_ram() {
+        let mut bus = Bus::new(vec![0; 0x8000]);
+        for &(source, wram) in &[(0xe1, 0xc100), (0xff, 0xdf00)] {
+            for (i, &byte) in dma_marker(source).iter().enumerate() {
+                bus.store8(wram + i as u16, byte).unwrap();
+            }
+            bus.store8(DMA, source).unwrap();
+            assert_eq!(bus.gpu.oam()[..DMA_LENGTH as usize], dma_marker(source)[..], "source {:#04x}", source);
+        }
+    }
+
+    #[test]
+    fn dma_reads_external_ram() {
+        // MBC1 with RAM
+        let mut rom = vec![0; 0x10000];
+        rom[0x0147] = 0x03;
+        let mut bus = Bus::new(rom);
+        bus.store8(0x0000, 0x0a).unwrap();
+        for (i, &byte) in dma_marker(0xa5).iter().enumerate() {
+            bus.store8(0xa000 + i as u16, byte).unwrap();
+        }
+        bus.store8(DMA, 0xa0).unwrap();
+        assert_eq!(bus.gpu.oam()[..DMA_LENGTH as usize], dma_marker(0xa5)[..]);
+        // disabled RAM reads 0xff
+        bus.store8(0x0000, 0x00).unwrap();
+        bus.store8(DMA, 0xa0).unwrap();
+        assert!(bus.gpu.oam()[..DMA_LENGTH as usize].iter().all(|&byte| byte == 0xff));
+    }
+}
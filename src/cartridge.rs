@@ -0,0 +1,140 @@
+/*
+ * Cartridge header located at 0x0100-0x014F of the ROM
+ *
+ * 0x0134-0x0143 title in upper case ASCII
+ * 0x0143        CGB flag, overlaps last byte of title
+ * 0x0147        cartridge type (MBC)
+ * 0x014D        header checksum over 0x0134-0x014C
+ * 0x014E-0x014F global checksum over the whole ROM, big endian
+ */
+const TITLE_START:     usize = 0x0134;
+const TITLE_END:       usize = 0x0143;
+const CGB_FLAG:        usize = 0x0143;
+const CATRIDGE_TYPE:   usize = 0x0147;
+const HEADER_CHECKSUM: usize = 0x014d;
+const GLOBAL_CHECKSUM: usize = 0x014e;
+const HEADER_END:      usize = 0x014f;
+
+/// How cartridge is mapped to 0x0000-0x7FFF and 0xA000-0xBFFF
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Mapper {
+    /// 32KB ROM mapped flat, type 0x00
+    RomOnly,
+    /// 32KB ROM and 8KB RAM without MBC, type 0x08 and 0x09 with battery
+    RomRam,
+    /// memory bank controller, with the cartridge type byte
+    Mbc(u8),
+}
+
+impl Mapper {
+    pub fn from_cartridge_type(cartridge_type: u8) -> Self {
+        match cartridge_type {
+            0x00 => Mapper::RomOnly,
+            0x08 | 0x09 => Mapper::RomRam,
+            cartridge_type => Mapper::Mbc(cartridge_type),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CartridgeHeader {
+    /// game title, trailing zero bytes removed
+    pub title: String,
+    /// 0x80: CGB compatible, 0xC0: CGB only, otherwise DMG
+    pub cgb_flag: u8,
+    /// cartridge type, which MBC is used
+    pub cartridge_type: u8,
+    /// checksum of header bytes 0x0134-0x014C
+    pub header_checksum: u8,
+    /// checksum of the whole ROM, excluding the checksum itself
+    pub global_checksum: u16,
+}
+
+impl CartridgeHeader {
+    pub fn new(rom: &[u8]) -> Result<Self, ()> {
+        if rom.len() <= HEADER_END {
+            return Err(());
+        }
+        let title = rom[TITLE_START..=TITLE_END].iter()
+                        .take_while(|&&c| c != 0)
+                        .map(|&c| c as char)
+                        .collect();
+        Ok(Self {
+            title,
+            cgb_flag: rom[CGB_FLAG],
+            cartridge_type: rom[CATRIDGE_TYPE],
+            header_checksum: rom[HEADER_CHECKSUM],
+            global_checksum: (rom[GLOBAL_CHECKSUM] as u16) << 8 | rom[GLOBAL_CHECKSUM + 1] as u16,
+        })
+    }
+
+    pub fn mapper(&self) -> Mapper {
+        Mapper::from_cartridge_type(self.cartridge_type)
+    }
+
+    /// Cartridge RAM is kept by battery, and saved between runs
+    pub fn has_battery(&self) -> bool {
+        matches!(self.cartridge_type, 0x03 | 0x06 | 0x09 | 0x0d | 0x0f | 0x10 | 0x13 |
+                                      0x1b | 0x1e | 0x22 | 0xff)
+    }
+
+    /// Run with CGB features, for both CGB compatible and CGB only ROM
+    pub fn cgb_mode(&self) -> bool {
+        self.cgb_flag & 0x80 != 0
+    }
+
+    /// Verify global checksum stored at 0x014E-0x014F.
+    /// The boot ROM never checks it, so the result is informational only.
+    pub fn verify_global_checksum(rom: &[u8]) -> bool {
+        let header = match CartridgeHeader::new(rom) {
+            Ok(header) => header,
+            Err(_) => return false,
+        };
+        let checksum = rom.iter()
+                          .enumerate()
+                          .filter(|(i, _)| *i != GLOBAL_CHECKSUM && *i != GLOBAL_CHECKSUM + 1)
+                          .fold(0u16, |sum, (_, &byte)| sum.wrapping_add(byte as u16));
+        checksum == header.global_checksum
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 32KB ROM with title and correct global checksum
+    fn rom() -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[TITLE_START..TITLE_START + 4].copy_from_slice(b"TEST");
+        rom[0x4000] = 0xff;
+        rom[0x7fff] = 0x12;
+        let sum = rom.iter().fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16));
+        rom[GLOBAL_CHECKSUM..=GLOBAL_CHECKSUM + 1].copy_from_slice(&sum.to_be_bytes());
+        rom
+    }
+
+    #[test]
+    fn global_checksum_matches_rom() {
+        let rom = rom();
+        assert_eq!(CartridgeHeader::new(&rom).unwrap().global_checksum,
+                   (b'T' as u16 * 2 + b'E' as u16 + b'S' as u16) + 0xff + 0x12);
+        assert!(CartridgeHeader::verify_global_checksum(&rom));
+    }
+
+    #[test]
+    fn corrupted_rom_fails_global_checksum() {
+        let mut rom = rom();
+        rom[0x2000] ^= 0x01;
+        assert!(!CartridgeHeader::verify_global_checksum(&rom));
+        assert!(!CartridgeHeader::verify_global_checksum(&rom[..0x100]));
+    }
+
+    #[test]
+    fn battery_by_cartridge_type() {
+        let mut rom = rom();
+        for &(cartridge_type, battery) in &[(0x00, false), (0x02, false), (0x03, true), (0x09, true), (0x13, true)] {
+            rom[CATRIDGE_TYPE] = cartridge_type;
+            assert_eq!(CartridgeHeader::new(&rom).unwrap().has_battery(), battery);
+        }
+    }
+}
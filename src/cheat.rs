@@ -0,0 +1,171 @@
+/*
+ * Cheat search over work RAM: snapshot 0xC000-0xDFFF, play a bit, then keep
+ * only addresses whose value changed the way the wanted value did, like lives
+ * decreased by dying. Repeat until few candidates are left, then freeze one
+ * of them, its value is written back every frame.
+ */
+use crate::vm::Vm;
+
+/// searched region, work RAM
+pub const SEARCH_START: u16 = 0xc000;
+pub const SEARCH_SIZE: usize = 0x2000;
+
+/// Compare value of candidate now with the last snapshot
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Filter {
+    Decreased,
+    Increased,
+    Unchanged,
+    Changed,
+    Equals(u8),
+}
+
+impl Filter {
+    fn matches(self, previous: u8, current: u8) -> bool {
+        match self {
+            Filter::Decreased => current < previous,
+            Filter::Increased => current > previous,
+            Filter::Unchanged => current == previous,
+            Filter::Changed => current != previous,
+            Filter::Equals(value) => current == value,
+        }
+    }
+}
+
+fn snapshot(vm: &Vm) -> Vec<u8> {
+    (0..SEARCH_SIZE).map(|offset| vm.peek(SEARCH_START + offset as u16)).collect()
+}
+
+#[derive(Debug,Clone)]
+pub struct CheatSearch {
+    snapshot: Vec<u8>,
+    candidates: Vec<u16>,
+}
+
+impl CheatSearch {
+    /// Snapshot work RAM, every address is a candidate
+    pub fn start(vm: &Vm) -> Self {
+        Self {
+            snapshot: snapshot(vm),
+            candidates: (0..SEARCH_SIZE).map(|offset| SEARCH_START + offset as u16).collect(),
+        }
+    }
+
+    /// Keep candidates matching filter against last snapshot, then snapshot again.
+    /// Return number of candidates left.
+    pub fn filter(&mut self, vm: &Vm, filter: Filter) -> usize {
+        let current = snapshot(vm);
+        let previous = &self.snapshot;
+        self.candidates.retain(|&addr| {
+            let offset = (addr - SEARCH_START) as usize;
+            filter.matches(previous[offset], current[offset])
+        });
+        self.snapshot = current;
+        self.candidates.len()
+    }
+
+    pub fn candidates(&self) -> &[u16] {
+        &self.candidates
+    }
+
+    /// Value of address in the last snapshot
+    pub fn value(&self, addr: u16) -> u8 {
+        self.snapshot[(addr - SEARCH_START) as usize]
+    }
+}
+
+/// Addresses and the values they are frozen at
+#[derive(Debug,Clone,Default)]
+pub struct FreezeList {
+    entries: Vec<(u16, u8)>,
+}
+
+impl FreezeList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Freeze addr at value, replace value if addr is frozen already
+    pub fn set(&mut self, addr: u16, value: u8) {
+        match self.entries.iter_mut().find(|(frozen, _)| *frozen == addr) {
+            Some(entry) => entry.1 = value,
+            None => self.entries.push((addr, value)),
+        }
+    }
+
+    /// Return false if addr was not frozen
+    pub fn remove(&mut self, addr: u16) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|(frozen, _)| *frozen != addr);
+        self.entries.len() != len
+    }
+
+    pub fn entries(&self) -> &[(u16, u8)] {
+        &self.entries
+    }
+
+    /// Write frozen values, called once every frame
+    pub fn apply(&self, vm: &mut Vm) {
+        for &(addr, value) in self.entries.iter() {
+            vm.cpu.bus.store8(addr, value).unwrap_or(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vm() -> Vm {
+        let mut rom = vec![0; 0x8000];
+        // JR -2
+        rom[0x100..0x102].copy_from_slice(&[0x18, 0xfe]);
+        Vm::new(rom)
+    }
+
+    #[test]
+    fn search_finds_decreasing_lives() {
+        let mut vm = vm();
+        let lives = 0xc123;
+        vm.cpu.bus.store8(lives, 3).unwrap();
+        vm.cpu.bus.store8(0xd000, 3).unwrap();
+        let mut search = CheatSearch::start(&vm);
+        // lose a life, an unrelated byte goes up
+        vm.cpu.bus.store8(lives, 2).unwrap();
+        vm.cpu.bus.store8(0xd000, 4).unwrap();
+        vm.run().unwrap();
+        assert_eq!(search.filter(&vm, Filter::Decreased), 1);
+        assert_eq!(search.candidates(), [lives]);
+        assert_eq!(search.value(lives), 2);
+        assert_eq!(search.filter(&vm, Filter::Equals(2)), 1);
+        assert_eq!(search.filter(&vm, Filter::Changed), 0);
+    }
+
+    #[test]
+    fn search_unchanged_and_equals() {
+        let mut vm = vm();
+        vm.cpu.bus.store8(0xc000, 7).unwrap();
+        let mut search = CheatSearch::start(&vm);
+        vm.cpu.bus.store8(0xc001, 1).unwrap();
+        assert_eq!(search.filter(&vm, Filter::Unchanged), SEARCH_SIZE - 1);
+        assert_eq!(search.filter(&vm, Filter::Equals(7)), 1);
+        assert_eq!(search.candidates(), [0xc000]);
+    }
+
+    #[test]
+    fn frozen_value_is_written_back() {
+        let mut vm = vm();
+        let mut freezes = FreezeList::new();
+        freezes.set(0xc123, 9);
+        freezes.set(0xc123, 5);
+        assert_eq!(freezes.entries(), [(0xc123, 5)]);
+        vm.cpu.bus.store8(0xc123, 1).unwrap();
+        freezes.apply(&mut vm);
+        assert_eq!(vm.peek(0xc123), 5);
+        assert!(freezes.remove(0xc123));
+        assert!(!freezes.remove(0xc123));
+        vm.cpu.bus.store8(0xc123, 1).unwrap();
+        freezes.apply(&mut vm);
+        assert_eq!(vm.peek(0xc123), 1);
+    }
+}
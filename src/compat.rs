@@ -0,0 +1,247 @@
+/*
+ * Per-game settings looked up by cartridge header.
+ *
+ * Built-in entries can be extended by a user file in a small subset of TOML,
+ * entries of the user file are searched first:
+ *
+ * [[game]]
+ * title = "POKEMON RED"
+ * header_checksum = 0x20      # optional, tells apart revisions of same title
+ * palette = [0xffffff, 0xff8484, 0x943a3a, 0x000000]
+ * cgb_mode = false
+ * accurate_timing = true
+ * mapper = 0x08               # cartridge type byte, when the header is wrong
+ *
+ * Settings of the entry replace defaults, options given by user replace both.
+ */
+use crate::cartridge::{CartridgeHeader, Mapper};
+use crate::vm::VmConfig;
+
+#[derive(Debug,Clone,Default,PartialEq)]
+pub struct CompatEntry {
+    pub title: String,
+    /// match any header checksum if None
+    pub header_checksum: Option<u8>,
+    /// screen colors of shade 0 to 3, like colorization of CGB boot ROM
+    pub palette: Option<[u32; 4]>,
+    pub cgb_mode: Option<bool>,
+    pub accurate_timing: Option<bool>,
+    pub mapper: Option<Mapper>,
+}
+
+impl CompatEntry {
+    fn matches(&self, header: &CartridgeHeader) -> bool {
+        self.title == header.title &&
+        self.header_checksum.is_none_or(|checksum| checksum == header.header_checksum)
+    }
+
+    /// Replace settings of config given by this entry
+    pub fn apply(&self, config: &mut VmConfig) {
+        if let Some(palette) = self.palette {
+            config.colors = palette;
+        }
+        if self.cgb_mode.is_some() {
+            config.cgb_mode = self.cgb_mode;
+        }
+        if let Some(accurate_timing) = self.accurate_timing {
+            config.accurate_timing = accurate_timing;
+        }
+        if self.mapper.is_some() {
+            config.mapper = self.mapper;
+        }
+    }
+
+    /// Set setting key to value written in TOML
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "title" => self.title = parse_string(value).ok_or("expect string")?,
+            "header_checksum" => self.header_checksum = Some(
+                parse_int(value).filter(|&n| n <= 0xff).ok_or("expect byte")? as u8),
+            "palette" => self.palette = Some(parse_palette(value).ok_or("expect 4 colors")?),
+            "cgb_mode" => self.cgb_mode = Some(parse_bool(value).ok_or("expect bool")?),
+            "accurate_timing" => self.accurate_timing = Some(parse_bool(value).ok_or("expect bool")?),
+            "mapper" => self.mapper = Some(Mapper::from_cartridge_type(
+                parse_int(value).filter(|&n| n <= 0xff).ok_or("expect byte")? as u8)),
+            _ => return Err(format!("unknown key {}", key)),
+        }
+        Ok(())
+    }
+}
+
+/// Config of a game, settings of the compat entry replace defaults,
+/// settings given by user replace both
+pub fn merge(entry: Option<&CompatEntry>, user: &CompatEntry) -> VmConfig {
+    let mut config = VmConfig::default();
+    if let Some(entry) = entry {
+        entry.apply(&mut config);
+    }
+    user.apply(&mut config);
+    config
+}
+
+/// DMG games colorized by CGB boot ROM
+fn builtin_entries() -> Vec<CompatEntry> {
+    let colorized = |title: &str, palette: [u32; 4]| CompatEntry {
+        title: String::from(title),
+        palette: Some(palette),
+        ..Default::default()
+    };
+    vec![
+        colorized("POKEMON RED",   [0xffffff, 0xff8484, 0x943a3a, 0x000000]),
+        colorized("POKEMON BLUE",  [0xffffff, 0x63a5ff, 0x0000ff, 0x000000]),
+        colorized("POKEMON GREEN", [0xffffff, 0x7bff31, 0x0063c5, 0x000000]),
+    ]
+}
+
+pub struct CompatDb {
+    /// searched from the last one, so later entries override earlier
+    entries: Vec<CompatEntry>,
+}
+
+impl Default for CompatDb {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CompatDb {
+    /// Database with built-in entries only
+    pub fn new() -> Self {
+        Self { entries: builtin_entries() }
+    }
+
+    /// Add entries of user file, they take precedence over existing ones
+    pub fn load(&mut self, text: &str) -> Result<(), String> {
+        let mut entries: Vec<CompatEntry> = Vec::new();
+        for (lineno, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |msg: &str| format!("line {}: {}", lineno + 1, msg);
+            if line == "[[game]]" {
+                entries.push(CompatEntry::default());
+                continue;
+            }
+            let entry = entries.last_mut().ok_or_else(|| error("expect [[game]] first"))?;
+            let (key, value) = match line.find('=') {
+                Some(pos) => (line[..pos].trim(), line[pos + 1..].trim()),
+                None => return Err(error("expect key = value")),
+            };
+            entry.set(key, value).map_err(|e| error(&e))?;
+        }
+        if entries.iter().any(|entry| entry.title.is_empty()) {
+            return Err(String::from("every [[game]] needs a title"));
+        }
+        self.entries.extend(entries);
+        Ok(())
+    }
+
+    /// Entry of the game, the latest added one wins
+    pub fn lookup(&self, header: &CartridgeHeader) -> Option<&CompatEntry> {
+        self.entries.iter().rev().find(|entry| entry.matches(header))
+    }
+}
+
+fn parse_string(value: &str) -> Option<String> {
+    value.strip_prefix('"')?.strip_suffix('"').map(String::from)
+}
+
+fn parse_int(value: &str) -> Option<u32> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "true" => Some(true),
+        "false" => Some(false),
+        _ => None,
+    }
+}
+
+fn parse_palette(value: &str) -> Option<[u32; 4]> {
+    let colors: Vec<u32> = value.strip_prefix('[')?.strip_suffix(']')?
+                                .split(',')
+                                .map(|color| parse_int(color.trim()))
+                                .collect::<Option<_>>()?;
+    match colors.as_slice() {
+        &[c0, c1, c2, c3] if colors.iter().all(|&c| c <= 0xffffff) => Some([c0, c1, c2, c3]),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(title: &str, header_checksum: u8) -> CartridgeHeader {
+        CartridgeHeader {
+            title: String::from(title),
+            cgb_flag: 0,
+            cartridge_type: 0,
+            header_checksum,
+            global_checksum: 0,
+        }
+    }
+
+    const GAME: &str = "[[game]]\n\
+                        title = \"HOMEBREW\"\n\
+                        palette = [0xffffff, 0xc0c0c0, 0x808080, 0x000000]\n\
+                        cgb_mode = false\n\
+                        mapper = 0x08\n";
+
+    #[test]
+    fn entry_replaces_defaults() {
+        let mut db = CompatDb::new();
+        db.load(GAME).unwrap();
+        let config = merge(db.lookup(&header("HOMEBREW", 0)), &CompatEntry::default());
+        assert_eq!(config.colors, [0xffffff, 0xc0c0c0, 0x808080, 0x000000]);
+        assert_eq!(config.cgb_mode, Some(false));
+        assert_eq!(config.mapper, Some(Mapper::RomRam));
+        assert!(!config.accurate_timing);
+    }
+
+    #[test]
+    fn user_option_replaces_entry() {
+        let mut db = CompatDb::new();
+        db.load(GAME).unwrap();
+        let mut user = CompatEntry { cgb_mode: Some(true), ..Default::default() };
+        user.set("mapper", "0x00").unwrap();
+        let config = merge(db.lookup(&header("HOMEBREW", 0)), &user);
+        assert_eq!(config.cgb_mode, Some(true));
+        assert_eq!(config.mapper, Some(Mapper::RomOnly));
+        // not given by user, kept from entry
+        assert_eq!(config.colors, [0xffffff, 0xc0c0c0, 0x808080, 0x000000]);
+    }
+
+    #[test]
+    fn user_file_replaces_builtin_entry() {
+        let mut db = CompatDb::new();
+        assert_eq!(db.lookup(&header("POKEMON RED", 0x20)).unwrap().palette,
+                   Some([0xffffff, 0xff8484, 0x943a3a, 0x000000]));
+        db.load("[[game]]\ntitle = \"POKEMON RED\"\naccurate_timing = true\n").unwrap();
+        let entry = db.lookup(&header("POKEMON RED", 0x20)).unwrap();
+        assert_eq!(entry.palette, None);
+        assert_eq!(entry.accurate_timing, Some(true));
+    }
+
+    #[test]
+    fn header_checksum_tells_revisions_apart() {
+        let mut db = CompatDb::new();
+        db.load("[[game]]\ntitle = \"HOMEBREW\"\nheader_checksum = 0x12\ncgb_mode = true\n").unwrap();
+        assert!(db.lookup(&header("HOMEBREW", 0x12)).is_some());
+        assert!(db.lookup(&header("HOMEBREW", 0x13)).is_none());
+    }
+
+    #[test]
+    fn load_reports_line_of_error() {
+        let mut db = CompatDb::new();
+        assert_eq!(db.load("[[game]]\ntitle = \"HOMEBREW\"\nmapper = 0x100\n"),
+                   Err(String::from("line 3: expect byte")));
+        assert_eq!(db.load("[[game]]\nspeed = 2\n"),
+                   Err(String::from("line 2: unknown key speed")));
+    }
+}
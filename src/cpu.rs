@@ -1,68 +1,293 @@
-use log::{debug, info};
+use log::{debug, info, error, log_enabled, Level};
+use std::fmt::{self, Write};
+use std::io::{self, Write as _};
 
-use crate::register::Register;
-use crate::instruction::{Instruction, Target, Condition, CBInstruction};
-use crate::bus::Bus;
+use crate::register::{Register, FlagRegister};
+use crate::instruction::{Instruction, Target, Condition, CBInstruction, OPCODES, CB_OPCODES};
+use crate::bus::{Bus, DataSize, Interrupt};
+use crate::error::EmuError;
 
-enum DataSize {
-    Byte,
-    Word,
+/// stack must stay in WRAM or HRAM
+fn is_stack_addr(addr: u16) -> bool {
+    (0xc000..=0xdfff).contains(&addr) || (0xff80..=0xfffe).contains(&addr)
 }
 
-#[derive(Eq,PartialEq,Clone,Copy)]
+/// address of LDH and LD (C) instructions, always in 0xFF00-0xFFFF
+fn high_addr(offset: u8) -> u16 {
+    0xff00 | offset as u16
+}
+
+/// append value as upper case hex of digits, trace line is written for every
+/// instruction and write! takes most of its time
+fn push_hex(output: &mut String, value: u16, digits: u32) {
+    for digit in (0..digits).rev() {
+        output.push(char::from(b"0123456789ABCDEF"[(value >> (digit * 4)) as usize & 0xf]));
+    }
+}
+
+/// Compact cpu state before an instruction, kept for crash report
+#[derive(Debug,Default,Clone,Copy,PartialEq)]
+pub struct TraceEntry {
+    pub pc: u16,
+    pub opcode: u8,
+    /// byte after opcode, the opcode of CB instruction
+    pub operand: u8,
+    pub af: u16,
+    pub bc: u16,
+    pub de: u16,
+    pub hl: u16,
+    pub sp: u16,
+}
+
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PC:{:04X} AF:{:04X} BC:{:04X} DE:{:04X} HL:{:04X} SP:{:04X} {:02X} ",
+               self.pc, self.af, self.bc, self.de, self.hl, self.sp, self.opcode)?;
+        if self.opcode == 0xcb {
+            write!(f, "{:?}", CBInstruction::from_byte(self.operand))
+        } else {
+            match Instruction::from_byte(self.opcode) {
+                Some(inst) => write!(f, "{:?}", inst),
+                None => write!(f, "unknown"),
+            }
+        }
+    }
+}
+
+#[derive(Eq,PartialEq,Clone,Copy,Default)]
 pub enum InterruptState {
+    #[default]
     IDisable,
     IEnable,
     IDisableNext,
     IEnableNext,
 }
 
-impl Default for InterruptState {
-    fn default() -> Self { InterruptState::IDisable }
+/// What CPU does on opcode not decoded by Instruction::from_byte
+#[derive(Debug,Eq,PartialEq,Clone,Copy,Default)]
+pub enum UnknownOpcode {
+    /// stop emulation with error
+    #[default]
+    Error,
+    /// log and run it as NOP, to see how far a ROM gets
+    SkipAsNop,
+    /// lock up CPU like hardware, peripherals keep running
+    Halt,
 }
 
+#[derive(Clone)]
 pub struct Cpu {
     regs: Register,
     sp: u16,
     pub pc: u16,
     pub bus: Bus,
     interrupt_state: InterruptState,
+    /// tick peripherals on every memory access instead of after instruction
+    accurate_timing: bool,
+    /// clock already ticked by memory access in current instruction
+    access_clock: u64,
+    /// clock of current instruction not yet ticked, used by tick
+    pending_clock: u64,
+    /// total clock passed since power on
+    cycles: u64,
+    /// stop on stack access outside of RAM
+    stack_check: bool,
+    /// stack address of the return address pushed by the last call
+    ret_slot: Option<u16>,
+    /// pc of the instruction executed, or the interrupted pc
+    inst_pc: u16,
+    last_error: Option<EmuError>,
+    /// stopped by HALT until an enabled interrupt is pending
+    halted: bool,
+    /// HALT did not stop, next opcode byte is read twice
+    halt_bug: bool,
+    /// interrupt serviced by the last step and the interrupted pc
+    serviced: Option<(Interrupt, u16)>,
+    unknown_opcode: UnknownOpcode,
+    /// locked up by unknown opcode, only peripherals run
+    locked: bool,
+    /// text of trace and debug log, reused between steps
+    dump_buffer: String,
 }
 
 impl Cpu {
     pub fn new(binary: Vec<u8>) -> Self {
-        Self {
+        let bus = Bus::new(binary);
+        let cgb_mode = bus.cgb_mode;
+        let mut cpu = Self {
             regs: Register::default(),
             sp: 0xfffe,
             pc: 0x0100, // Starting point of execution
-            bus: Bus::new(binary),
+            bus,
             interrupt_state: InterruptState::default(),
-        }
+            accurate_timing: false,
+            access_clock: 0,
+            pending_clock: 0,
+            cycles: 0,
+            stack_check: false,
+            ret_slot: None,
+            inst_pc: 0x0100,
+            last_error: None,
+            halted: false,
+            halt_bug: false,
+            serviced: None,
+            unknown_opcode: UnknownOpcode::default(),
+            locked: false,
+            dump_buffer: String::new(),
+        };
+        cpu.set_cgb_mode(cgb_mode);
+        cpu
+    }
+
+    /// Switch CGB features, only meaningful before running
+    pub fn set_cgb_mode(&mut self, enable: bool) {
+        self.bus.set_cgb_mode(enable);
+        // games check A after boot to detect CGB hardware
+        self.regs.a = if enable { 0x11 } else { 0x01 };
+    }
+
+    pub fn set_accurate_timing(&mut self, enable: bool) {
+        self.accurate_timing = enable;
+    }
+
+    /// sanity mode for homebrew, off by default
+    pub fn set_stack_check(&mut self, enable: bool) {
+        self.stack_check = enable;
+    }
+
+    pub fn set_unknown_opcode(&mut self, policy: UnknownOpcode) {
+        self.unknown_opcode = policy;
+    }
+
+    /// IME, whether interrupts are serviced after current instruction
+    pub fn interrupt_master_enabled(&self) -> bool {
+        self.interrupt_state == InterruptState::IEnable ||
+        self.interrupt_state == InterruptState::IDisableNext
+    }
+
+    /// error that stopped the CPU
+    pub fn last_error(&self) -> Option<&EmuError> {
+        self.last_error.as_ref()
+    }
+
+    /// stopped by HALT, waiting for an enabled interrupt
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Interrupt serviced after the last instruction and the pc it interrupted,
+    /// pc is at the handler now
+    pub fn serviced_interrupt(&self) -> Option<(Interrupt, u16)> {
+        self.serviced
+    }
+
+    /// total clock passed since power on
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// debug API to inspect flags
+    pub fn flags(&self) -> &FlagRegister {
+        &self.regs.f
+    }
+
+    /// debug API to set flags before running an instruction
+    pub fn set_flags(&mut self, zero: bool, subtract: bool, half_carry: bool, carry: bool) {
+        self.regs.f = FlagRegister { zero, subtract, half_carry, carry };
     }
 
     pub fn fetch(&mut self) -> Result<u16, ()> {
-        let byte = self.load(self.pc, DataSize::Word);
-        self.pc += 1;
+        let byte = self.load(self.pc, DataSize::Byte);
+        self.pc = self.pc.wrapping_add(1);
         byte
     }
 
-    fn load(&self, addr: u16, size: DataSize) -> Result<u16, ()> {
-        match size {
-            DataSize::Byte => self.bus.load8(addr).map(|v| v as u16),
-            DataSize::Word => self.bus.load16(addr),
+    /// read one byte operand at pc and move pc past it
+    fn read_imm8(&mut self) -> Result<u8, ()> {
+        let byte = self.load(self.pc, DataSize::Byte)? as u8;
+        self.pc = self.pc.wrapping_add(1);
+        Ok(byte)
+    }
+
+    /// read little-endian word operand at pc and move pc past it
+    fn read_imm16(&mut self) -> Result<u16, ()> {
+        let word = self.load(self.pc, DataSize::Word)?;
+        self.pc = self.pc.wrapping_add(2);
+        Ok(word)
+    }
+
+    // in accurate timing mode, every memory access takes 4 clocks per byte
+    fn access_tick(&mut self, size: &DataSize) {
+        if self.accurate_timing {
+            let clock = 4 * size.bytes() as u64;
+            self.access_clock += clock;
+            self.update_peripherals(clock);
+        }
+    }
+
+    // clock not yet ticked by memory access
+    fn unticked_clock(&mut self, clock: u64) -> u64 {
+        let clock = clock.saturating_sub(self.access_clock);
+        self.access_clock = 0;
+        clock
+    }
+
+    fn update_peripherals(&mut self, clock: u64) {
+        self.cycles += clock;
+        self.bus.gpu.update(clock);
+        self.bus.timer.update(clock);
+        self.bus.apu.update(clock);
+        self.bus.update_dma(clock);
+        self.bus.collect_interrupts();
+    }
+
+    // check word access on stack at addr when stack check is on
+    fn check_stack(&mut self, addr: u16) -> Result<(), ()> {
+        if !self.stack_check ||
+           (is_stack_addr(addr) && addr.checked_add(1).is_some_and(is_stack_addr)) {
+            return Ok(());
+        }
+        self.stack_error()
+    }
+
+    // check PUSH of value does not overwrite the return address of the last call,
+    // like a PUSH after POP of the return address
+    fn check_push(&mut self, value: u16) -> Result<(), ()> {
+        let addr = self.sp.wrapping_sub(1);
+        match self.ret_slot {
+            Some(slot) if self.stack_check &&
+                (addr == slot || addr.wrapping_add(1) == slot || addr == slot.wrapping_add(1)) &&
+                self.bus.load16(slot)? != value => self.stack_error(),
+            _ => Ok(()),
+        }
+    }
+
+    fn stack_error(&mut self) -> Result<(), ()> {
+        let err = EmuError::StackCorruption { sp: self.sp, pc: self.inst_pc };
+        error!("{}", err);
+        self.last_error = Some(err);
+        Err(())
+    }
+
+    fn load(&mut self, addr: u16, size: DataSize) -> Result<u16, ()> {
+        self.access_tick(&size);
+        if let Some(count) = self.bus.access_count.as_mut() {
+            count.count(addr, &size, false);
         }
+        self.bus.read(addr, size)
     }
 
     fn store(&mut self, addr: u16, size: DataSize, value: u16) -> Result<(), ()> {
-        match size {
-            DataSize::Byte => self.bus.store8(addr, value as u8),
-            DataSize::Word => self.bus.store16(addr, value),
+        self.access_tick(&size);
+        if let Some(count) = self.bus.access_count.as_mut() {
+            count.count(addr, &size, true);
         }
+        self.bus.write(addr, size, value)
     }
 
     // helper function for command with operation on register
     // B, C, D, E, H, L, (HL), A, d8
-    fn get_r8(&self, target: &Target) -> Result<u8, ()> {
+    fn get_r8(&mut self, target: &Target) -> Result<u8, ()> {
         match target {
             Target::B  => Ok(self.regs.b),
             Target::C  => Ok(self.regs.c),
@@ -72,10 +297,10 @@ impl Cpu {
             Target::L  => Ok(self.regs.l),
             Target::HL => Ok(self.load(self.regs.get_hl(), DataSize::Byte)? as u8),
             Target::A  => Ok(self.regs.a),
-            Target::D8 => Ok(self.load(self.pc, DataSize::Byte)? as u8),
+            Target::D8 => self.read_imm8(),
             _ => {
                 info!("Invalid target for instruction {:?}", target);
-                return Err(());
+                Err(())
             }
         }
     }
@@ -111,18 +336,75 @@ impl Cpu {
 
     /// run single command in CPU return the clock length
     pub fn step(&mut self) -> Result<(), ()> {
-        debug!("{}", self.dump());
-        let clock = self.exec_one_instruction()?;
-        self.bus.gpu.update(clock);
-        self.bus.timer.update(clock);
+        self.step_with_trace(&mut None)
+    }
 
-        // handle interrupt
-        if self.interrupt_state == InterruptState::IEnable ||
-           self.interrupt_state == InterruptState::IDisableNext {
-            let clock = self.handle_interrupt()?;
+    /// step, writing the trace line of the state before it to trace if given.
+    /// The line is formatted once for trace and debug log, trace is dropped on write error.
+    pub fn step_with_trace(&mut self, trace: &mut Option<Box<dyn io::Write>>) -> Result<(), ()> {
+        // finish instruction left by tick first
+        while self.pending_clock != 0 {
+            self.tick()?;
+        }
 
-            self.bus.gpu.update(clock);
-            self.bus.timer.update(clock);
+        self.log_state(trace);
+        let clock = if self.halted {
+            self.halt_cycle()
+        } else {
+            let clock = self.exec_one_instruction()?;
+            self.unticked_clock(clock)
+        };
+        self.update_peripherals(clock);
+
+        let clock = self.dispatch_interrupt()?;
+        self.update_peripherals(clock);
+        Ok(())
+    }
+
+    /// run single machine cycle (4 clocks) in CPU.
+    /// The instruction is executed at its first machine cycle,
+    /// the following cycles only advance peripherals.
+    pub fn tick(&mut self) -> Result<(), ()> {
+        if self.pending_clock == 0 {
+            self.pending_clock = if self.halted {
+                self.halt_cycle()
+            } else {
+                self.log_state(&mut None);
+                let clock = self.exec_one_instruction()?;
+                self.unticked_clock(clock)
+            };
+        }
+
+        let clock = self.pending_clock.min(4);
+        self.pending_clock -= clock;
+        self.update_peripherals(clock);
+
+        // instruction is done, check interrupt
+        if self.pending_clock == 0 {
+            self.pending_clock = self.dispatch_interrupt()?;
+        }
+        Ok(())
+    }
+
+    // One machine cycle spent in HALT. An enabled interrupt pending wakes cpu
+    // in this cycle, then it is serviced if IME is set, otherwise execution
+    // simply resumes after HALT.
+    fn halt_cycle(&mut self) -> u64 {
+        if !self.locked && self.bus.requested_interrupts() != 0 {
+            self.halted = false;
+        }
+        4
+    }
+
+    // handle interrupt and update interrupt state after an instruction,
+    // return the clock not yet ticked
+    fn dispatch_interrupt(&mut self) -> Result<u64, ()> {
+        let mut clock = 0;
+        self.serviced = None;
+        if !self.locked && (self.interrupt_state == InterruptState::IEnable ||
+                            self.interrupt_state == InterruptState::IDisableNext) {
+            clock = self.handle_interrupt()?;
+            clock = self.unticked_clock(clock);
         }
 
         // update interrupt state
@@ -131,61 +413,94 @@ impl Cpu {
             InterruptState::IEnableNext => InterruptState::IEnable,
             _ => self.interrupt_state,
         };
-
-        Ok(())
+        Ok(clock)
     }
 
+    // Service the pending interrupt of highest priority.
+    // Only its flag is cleared, the others stay pending until IME is enabled again
+    // by RETI or EI in the handler.
     fn handle_interrupt(&mut self) -> Result<u64, ()> {
-        // Vblank, priority 1, highest
-        if self.bus.interruptenb.vblank && self.bus.gpu.is_interrupt {
-            debug!("VBlank Interrupt");
-            self.bus.gpu.is_interrupt = false;
-            self.interrupt_state = InterruptState::IDisable;
-            return self.execute(Instruction::RST(0x40))
-        }
-        if self.bus.interruptenb.timer && self.bus.timer.is_interrupt {
-            debug!("Timer Interrupt");
-            self.bus.timer.is_interrupt = false;
-            self.interrupt_state = InterruptState::IDisable;
-            return self.execute(Instruction::RST(0x48))
-        }
-        if self.bus.interruptenb.joypad && self.bus.joypad.is_interrupt {
-            debug!("Joypad Interrupt");
-            self.bus.joypad.is_interrupt = false;
-            self.interrupt_state = InterruptState::IDisable;
-            return self.execute(Instruction::RST(0x60))
+        let requested = self.bus.requested_interrupts();
+        match Interrupt::ALL.iter().find(|source| requested & source.mask() != 0) {
+            Some(&source) => {
+                debug!("{:?} Interrupt", source);
+                self.bus.clear_interrupt(source);
+                self.service_interrupt(source)
+            },
+            None => Ok(0),
         }
-        Ok(0)
+    }
+
+    // jump to handler of source, pushing the interrupted pc,
+    // takes 20 clocks, one machine cycle more than RST
+    fn service_interrupt(&mut self, source: Interrupt) -> Result<u64, ()> {
+        self.interrupt_state = InterruptState::IDisable;
+        self.halted = false;
+        self.serviced = Some((source, self.pc));
+        self.inst_pc = self.pc;
+        self.execute(Instruction::RST(source.vector())).map(|clock| clock + 4)
     }
 
     fn exec_one_instruction(&mut self) -> Result<u64, ()> {
+        self.inst_pc = self.pc;
         let byte = self.fetch()? as u8;
+        if self.halt_bug {
+            self.halt_bug = false;
+            self.pc = self.pc.wrapping_sub(1);
+        }
         if byte == 0xcb {
             let byte = self.fetch()? as u8;
             // CB instruction is full, should not fail
-            let inst = CBInstruction::from_byte(byte);
-            self.execute_cb(inst)
+            self.execute_cb(CB_OPCODES[byte as usize])
         } else {
-            if let Some(inst) = Instruction::from_byte(byte) {
-                self.execute(inst)
-            } else {
-                debug!("Unsupport instruction {:#x}", byte as u8);
-                Err(())
+            match OPCODES[byte as usize] {
+                Some(opcode) => self.execute(opcode.inst),
+                None => self.unknown_instruction(byte),
             }
         }
     }
 
+    fn unknown_instruction(&mut self, byte: u8) -> Result<u64, ()> {
+        let pc = self.pc.wrapping_sub(1);
+        match self.unknown_opcode {
+            UnknownOpcode::Error => {
+                debug!("Unsupport instruction {:#x}", byte);
+                Err(())
+            },
+            UnknownOpcode::SkipAsNop => {
+                info!("Unsupport instruction {:#x} at {:#06x}, run as NOP", byte, pc);
+                Ok(Instruction::NOP.clock())
+            },
+            UnknownOpcode::Halt => {
+                info!("Unsupport instruction {:#x} at {:#06x}, CPU locks up", byte, pc);
+                self.halted = true;
+                self.locked = true;
+                Ok(Instruction::NOP.clock())
+            },
+        }
+    }
+
+    // SP plus signed offset, carries are from the low byte as unsigned add
+    fn add_sp_offset(&mut self, offset: u8) -> u16 {
+        let sp = self.sp;
+        self.regs.f.zero = false;
+        self.regs.f.subtract = false;
+        self.regs.f.half_carry = (sp & 0xf) + (offset as u16 & 0xf) > 0xf;
+        self.regs.f.carry = (sp & 0xff) + offset as u16 > 0xff;
+        sp.wrapping_add(offset as i8 as u16)
+    }
+
     // execute one non-prefix (0xcb) command, and return the clock passed
     fn execute(&mut self, inst: Instruction) -> Result<u64, ()> {
-        let len = inst.len();
         let clock = inst.clock();
         match inst {
             Instruction::NOP => {},
             Instruction::JP(condition) => {
+                // operand is read even if jump is not taken
+                let addr = self.read_imm16()?;
                 if self.check_condition(&condition) {
-                    let addr = self.load(self.pc, DataSize::Word)?;
                     self.pc = addr;
-                    return Ok(16);
+                    return Ok(inst.clock_taken());
                 }
             },
             Instruction::JPHL => {
@@ -199,12 +514,15 @@ impl Cpu {
                 self.interrupt_state = InterruptState::IEnableNext;
             }
             Instruction::LDIMM16(target) => {
-                let imm = self.load(self.pc, DataSize::Word)?;
-                match &target {
-                    &Target::BC => self.regs.set_bc(imm),
-                    &Target::DE => self.regs.set_de(imm),
-                    &Target::HL => self.regs.set_hl(imm),
-                    &Target::SP => self.sp = imm,
+                let imm = self.read_imm16()?;
+                match target {
+                    Target::BC => self.regs.set_bc(imm),
+                    Target::DE => self.regs.set_de(imm),
+                    Target::HL => self.regs.set_hl(imm),
+                    Target::SP => {
+                        self.sp = imm;
+                        self.ret_slot = None;
+                    }
                     _ => {
                         info!("Invalid target for instruction {:?}", target);
                         return Err(());
@@ -212,38 +530,41 @@ impl Cpu {
                 }
             }
             Instruction::LD16A => {
-                let addr = self.load(self.pc, DataSize::Word)?;
+                let addr = self.read_imm16()?;
                 self.store(addr, DataSize::Byte, self.regs.a as u16)?;
             }
             Instruction::LDA16 => {
-                let addr = self.load(self.pc, DataSize::Word)?;
+                let addr = self.read_imm16()?;
                 self.regs.a = self.load(addr, DataSize::Byte)? as u8;
             }
             Instruction::LDA16SP => {
-                let addr = self.load(self.pc, DataSize::Word)?;
+                let addr = self.read_imm16()?;
                 self.store(addr, DataSize::Word, self.sp)?;
             }
             Instruction::LDSPHL => {
                 self.sp = self.regs.get_hl();
+                self.ret_slot = None;
             }
             Instruction::LDIMM8(target) => {
-                let imm = self.load(self.pc, DataSize::Byte)? as u8;
+                let imm = self.read_imm8()?;
                 self.set_r8(&target, imm)?;
             }
             Instruction::LD8A => {
-                let addr = 0xff00 + (self.load(self.pc, DataSize::Byte)?);
+                let offset = self.read_imm8()?;
+                let addr = high_addr(offset);
                 self.store(addr, DataSize::Byte, self.regs.a as u16)?;
             }
             Instruction::LDA8 => {
-                let addr = 0xff00 + (self.load(self.pc, DataSize::Byte)?);
+                let offset = self.read_imm8()?;
+                let addr = high_addr(offset);
                 self.regs.a = self.load(addr, DataSize::Byte)? as u8;
             }
             Instruction::LDCA => {
-                let addr = 0xff00 + self.regs.c as u16;
+                let addr = high_addr(self.regs.c);
                 self.store(addr, DataSize::Byte, self.regs.a as u16)?;
             }
             Instruction::LDAC => {
-                let addr = 0xff00 + self.regs.c as u16;
+                let addr = high_addr(self.regs.c);
                 self.regs.a = self.load(addr, DataSize::Byte)? as u8;
             }
             Instruction::LDRR(source, target) => {
@@ -342,26 +663,31 @@ impl Cpu {
                 }
             }
             Instruction::CALL(condition) => {
+                let addr = self.read_imm16()?;
                 if self.check_condition(&condition) {
-                    let addr = self.load(self.pc, DataSize::Word)?;
-                    self.store(self.sp-1, DataSize::Word, self.pc + 2)?;
-                    self.sp -= 2;
+                    self.check_stack(self.sp.wrapping_sub(1))?;
+                    self.store(self.sp.wrapping_sub(1), DataSize::Word, self.pc)?;
+                    self.sp = self.sp.wrapping_sub(2);
+                    self.ret_slot = Some(self.sp.wrapping_add(1));
                     self.pc = addr;
-                    return Ok(24);
+                    return Ok(inst.clock_taken());
                 }
             }
             Instruction::RET(condition) => {
                 if self.check_condition(&condition) {
-                    self.pc = self.load(self.sp + 1, DataSize::Word)?;
-                    self.sp += 2;
-                    let clock = if condition == Condition::Always { 16 } else { 20 };
-                    return Ok(clock);
+                    self.check_stack(self.sp.wrapping_add(1))?;
+                    self.pc = self.load(self.sp.wrapping_add(1), DataSize::Word)?;
+                    self.sp = self.sp.wrapping_add(2);
+                    self.ret_slot = None;
+                    return Ok(inst.clock_taken());
                 }
             }
             Instruction::RETI => {
                 self.interrupt_state = InterruptState::IEnable;
-                self.pc = self.load(self.sp + 1, DataSize::Word)?;
-                self.sp += 2;
+                self.check_stack(self.sp.wrapping_add(1))?;
+                self.pc = self.load(self.sp.wrapping_add(1), DataSize::Word)?;
+                self.sp = self.sp.wrapping_add(2);
+                self.ret_slot = None;
                 return Ok(clock);
             }
             Instruction::PUSH(target) => {
@@ -375,11 +701,14 @@ impl Cpu {
                         return Err(());
                     }
                 };
-                self.store(self.sp-1, DataSize::Word, value)?;
-                self.sp -= 2;
+                self.check_stack(self.sp.wrapping_sub(1))?;
+                self.check_push(value)?;
+                self.store(self.sp.wrapping_sub(1), DataSize::Word, value)?;
+                self.sp = self.sp.wrapping_sub(2);
             }
             Instruction::POP(target) => {
-                let value = self.load(self.sp+1, DataSize::Word)?;
+                self.check_stack(self.sp.wrapping_add(1))?;
+                let value = self.load(self.sp.wrapping_add(1), DataSize::Word)?;
                 match target {
                     Target::BC => self.regs.set_bc(value),
                     Target::DE => self.regs.set_de(value),
@@ -390,14 +719,13 @@ impl Cpu {
                         return Err(());
                     }
                 };
-                self.sp += 2;
+                self.sp = self.sp.wrapping_add(2);
             }
             Instruction::JR(condition) => {
+                let offset = self.read_imm8()? as i8;
                 if self.check_condition(&condition) {
-                    let offset = self.load(self.pc, DataSize::Byte)? as i8;
                     self.pc = self.pc.wrapping_add(offset as u16);
-                    self.pc += len;
-                    return Ok(12);
+                    return Ok(inst.clock_taken());
                 }
             }
             Instruction::INC16(target) => {
@@ -405,7 +733,7 @@ impl Cpu {
                     Target::BC => self.regs.set_bc(self.regs.get_bc().wrapping_add(1)),
                     Target::DE => self.regs.set_de(self.regs.get_de().wrapping_add(1)),
                     Target::HL => self.regs.set_hl(self.regs.get_hl().wrapping_add(1)),
-                    Target::SP => self.sp += 1,
+                    Target::SP => self.sp = self.sp.wrapping_add(1),
                     _ => {
                         info!("Invalid target for instruction {:?}", target);
                         return Err(());
@@ -417,7 +745,7 @@ impl Cpu {
                     Target::BC => self.regs.set_bc(self.regs.get_bc().wrapping_sub(1)),
                     Target::DE => self.regs.set_de(self.regs.get_de().wrapping_sub(1)),
                     Target::HL => self.regs.set_hl(self.regs.get_hl().wrapping_sub(1)),
-                    Target::SP => self.sp -= 1,
+                    Target::SP => self.sp = self.sp.wrapping_sub(1),
                     _ => {
                         info!("Invalid target for instruction {:?}", target);
                         return Err(());
@@ -516,8 +844,10 @@ impl Cpu {
             Instruction::RST(addr) => {
                 // note that PC is added in the fetch step
                 // so RST will store PC+1, instead of PC.
-                self.store(self.sp-1, DataSize::Word, self.pc)?;
-                self.sp -= 2;
+                self.check_stack(self.sp.wrapping_sub(1))?;
+                self.store(self.sp.wrapping_sub(1), DataSize::Word, self.pc)?;
+                self.sp = self.sp.wrapping_sub(2);
+                self.ret_slot = Some(self.sp.wrapping_add(1));
                 self.pc = addr;
             }
             Instruction::CPL => {
@@ -531,16 +861,17 @@ impl Cpu {
                 self.regs.f.carry = !self.regs.f.carry;
             }
             Instruction::ADDHL(target) => {
-                let value = match &target {
-                    &Target::BC => self.regs.get_bc(),
-                    &Target::DE => self.regs.get_de(),
-                    &Target::HL => self.regs.get_hl(),
-                    &Target::SP => self.sp,
+                let value = match target {
+                    Target::BC => self.regs.get_bc(),
+                    Target::DE => self.regs.get_de(),
+                    Target::HL => self.regs.get_hl(),
+                    Target::SP => self.sp,
                     _ => {
                         info!("Invalid target for instruction {:?}", target);
                         return Err(());
                     }
                 };
+                // zero flag is not affected, H from bit 11 and C from bit 15
                 let hl = self.regs.get_hl();
                 self.regs.f.subtract = false;
                 self.regs.f.half_carry = ((hl & 0xfff) + (value & 0xfff)) & 0x1000 != 0;
@@ -583,10 +914,40 @@ impl Cpu {
                 }
                 self.regs.a = value as u8;
             }
+            Instruction::SCF => {
+                self.regs.f.subtract = false;
+                self.regs.f.half_carry = false;
+                self.regs.f.carry = true;
+            }
+            Instruction::RLA => {
+                let value = self.regs.a;
+                self.regs.a = (value << 1) | self.regs.f.carry as u8;
+                self.regs.f.zero = false;
+                self.regs.f.subtract = false;
+                self.regs.f.half_carry = false;
+                self.regs.f.carry = (value & 0x80) != 0;
+            }
+            Instruction::RRCA => {
+                let value = self.regs.a;
+                self.regs.a = value.rotate_right(1);
+                self.regs.f.zero = false;
+                self.regs.f.subtract = false;
+                self.regs.f.half_carry = false;
+                self.regs.f.carry = (value & 0x01) != 0;
+            }
+            Instruction::ADDSP => {
+                let offset = self.read_imm8()?;
+                self.sp = self.add_sp_offset(offset);
+            }
+            Instruction::LDHLSP => {
+                let offset = self.read_imm8()?;
+                let value = self.add_sp_offset(offset);
+                self.regs.set_hl(value);
+            }
             Instruction::RLCA => {
                 // rotate target left
                 let value = self.get_r8(&Target::A)?;
-                let result = value << 1 | value >> 7;
+                let result = value.rotate_left(1);
                 self.regs.f.zero = result == 0;
                 self.regs.f.subtract = false;
                 self.regs.f.half_carry = false;
@@ -595,9 +956,18 @@ impl Cpu {
             }
             Instruction::STOP => {
                 // FIXME: we do not implement CPU, LCD behavior
+                self.read_imm8()?;
+            }
+            Instruction::HALT => {
+                // HALT bug: IME off with an interrupt already pending, HALT
+                // ends at once and pc fails to move past the next opcode
+                if !self.interrupt_master_enabled() && self.bus.requested_interrupts() != 0 {
+                    self.halt_bug = true;
+                } else {
+                    self.halted = true;
+                }
             }
         }
-        self.pc += len;
         Ok(clock)
     }
 
@@ -607,7 +977,7 @@ impl Cpu {
             CBInstruction::RLC(target) => {
                 // rotate target left
                 let value = self.get_r8(&target)?;
-                let result = value << 1 | value >> 7;
+                let result = value.rotate_left(1);
                 self.regs.f.zero = result == 0;
                 self.regs.f.subtract = false;
                 self.regs.f.half_carry = false;
@@ -617,7 +987,7 @@ impl Cpu {
             CBInstruction::RRC(target) => {
                 // rotate target right
                 let value = self.get_r8(&target)?;
-                let result = (value >> 1) | (value << 7);
+                let result = value.rotate_right(1);
                 self.regs.f.zero = result == 0;
                 self.regs.f.subtract = false;
                 self.regs.f.half_carry = false;
@@ -667,7 +1037,7 @@ impl Cpu {
             CBInstruction::SWAP(target) => {
                 // swap register nibble
                 let value = self.get_r8(&target)?;
-                let result = (value << 4) | (value >> 4);
+                let result = value.rotate_right(4);
                 self.regs.f.zero = result == 0;
                 self.regs.f.subtract = false;
                 self.regs.f.half_carry = false;
@@ -703,19 +1073,492 @@ impl Cpu {
         Ok(clock)
     }
 
+    /// one line trace in the format of reference emulator logs (gameboy doctor)
+    /// compact state before executing instruction at pc
+    pub fn trace_entry(&self) -> TraceEntry {
+        TraceEntry {
+            pc: self.pc,
+            opcode: self.bus.load8(self.pc).unwrap_or(0),
+            operand: self.bus.load8(self.pc.wrapping_add(1)).unwrap_or(0),
+            af: self.regs.get_af(),
+            bc: self.regs.get_bc(),
+            de: self.regs.get_de(),
+            hl: self.regs.get_hl(),
+            sp: self.sp,
+        }
+    }
+
+    pub fn trace(&self) -> String {
+        let mut output = String::new();
+        self.write_trace(&mut output);
+        output
+    }
+
+    /// append trace line to output, without allocation once output has grown
+    pub fn write_trace(&self, output: &mut String) {
+        let bytes = [("A:", self.regs.a), ("F:", u8::from(&self.regs.f)), ("B:", self.regs.b), ("C:", self.regs.c),
+                     ("D:", self.regs.d), ("E:", self.regs.e), ("H:", self.regs.h), ("L:", self.regs.l)];
+        for (name, value) in bytes.iter() {
+            output.push_str(name);
+            push_hex(output, *value as u16, 2);
+            output.push(' ');
+        }
+        output.push_str("SP:");
+        push_hex(output, self.sp, 4);
+        output.push_str(" PC:");
+        push_hex(output, self.pc, 4);
+        output.push_str(" PCMEM:");
+        for i in 0..4 {
+            if i != 0 {
+                output.push(',');
+            }
+            push_hex(output, self.bus.load8(self.pc.wrapping_add(i)).unwrap_or(0) as u16, 2);
+        }
+    }
+
     pub fn dump(&self) -> String {
         let mut output = String::new();
-        output.push_str(&format!("\tPC:{:04X} SP:{:04X}\t", self.pc, self.sp));
-        output.push_str(&format!("{}\t", self.regs));
-        let byte = self.load(self.pc, DataSize::Byte).unwrap() as u8;
-        if byte == 0xcb {
-            let byte = self.load(self.pc+1, DataSize::Byte).unwrap() as u8;
-            output.push_str(&format!("byte:{:02X}\t", byte));
-            output.push_str(&format!("inst:{:?}", CBInstruction::from_byte(byte)));
+        self.write_dump(&mut output);
+        output
+    }
+
+    /// append trace line and next instruction to output
+    pub fn write_dump(&self, output: &mut String) {
+        self.write_trace(output);
+        self.write_instruction(output);
+    }
+
+    fn write_instruction(&self, output: &mut String) {
+        let byte = self.bus.load8(self.pc).unwrap_or(0);
+        let _ = if byte == 0xcb {
+            let byte = self.bus.load8(self.pc.wrapping_add(1)).unwrap_or(0);
+            write!(output, "\tinst:{:?}", CBInstruction::from_byte(byte))
         } else {
-            output.push_str(&format!("byte:{:02X}\t", byte));
-            output.push_str(&format!("inst:{:?}", Instruction::from_byte(byte)));
+            write!(output, "\tinst:{:?}", Instruction::from_byte(byte))
+        };
+    }
+
+    // trace line is formatted only when trace or debug log wants it, into the kept buffer,
+    // debug log adds the instruction to the same text
+    fn log_state(&mut self, trace: &mut Option<Box<dyn io::Write>>) {
+        let debug = !self.halted && log_enabled!(Level::Debug);
+        if trace.is_none() && !debug {
+            return;
         }
-        output
+        let mut output = std::mem::take(&mut self.dump_buffer);
+        output.clear();
+        self.write_trace(&mut output);
+        if let Some(writer) = trace.as_mut() {
+            output.push('\n');
+            if let Err(e) = writer.write_all(output.as_bytes()) {
+                error!("trace log: {}", e);
+                *trace = None;
+            }
+            output.pop();
+        }
+        if debug {
+            self.write_instruction(&mut output);
+            debug!("{}", output);
+        }
+        self.dump_buffer = output;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use crate::gpu::GpuMode;
+    use crate::memory::RamInit;
+
+    // cpu running program from 0x100
+    fn cpu_with(program: &[u8]) -> Cpu {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + program.len()].copy_from_slice(program);
+        Cpu::new(rom)
+    }
+
+    // run one step, return clocks it took
+    fn step_clock(cpu: &mut Cpu) -> u64 {
+        let cycles = cpu.cycles();
+        cpu.step().unwrap();
+        cpu.cycles() - cycles
+    }
+
+    #[test]
+    fn jp_hl_jumps_to_hl() {
+        let mut cpu = cpu_with(&asm![LD HL, 0x1234; JP HL]);
+        cpu.step().unwrap();
+        assert_eq!(step_clock(&mut cpu), 4);
+        assert_eq!(cpu.pc, 0x1234);
+    }
+
+    // run opcode with operands and registers pointing to work RAM
+    fn run_opcode(bytes: &[u8]) -> (u16, u64) {
+        let mut program = bytes.to_vec();
+        program.extend_from_slice(&[0xc0, 0xc0]);
+        let mut cpu = cpu_with(&program);
+        cpu.regs.set_bc(0xc000);
+        cpu.regs.set_de(0xc000);
+        cpu.regs.set_hl(0xc000);
+        let clock = step_clock(&mut cpu);
+        (cpu.pc - 0x100, clock)
+    }
+
+    #[test]
+    fn opcodes_run_in_length_and_clock_of_table() {
+        for byte in 0..=255u8 {
+            let opcode = match OPCODES[byte as usize] {
+                Some(opcode) => opcode,
+                None => continue,
+            };
+            // jumps are checked by their own tests
+            if matches!(opcode.inst, Instruction::JP(_) | Instruction::JR(_) | Instruction::JPHL |
+                                     Instruction::CALL(_) | Instruction::RET(_) | Instruction::RETI |
+                                     Instruction::RST(_)) {
+                continue;
+            }
+            assert_eq!(run_opcode(&[byte]), (1 + opcode.len, opcode.clock), "{:#04x} {:?}", byte, opcode.inst);
+        }
+        for byte in 0..=255u8 {
+            let inst = CB_OPCODES[byte as usize];
+            assert_eq!(run_opcode(&[0xcb, byte]), (2, inst.clock()), "0xcb {:#04x} {:?}", byte, inst);
+        }
+    }
+
+    #[test]
+    fn clear_loop_takes_exact_clocks() {
+        // LD HL, 0xc000; LD BC, 0x2000; loop: XOR A; LD (HL+), A; DEC BC; LD A, B; OR C; JR NZ loop
+        let mut cpu = cpu_with(&[0x21, 0x00, 0xc0, 0x01, 0x00, 0x20,
+                                 0xaf, 0x22, 0x0b, 0x78, 0xb1, 0x20, 0xf9]);
+        cpu.bus.init_ram(RamInit::Fill(0xff));
+        while cpu.pc != 0x10d {
+            cpu.step().unwrap();
+        }
+        // last JR is not taken
+        assert_eq!(cpu.cycles(), 12 + 12 + 0x2000 * 40 - 4);
+        assert!((0xc000..0xe000).all(|addr| cpu.bus.load8(addr) == Ok(0)));
+    }
+
+    fn stack_error(cpu: &mut Cpu) -> Option<&EmuError> {
+        cpu.set_stack_check(true);
+        while cpu.step().is_ok() {}
+        cpu.last_error()
+    }
+
+    #[test]
+    fn unbalanced_ret_is_stack_corruption() {
+        let mut cpu = cpu_with(&asm![RET]);
+        assert_eq!(stack_error(&mut cpu),
+                   Some(&EmuError::StackCorruption { sp: 0xfffe, pc: 0x100 }));
+    }
+
+    #[test]
+    fn stack_corruption_reports_call_pc() {
+        let mut cpu = cpu_with(&asm![LD SP, 0xc000; CALL 0x0200]);
+        assert_eq!(stack_error(&mut cpu),
+                   Some(&EmuError::StackCorruption { sp: 0xc000, pc: 0x103 }));
+    }
+
+    #[test]
+    fn push_over_return_address_is_stack_corruption() {
+        let mut cpu = cpu_with(&asm![LD DE, 0x1234; CALL 0x0106; POP HL; PUSH DE]);
+        assert_eq!(stack_error(&mut cpu),
+                   Some(&EmuError::StackCorruption { sp: 0xfffe, pc: 0x107 }));
+    }
+
+    #[test]
+    fn push_back_return_address_is_not_corruption() {
+        let mut cpu = cpu_with(&asm![CALL 0x0104; HALT; POP HL; PUSH HL; RET]);
+        cpu.set_stack_check(true);
+        for _ in 0..5 {
+            cpu.step().unwrap();
+        }
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.last_error(), None);
+    }
+
+    // IE and timer at 262144 Hz, TIMA about to overflow
+    fn arm_timer(cpu: &mut Cpu, enabled: u8) {
+        cpu.bus.store8(0xffff, enabled).unwrap();
+        cpu.bus.store8(0xff0f, 0).unwrap();
+        cpu.bus.store8(0xff05, 0xfe).unwrap();
+        cpu.bus.store8(0xff07, 0x05).unwrap();
+    }
+
+    #[test]
+    fn halt_wake_and_dispatch_take_24_clocks() {
+        let mut cpu = cpu_with(&asm![EI; HALT; NOP]);
+        arm_timer(&mut cpu, Interrupt::Timer.mask());
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert!(cpu.is_halted());
+        let mut clock = 0;
+        for _ in 0..100 {
+            if !cpu.is_halted() {
+                break;
+            }
+            clock = step_clock(&mut cpu);
+        }
+        // one halt cycle, then 20 clocks of dispatch
+        assert_eq!(clock, 24);
+        assert_eq!(cpu.pc, 0x0050);
+        assert_eq!(cpu.serviced_interrupt(), Some((Interrupt::Timer, 0x102)));
+    }
+
+    #[test]
+    fn halt_wakes_without_dispatch_when_ime_off() {
+        let mut cpu = cpu_with(&asm![DI; HALT; NOP]);
+        cpu.step().unwrap();
+        arm_timer(&mut cpu, Interrupt::Timer.mask());
+        cpu.step().unwrap();
+        assert!(cpu.is_halted());
+        let mut clock = 0;
+        for _ in 0..100 {
+            if !cpu.is_halted() {
+                break;
+            }
+            clock = step_clock(&mut cpu);
+        }
+        assert!(!cpu.is_halted());
+        assert_eq!(clock, 4);
+        assert_eq!(cpu.pc, 0x102);
+        assert_eq!(cpu.serviced_interrupt(), None);
+        assert_ne!(cpu.bus.requested_interrupts() & Interrupt::Timer.mask(), 0);
+    }
+
+    #[test]
+    fn masked_interrupt_does_not_wake_halt() {
+        let mut cpu = cpu_with(&asm![DI; HALT; NOP]);
+        cpu.step().unwrap();
+        arm_timer(&mut cpu, Interrupt::VBlank.mask());
+        cpu.step().unwrap();
+        // timer overflows many times before the first VBlank
+        while cpu.is_halted() {
+            cpu.step().unwrap();
+            assert!(cpu.bus.gpu.mode == GpuMode::VBlank || cpu.is_halted());
+        }
+        assert_eq!(cpu.bus.gpu.mode, GpuMode::VBlank);
+        assert_ne!(cpu.bus.load8(0xff0f).unwrap() & Interrupt::Timer.mask(), 0);
+        assert_eq!(cpu.pc, 0x102);
+    }
+
+    #[test]
+    fn halt_bug_reads_next_opcode_twice() {
+        let mut cpu = cpu_with(&asm![DI; HALT; INC A; NOP]);
+        cpu.step().unwrap();
+        cpu.bus.store8(0xffff, Interrupt::Timer.mask()).unwrap();
+        cpu.bus.request_interrupt(Interrupt::Timer);
+        let a = cpu.regs.a;
+        cpu.step().unwrap();
+        assert!(!cpu.is_halted());
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.regs.a, a.wrapping_add(2));
+        assert_eq!(cpu.pc, 0x103);
+    }
+
+    // run LD SP,sp and LD HL,hl, then the instruction bytes with Z set
+    fn run_with_sp(sp: u16, hl: u16, inst: &[u8]) -> Cpu {
+        let mut program = asm![LD SP, sp as i64; LD HL, hl as i64];
+        program.extend_from_slice(inst);
+        let mut cpu = cpu_with(&program);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        cpu.set_flags(true, true, false, false);
+        cpu.step().unwrap();
+        cpu
+    }
+
+    fn flags(cpu: &Cpu) -> (bool, bool, bool, bool) {
+        let f = cpu.flags();
+        (f.zero, f.subtract, f.half_carry, f.carry)
+    }
+
+    #[test]
+    fn add_hl_keeps_zero() {
+        let cpu = run_with_sp(0xfffe, 0x0fff, &asm![ADD HL, HL]);
+        assert_eq!(cpu.regs.get_hl(), 0x1ffe);
+        assert_eq!(flags(&cpu), (true, false, true, false));
+    }
+
+    #[test]
+    fn add_hl_sp_carries_from_bit_15() {
+        let cpu = run_with_sp(0x8000, 0x8000, &asm![ADD HL, SP]);
+        assert_eq!(cpu.regs.get_hl(), 0);
+        assert_eq!(flags(&cpu), (true, false, false, true));
+        let cpu = run_with_sp(0x0001, 0x0fff, &asm![ADD HL, SP]);
+        assert_eq!(cpu.regs.get_hl(), 0x1000);
+        assert_eq!(flags(&cpu), (true, false, true, false));
+    }
+
+    #[test]
+    fn add_sp_offset_carries_from_low_byte() {
+        // ADD SP,+1
+        let cpu = run_with_sp(0x00ff, 0, &[0xe8, 0x01]);
+        assert_eq!(cpu.sp, 0x0100);
+        assert_eq!(flags(&cpu), (false, false, true, true));
+        // ADD SP,-1 without carry from low byte
+        let cpu = run_with_sp(0x1000, 0, &[0xe8, 0xff]);
+        assert_eq!(cpu.sp, 0x0fff);
+        assert_eq!(flags(&cpu), (false, false, false, false));
+        // ADD SP,-1 carrying from low byte
+        let cpu = run_with_sp(0x0001, 0, &[0xe8, 0xff]);
+        assert_eq!(cpu.sp, 0x0000);
+        assert_eq!(flags(&cpu), (false, false, true, true));
+    }
+
+    #[test]
+    fn ld_hl_sp_offset_keeps_sp() {
+        let cpu = run_with_sp(0xfff8, 0, &[0xf8, 0x02]);
+        assert_eq!((cpu.regs.get_hl(), cpu.sp), (0xfffa, 0xfff8));
+        assert_eq!(flags(&cpu), (false, false, false, false));
+        let cpu = run_with_sp(0xc00f, 0, &[0xf8, 0xf1]);
+        assert_eq!(cpu.regs.get_hl(), 0xc000);
+        assert_eq!(flags(&cpu), (false, false, true, true));
+    }
+
+    #[test]
+    fn adc_consumes_carry_set_by_test() {
+        // LD A,0x10; ADC A,A
+        let mut cpu = cpu_with(&[0x3e, 0x10, 0x8f]);
+        cpu.step().unwrap();
+        cpu.set_flags(false, false, false, true);
+        assert!(cpu.flags().carry);
+        cpu.step().unwrap();
+        assert_eq!(cpu.regs.a, 0x21);
+        assert!(!cpu.flags().carry);
+        assert!(!cpu.flags().zero);
+    }
+
+    #[test]
+    fn tick_advances_peripherals_inside_instruction() {
+        // CALL 0x0200 takes 6 machine cycles
+        let mut cpu = cpu_with(&[0xcd, 0x00, 0x02]);
+        cpu.bus.gpu.force_position(0, GpuMode::ScanlineOAM, 64);
+        let div_counter = cpu.bus.timer.state().div_counter;
+        cpu.tick().unwrap();
+        // executed at first cycle, the rest only runs peripherals
+        assert_eq!(cpu.pc, 0x0200);
+        for tick in 1..6 {
+            assert_eq!(cpu.cycles(), tick * 4);
+            assert_eq!(cpu.bus.timer.state().div_counter, div_counter + tick * 4);
+            let mode = if tick < 4 { GpuMode::ScanlineOAM } else { GpuMode::ScanlineVRAM };
+            assert_eq!(cpu.bus.gpu.mode, mode, "tick {}", tick);
+            cpu.tick().unwrap();
+            assert_eq!(cpu.pc, 0x0200);
+        }
+        assert_eq!(cpu.cycles(), 24);
+    }
+
+    #[test]
+    fn ldh_writes_ly_handler() {
+        // LD A,0x99; LDH (0x44),A; LDH A,(0x44)
+        let mut cpu = cpu_with(&[0x3e, 0x99, 0xe0, 0x44, 0xf0, 0x44]);
+        cpu.bus.gpu.force_position(10, GpuMode::ScanlineOAM, 0);
+        cpu.step().unwrap();
+        assert_eq!(step_clock(&mut cpu), 12);
+        assert_eq!(cpu.pc, 0x0104);
+        // LY is read only, the write is ignored
+        assert_eq!(cpu.bus.gpu.line, 10);
+        assert_eq!(step_clock(&mut cpu), 12);
+        assert_eq!(cpu.pc, 0x0106);
+        assert_eq!(cpu.regs.a, cpu.bus.gpu.ly());
+        assert_ne!(cpu.regs.a, 0x99);
+    }
+
+    #[test]
+    fn wait_for_ly_144_ends_at_vblank() {
+        // loop: LDH A,(0x44); CP 144; JR NZ,loop; HALT
+        let mut cpu = cpu_with(&[0xf0, 0x44, 0xfe, 0x90, 0x20, 0xfa, 0x76]);
+        while cpu.pc != 0x0106 {
+            cpu.step().unwrap();
+        }
+        // loop takes 32 clocks, the read at 144 lines of 456 clocks sees LY 144
+        assert_eq!(cpu.cycles(), 144 * 456 + 12 + 8 + 8);
+        let mut last_line = 0;
+        while cpu.bus.gpu.mode != GpuMode::ScanlineOAM {
+            last_line = last_line.max(cpu.bus.gpu.line);
+            cpu.step().unwrap();
+        }
+        // VBlank is 10 lines, from 144 to 153
+        assert_eq!(last_line, 153);
+        assert_eq!(cpu.cycles(), 154 * 456);
+    }
+
+    #[test]
+    fn pending_timer_waits_for_vblank_handler() {
+        // EI; NOP; NOP
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x103].copy_from_slice(&[0xfb, 0x00, 0x00]);
+        // handlers count in B and C: INC B; RETI and INC C; RETI
+        rom[0x40..0x42].copy_from_slice(&[0x04, 0xd9]);
+        rom[0x50..0x52].copy_from_slice(&[0x0c, 0xd9]);
+        let mut cpu = Cpu::new(rom);
+        cpu.bus.store8(0xffff, 0x05).unwrap();
+        cpu.bus.store8(0xff0f, 0x05).unwrap();
+        let (b, c) = (cpu.regs.b, cpu.regs.c);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        // VBlank first, only its IF bit is cleared
+        assert_eq!(cpu.pc, 0x0040);
+        assert_eq!(cpu.bus.requested_interrupts(), 0x04);
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x0041);
+        assert_eq!(cpu.regs.b, b.wrapping_add(1));
+        // RETI enables IME, timer is serviced before returning to main
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x0050);
+        assert_eq!(cpu.bus.requested_interrupts(), 0);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.regs.c, c.wrapping_add(1));
+        assert_eq!(cpu.pc, 0x0102);
+    }
+
+    #[test]
+    fn jp_advances_pc_taken_or_not() {
+        // JP NZ,0x0200; JP Z,0x0200
+        let mut cpu = cpu_with(&[0xc2, 0x00, 0x02, 0xca, 0x00, 0x02]);
+        cpu.set_flags(true, false, false, false);
+        assert_eq!(step_clock(&mut cpu), 12);
+        assert_eq!(cpu.pc, 0x0103);
+        assert_eq!(step_clock(&mut cpu), 16);
+        assert_eq!(cpu.pc, 0x0200);
+        // JP 0x0150 unconditional
+        let mut cpu = cpu_with(&[0xc3, 0x50, 0x01]);
+        assert_eq!(step_clock(&mut cpu), 16);
+        assert_eq!(cpu.pc, 0x0150);
+    }
+
+    #[test]
+    fn jr_clocks_reach_peripherals() {
+        // JR NZ,+0; JR Z,+0
+        let mut cpu = cpu_with(&[0x20, 0x00, 0x28, 0x00]);
+        cpu.set_flags(true, false, false, false);
+        cpu.bus.gpu.force_position(0, GpuMode::ScanlineOAM, 0);
+        let div_counter = cpu.bus.timer.state().div_counter;
+        // not taken
+        assert_eq!(step_clock(&mut cpu), 8);
+        assert_eq!(cpu.bus.timer.state().div_counter, div_counter + 8);
+        // taken, OAM scan of 80 clocks ends after 68 more
+        assert_eq!(step_clock(&mut cpu), 12);
+        assert_eq!(cpu.pc, 0x0104);
+        assert_eq!(cpu.bus.timer.state().div_counter, div_counter + 20);
+        for _ in 0..15 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.cycles(), 80);
+        assert_eq!(cpu.bus.gpu.mode, GpuMode::ScanlineVRAM);
+    }
+
+    #[test]
+    fn dump_is_trace_line_and_instruction() {
+        // LD A,0x42; RLC B
+        let cpu = cpu_with(&[0x3e, 0x42, 0xcb, 0x00]);
+        assert_eq!(cpu.dump(), format!("{}\tinst:{:?}", cpu.trace(), Instruction::from_byte(0x3e)));
+        let mut cpu = cpu;
+        cpu.step().unwrap();
+        assert_eq!(cpu.dump(), format!("{}\tinst:{:?}", cpu.trace(), CBInstruction::from_byte(0x00)));
     }
 }
@@ -0,0 +1,391 @@
+use crate::Command;
+use rugameboy::vm::{Vm, StopReason};
+use rugameboy::cheat::{CheatSearch, Filter, FreezeList};
+
+use std::io::{stdout, Stdout, Write};
+use std::sync::mpsc::{Receiver, SyncSender};
+use std::time::{Duration, Instant};
+
+use crossterm::{execute, queue};
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyModifiers};
+use crossterm::style::{Attribute, Print, SetAttribute};
+use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+
+/// redraw interval of terminal while running
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+/// width of disassembly pane
+const DISASM_WIDTH: u16 = 40;
+/// bytes per line of hex view
+const HEX_COLUMNS: u16 = 16;
+/// start of memory regions Tab cycles hex view through
+const HEX_REGIONS: [u16; 6] = [0x0000, 0x8000, 0xa000, 0xc000, 0xfe00, 0xff00];
+/// cheat search candidates listed in right pane
+const CHEAT_LINES: usize = 8;
+
+const HELP: &str = "s:step r:step back c:continue p:pause b:breakpoint up/down:select \
+                    tab:region pgup/pgdn:scroll ::command q:quit";
+const CHEAT_HELP: &str = "commands: snap, dec, inc, same, changed, eq N, \
+                          freeze ADDR [N], unfreeze ADDR";
+
+fn parse_addr(arg: &str) -> Result<u16, String> {
+    let digits = arg.trim_start_matches("0x").trim_start_matches('$');
+    u16::from_str_radix(digits, 16).map_err(|_| format!("bad address {}", arg))
+}
+
+// decimal, or hex with 0x or $
+fn parse_value(arg: &str) -> Result<u8, String> {
+    let parsed = match arg.strip_prefix("0x").or_else(|| arg.strip_prefix('$')) {
+        Some(digits) => u8::from_str_radix(digits, 16),
+        None => arg.parse::<u8>(),
+    };
+    parsed.map_err(|_| format!("bad value {}", arg))
+}
+
+/*
+ * Debugger in terminal, runs in the emulation thread in place of emulation().
+ * Left pane disassembles from top of view, right panes show registers and
+ * a hex view of memory, status line shows cycle and frame counters.
+ * Frames are still sent to the window thread, so the game keeps showing
+ * in the window while the terminal is used by the debugger.
+ * ':' opens a command line for cheat search, see CHEAT_HELP.
+ */
+pub struct Debugger {
+    stdout: Stdout,
+    vm: Vm,
+    running: bool,
+    quit: bool,
+    /// address of the first disassembly line
+    top: u16,
+    /// selected disassembly line, breakpoint is toggled on it
+    cursor: u16,
+    hex_addr: u16,
+    message: String,
+    /// command typed after ':', None if not typing
+    prompt: Option<String>,
+    search: Option<CheatSearch>,
+    /// written every frame while running
+    freeze: FreezeList,
+}
+
+impl Debugger {
+    /// Start running, or stopped at the first instruction if paused
+    pub fn new(mut vm: Vm, paused: bool) -> crossterm::Result<Self> {
+        vm.set_reverse_step(true);
+        let mut stdout = stdout();
+        terminal::enable_raw_mode()?;
+        execute!(stdout, EnterAlternateScreen, Hide, Clear(ClearType::All))?;
+        let pc = vm.cpu.pc;
+        Ok(Self {
+            stdout,
+            vm,
+            running: !paused,
+            quit: false,
+            top: pc,
+            cursor: 0,
+            hex_addr: 0xc000,
+            message: String::from(HELP),
+            prompt: None,
+            search: None,
+            freeze: FreezeList::new(),
+        })
+    }
+
+    pub fn vm(&self) -> &Vm {
+        &self.vm
+    }
+
+    /// Debugger main loop, return when user quits or window is closed
+    pub fn run(&mut self, frame_tx: SyncSender<Vec<u32>>, cmd_rx: Receiver<Command>) {
+        let mut last_draw = Instant::now() - REDRAW_INTERVAL;
+        while !self.quit {
+            for cmd in cmd_rx.try_iter() {
+                match cmd {
+                    Command::PressKey(key)   => self.vm.set_button(key, true),
+                    Command::ReleaseKey(key) => self.vm.set_button(key, false),
+                    Command::Turbo(key, enable) => self.vm.set_turbo(key, enable),
+                    Command::Pause => self.running = !self.running,
+                    // stepped by instruction in debugger instead
+                    Command::FrameAdvance => {},
+                    Command::Menu(open) => if open && self.running {
+                        self.stop(String::from("paused by menu"));
+                    },
+                    Command::Reset => {
+                        self.vm.reset();
+                        self.follow_pc();
+                    },
+                    Command::Quit  => self.quit = true,
+                }
+            }
+            // wait for key when stopped, only peek while running
+            let timeout = if self.running { Duration::from_millis(0) } else { REDRAW_INTERVAL };
+            if self.poll_key(timeout).is_err() {
+                break;
+            }
+
+            if self.running {
+                match self.vm.run_until_break() {
+                    Ok(Some(StopReason::Breakpoint(pc))) => {
+                        self.stop(format!("breakpoint at {:04X}", pc));
+                    },
+                    Ok(Some(StopReason::Interrupt { source, pc })) => {
+                        self.stop(format!("{:?} interrupt at {:04X}", source, pc));
+                    },
+                    Ok(Some(StopReason::BankSwitch { switch, pc })) => {
+                        self.stop(format!("{} at {:04X}", switch, pc));
+                    },
+                    Ok(None) => {
+                        self.freeze.apply(&mut self.vm);
+                        if frame_tx.send(self.vm.buffer.clone()).is_err() {
+                            break;
+                        }
+                    },
+                    Err(_) => self.stop(String::from("emulation error, see log")),
+                }
+            }
+            if !self.running || last_draw.elapsed() >= REDRAW_INTERVAL {
+                last_draw = Instant::now();
+                if self.draw().is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    fn stop(&mut self, message: String) {
+        self.running = false;
+        self.message = message;
+        self.follow_pc();
+    }
+
+    /// Move view to pc if pc is out of it
+    fn follow_pc(&mut self) {
+        let pc = self.vm.cpu.pc;
+        let (_, rows) = terminal::size().unwrap_or((80, 24));
+        let lines = self.disasm_lines(rows.saturating_sub(1));
+        if !lines.iter().any(|(addr, _)| *addr == pc) {
+            self.top = pc;
+            self.cursor = 0;
+        }
+    }
+
+    fn poll_key(&mut self, timeout: Duration) -> crossterm::Result<()> {
+        if !event::poll(timeout)? {
+            return Ok(());
+        }
+        let (code, modifiers) = match event::read()? {
+            Event::Key(KeyEvent { code, modifiers }) => (code, modifiers),
+            _ => return Ok(()),
+        };
+        if let Some(prompt) = self.prompt.as_mut() {
+            match code {
+                KeyCode::Char(c) => prompt.push(c),
+                KeyCode::Backspace => { prompt.pop(); },
+                KeyCode::Enter => {
+                    let line = self.prompt.take().unwrap_or_default();
+                    self.message = self.command(&line).unwrap_or_else(|e| e);
+                },
+                KeyCode::Esc => {
+                    self.prompt = None;
+                    self.message = String::from(HELP);
+                },
+                _ => {},
+            }
+            return Ok(());
+        }
+        let (_, rows) = terminal::size()?;
+        let lines = rows.saturating_sub(1);
+        match code {
+            KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => self.quit = true,
+            KeyCode::Char('q') | KeyCode::Esc => self.quit = true,
+            KeyCode::Char('c') => {
+                self.running = true;
+                self.message = String::from("running");
+            },
+            KeyCode::Char('p') if self.running => self.stop(String::from("paused")),
+            KeyCode::Char('r') if !self.running => {
+                self.message = match self.vm.rstep() {
+                    Ok(()) => String::from(HELP),
+                    Err(_) => String::from("no earlier snapshot to step back from"),
+                };
+                self.follow_pc();
+            },
+            KeyCode::Char('s') if !self.running => {
+                self.message = match self.vm.step() {
+                    Ok(()) => String::from(HELP),
+                    Err(_) => String::from("emulation error, see log"),
+                };
+                self.follow_pc();
+            },
+            KeyCode::Char('b') => {
+                let selected = self.disasm_lines(lines).get(self.cursor as usize).map(|(addr, _)| *addr);
+                if let Some(addr) = selected {
+                    let set = self.vm.toggle_breakpoint(addr);
+                    self.message = format!("breakpoint at {:04X} {}", addr, if set { "set" } else { "cleared" });
+                }
+            },
+            KeyCode::Up if self.cursor > 0 => self.cursor -= 1,
+            KeyCode::Down => {
+                if self.cursor + 1 < lines {
+                    self.cursor += 1;
+                } else {
+                    // scroll down by one instruction
+                    self.top = self.top.wrapping_add(self.vm.disassemble(self.top).1);
+                }
+            },
+            KeyCode::Tab => {
+                let next = HEX_REGIONS.iter().position(|&start| start > self.hex_addr).unwrap_or(0);
+                self.hex_addr = HEX_REGIONS[next];
+            },
+            KeyCode::PageUp => self.hex_addr = self.hex_addr.wrapping_sub(HEX_COLUMNS * 8),
+            KeyCode::PageDown => self.hex_addr = self.hex_addr.wrapping_add(HEX_COLUMNS * 8),
+            KeyCode::Char(':') => self.prompt = Some(String::new()),
+            _ => {},
+        }
+        Ok(())
+    }
+
+    /// Run cheat search command, return message to show
+    fn command(&mut self, line: &str) -> Result<String, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let filter = match words.as_slice() {
+            ["snap"] => {
+                self.search = Some(CheatSearch::start(&self.vm));
+                return Ok(String::from("work RAM snapshot taken, all addresses are candidates"));
+            },
+            ["dec"] => Filter::Decreased,
+            ["inc"] => Filter::Increased,
+            ["same"] => Filter::Unchanged,
+            ["changed"] => Filter::Changed,
+            ["eq", value] => Filter::Equals(parse_value(value)?),
+            ["freeze", addr] => {
+                let addr = parse_addr(addr)?;
+                return self.freeze_at(addr, self.vm.peek(addr));
+            },
+            ["freeze", addr, value] => return self.freeze_at(parse_addr(addr)?, parse_value(value)?),
+            ["unfreeze", addr] => {
+                let addr = parse_addr(addr)?;
+                return match self.freeze.remove(addr) {
+                    true => Ok(format!("{:04X} unfrozen", addr)),
+                    false => Err(format!("{:04X} is not frozen", addr)),
+                };
+            },
+            _ => return Err(String::from(CHEAT_HELP)),
+        };
+        match self.search.as_mut() {
+            Some(search) => Ok(format!("{} candidates left", search.filter(&self.vm, filter))),
+            None => Err(String::from("no search, take a snapshot with snap first")),
+        }
+    }
+
+    fn freeze_at(&mut self, addr: u16, value: u8) -> Result<String, String> {
+        self.freeze.set(addr, value);
+        self.freeze.apply(&mut self.vm);
+        Ok(format!("{:04X} frozen at {:02X}", addr, value))
+    }
+
+    /// Lines of cheat search candidates and frozen addresses for right pane
+    fn cheat_lines(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        if let Some(search) = self.search.as_ref() {
+            let candidates = search.candidates();
+            lines.push(format!("Search: {} candidates", candidates.len()));
+            for &addr in candidates.iter().take(CHEAT_LINES) {
+                lines.push(format!("  {:04X}: {:02X} (was {:02X})", addr, self.vm.peek(addr), search.value(addr)));
+            }
+        }
+        if !self.freeze.entries().is_empty() {
+            let frozen: Vec<String> = self.freeze.entries().iter()
+                .map(|(addr, value)| format!("{:04X}={:02X}", addr, value))
+                .collect();
+            lines.push(format!("Frozen: {}", frozen.join(" ")));
+        }
+        if !lines.is_empty() {
+            lines.push(String::new());
+        }
+        lines
+    }
+
+    /// Address and text of disassembly lines from top
+    fn disasm_lines(&self, count: u16) -> Vec<(u16, String)> {
+        let mut addr = self.top;
+        (0..count).map(|_| {
+            let (text, len) = self.vm.disassemble(addr);
+            let line = (addr, text);
+            addr = addr.wrapping_add(len);
+            line
+        }).collect()
+    }
+
+    fn registers(&self) -> Vec<String> {
+        let regs = self.vm.cpu.trace_entry();
+        let flag = |bit: u16, name: char| if regs.af & (1 << bit) != 0 { name } else { '-' };
+        vec![
+            format!("AF {:04X}  BC {:04X}", regs.af, regs.bc),
+            format!("DE {:04X}  HL {:04X}", regs.de, regs.hl),
+            format!("SP {:04X}  PC {:04X}", regs.sp, regs.pc),
+            format!("Flags {}{}{}{}  IME {}", flag(7, 'Z'), flag(6, 'N'), flag(5, 'H'), flag(4, 'C'),
+                    self.vm.interrupt_master_enabled() as u8),
+            format!("IF {:02X}  IE {:02X}", self.vm.peek(0xff0f), self.vm.peek(0xffff)),
+            format!("LCD {:?} LY {}", self.vm.cpu.bus.gpu.mode, self.vm.cpu.bus.gpu.line),
+        ]
+    }
+
+    fn draw(&mut self) -> crossterm::Result<()> {
+        let (cols, rows) = terminal::size()?;
+        let lines = rows.saturating_sub(1);
+        let pc = self.vm.cpu.pc;
+
+        for (row, (addr, text)) in self.disasm_lines(lines).iter().enumerate() {
+            let mark = if self.vm.has_breakpoint(*addr) { '*' } else { ' ' };
+            let arrow = if *addr == pc { '>' } else { ' ' };
+            let line = format!("{}{}{:04X}  {}", mark, arrow, addr, text);
+            let selected = row as u16 == self.cursor;
+            queue!(self.stdout, MoveTo(0, row as u16))?;
+            if *addr == pc {
+                queue!(self.stdout, SetAttribute(Attribute::Reverse))?;
+            }
+            if selected {
+                queue!(self.stdout, SetAttribute(Attribute::Underlined))?;
+            }
+            queue!(self.stdout, Print(format!("{:<width$.width$}", line, width = DISASM_WIDTH as usize)),
+                   SetAttribute(Attribute::Reset))?;
+        }
+
+        let mut right = self.registers();
+        right.push(String::new());
+        right.extend(self.cheat_lines());
+        let hex_rows = lines.saturating_sub(right.len() as u16);
+        for row in 0..hex_rows {
+            let addr = self.hex_addr.wrapping_add(row * HEX_COLUMNS);
+            let bytes: Vec<String> = (0..HEX_COLUMNS)
+                .map(|i| format!("{:02X}", self.vm.peek(addr.wrapping_add(i))))
+                .collect();
+            right.push(format!("{:04X}: {}", addr, bytes.join(" ")));
+        }
+        for (row, text) in right.iter().enumerate() {
+            queue!(self.stdout, MoveTo(DISASM_WIDTH + 1, row as u16), Print("│ "), Print(text),
+                   Clear(ClearType::UntilNewLine))?;
+        }
+
+        let message = match self.prompt.as_ref() {
+            Some(prompt) => format!(":{}", prompt),
+            None => self.message.clone(),
+        };
+        let status = format!("{} | cycle {} frame {} | {}",
+                             if self.running { "RUN " } else { "STOP" },
+                             self.vm.cpu.cycles(), self.vm.frame_count(), message);
+        queue!(self.stdout, MoveTo(0, lines), SetAttribute(Attribute::Reverse),
+               Print(format!("{:<width$.width$}", status, width = cols as usize)),
+               SetAttribute(Attribute::Reset))?;
+        self.stdout.flush()?;
+        Ok(())
+    }
+}
+
+impl Drop for Debugger {
+    fn drop(&mut self) {
+        execute!(self.stdout, Show, LeaveAlternateScreen).unwrap_or(());
+        terminal::disable_raw_mode().unwrap_or(());
+    }
+}
@@ -0,0 +1,17 @@
+use std::fmt;
+
+/// Error detected while emulating, kept by the CPU for inspection
+#[derive(Debug,Clone,PartialEq)]
+pub enum EmuError {
+    /// stack access outside of WRAM and HRAM
+    StackCorruption { sp: u16, pc: u16 },
+}
+
+impl fmt::Display for EmuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EmuError::StackCorruption { sp, pc } =>
+                write!(f, "Stack corruption at PC {:#06x}, SP {:#06x}", pc, sp),
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use rugameboy::joypad::JoypadKey;
+
+/// Input event reported by frontend
+pub enum InputEvent {
+    Press(JoypadKey),
+    Release(JoypadKey),
+    /// turbo of key is turned on or off
+    Turbo(JoypadKey, bool),
+    Pause,
+    /// run one frame while paused
+    FrameAdvance,
+    /// slow motion key is pressed or released
+    SlowMotion(bool),
+    /// open or close pause menu
+    Menu,
+}
+
+/// Keyboard layout of user. Keys are bound by position on QWERTY keyboard,
+/// the window library reports keys by layout, so letters are moved back.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum KeyboardLayout {
+    Qwerty,
+    Azerty,
+    Qwertz,
+}
+
+impl KeyboardLayout {
+    /// Lowercase letter at the same position on QWERTY keyboard
+    pub fn to_qwerty(self, letter: char) -> char {
+        match (self, letter) {
+            (KeyboardLayout::Azerty, 'a') => 'q',
+            (KeyboardLayout::Azerty, 'q') => 'a',
+            (KeyboardLayout::Azerty, 'z') => 'w',
+            (KeyboardLayout::Azerty, 'w') => 'z',
+            (KeyboardLayout::Qwertz, 'z') => 'y',
+            (KeyboardLayout::Qwertz, 'y') => 'z',
+            _ => letter,
+        }
+    }
+}
+
+/// Frontend shows the frame and collects user input,
+/// main loop only talks to this trait so the window library is not hard-wired
+pub trait Frontend {
+    /// show one frame of WIDTH x HEIGHT pixels
+    fn present_frame(&mut self, buffer: &[u32]);
+    /// input events since the last poll
+    fn poll_input(&mut self) -> Vec<InputEvent>;
+    /// whether user requests to close the emulator
+    fn should_close(&self) -> bool;
+}
@@ -1,12 +1,14 @@
 use crate::bus::{Device};
-use crate::{WIDTH, HEIGHT};
+use crate::vm::{WIDTH, HEIGHT};
 
-use std::cmp::min;
+use std::cmp::{min, Reverse};
 
 const BLACK: u32 = 0x00000000u32;
 const DGRAY: u32 = 0x00555555u32;
 const LGRAY: u32 = 0x00AAAAAAu32;
 const WHITE: u32 = 0x00FFFFFFu32;
+/// screen colors of shade 0 to 3
+pub const GRAYSCALE: [u32; 4] = [WHITE, LGRAY, DGRAY, BLACK];
 
 /*
  * VRAM from 0x8000 to 0xA000, 8192 bytes total
@@ -24,10 +26,24 @@ const WHITE: u32 = 0x00FFFFFFu32;
  */
 pub const VRAM_START:     u16 = 0x8000;
 pub const VRAM_END:       u16 = 0x9fff;
+const VRAM_SIZE:          usize = 0x2000;
+/// tiles in tile data 0x8000-0x97FF, 16 bytes each
+pub const TILE_COUNT:     usize = 384;
 pub const OAM_START:      u16 = 0xfe00;
 pub const OAM_END:        u16 = 0xfe9f;
+pub const OAM_SIZE:       usize = 160;
 
-#[derive(PartialEq)]
+/// sprites drawn on one line at most
+const MAX_SPRITES_PER_LINE: usize = 10;
+
+/// clock of each mode in one scanline, HBlank fills the remainder of the line
+const LINE_CLOCK:     u64 = 456;
+const OAM_CLOCK:      u64 = 80;
+const VRAM_CLOCK:     u64 = 172;
+/// LY reads 153 for this many clocks of line 153, then 0
+const LY_153_CLOCK:   u64 = 4;
+
+#[derive(Debug,PartialEq,Clone)]
 pub enum GpuMode {
     /// First scanline mode, render data from OAM memory
     ScanlineOAM,
@@ -39,6 +55,17 @@ pub enum GpuMode {
     VBlank,
 }
 
+/// Palette registers of DMG
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Palette {
+    /// BGP: background & window
+    Bg = 0,
+    /// OBP0: object palette 0
+    Obj0 = 1,
+    /// OBP1: object palette 1
+    Obj1 = 2,
+}
+
 #[derive(Debug,Clone,Copy)]
 pub struct LCDC {
     /// LCD control operation
@@ -101,88 +128,132 @@ impl LCDC {
     }
 }
 
+/// decode one line of tile from its two bytes, leftmost pixel first.
+/// First byte holds the low bit of each pixel, second byte the high bit.
+fn decode_line(low: u8, high: u8) -> [u8; 8] {
+    let mut pixels = [0; 8];
+    for (i, pixel) in pixels.iter_mut().enumerate() {
+        let bit0 = (low >> (7 - i)) & 0x1;
+        let bit1 = (high >> (7 - i)) & 0x1;
+        *pixel = bit1 << 1 | bit0;
+    }
+    pixels
+}
+
 #[derive(Default,Clone,Copy,Debug)]
 pub struct Sprite {
     /// tile_idx: sprite shows tile number
-    tile_idx: u8,
+    pub tile_idx: u8,
     /// x: sprite left position
     /// y: sprite top position
-    x: isize,
-    y: isize,
+    pub x: isize,
+    pub y: isize,
     /// priority:
     /// 0: on top of background and window
     /// 1: behind color 1, 2, 3 of background and window
-    priority: bool,
+    pub priority: bool,
     /// flip_y: flip if 1
-    flip_y: bool,
+    pub flip_y: bool,
     /// flip: flip if 1
-    flip_x: bool,
+    pub flip_x: bool,
     /// palette_number:
     /// 0: from OBJ0PAL
     /// 1: from OBJ1PAL
-    palette_number: bool
+    pub palette_number: bool
 }
 
+#[derive(Clone)]
 pub struct Gpu {
     /// Clock to switch mode
     clock: u64,
+    /// length of ScanlineVRAM mode of current line
+    mode3_clock: u64,
     /// current display line number
     pub line: u8,
     /// lcdc, LCD control line
     pub lcdc: LCDC,
-    /// background & window palette data
-    pub bg_palette: u8,
-    /// object palette 0
-    pub ob0_palette: u8,
-    /// object palette 1
-    pub ob1_palette: u8,
+    /// BGP, OBP0, OBP1 as written by CPU, indexed by Palette
+    palettes: [u8; 3],
+    /// screen color of pixel 0 to 3 of each palette, updated on palette write
+    palette_colors: [[u32; 4]; 3],
+    /// palette_colors each screen line is drawn with,
+    /// palette write only changes lines not drawn yet
+    line_colors: Vec<[[u32; 4]; 3]>,
     /// current display mode
     pub mode: GpuMode,
     /// SCY: background Y position
     pub scy: u8,
     /// SCX: background X position
     pub scx: u8,
-    /// vram: 0x8000-0x9FFF 8192 bytes
+    /// vram: 0x8000-0x9FFF 8192 bytes, two banks in CGB mode
     vram: Vec<u8>,
+    /// VBK: vram bank accessed by CPU, always 0 in DMG mode
+    vram_bank: usize,
+    /// CGB features enabled
+    pub cgb_mode: bool,
+    /// OPRI: sprite priority by X coordinate like DMG, or by OAM order only
+    coordinate_priority: bool,
     /// oam: 0xFE00-0xFE9F 160 bytes
     oam: Vec<u8>,
 
     /// sprite
     sprite: [Sprite;40],
+    /// sprites with OAM index selected by OAM scan of each screen line,
+    /// so OAM written after the scan does not change the line
+    line_sprites: Vec<Vec<(usize, Sprite)>>,
+    /// sprite height at OAM scan of each screen line, LCDC may change it later
+    line_sprite_height: Vec<isize>,
     /// background buffer not mapped by bg_palette
     unmapped_bg: Vec<u8>,
+    /// screen color of each shade, lightest first
+    colors: [u32; 4],
     // whether vblank interrupt is occured
     pub is_interrupt: bool
 }
 
+impl Default for Gpu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Gpu {
     pub fn new() -> Self {
-        let vram = vec![0; (VRAM_END - VRAM_START + 1) as usize];
-        let oam = vec![0; (OAM_END - OAM_START + 1) as usize];
-        let unmapped_bg = vec![0; WIDTH * HEIGHT as usize];
-        Self {
+        let vram = vec![0; VRAM_SIZE];
+        let oam = vec![0; OAM_SIZE];
+        let unmapped_bg = vec![0; WIDTH * HEIGHT];
+        let mut gpu = Self {
             clock: 0,
+            mode3_clock: VRAM_CLOCK,
             line: 0,
             lcdc: LCDC::from_u8(0x91),
-            bg_palette: 0xfc,
-            ob0_palette: 0xff,
-            ob1_palette: 0xff,
+            palettes: [0xfc, 0xff, 0xff],
+            palette_colors: [[0; 4]; 3],
+            line_colors: vec![[[0; 4]; 3]; HEIGHT],
             mode: GpuMode::ScanlineOAM,
             scy: 0,
             scx: 0,
             vram,
+            vram_bank: 0,
+            cgb_mode: false,
+            coordinate_priority: true,
             oam,
             unmapped_bg,
+            colors: GRAYSCALE,
             sprite: [Default::default();40],
+            line_sprites: (0..HEIGHT).map(|_| Vec::with_capacity(MAX_SPRITES_PER_LINE)).collect(),
+            line_sprite_height: vec![8; HEIGHT],
             is_interrupt: false
-        }
+        };
+        gpu.update_palette_colors(0);
+        gpu
     }
 
     pub fn get_tile_line(&self, tile_idx: u8, line_idx: usize, is_sprite: bool) -> Vec<u8> {
         assert!(line_idx < 8);
         let line_idx = line_idx as isize;
         let addr = if is_sprite || self.lcdc.bg_tile_data_select {
-            let baseaddr = 0x8000 - 0x8000;
+            let baseaddr = 0;
             let tile_idx = tile_idx as isize;
             baseaddr + (tile_idx * 8 + line_idx) * 2
         } else {
@@ -191,42 +262,101 @@ impl Gpu {
             baseaddr + (tile_idx * 8 + line_idx) * 2
         } as usize;
 
-        let byte1 = self.vram[addr];
-        let byte2 = self.vram[addr+1];
+        decode_line(self.vram[addr], self.vram[addr+1]).to_vec()
+    }
 
-        let mut pxs = Vec::with_capacity(8);
+    /// Pixels of tile in 0x8000-0x97FF before palette, index 0 to 383.
+    /// Bank 1 is only available in CGB mode, None if index or bank is out of range.
+    pub fn tile_pixels(&self, index: usize, bank: usize) -> Option<[[u8; 8]; 8]> {
+        if index >= TILE_COUNT || (bank + 1) * VRAM_SIZE > self.vram.len() {
+            return None;
+        }
+        let mut pixels = [[0; 8]; 8];
+        for (line_idx, line) in pixels.iter_mut().enumerate() {
+            let addr = bank * VRAM_SIZE + index * 16 + line_idx * 2;
+            *line = decode_line(self.vram[addr], self.vram[addr+1]);
+        }
+        Some(pixels)
+    }
 
-        for j in (0..8).rev() {
-            let bit1 = (byte1 >> j) & 0x1;
-            let bit2 = (byte2 >> j) & 0x1;
-            let pixel = bit1 << 1 | bit2;
-            pxs.push(pixel);
+    /// Tile indices of background map at 0x9800 or 0x9C00 if map_select
+    pub fn bg_map(&self, map_select: bool) -> [[u8; 32]; 32] {
+        let base = if map_select { 0x9c00 } else { 0x9800 } - VRAM_START as usize;
+        let mut map = [[0; 32]; 32];
+        for (row, tiles) in map.iter_mut().enumerate() {
+            tiles.copy_from_slice(&self.vram[base + row * 32..base + (row + 1) * 32]);
         }
-        pxs
+        map
+    }
+
+    /// Background map address and tile index under screen pixel (x, y),
+    /// scrolled by SCX/SCY and wrapped around the 256x256 map
+    pub fn bg_tile_at(&self, x: u8, y: u8) -> (u16, u8) {
+        let map_x = x.wrapping_add(self.scx) as u16 / 8;
+        let map_y = y.wrapping_add(self.scy) as u16 / 8;
+        let base = if self.lcdc.bg_tile_map_select { 0x9c00 } else { 0x9800 };
+        let addr = base + map_y * 32 + map_x;
+        (addr, self.vram[(addr - VRAM_START) as usize])
     }
 
-    fn pixel_to_color(&self, pixel: u8) -> u32 {
-        match pixel {
-            3 => BLACK,
-            2 => DGRAY,
-            1 => LGRAY,
-            0 => WHITE,
-            _ => panic!("Invalid value in u8_to_grayscale"),
+    /// Index of tile for tile_pixels used by background map entry,
+    /// by the current BG & window tile data select
+    pub fn bg_tile_index(&self, map_entry: u8) -> usize {
+        if self.lcdc.bg_tile_data_select {
+            map_entry as usize
+        } else {
+            (256 + (map_entry as i8) as isize) as usize
         }
     }
 
-    fn pixel_map_by_palette(&self, palette: u8, pixel: u8) -> u8 {
-        match pixel {
-            3 => (palette >> 6) & 0x3,
-            2 => (palette >> 4) & 0x3,
-            1 => (palette >> 2) & 0x3,
-            0 => (palette >> 0) & 0x3,
-            _ => panic!("Invalid value in u8_from_palette"),
+    /// Background pixels of the last built screen before bg_palette, WIDTH x HEIGHT
+    pub fn background_indices(&self) -> &[u8] {
+        &self.unmapped_bg
+    }
+
+    /// Sprites decoded from OAM, in OAM order
+    pub fn sprites(&self) -> &[Sprite; 40] {
+        &self.sprite
+    }
+
+    /// Set screen colors of shade 0 to 3, lightest first, 0x00RRGGBB
+    pub fn set_colors(&mut self, colors: [u32; 4]) {
+        self.colors = colors;
+        self.update_palette_colors(0);
+    }
+
+    /// Palette register byte as last written
+    pub fn palette(&self, palette: Palette) -> u8 {
+        self.palettes[palette as usize]
+    }
+
+    pub fn set_palette(&mut self, palette: Palette, value: u8) {
+        self.palettes[palette as usize] = value;
+        // line being drawn keeps the old palette, all lines of next frame in VBlank
+        let first_line = match self.mode {
+            GpuMode::VBlank => 0,
+            GpuMode::ScanlineOAM => self.line as usize,
+            GpuMode::ScanlineVRAM | GpuMode::HBlank => self.line as usize + 1,
+        };
+        self.update_palette_colors(first_line);
+    }
+
+    /// Screen color of pixel 0 to 3 mapped by palette
+    pub fn palette_colors(&self, palette: Palette) -> [u32; 4] {
+        self.palette_colors[palette as usize]
+    }
+
+    fn update_palette_colors(&mut self, first_line: usize) {
+        for (colors, &palette) in self.palette_colors.iter_mut().zip(self.palettes.iter()) {
+            for (pixel, color) in colors.iter_mut().enumerate() {
+                *color = self.colors[((palette >> (pixel * 2)) & 0x3) as usize];
+            }
         }
+        let palette_colors = self.palette_colors;
+        self.line_colors.iter_mut().skip(first_line).for_each(|colors| *colors = palette_colors);
     }
 
     fn build_background(&mut self, buffer: &mut Vec<u32>) {
-        let bg_palette = self.bg_palette;
         let x = self.scx as usize;
         let y = self.scy as usize;
         let tile_base = if self.lcdc.bg_tile_map_select { 0x9C00 } else { 0x9800 } - 0x8000;
@@ -255,44 +385,42 @@ impl Gpu {
                 }
                 let pixel_end = min((offset_row + 1) * WIDTH, pixel_start + 8);
 
+                let bg_colors = self.line_colors[offset_row][Palette::Bg as usize];
                 self.unmapped_bg.splice(pixel_start..pixel_end, pixels.iter().cloned());
                 buffer.splice(pixel_start..pixel_end,
-                    pixels.iter()
-                          .map(|p| self.pixel_map_by_palette(bg_palette, *p))
-                          .map(|p| self.pixel_to_color(p)));
+                    pixels.iter().map(|p| bg_colors[*p as usize]));
             }
         }
     }
 
-    fn build_sprite(&self, buffer: &mut Vec<u32>) {
-        for sprite in self.sprite.iter() {
-            // check sprite intersect with screen
-            let sprite_height = if self.lcdc.obj_size {
-                16
+    fn build_sprite(&self, buffer: &mut [u32]) {
+        for y in 0..HEIGHT as isize {
+            let sprite_height = self.line_sprite_height[y as usize];
+            let mut selected = self.line_sprites[y as usize].clone();
+            // draw lowest priority first so the highest one is on top,
+            // smaller x wins, OAM index breaks the tie, CGB priority uses OAM index only
+            if self.coordinate_priority {
+                selected.sort_by_key(|&(idx, sprite)| Reverse((sprite.x, idx)));
             } else {
-                8
-            };
-            if sprite.y + sprite_height <= 0 || sprite.x + 8 <= 0 ||
-               (sprite.x as usize) > WIDTH || (sprite.y as usize) > HEIGHT {
-                continue;
+                selected.sort_by_key(|&(idx, _)| Reverse(idx));
             }
 
-            let palette = if sprite.palette_number {
-                self.ob1_palette
-            } else {
-                self.ob0_palette
-            };
+            for (_, sprite) in selected.iter() {
+                let palette = if sprite.palette_number { Palette::Obj1 } else { Palette::Obj0 };
+                let colors = self.line_colors[y as usize][palette as usize];
 
-            for row_idx in 0..8 {
-                let y = sprite.y + row_idx as isize;
-                if y < 0 || (y as usize) > HEIGHT {
-                    continue;
-                }
-                let y_idx = if sprite.flip_y { 7-row_idx } else { row_idx };
-                let pixels = self.get_tile_line(sprite.tile_idx, y_idx, true);
+                let row_idx = (y - sprite.y) as usize;
+                let y_idx = if sprite.flip_y { sprite_height as usize - 1 - row_idx } else { row_idx };
+                // 8x16 sprite uses tile pair, upper tile index ignores bit 0
+                let tile_idx = if sprite_height == 16 {
+                    (sprite.tile_idx & 0xfe) + (y_idx / 8) as u8
+                } else {
+                    sprite.tile_idx
+                };
+                let pixels = self.get_tile_line(tile_idx, y_idx % 8, true);
                 for col_idx in 0..8 {
                     let x = sprite.x + col_idx as isize;
-                    if x < 0 || (x as usize) > WIDTH {
+                    if x < 0 || (x as usize) >= WIDTH {
                         continue;
                     }
                     let x_idx = if sprite.flip_x { 7-col_idx } else { col_idx };
@@ -300,11 +428,9 @@ impl Gpu {
                         continue;
                     }
 
-                    // fill the buffer
-                    let dibit = self.pixel_map_by_palette(palette, pixels[x_idx]);
-                    if dibit != 0 {
-                        let color = self.pixel_to_color(dibit);
-                        buffer[y as usize * WIDTH + x as usize] = color;
+                    // fill the buffer, pixel 0 is transparent
+                    if pixels[x_idx] != 0 {
+                        buffer[y as usize * WIDTH + x as usize] = colors[pixels[x_idx] as usize];
                     }
                 }
             }
@@ -327,16 +453,18 @@ impl Gpu {
         // switch state
         self.clock = self.clock.wrapping_add(clock);
         match self.mode {
-            GpuMode::ScanlineOAM if self.clock >= 80 => {
-                self.clock -= 80;
+            GpuMode::ScanlineOAM if self.clock >= OAM_CLOCK => {
+                self.clock -= OAM_CLOCK;
+                self.scan_sprites();
+                self.mode3_clock = VRAM_CLOCK;
                 self.mode = GpuMode::ScanlineVRAM;
             },
-            GpuMode::ScanlineVRAM if self.clock >= 172 => {
-                self.clock -= 172;
+            GpuMode::ScanlineVRAM if self.clock >= self.mode3_clock => {
+                self.clock -= self.mode3_clock;
                 self.mode = GpuMode::HBlank;
             },
-            GpuMode::HBlank if self.clock >= 204 => {
-                self.clock -= 204;
+            GpuMode::HBlank if self.clock >= LINE_CLOCK - OAM_CLOCK - self.mode3_clock => {
+                self.clock -= LINE_CLOCK - OAM_CLOCK - self.mode3_clock;
                 if self.line >= 143 {
                     self.mode = GpuMode::VBlank;
                     // enable vblank interrupt
@@ -346,18 +474,115 @@ impl Gpu {
                 }
                 self.line += 1;
             },
-            GpuMode::VBlank if self.clock >= 456 => {
-                self.clock -= 456;
-                self.line += 1;
+            GpuMode::VBlank if self.clock >= LINE_CLOCK => {
+                self.clock -= LINE_CLOCK;
+                // VBlank lasts 10 lines, from line 144 to 153
                 if self.line >= 153 {
                     self.line = 0;
                     self.mode = GpuMode::ScanlineOAM;
+                    self.update_palette_colors(0);
+                } else {
+                    self.line += 1;
                 }
             },
             _ => {},
         }
     }
 
+    /// Jump to raster position for tests and debugging, clock is spent in
+    /// mode already. Sprites of the line are scanned again past OAM scan.
+    pub fn force_position(&mut self, line: u8, mode: GpuMode, clock: u64) {
+        assert!((mode == GpuMode::VBlank) == (line as usize >= HEIGHT) && line <= 153,
+                "line {} is not in {:?}", line, mode);
+        let scanned = mode == GpuMode::ScanlineVRAM || mode == GpuMode::HBlank;
+        self.line = line;
+        self.mode = mode;
+        self.clock = clock;
+        self.mode3_clock = VRAM_CLOCK;
+        if scanned {
+            self.scan_sprites();
+        }
+    }
+
+    /// LY register, the last line wraps to 0 early
+    pub fn ly(&self) -> u8 {
+        if self.line == 153 && self.clock >= LY_153_CLOCK { 0 } else { self.line }
+    }
+
+    // hardware selects the first 10 sprites on the line in OAM order
+    fn scan_sprites(&mut self) {
+        let y = self.line as isize;
+        if self.line as usize >= HEIGHT {
+            return;
+        }
+        let sprite_height = if self.lcdc.obj_size { 16 } else { 8 };
+        self.line_sprite_height[y as usize] = sprite_height;
+        let selected = &mut self.line_sprites[y as usize];
+        selected.clear();
+        selected.extend(self.sprite.iter()
+            .cloned()
+            .enumerate()
+            .filter(|(_, sprite)| sprite.y <= y && y < sprite.y + sprite_height)
+            .take(MAX_SPRITES_PER_LINE));
+    }
+
+    /// enable CGB features, add the second vram bank
+    pub fn set_cgb_mode(&mut self, enable: bool) {
+        self.cgb_mode = enable;
+        // boot ROM selects CGB priority for CGB games
+        self.coordinate_priority = !enable;
+        self.vram_bank = 0;
+        self.vram.resize(if enable { VRAM_SIZE * 2 } else { VRAM_SIZE }, 0);
+    }
+
+    /// load VBK register, only bit 0 is used
+    pub fn load_vram_bank(&self) -> u8 {
+        0xfe | self.vram_bank as u8
+    }
+
+    /// store VBK register, ignored in DMG mode
+    pub fn store_vram_bank(&mut self, value: u8) {
+        if self.cgb_mode {
+            self.vram_bank = (value & 0x1) as usize;
+        }
+    }
+
+    /// load OPRI register, bit 0 set for priority by X coordinate
+    pub fn load_object_priority(&self) -> u8 {
+        0xfe | self.coordinate_priority as u8
+    }
+
+    /// store OPRI register, ignored in DMG mode
+    pub fn store_object_priority(&mut self, value: u8) {
+        if self.cgb_mode {
+            self.coordinate_priority = value & 0x1 != 0;
+        }
+    }
+
+    /// Whole OAM, 4 bytes per sprite
+    pub fn oam(&self) -> [u8; OAM_SIZE] {
+        let mut oam = [0; OAM_SIZE];
+        oam.copy_from_slice(&self.oam);
+        oam
+    }
+
+    /// Write whole OAM without DMA, sprites are decoded again
+    pub fn set_oam(&mut self, oam: &[u8; OAM_SIZE]) {
+        self.oam.copy_from_slice(oam);
+        for addr in 0..OAM_SIZE {
+            self.update_sprite(addr);
+        }
+    }
+
+    /// fill VRAM and OAM with bytes from next
+    pub fn fill_with(&mut self, next: &mut dyn FnMut() -> u8) {
+        self.vram.iter_mut().for_each(|byte| *byte = next());
+        for addr in 0..self.oam.len() {
+            self.oam[addr] = next();
+            self.update_sprite(addr);
+        }
+    }
+
     fn update_sprite(&mut self, addr: usize) {
         let sprite_idx = addr / 4;
         let value = self.oam[addr];
@@ -380,7 +605,7 @@ impl Device for Gpu {
     fn load(&self, addr: u16) -> Result<u8, ()> {
         match addr {
             VRAM_START ..= VRAM_END => {
-                let addr = (addr - VRAM_START) as usize;
+                let addr = self.vram_bank * VRAM_SIZE + (addr - VRAM_START) as usize;
                 match self.vram.get(addr) {
                     Some(elem) => Ok(*elem),
                     None => Err(()),
@@ -400,8 +625,8 @@ impl Device for Gpu {
     fn store(&mut self, addr: u16, value: u8) -> Result<(), ()> {
         match addr {
             VRAM_START ..= VRAM_END => {
-                let addr = (addr - VRAM_START) as usize;
-                match self.vram.get_mut(addr as usize) {
+                let addr = self.vram_bank * VRAM_SIZE + (addr - VRAM_START) as usize;
+                match self.vram.get_mut(addr) {
                     Some(elem) => {
                         *elem = value;
                         Ok(())
@@ -411,7 +636,7 @@ impl Device for Gpu {
             }
             OAM_START ..= OAM_END => {
                 let addr = (addr - OAM_START) as usize;
-                match self.oam.get_mut(addr as usize) {
+                match self.oam.get_mut(addr) {
                     Some(elem) => {
                         *elem = value;
                         self.update_sprite(addr);
@@ -424,3 +649,173 @@ impl Device for Gpu {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sprite_keeps_height_of_oam_scan() {
+        let mut gpu = Gpu::new();
+        gpu.lcdc = LCDC::from_u8(0x97);
+        // sprite 0 at line 0-15 flipped, line 10 shows line 5 of the tile pair
+        gpu.set_oam(&{
+            let mut oam = [0; OAM_SIZE];
+            oam[..4].copy_from_slice(&[16, 8, 0, 0x40]);
+            oam
+        });
+        gpu.store(0x800a, 0xff).unwrap();
+        gpu.force_position(10, GpuMode::ScanlineVRAM, 0);
+        // switch to 8x8 sprites after the scan
+        gpu.lcdc = LCDC::from_u8(0x93);
+        let mut buffer = vec![0; WIDTH * HEIGHT];
+        gpu.build_screen(&mut buffer);
+        assert_eq!(buffer[10 * WIDTH], gpu.palette_colors(Palette::Obj0)[1]);
+    }
+
+    #[test]
+    fn every_visible_line_takes_456_clocks() {
+        let mut gpu = Gpu::new();
+        // 10 sprites on line 0-7, none below
+        gpu.set_oam(&{
+            let mut oam = [0; OAM_SIZE];
+            for i in 0..10 {
+                oam[i * 4..i * 4 + 4].copy_from_slice(&[16, 8 + 8 * i as u8, 0, 0]);
+            }
+            oam
+        });
+        for line in 0..HEIGHT as u8 {
+            let mut clocks = 0;
+            let mut oam_clocks = 0;
+            while gpu.line == line {
+                if gpu.mode == GpuMode::ScanlineOAM {
+                    oam_clocks += 4;
+                }
+                gpu.update(4);
+                clocks += 4;
+                // longer mode 3 on odd lines, like sprite or SCX penalty
+                if gpu.mode == GpuMode::ScanlineVRAM && line % 2 == 1 {
+                    gpu.mode3_clock = VRAM_CLOCK + 12;
+                }
+            }
+            assert_eq!(oam_clocks, OAM_CLOCK, "line {}", line);
+            assert_eq!(clocks, LINE_CLOCK, "line {}", line);
+        }
+    }
+
+    #[test]
+    fn tile_pixels_decode_bit_planes() {
+        let mut gpu = Gpu::new();
+        let data = [0xff, 0x00, 0x00, 0xff, 0xff, 0xff, 0xf0, 0x3c];
+        for (i, byte) in data.iter().enumerate() {
+            gpu.store(0x8050 + i as u16, *byte).unwrap();
+        }
+        let pixels = gpu.tile_pixels(5, 0).unwrap();
+        assert_eq!(pixels[0], [1; 8]);
+        assert_eq!(pixels[1], [2; 8]);
+        assert_eq!(pixels[2], [3; 8]);
+        assert_eq!(pixels[3], [1, 1, 3, 3, 2, 2, 0, 0]);
+        assert_eq!(pixels[4..], [[0; 8]; 4]);
+    }
+
+    #[test]
+    fn tile_pixels_out_of_range() {
+        let gpu = Gpu::new();
+        assert_eq!(gpu.tile_pixels(TILE_COUNT, 0), None);
+        assert!(gpu.tile_pixels(TILE_COUNT - 1, 0).is_some());
+        // DMG has no VRAM bank 1
+        assert_eq!(gpu.tile_pixels(0, 1), None);
+    }
+
+    #[test]
+    fn background_indices_are_tile_data() {
+        let mut gpu = Gpu::new();
+        // tile 1: line 0 all 1, line 1 all 2, at the second tile of the map
+        for (i, byte) in [0xff, 0x00, 0x00, 0xff].iter().enumerate() {
+            gpu.store(0x8010 + i as u16, *byte).unwrap();
+        }
+        gpu.store(0x9801, 1).unwrap();
+        let mut buffer = vec![0; WIDTH * HEIGHT];
+        gpu.build_screen(&mut buffer);
+        let indices = gpu.background_indices();
+        assert_eq!(indices[..16], [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 1]);
+        assert_eq!(indices[WIDTH + 8..WIDTH + 16], [2; 8]);
+        // screen is the same pixels after palette
+        let colors = gpu.palette_colors(Palette::Bg);
+        assert_eq!(buffer[8], colors[1]);
+        assert_eq!(buffer[WIDTH + 8], colors[2]);
+    }
+
+    #[test]
+    fn palette_write_mid_frame_splits_screen() {
+        let mut gpu = Gpu::new();
+        while gpu.line < 72 {
+            gpu.update(4);
+        }
+        // fade the lower half to black
+        gpu.set_palette(Palette::Bg, 0xff);
+        while gpu.mode != GpuMode::VBlank {
+            gpu.update(4);
+        }
+        let mut buffer = vec![0; WIDTH * HEIGHT];
+        gpu.build_screen(&mut buffer);
+        assert_eq!(buffer[71 * WIDTH], GRAYSCALE[0]);
+        assert_eq!(buffer[72 * WIDTH], GRAYSCALE[3]);
+        assert!(buffer[..72 * WIDTH].iter().all(|&p| p == GRAYSCALE[0]));
+        assert!(buffer[72 * WIDTH..].iter().all(|&p| p == GRAYSCALE[3]));
+    }
+
+    #[test]
+    fn object_priority_register_picks_winner() {
+        use crate::testing::Scene;
+        // sprite 0 of color 1 at x 4, sprite 1 of color 2 at x 0, overlapping at x 4-7
+        let render = |opri: Option<u8>| {
+            let mut scene = Scene::new();
+            scene.gpu.set_cgb_mode(true);
+            if let Some(value) = opri {
+                scene.gpu.store_object_priority(value);
+            }
+            scene.gpu.lcdc = LCDC::from_u8(0x93);
+            scene.gpu.set_palette(Palette::Obj0, 0xe4);
+            scene.set_tile(1, [[1; 8]; 8]);
+            scene.set_tile(2, [[2; 8]; 8]);
+            scene.set_sprite(0, 16, 8 + 4, 1, 0);
+            scene.set_sprite(1, 16, 8, 2, 0);
+            let colors = scene.gpu.palette_colors(Palette::Obj0);
+            let pixel = scene.render()[4];
+            colors.iter().position(|&color| color == pixel)
+        };
+        // CGB game default and OPRI 0: OAM order
+        assert_eq!(render(None), Some(1));
+        assert_eq!(render(Some(0)), Some(1));
+        // OPRI 1, as set for DMG game: smaller x
+        assert_eq!(render(Some(1)), Some(2));
+    }
+
+    #[test]
+    fn dmg_same_x_sprite_lower_index_on_top() {
+        use crate::testing::Scene;
+        // sprites given as (OAM index, x, color), the color seen at x 4 is returned
+        let render = |sprites: &[(usize, u8, u8)]| {
+            let mut scene = Scene::new();
+            scene.gpu.lcdc = LCDC::from_u8(0x93);
+            scene.gpu.set_palette(Palette::Obj0, 0xe4);
+            for color in 1..4 {
+                scene.set_tile(color, [[color as u8; 8]; 8]);
+            }
+            for &(idx, x, color) in sprites {
+                scene.set_sprite(idx, 16, 8 + x, color, 0);
+            }
+            let colors = scene.gpu.palette_colors(Palette::Obj0);
+            let pixel = scene.render()[4];
+            colors.iter().position(|&color| color == pixel)
+        };
+        // same x, lower OAM index wins whichever color it has
+        assert_eq!(render(&[(0, 4, 1), (1, 4, 2)]), Some(1));
+        assert_eq!(render(&[(0, 4, 2), (1, 4, 1)]), Some(2));
+        assert_eq!(render(&[(7, 4, 3), (3, 4, 2)]), Some(2));
+        // higher index at smaller x is drawn last, over lower index at larger x,
+        // and over the sprite after it in OAM at the same x
+        assert_eq!(render(&[(0, 4, 1), (1, 0, 2), (2, 0, 3)]), Some(2));
+    }
+}
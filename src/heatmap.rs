@@ -0,0 +1,69 @@
+/*
+ * Memory heat map: CPU reads and writes of each 256 bytes page over a run,
+ * written as text report or PNG image at exit. Traffic on a page tells what
+ * the game expects, like cartridge RAM at 0xA000 or STAT polling at 0xFF41.
+ */
+use std::io;
+use std::path::Path;
+
+use crate::png;
+use rugameboy::bus::AccessCount;
+
+/// pages per row of image, 16 rows cover all memory
+const IMAGE_COLUMNS: usize = 16;
+/// pixels of a page in image, left half reads and right half writes
+const CELL_SIZE: usize = 8;
+
+fn region(page: usize) -> &'static str {
+    match page {
+        0x00 ..= 0x3f => "ROM bank 0",
+        0x40 ..= 0x7f => "ROM bank N",
+        0x80 ..= 0x97 => "VRAM tiles",
+        0x98 ..= 0x9f => "VRAM maps",
+        0xa0 ..= 0xbf => "cartridge RAM",
+        0xc0 ..= 0xdf => "WRAM",
+        0xe0 ..= 0xfd => "echo RAM",
+        0xfe => "OAM",
+        _ => "IO and HRAM",
+    }
+}
+
+/// One line per page accessed, busiest first
+pub fn report(count: &AccessCount) -> String {
+    let mut pages: Vec<usize> = (0..256).filter(|&page| count.reads[page] + count.writes[page] > 0).collect();
+    pages.sort_by_key(|&page| std::cmp::Reverse(count.reads[page] as u64 + count.writes[page] as u64));
+    let mut text = format!("{:<6} {:>10} {:>10}  region\n", "page", "reads", "writes");
+    for page in pages {
+        text.push_str(&format!("{:02X}00   {:>10} {:>10}  {}\n",
+                               page, count.reads[page], count.writes[page], region(page)));
+    }
+    text
+}
+
+// brightness of count on log scale against the busiest page
+fn intensity(value: u32, max: u32) -> u32 {
+    if value == 0 || max == 0 {
+        return 0;
+    }
+    let scale = (value as f64).ln_1p() / (max as f64).ln_1p();
+    (64.0 + 191.0 * scale) as u32
+}
+
+/// Image of 16 x 16 pages, reads in green and writes in red
+pub fn write_image<P: AsRef<Path>>(path: P, count: &AccessCount) -> io::Result<()> {
+    let width = IMAGE_COLUMNS * CELL_SIZE;
+    let height = 256 / IMAGE_COLUMNS * CELL_SIZE;
+    let max_read = count.reads.iter().cloned().max().unwrap_or(0);
+    let max_write = count.writes.iter().cloned().max().unwrap_or(0);
+    let mut pixels = vec![0; width * height];
+    for (idx, pixel) in pixels.iter_mut().enumerate() {
+        let (x, y) = (idx % width, idx / width);
+        let page = y / CELL_SIZE * IMAGE_COLUMNS + x / CELL_SIZE;
+        *pixel = if x % CELL_SIZE < CELL_SIZE / 2 {
+            intensity(count.reads[page], max_read) << 8
+        } else {
+            intensity(count.writes[page], max_write) << 16
+        };
+    }
+    png::write_rgb(path, width, height, &pixels)
+}
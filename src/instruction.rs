@@ -1,6 +1,6 @@
 
 type Source = Target;
-#[derive(Debug,PartialEq)]
+#[derive(Debug,Clone,Copy,PartialEq)]
 pub enum Target {
     A,
     B,
@@ -19,7 +19,7 @@ pub enum Target {
     D8
 }
 
-#[derive(Debug,PartialEq)]
+#[derive(Debug,Clone,Copy,PartialEq)]
 pub enum Condition {
     NotZero,
     Zero,
@@ -28,7 +28,7 @@ pub enum Condition {
     Always,
 }
 
-#[derive(Debug)]
+#[derive(Debug,Clone,Copy,PartialEq)]
 pub enum Instruction {
     NOP,
     JP(Condition),
@@ -68,13 +68,21 @@ pub enum Instruction {
     RST(u16),
     CPL,
     CCF,
+    SCF,
     RRA,
+    RLA,
     DAA,
     RLCA,
+    RRCA,
+    /// ADD SP,e8
+    ADDSP,
+    /// LD HL,SP+e8
+    LDHLSP,
     STOP,
+    HALT,
 }
 
-#[derive(Debug)]
+#[derive(Debug,Clone,Copy,PartialEq)]
 pub enum CBInstruction {
     RLC(Target),
     RRC(Target),
@@ -89,8 +97,72 @@ pub enum CBInstruction {
     SET(Target, u32),
 }
 
+/// Instruction decoded from opcode with its operand length and base clock
+#[derive(Debug,Clone,Copy)]
+pub struct Opcode {
+    pub inst: Instruction,
+    pub len: u16,
+    pub clock: u64,
+}
+
+/// Decode table of non-prefix opcodes, None for invalid ones
+pub const OPCODES: [Option<Opcode>; 256] = opcode_table();
+/// Decode table of opcodes after prefix 0xcb
+pub const CB_OPCODES: [CBInstruction; 256] = cb_opcode_table();
+
+const fn opcode_table() -> [Option<Opcode>; 256] {
+    let mut table = [None; 256];
+    let mut byte = 0;
+    while byte < 256 {
+        table[byte] = match Instruction::decode(byte as u8) {
+            Some(inst) => Some(Opcode { inst, len: inst.len(), clock: inst.clock() }),
+            None => None,
+        };
+        byte += 1;
+    }
+    table
+}
+
+const fn cb_opcode_table() -> [CBInstruction; 256] {
+    let mut table = [CBInstruction::RLC(Target::B); 256];
+    let mut byte = 0;
+    while byte < 256 {
+        table[byte] = CBInstruction::decode(byte as u8);
+        byte += 1;
+    }
+    table
+}
+
+/// Bytes of instruction followed by its operand bytes, inverse of from_byte.
+/// Panics if instruction has no opcode or operands do not fit.
+pub fn encode(inst: &Instruction, operands: &[u8]) -> Vec<u8> {
+    let opcode = (0..=255).find(|&byte| Instruction::from_byte(byte).as_ref() == Some(inst))
+                          .unwrap_or_else(|| panic!("encode: {:?} has no opcode", inst));
+    assert_eq!(operands.len(), inst.len() as usize, "encode: {:?} takes {} operand bytes", inst, inst.len());
+    let mut bytes = vec![opcode];
+    bytes.extend_from_slice(operands);
+    bytes
+}
+
+/// Bytes of CB instruction, prefix first
+pub fn encode_cb(inst: &CBInstruction) -> Vec<u8> {
+    let opcode = (0..=255).find(|&byte| CBInstruction::from_byte(byte) == *inst)
+                          .unwrap_or_else(|| panic!("encode: {:?} has no opcode", inst));
+    vec![0xcb, opcode]
+}
+
+// LD between A and memory pointed by 16-bit register
+const fn is_memory(target: &Target) -> bool {
+    matches!(target, Target::HL | Target::BC | Target::DE | Target::HLINC | Target::HLDEC)
+}
+
 impl Instruction {
     pub fn from_byte(byte: u8) -> Option<Instruction> {
+        OPCODES[byte as usize].map(|opcode| opcode.inst)
+    }
+
+    // every opcode is listed, so the table has no slot left undecided
+    const fn decode(byte: u8) -> Option<Instruction> {
         match byte {
             0x00 => Some(Instruction::NOP),
             0xc2 => Some(Instruction::JP(Condition::NotZero)),
@@ -327,14 +399,24 @@ impl Instruction {
             0x29 => Some(Instruction::ADDHL(Target::HL)),
             0x39 => Some(Instruction::ADDHL(Target::SP)),
             0x1f => Some(Instruction::RRA),
-            0x27 => Some(Instruction::DAA),
+            0x17 => Some(Instruction::RLA),
+            0x0f => Some(Instruction::RRCA),
             0x07 => Some(Instruction::RLCA),
             0x10 => Some(Instruction::STOP),
-            _ => None
+            0x76 => Some(Instruction::HALT),
+            0x27 => Some(Instruction::DAA),
+            0x37 => Some(Instruction::SCF),
+            0xe8 => Some(Instruction::ADDSP),
+            0xf8 => Some(Instruction::LDHLSP),
+            // prefix of CB instructions, decoded by CBInstruction
+            0xcb => None,
+            0xd3 | 0xdb | 0xdd | 0xe3 | 0xe4 | 0xeb | 0xec | 0xed | 0xf4 | 0xfc | 0xfd => None,
         }
     }
 
-    pub fn len(&self) -> u16 {
+    #[allow(clippy::len_without_is_empty)]
+    pub const fn len(&self) -> u16 {
+        // return length of operand, opcode itself is consumed by fetch
         match self {
             Instruction::JP(_) => 2,
             Instruction::LDIMM16(_) => 2,
@@ -355,20 +437,36 @@ impl Instruction {
             Instruction::OR(Target::D8) =>  1,
             Instruction::CMP(Target::D8) => 1,
             Instruction::STOP => 1,
+            Instruction::ADDSP => 1,
+            Instruction::LDHLSP => 1,
             _ => 0,
         }
     }
 
-    pub fn clock(&self) -> u64 {
+    /// Clock of conditional jump, call or return when the branch is taken,
+    /// the same as clock for other instructions
+    pub const fn clock_taken(&self) -> u64 {
+        match self {
+            Instruction::JP(_) => 16,
+            Instruction::JR(_) => 12,
+            Instruction::CALL(_) => 24,
+            // conditional RET spends one more cycle checking the flag
+            Instruction::RET(Condition::Always) => 16,
+            Instruction::RET(_) => 20,
+            _ => self.clock(),
+        }
+    }
+
+    pub const fn clock(&self) -> u64 {
         // return clock of instruction, default non-taken action
         match self {
             Instruction::NOP => 4,
-            Instruction::JP(_) => 12,
+            Instruction::JP(c) => if matches!(c, Condition::Always) { 16 } else { 12 },
             Instruction::JPHL => 4,
             Instruction::DI => 4,
             Instruction::EI => 4,
             Instruction::LDIMM16(_) => 12,
-            Instruction::LDIMM8(t) => if t == &Target::HL { 12 } else { 8 },
+            Instruction::LDIMM8(t) => if matches!(t, Target::HL) { 12 } else { 8 },
             Instruction::LD16A => 16,
             Instruction::LDA16 => 16,
             Instruction::LDA16SP => 20,
@@ -378,47 +476,57 @@ impl Instruction {
             Instruction::LDCA => 8,
             Instruction::LDAC => 8,
             Instruction::LDRR(s, t) =>
-                if s == &Target::HL || t == &Target::HL {
+                if is_memory(s) || is_memory(t) {
                     8
                 } else {
                     4
                 },
-            Instruction::CALL(_) => 12,
-            Instruction::RET(_) => 8,
+            Instruction::CALL(c) => if matches!(c, Condition::Always) { 24 } else { 12 },
+            Instruction::RET(c) => if matches!(c, Condition::Always) { 16 } else { 8 },
             Instruction::RETI => 16,
             Instruction::PUSH(_) => 16,
             Instruction::POP(_)  => 12,
-            Instruction::JR(_) => 8,
+            Instruction::JR(c) => if matches!(c, Condition::Always) { 12 } else { 8 },
             Instruction::INC16(_) => 8,
             Instruction::DEC16(_) => 8,
             Instruction::INC8(t) | Instruction::DEC8(t) =>
-                if t == &Target::HL {
+                if matches!(t, Target::HL) {
                     12
                 } else {
                     4
                 },
-            Instruction::ADD(t) => if t == &Target::D8 || t == &Target::HL { 2 } else { 1 },
-            Instruction::ADC(t) => if t == &Target::D8 || t == &Target::HL { 2 } else { 1 },
-            Instruction::SUB(t) => if t == &Target::D8 || t == &Target::HL { 2 } else { 1 },
-            Instruction::SBC(t) => if t == &Target::D8 || t == &Target::HL { 2 } else { 1 },
-            Instruction::AND(t) => if t == &Target::D8 || t == &Target::HL { 2 } else { 1 },
-            Instruction::XOR(t) => if t == &Target::D8 || t == &Target::HL { 2 } else { 1 },
-            Instruction::OR(t) =>  if t == &Target::D8 || t == &Target::HL { 2 } else { 1 },
-            Instruction::CMP(t) => if t == &Target::D8 || t == &Target::HL { 2 } else { 1 },
+            Instruction::ADD(t) |
+            Instruction::ADC(t) |
+            Instruction::SUB(t) |
+            Instruction::SBC(t) |
+            Instruction::AND(t) |
+            Instruction::XOR(t) |
+            Instruction::OR(t)  |
+            Instruction::CMP(t) => if matches!(t, Target::D8 | Target::HL) { 8 } else { 4 },
             Instruction::RST(_) => 16,
             Instruction::CPL => 4,
             Instruction::CCF => 4,
+            Instruction::SCF => 4,
             Instruction::ADDHL(_) => 8,
+            Instruction::ADDSP => 16,
+            Instruction::LDHLSP => 12,
             Instruction::RRA => 4,
+            Instruction::RLA => 4,
             Instruction::DAA => 4,
             Instruction::RLCA => 4,
+            Instruction::RRCA => 4,
             Instruction::STOP => 4,
+            Instruction::HALT => 4,
         }
     }
 }
 
 impl CBInstruction {
     pub fn from_byte(byte: u8) -> CBInstruction {
+        CB_OPCODES[byte as usize]
+    }
+
+    const fn decode(byte: u8) -> CBInstruction {
         match byte {
             0x00 => CBInstruction::RLC(Target::B),
             0x01 => CBInstruction::RLC(Target::C),
@@ -679,8 +787,8 @@ impl CBInstruction {
         }
     }
 
-    pub fn clock(&self) -> u64 {
-        match &self {
+    pub const fn clock(&self) -> u64 {
+        match self {
             CBInstruction::RLC(target)      |
             CBInstruction::RRC(target)      |
             CBInstruction::RL(target)       |
@@ -689,11 +797,128 @@ impl CBInstruction {
             CBInstruction::SRA(target)      |
             CBInstruction::SWAP(target)     |
             CBInstruction::SRL(target)      |
-            CBInstruction::BIT(target, _) |
             CBInstruction::RES(target, _) |
             CBInstruction::SET(target, _) => {
-                if target == &Target::HL { 16 } else { 8 }
+                if matches!(target, Target::HL) { 16 } else { 8 }
+            }
+            CBInstruction::BIT(target, _) => {
+                // BIT only reads (HL), no write back
+                if matches!(target, Target::HL) { 12 } else { 8 }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // documented length in bytes with opcode, 0 for invalid opcodes and prefix 0xcb
+    const LENGTHS: [u16; 256] = [
+        1, 3, 1, 1, 1, 1, 2, 1, 3, 1, 1, 1, 1, 1, 2, 1, // 0x00
+        2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1, // 0x10
+        2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1, // 0x20
+        2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1, // 0x30
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x40
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x50
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x60
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x70
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x80
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x90
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0xa0
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0xb0
+        1, 1, 3, 3, 3, 1, 2, 1, 1, 1, 3, 0, 3, 3, 2, 1, // 0xc0
+        1, 1, 3, 0, 3, 1, 2, 1, 1, 1, 3, 0, 3, 0, 2, 1, // 0xd0
+        2, 1, 1, 0, 0, 1, 2, 1, 2, 1, 3, 0, 0, 0, 2, 1, // 0xe0
+        2, 1, 1, 1, 0, 1, 2, 1, 2, 1, 3, 1, 0, 0, 2, 1, // 0xf0
+    ];
+
+    // documented clocks, branch not taken
+    const CLOCKS: [u64; 256] = [
+         4, 12,  8,  8,  4,  4,  8,  4, 20,  8,  8,  8,  4,  4,  8,  4, // 0x00
+         4, 12,  8,  8,  4,  4,  8,  4, 12,  8,  8,  8,  4,  4,  8,  4, // 0x10
+         8, 12,  8,  8,  4,  4,  8,  4,  8,  8,  8,  8,  4,  4,  8,  4, // 0x20
+         8, 12,  8,  8, 12, 12, 12,  4,  8,  8,  8,  8,  4,  4,  8,  4, // 0x30
+         4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 0x40
+         4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 0x50
+         4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 0x60
+         8,  8,  8,  8,  8,  8,  4,  8,  4,  4,  4,  4,  4,  4,  8,  4, // 0x70
+         4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 0x80
+         4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 0x90
+         4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 0xa0
+         4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 0xb0
+         8, 12, 12, 16, 12, 16,  8, 16,  8, 16, 12,  0, 12, 24,  8, 16, // 0xc0
+         8, 12, 12,  0, 12, 16,  8, 16,  8, 16, 12,  0, 12,  0,  8, 16, // 0xd0
+        12, 12,  8,  0,  0, 16,  8, 16, 16,  4, 16,  0,  0,  0,  8, 16, // 0xe0
+        12, 12,  8,  4,  0, 16,  8, 16, 12,  8, 16,  4,  0,  0,  8, 16, // 0xf0
+    ];
+
+    // documented clocks of opcodes after prefix 0xcb, all are 2 bytes
+    const CB_CLOCKS: [u64; 256] = [
+         8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8, // 0x00
+         8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8, // 0x10
+         8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8, // 0x20
+         8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8, // 0x30
+         8,  8,  8,  8,  8,  8, 12,  8,  8,  8,  8,  8,  8,  8, 12,  8, // 0x40
+         8,  8,  8,  8,  8,  8, 12,  8,  8,  8,  8,  8,  8,  8, 12,  8, // 0x50
+         8,  8,  8,  8,  8,  8, 12,  8,  8,  8,  8,  8,  8,  8, 12,  8, // 0x60
+         8,  8,  8,  8,  8,  8, 12,  8,  8,  8,  8,  8,  8,  8, 12,  8, // 0x70
+         8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8, // 0x80
+         8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8, // 0x90
+         8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8, // 0xa0
+         8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8, // 0xb0
+         8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8, // 0xc0
+         8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8, // 0xd0
+         8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8, // 0xe0
+         8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8, // 0xf0
+    ];
+
+    #[test]
+    fn opcode_table_matches_reference() {
+        let mut mismatches = Vec::new();
+        for byte in 0..256 {
+            match OPCODES[byte] {
+                Some(opcode) if 1 + opcode.len != LENGTHS[byte] || opcode.clock != CLOCKS[byte] => {
+                    mismatches.push(format!("{:#04x} {:?}: len {} clock {}, expect len {} clock {}",
+                                            byte, opcode.inst, 1 + opcode.len, opcode.clock,
+                                            LENGTHS[byte], CLOCKS[byte]));
+                },
+                Some(_) => {},
+                None if LENGTHS[byte] != 0 => mismatches.push(format!("{:#04x}: not decoded", byte)),
+                None => {},
             }
         }
+        for byte in 0..256 {
+            let inst = CB_OPCODES[byte];
+            if inst.clock() != CB_CLOCKS[byte] {
+                mismatches.push(format!("0xcb {:#04x} {:?}: clock {}, expect {}",
+                                        byte, inst, inst.clock(), CB_CLOCKS[byte]));
+            }
+        }
+        assert!(mismatches.is_empty(), "opcodes differ from reference:\n{}", mismatches.join("\n"));
+    }
+
+    #[test]
+    fn encode_round_trips_every_opcode() {
+        for byte in 0..=255u8 {
+            let inst = match Instruction::from_byte(byte) {
+                Some(inst) => inst,
+                None => continue,
+            };
+            let operands: Vec<u8> = (1..=inst.len() as u8).collect();
+            let bytes = encode(&inst, &operands);
+            assert_eq!(Instruction::from_byte(bytes[0]).as_ref(), Some(&inst), "opcode {:#04x}", byte);
+            assert_eq!(bytes[1..], operands[..], "opcode {:#04x}", byte);
+        }
+        for byte in 0..=255u8 {
+            let inst = CBInstruction::from_byte(byte);
+            assert_eq!(encode_cb(&inst), [0xcb, byte]);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn encode_checks_operand_count() {
+        encode(&Instruction::JP(Condition::Always), &[0x00]);
     }
 }
@@ -1,7 +1,10 @@
 use crate::bus::Device;
+use crate::sgb::SgbReceiver;
 
 pub const JOYPAD_ADDR: u16 = 0xff00;
 
+/// order of the keys is the bit order in button bitmask
+#[derive(Debug,Clone,Copy)]
 pub enum JoypadKey {
     RIGHT,
     LEFT,
@@ -13,24 +16,69 @@ pub enum JoypadKey {
     START,
 }
 
+pub const JOYPAD_KEYS: [JoypadKey; 8] = [
+    JoypadKey::RIGHT,
+    JoypadKey::LEFT,
+    JoypadKey::UP,
+    JoypadKey::DOWN,
+    JoypadKey::A,
+    JoypadKey::B,
+    JoypadKey::SELECT,
+    JoypadKey::START,
+];
+
+impl JoypadKey {
+    /// mask of key in button bitmask
+    pub fn mask(self) -> u8 {
+        1 << (self as u8)
+    }
+}
+
+#[derive(Clone)]
 pub struct Joypad {
     p14: u8,
     p15: u8,
     mask: u8,
+    /// allow Left+Right or Up+Down pressed together, impossible on hardware
+    allow_opposite: bool,
+    /// Super Game Boy packets sent through select bits
+    pub sgb: SgbReceiver,
     pub is_interrupt: bool,
 }
 
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Joypad {
     pub fn new() -> Self {
         Self {
             p14: 0x0F,
             p15: 0x0F,
             mask: 0x30,
+            allow_opposite: false,
+            sgb: SgbReceiver::new(),
             is_interrupt: false,
         }
     }
 
+    pub fn set_allow_opposite(&mut self, allow: bool) {
+        self.allow_opposite = allow;
+    }
+
     pub fn presskey(&mut self, key: JoypadKey) {
+        // release the opposite direction
+        if !self.allow_opposite {
+            match key {
+                JoypadKey::RIGHT => self.p14 |= 0x02,
+                JoypadKey::LEFT  => self.p14 |= 0x01,
+                JoypadKey::UP    => self.p14 |= 0x08,
+                JoypadKey::DOWN  => self.p14 |= 0x04,
+                _ => {},
+            }
+        }
         match key {
             JoypadKey::RIGHT  => self.p14 &= !0x01,
             JoypadKey::LEFT   => self.p14 &= !0x02,
@@ -60,15 +108,79 @@ impl Joypad {
 
 impl Device for Joypad {
     fn load(&self, _addr: u16) -> Result<u8, ()> {
-        match self.mask {
-            0x20 => Ok(self.p14), // read P14: Left, Right, Up, Down
-            0x10 => Ok(self.p15), // read P15: A, B, Select, Start
-            _ => Ok(0x0F)     // other value just read nothing
+        // select bits are active low, both lines selected read both groups
+        let select = self.mask & 0x30;
+        let mut buttons = 0x0F;
+        if select & 0x10 == 0 {
+            buttons &= self.p14; // P14: Left, Right, Up, Down
+        }
+        if select & 0x20 == 0 {
+            buttons &= self.p15; // P15: A, B, Select, Start
         }
+        // bits 6-7 are unused and read high
+        Ok(0xC0 | select | buttons)
     }
 
     fn store(&mut self, _addr: u16, value: u8) -> Result<(), ()> {
         self.mask = value;
+        self.sgb.write(value);
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unselected_reads_ff() {
+        let mut joypad = Joypad::new();
+        joypad.store(JOYPAD_ADDR, 0x30).unwrap();
+        assert_eq!(joypad.load(JOYPAD_ADDR), Ok(0xFF));
+        // pressed keys are not seen without a selected line
+        joypad.presskey(JoypadKey::A);
+        assert_eq!(joypad.load(JOYPAD_ADDR), Ok(0xFF));
+        joypad.store(JOYPAD_ADDR, 0x10).unwrap();
+        assert_eq!(joypad.load(JOYPAD_ADDR), Ok(0xDE));
+    }
+
+    #[test]
+    fn press_releases_opposite_direction() {
+        let mut joypad = Joypad::new();
+        joypad.presskey(JoypadKey::LEFT);
+        joypad.presskey(JoypadKey::RIGHT);
+        assert_eq!(joypad.p14, 0x0E);
+        joypad.presskey(JoypadKey::UP);
+        joypad.presskey(JoypadKey::DOWN);
+        assert_eq!(joypad.p14, 0x06);
+        // releasing the one held keeps the other released
+        joypad.releasekey(JoypadKey::DOWN);
+        assert_eq!(joypad.p14, 0x0E);
+    }
+
+    #[test]
+    fn opposite_directions_allowed_by_option() {
+        let mut joypad = Joypad::new();
+        joypad.set_allow_opposite(true);
+        joypad.presskey(JoypadKey::LEFT);
+        joypad.presskey(JoypadKey::RIGHT);
+        joypad.presskey(JoypadKey::UP);
+        joypad.presskey(JoypadKey::DOWN);
+        assert_eq!(joypad.p14, 0x00);
+    }
+
+    #[test]
+    fn read_keeps_select_and_unused_bits() {
+        let mut joypad = Joypad::new();
+        joypad.presskey(JoypadKey::DOWN);
+        // 0x20 selects directions
+        joypad.store(JOYPAD_ADDR, 0x20).unwrap();
+        let value = joypad.load(JOYPAD_ADDR).unwrap();
+        assert_eq!(value & 0xf0, 0xe0);
+        assert_eq!(value & 0x0f, 0x07);
+        // both lines selected, low bits of both groups
+        joypad.presskey(JoypadKey::A);
+        joypad.store(JOYPAD_ADDR, 0x00).unwrap();
+        assert_eq!(joypad.load(JOYPAD_ADDR), Ok(0xc6));
+    }
+}
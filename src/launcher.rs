@@ -0,0 +1,78 @@
+use std::io::{self, prelude::*};
+use std::path::PathBuf;
+
+use crate::frontend::{Frontend, InputEvent};
+use crate::osd;
+use crate::recent::{RecentRom, RecentRoms};
+use rugameboy::joypad::JoypadKey;
+use rugameboy::vm::{WIDTH, HEIGHT};
+
+const LIST_X: usize = 4;
+const LIST_Y: usize = 4;
+/// rows between items, height of OSD text box
+const LINE_HEIGHT: usize = 7;
+/// characters of title shown, leaves room for mark and play time
+const TITLE_CHARS: usize = 28;
+const OPEN_PATH: &str = "OPEN PATH...";
+
+fn play_time(rom: &RecentRom) -> String {
+    let minutes = rom.play_time / 60;
+    format!("{}H{:02}M", minutes / 60, minutes % 60)
+}
+
+/// Read path typed or pasted on terminal, None if nothing is given
+fn read_path() -> Option<PathBuf> {
+    print!("ROM path: ");
+    io::stdout().flush().ok()?;
+    let mut line = String::new();
+    io::stdin().read_line(&mut line).ok()?;
+    let path = line.trim();
+    if path.is_empty() {
+        None
+    } else {
+        Some(PathBuf::from(path))
+    }
+}
+
+/*
+ * Launcher shown when no ROM is given, lists recently played ROMs
+ * and an entry to type the path on terminal.
+ * Up and down select an entry, A or Start picks it, Esc quits.
+ */
+pub fn pick(frontend: &mut dyn Frontend, recents: &RecentRoms) -> Option<PathBuf> {
+    let roms = recents.entries();
+    let count = roms.len() + 1;
+    let mut selected = 0;
+    let mut buffer = vec![0; WIDTH * HEIGHT];
+
+    while !frontend.should_close() {
+        for event in frontend.poll_input() {
+            match event {
+                InputEvent::Press(JoypadKey::UP) => selected = (selected + count - 1) % count,
+                InputEvent::Press(JoypadKey::DOWN) => selected = (selected + 1) % count,
+                InputEvent::Press(JoypadKey::A) | InputEvent::Press(JoypadKey::START) => {
+                    match roms.get(selected) {
+                        Some(rom) => return Some(rom.path.clone()),
+                        None => if let Some(path) = read_path() {
+                            return Some(path);
+                        },
+                    }
+                },
+                InputEvent::Menu => return None,
+                _ => {},
+            }
+        }
+
+        buffer.iter_mut().for_each(|pixel| *pixel = 0);
+        for (i, rom) in roms.iter().enumerate() {
+            let mark = if i == selected { ">" } else { " " };
+            let title: String = rom.title.chars().take(TITLE_CHARS).collect();
+            let text = format!("{}{:width$} {}", mark, title, play_time(rom), width = TITLE_CHARS);
+            osd::draw_text(&mut buffer, LIST_X, LIST_Y + i * LINE_HEIGHT, &text);
+        }
+        let mark = if selected == roms.len() { ">" } else { " " };
+        osd::draw_text(&mut buffer, LIST_X, LIST_Y + roms.len() * LINE_HEIGHT, &format!("{}{}", mark, OPEN_PATH));
+        frontend.present_frame(&buffer);
+    }
+    None
+}
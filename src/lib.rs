@@ -0,0 +1,31 @@
+// devices and bus report a failed access as Err(()), the address is logged where it happens
+#![allow(clippy::result_unit_err)]
+
+pub mod cpu;
+pub mod gpu;
+pub mod register;
+pub mod instruction;
+pub mod bus;
+pub mod memory;
+pub mod vm;
+pub mod timer;
+pub mod apu;
+pub mod resampler;
+pub mod audio;
+pub mod joypad;
+pub mod sgb;
+pub mod printer;
+pub mod cartridge;
+pub mod mbc;
+pub mod error;
+#[cfg(test)]
+pub mod asm;
+pub mod compat;
+pub mod boot;
+pub mod lockup;
+pub mod cheat;
+#[cfg(test)]
+pub mod testing;
+
+#[cfg(feature = "libretro")]
+pub mod libretro;
@@ -0,0 +1,291 @@
+/*
+ * libretro core, exposing retro_* C ABI so frontends like RetroArch can load it.
+ * Build with `cargo build --release --features libretro`, then install
+ * librugameboy.so as rugameboy_libretro.so together with rugameboy_libretro.info
+ */
+// retro_* functions are only called by the frontend, with pointers valid as the libretro API defines
+#![allow(clippy::missing_safety_doc)]
+use crate::vm::{Vm, WIDTH, HEIGHT};
+use crate::apu;
+
+use std::os::raw::{c_char, c_uint, c_void};
+use std::ptr;
+use std::slice;
+use log::error;
+
+const RETRO_API_VERSION: c_uint = 1;
+const RETRO_REGION_NTSC: c_uint = 0;
+const RETRO_DEVICE_JOYPAD: c_uint = 1;
+const RETRO_ENVIRONMENT_SET_PIXEL_FORMAT: c_uint = 10;
+/// our framebuffer is 0x00RRGGBB
+const RETRO_PIXEL_FORMAT_XRGB8888: c_uint = 1;
+
+const RETRO_DEVICE_ID_JOYPAD_B:      c_uint = 0;
+const RETRO_DEVICE_ID_JOYPAD_SELECT: c_uint = 2;
+const RETRO_DEVICE_ID_JOYPAD_START:  c_uint = 3;
+const RETRO_DEVICE_ID_JOYPAD_UP:     c_uint = 4;
+const RETRO_DEVICE_ID_JOYPAD_DOWN:   c_uint = 5;
+const RETRO_DEVICE_ID_JOYPAD_LEFT:   c_uint = 6;
+const RETRO_DEVICE_ID_JOYPAD_RIGHT:  c_uint = 7;
+const RETRO_DEVICE_ID_JOYPAD_A:      c_uint = 8;
+
+/// libretro button id in the order of Vm button bitmask
+const BUTTONS: [c_uint; 8] = [
+    RETRO_DEVICE_ID_JOYPAD_RIGHT,
+    RETRO_DEVICE_ID_JOYPAD_LEFT,
+    RETRO_DEVICE_ID_JOYPAD_UP,
+    RETRO_DEVICE_ID_JOYPAD_DOWN,
+    RETRO_DEVICE_ID_JOYPAD_A,
+    RETRO_DEVICE_ID_JOYPAD_B,
+    RETRO_DEVICE_ID_JOYPAD_SELECT,
+    RETRO_DEVICE_ID_JOYPAD_START,
+];
+
+/// 4194304 Hz / 70224 clocks per frame
+const FPS: f64 = 59.7275;
+const SAMPLE_RATE: f64 = apu::SAMPLE_RATE as f64;
+
+#[repr(C)]
+pub struct RetroSystemInfo {
+    library_name: *const c_char,
+    library_version: *const c_char,
+    valid_extensions: *const c_char,
+    need_fullpath: bool,
+    block_extract: bool,
+}
+
+#[repr(C)]
+pub struct RetroGameGeometry {
+    base_width: c_uint,
+    base_height: c_uint,
+    max_width: c_uint,
+    max_height: c_uint,
+    aspect_ratio: f32,
+}
+
+#[repr(C)]
+pub struct RetroSystemTiming {
+    fps: f64,
+    sample_rate: f64,
+}
+
+#[repr(C)]
+pub struct RetroSystemAvInfo {
+    geometry: RetroGameGeometry,
+    timing: RetroSystemTiming,
+}
+
+#[repr(C)]
+pub struct RetroGameInfo {
+    path: *const c_char,
+    data: *const c_void,
+    size: usize,
+    meta: *const c_char,
+}
+
+type EnvironmentFn = unsafe extern "C" fn(cmd: c_uint, data: *mut c_void) -> bool;
+type VideoRefreshFn = unsafe extern "C" fn(data: *const c_void, width: c_uint, height: c_uint, pitch: usize);
+type AudioSampleFn = unsafe extern "C" fn(left: i16, right: i16);
+type AudioSampleBatchFn = unsafe extern "C" fn(data: *const i16, frames: usize) -> usize;
+type InputPollFn = unsafe extern "C" fn();
+type InputStateFn = unsafe extern "C" fn(port: c_uint, device: c_uint, index: c_uint, id: c_uint) -> i16;
+
+struct Core {
+    rom: Vec<u8>,
+    vm: Option<Vm>,
+    environment: Option<EnvironmentFn>,
+    video_refresh: Option<VideoRefreshFn>,
+    audio_batch: Option<AudioSampleBatchFn>,
+    input_poll: Option<InputPollFn>,
+    input_state: Option<InputStateFn>,
+}
+
+static mut CORE: Core = Core {
+    rom: Vec::new(),
+    vm: None,
+    environment: None,
+    video_refresh: None,
+    audio_batch: None,
+    input_poll: None,
+    input_state: None,
+};
+
+// libretro calls every function from the same thread
+unsafe fn core() -> &'static mut Core {
+    &mut *ptr::addr_of_mut!(CORE)
+}
+
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> c_uint {
+    RETRO_API_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn retro_init() {}
+
+#[no_mangle]
+pub extern "C" fn retro_deinit() {}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut RetroSystemInfo) {
+    *info = RetroSystemInfo {
+        library_name: b"ruGameboy\0".as_ptr() as *const c_char,
+        library_version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char,
+        valid_extensions: b"gb\0".as_ptr() as *const c_char,
+        need_fullpath: false,
+        block_extract: false,
+    };
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut RetroSystemAvInfo) {
+    *info = RetroSystemAvInfo {
+        geometry: RetroGameGeometry {
+            base_width: WIDTH as c_uint,
+            base_height: HEIGHT as c_uint,
+            max_width: WIDTH as c_uint,
+            max_height: HEIGHT as c_uint,
+            aspect_ratio: WIDTH as f32 / HEIGHT as f32,
+        },
+        timing: RetroSystemTiming {
+            fps: FPS,
+            sample_rate: SAMPLE_RATE,
+        },
+    };
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_set_environment(callback: EnvironmentFn) {
+    core().environment = Some(callback);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_set_video_refresh(callback: VideoRefreshFn) {
+    core().video_refresh = Some(callback);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample(_callback: AudioSampleFn) {}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_set_audio_sample_batch(callback: AudioSampleBatchFn) {
+    core().audio_batch = Some(callback);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_set_input_poll(callback: InputPollFn) {
+    core().input_poll = Some(callback);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_set_input_state(callback: InputStateFn) {
+    core().input_state = Some(callback);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_controller_port_device(_port: c_uint, _device: c_uint) {}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_reset() {
+    let core = core();
+    core.vm = Some(Vm::new(core.rom.clone()));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_run() {
+    let core = core();
+    if let Some(poll) = core.input_poll {
+        poll();
+    }
+    let vm = match core.vm.as_mut() {
+        Some(vm) => vm,
+        None => return,
+    };
+
+    if let Some(state) = core.input_state {
+        let buttons = BUTTONS.iter()
+                             .enumerate()
+                             .filter(|(_, &id)| state(0, RETRO_DEVICE_JOYPAD, 0, id) != 0)
+                             .fold(0, |buttons, (i, _)| buttons | (1 << i));
+        vm.set_buttons(buttons);
+    }
+
+    if vm.run().is_err() {
+        error!("libretro: emulation stopped");
+    }
+    if let Some(video) = core.video_refresh {
+        video(vm.buffer.as_ptr() as *const c_void, WIDTH as c_uint, HEIGHT as c_uint,
+              WIDTH * std::mem::size_of::<u32>());
+    }
+    let samples = vm.cpu.bus.apu.take_samples();
+    if let Some(audio) = core.audio_batch {
+        audio(samples.as_ptr(), samples.len() / 2);
+    }
+}
+
+// TODO: save state is not supported yet
+#[no_mangle]
+pub extern "C" fn retro_serialize_size() -> usize {
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn retro_serialize(_data: *mut c_void, _size: usize) -> bool {
+    false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unserialize(_data: *const c_void, _size: usize) -> bool {
+    false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_reset() {}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_set(_index: c_uint, _enabled: bool, _code: *const c_char) {}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_load_game(game: *const RetroGameInfo) -> bool {
+    if game.is_null() || (*game).data.is_null() {
+        return false;
+    }
+    let core = core();
+    if let Some(environment) = core.environment {
+        let mut format = RETRO_PIXEL_FORMAT_XRGB8888;
+        if !environment(RETRO_ENVIRONMENT_SET_PIXEL_FORMAT, &mut format as *mut c_uint as *mut c_void) {
+            error!("libretro: XRGB8888 is not supported");
+            return false;
+        }
+    }
+    core.rom = slice::from_raw_parts((*game).data as *const u8, (*game).size).to_vec();
+    core.vm = Some(Vm::new(core.rom.clone()));
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn retro_load_game_special(_type: c_uint, _info: *const RetroGameInfo, _num: usize) -> bool {
+    false
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_unload_game() {
+    let core = core();
+    core.vm = None;
+    core.rom = Vec::new();
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_region() -> c_uint {
+    RETRO_REGION_NTSC
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_data(_id: c_uint) -> *mut c_void {
+    ptr::null_mut()
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_size(_id: c_uint) -> usize {
+    0
+}
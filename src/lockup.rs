@@ -0,0 +1,178 @@
+/*
+ * Detect a game stuck in a small loop for many frames without any interrupt,
+ * usually polling a register or waiting an interrupt the emulator never
+ * delivers. Instructions of the loop are decoded to find the polled address.
+ */
+use std::fmt;
+
+use crate::bus::Interrupt;
+use crate::cpu::Cpu;
+use crate::instruction::Instruction;
+
+/// pc stays in this many bytes to be a loop
+const LOCKUP_SPAN: u16 = 8;
+/// frames in the same loop before it is reported
+const LOCKUP_FRAMES: u64 = 120;
+
+fn register_name(addr: u16) -> Option<&'static str> {
+    match addr {
+        0xff00 => Some("P1"),
+        0xff02 => Some("SC"),
+        0xff04 => Some("DIV"),
+        0xff05 => Some("TIMA"),
+        0xff0f => Some("IF"),
+        0xff26 => Some("NR52"),
+        0xff41 => Some("STAT"),
+        0xff44 => Some("LY"),
+        0xffff => Some("IE"),
+        _ => None,
+    }
+}
+
+fn address_name(addr: u16) -> String {
+    match register_name(addr) {
+        Some(name) => format!("{} ({:04X})", name, addr),
+        None => format!("{:04X}", addr),
+    }
+}
+
+/// Loop a game is stuck in
+#[derive(Debug,Clone)]
+pub struct Lockup {
+    /// lowest and highest pc in the loop
+    pub start: u16,
+    pub end: u16,
+    /// addresses read by loads in the loop
+    pub polled: Vec<u16>,
+    /// interrupts enabled in IE but never requested while looping
+    pub waiting: Vec<Interrupt>,
+    pub ime: bool,
+}
+
+impl Lockup {
+    /// Short text fits in OSD
+    pub fn summary(&self) -> String {
+        match self.polled.first() {
+            Some(&addr) => format!("STUCK AT {:04X} ON {}",
+                                   self.start, register_name(addr).map_or(format!("{:04X}", addr), String::from)),
+            None => format!("STUCK AT {:04X}", self.start),
+        }
+    }
+}
+
+impl fmt::Display for Lockup {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "busy-waiting in loop {:04X}-{:04X}", self.start, self.end)?;
+        if !self.polled.is_empty() {
+            let polled: Vec<String> = self.polled.iter().map(|&addr| address_name(addr)).collect();
+            write!(f, " on {}", polled.join(", "))?;
+        }
+        write!(f, ", IME {}", if self.ime { "on" } else { "off" })?;
+        if !self.waiting.is_empty() {
+            write!(f, ", enabled but never pending: {:?}", self.waiting)?;
+        }
+        Ok(())
+    }
+}
+
+// addresses read by instructions from start to end, decoded from start
+fn polled_addresses(cpu: &Cpu, start: u16, end: u16) -> Vec<u16> {
+    let entry = cpu.trace_entry();
+    let load = |addr: u16| cpu.bus.load8(addr).unwrap_or(0);
+    let mut polled = Vec::new();
+    let mut pc = start;
+    while pc <= end {
+        let opcode = load(pc);
+        let operand = load(pc.wrapping_add(1));
+        let addr = match opcode {
+            // LDH A,(n) and LD A,(C)
+            0xf0 => Some(0xff00 | operand as u16),
+            0xf2 => Some(0xff00 | entry.bc & 0xff),
+            // LD A,(nn)
+            0xfa => Some((load(pc.wrapping_add(2)) as u16) << 8 | operand as u16),
+            0x0a => Some(entry.bc),
+            0x1a => Some(entry.de),
+            // loads, ALU and BIT with (HL)
+            0x2a | 0x3a | 0x46 | 0x4e | 0x56 | 0x5e | 0x66 | 0x6e | 0x7e |
+            0x86 | 0x8e | 0x96 | 0x9e | 0xa6 | 0xae | 0xb6 | 0xbe => Some(entry.hl),
+            0xcb if operand & 0xc7 == 0x46 => Some(entry.hl),
+            _ => None,
+        };
+        if let Some(addr) = addr {
+            if !polled.contains(&addr) {
+                polled.push(addr);
+            }
+        }
+        let len = match opcode {
+            0xcb => 1,
+            _ => Instruction::from_byte(opcode).map_or(0, |inst| inst.len()),
+        };
+        pc = match pc.checked_add(1 + len) {
+            Some(pc) => pc,
+            None => break,
+        };
+    }
+    polled
+}
+
+/// Collect pc range and interrupts of every instruction, checked once a frame
+#[derive(Debug,Default,Clone)]
+pub struct LockupWatch {
+    /// pc range of current frame, None before the first instruction
+    range: Option<(u16, u16)>,
+    /// start of loop in previous frames
+    loop_start: Option<u16>,
+    serviced: bool,
+    /// interrupts requested and enabled since loop starts, bits as IF
+    requested: u8,
+    frames: u64,
+    reported: bool,
+}
+
+impl LockupWatch {
+    /// Record instruction at pc, with interrupts requested and enabled after it
+    pub fn step(&mut self, pc: u16, serviced: bool, requested: u8) {
+        self.range = Some(match self.range {
+            Some((low, high)) => (low.min(pc), high.max(pc)),
+            None => (pc, pc),
+        });
+        self.serviced |= serviced;
+        self.requested |= requested;
+    }
+
+    /// Check the frame done, return the loop once when it lasts long enough
+    pub fn end_frame(&mut self, cpu: &Cpu) -> Option<Lockup> {
+        let range = self.range.take().filter(|&(low, high)| high - low < LOCKUP_SPAN);
+        let serviced = std::mem::replace(&mut self.serviced, false);
+        match range {
+            Some((low, _)) if !serviced && self.loop_start == Some(low) => self.frames += 1,
+            Some((low, _)) if !serviced => {
+                self.loop_start = Some(low);
+                self.frames = 1;
+                self.requested = 0;
+                self.reported = false;
+            },
+            _ => {
+                self.loop_start = None;
+                self.frames = 0;
+                self.requested = 0;
+                self.reported = false;
+            },
+        }
+        if self.reported || self.frames < LOCKUP_FRAMES {
+            return None;
+        }
+        self.reported = true;
+        let (start, end) = range?;
+        let enabled = u8::from(&cpu.bus.interruptenb);
+        Some(Lockup {
+            start,
+            end,
+            polled: polled_addresses(cpu, start, end),
+            waiting: Interrupt::ALL.iter().cloned()
+                .filter(|source| enabled & !self.requested & source.mask() != 0)
+                .collect(),
+            ime: cpu.interrupt_master_enabled(),
+        })
+    }
+}
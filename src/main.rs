@@ -1,28 +1,65 @@
-use std::env;
 use std::fs::File;
 use std::io;
+use std::io::BufWriter;
 use std::io::prelude::*;
-use log::{error, debug};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+use log::{error, info};
 use clap::{App, Arg};
 
-#[macro_use]
-extern crate num_derive;
-use minifb::{Key, Window, WindowOptions, KeyRepeat};
+mod audio_pipe;
+mod debugger;
+mod frontend;
+mod heatmap;
+mod launcher;
+mod menu;
+mod minifb_frontend;
+mod terminal_frontend;
+mod osd;
+mod patch;
+mod paths;
+mod png;
+mod profile;
+mod recent;
+mod suite;
+mod tracediff;
+mod wav;
 
-mod cpu;
-mod gpu;
-mod register;
-mod instruction;
-mod bus;
-mod memory;
-mod vm;
-mod timer;
-mod joypad;
-
-use vm::{Vm, WIDTH, HEIGHT};
-use joypad::{JoypadKey};
+use rugameboy::vm::{Vm, VmConfig, WIDTH, HEIGHT, CLOCK_RATE, HISTORY_SIZE};
+use rugameboy::joypad::{JoypadKey};
+use rugameboy::cartridge::CartridgeHeader;
+use rugameboy::memory::RamInit;
+use rugameboy::cpu::UnknownOpcode;
+use rugameboy::bus::Interrupt;
+use rugameboy::compat::{self, CompatDb, CompatEntry};
+use rugameboy::boot::HleBoot;
+use rugameboy::gpu::{Palette, TILE_COUNT, GRAYSCALE};
+use rugameboy::printer::{Printer, Printout};
+use frontend::{Frontend, InputEvent, KeyboardLayout};
+use minifb_frontend::{MinifbFrontend, Scaling};
+use terminal_frontend::TerminalFrontend;
+use wav::AudioDump;
+use audio_pipe::AudioPipe;
+use debugger::Debugger;
+use menu::{Menu, MenuItem};
+use paths::SaveDir;
+use recent::RecentRoms;
 
 const MAX_ENLARGE_SCALE: usize = 5;
+const MAX_TURBO_PERIOD: u64 = 30;
+/// frames run before --dump-graphics if --frames is not given
+const DUMP_GRAPHICS_FRAMES: u64 = 600;
+/// tiles per row of tile sheet
+const TILE_SHEET_COLUMNS: usize = 16;
+/// speed multiplied while slow motion key is held
+const SLOW_MOTION_SPEED: f64 = 0.25;
+/// frames lock-up message stays on screen
+const LOCKUP_OSD_FRAMES: u32 = 300;
+/// frames and real time a test ROM runs in suite without result
+const SUITE_FRAMES: u64 = 60 * 60;
+const SUITE_TIMEOUT: Duration = Duration::from_secs(60);
 
 fn arg_check_range<T>(arg: &str, range: (T, T)) -> Result<T, String>
     where T: Ord + std::str::FromStr + std::fmt::Display
@@ -35,6 +72,357 @@ fn arg_check_range<T>(arg: &str, range: (T, T)) -> Result<T, String>
     }
 }
 
+fn parse_speed(arg: &str) -> Result<f64, String> {
+    match arg.parse::<f64>() {
+        Ok(speed) if 0.0 < speed && speed <= 1.0 => Ok(speed),
+        _ => Err(String::from("Please select a number in range (0, 1]")),
+    }
+}
+
+fn parse_interrupts(arg: &str) -> Vec<Interrupt> {
+    match arg {
+        "vblank" => vec![Interrupt::VBlank],
+        "stat"   => vec![Interrupt::Stat],
+        "timer"  => vec![Interrupt::Timer],
+        "serial" => vec![Interrupt::Serial],
+        "joypad" => vec![Interrupt::Joypad],
+        _ => vec![Interrupt::VBlank, Interrupt::Stat, Interrupt::Timer,
+                  Interrupt::Serial, Interrupt::Joypad],
+    }
+}
+
+fn parse_ram_init(arg: &str) -> Result<RamInit, String> {
+    match arg {
+        "zero" => Ok(RamInit::Zero),
+        "ff" => Ok(RamInit::Fill(0xff)),
+        "random" => {
+            let seed = std::time::SystemTime::now()
+                           .duration_since(std::time::UNIX_EPOCH)
+                           .map(|t| t.as_nanos() as u64)
+                           .unwrap_or(0);
+            Ok(RamInit::Random(seed))
+        },
+        _ => match arg.strip_prefix("random:").map(|seed| seed.parse::<u64>()) {
+            Some(Ok(seed)) => Ok(RamInit::Random(seed)),
+            _ => Err(String::from("Please select one of random[:seed], zero, ff")),
+        }
+    }
+}
+
+/// Message from the window thread to the emulation thread
+enum Command {
+    PressKey(JoypadKey),
+    ReleaseKey(JoypadKey),
+    Turbo(JoypadKey, bool),
+    Pause,
+    FrameAdvance,
+    /// pause menu is opened or closed, emulation stops while it is open
+    Menu(bool),
+    Reset,
+    Quit,
+}
+
+/// Emulation thread, run vm and send every frame to the window thread.
+/// Stop after frames if given, write audio to dump if given.
+/// Play audio through pipe if given, its fill level adjusts the APU rate every frame.
+/// Start paused if paused is set, until pause key is pressed.
+/// Frame advance key runs one frame while paused.
+/// Show boot animation first if boot is set, any key skips it.
+/// Write profile report at exit if profile is given.
+/// Write memory heat map at exit if heat_map is given.
+/// Connect Game Boy Printer and save printouts to printer directory if given.
+/// Return vm when stopped by quit, error or frames.
+#[allow(clippy::too_many_arguments)]
+fn emulation(mut vm: Vm, frame_tx: SyncSender<Vec<u32>>, cmd_rx: Receiver<Command>,
+             frames: Option<u64>, mut audio: Option<AudioDump>, mut paused: bool, boot: bool,
+             profile: Option<(PathBuf, Vec<profile::Symbol>)>, heat_map: Option<PathBuf>,
+             printer: Option<PathBuf>, pipe: Option<AudioPipe>) -> Vm {
+    let mut frame_count = 0;
+    let mut print_count = 0;
+    if printer.is_some() {
+        vm.connect_serial(Box::new(Printer::new()));
+    }
+    if let Some(dump) = audio.as_ref() {
+        dump.attach(&mut vm.cpu.bus.apu);
+    }
+    vm.set_profile(profile.is_some());
+    vm.set_access_count(heat_map.is_some());
+    let mut boot = if boot { HleBoot::start(&mut vm).ok() } else { None };
+    let mut menu = false;
+    // lock-up message and frames it is still shown
+    let mut lockup: Option<(String, u32)> = None;
+
+    'emulation: loop {
+        // block on command channel when paused so we do not spin
+        let commands: Vec<Command> = if paused || menu {
+            match cmd_rx.recv() {
+                Ok(cmd) => vec![cmd],
+                Err(_) => break,
+            }
+        } else {
+            cmd_rx.try_iter().collect()
+        };
+
+        // run one frame and stay paused
+        let mut advance = false;
+        for cmd in commands {
+            match cmd {
+                Command::PressKey(key) => match boot.take() {
+                    Some(animation) => if animation.finish(&mut vm).is_err() {
+                        break 'emulation;
+                    },
+                    None => vm.set_button(key, true),
+                },
+                Command::ReleaseKey(key) => vm.set_button(key, false),
+                Command::Turbo(key, enable) => vm.set_turbo(key, enable),
+                Command::Pause => paused = !paused,
+                Command::FrameAdvance => advance = paused,
+                Command::Menu(open) => menu = open,
+                Command::Reset => {
+                    boot = None;
+                    vm.reset();
+                },
+                Command::Quit  => break 'emulation,
+            }
+        }
+        if (paused || menu) && !advance {
+            continue;
+        }
+
+        let result = match boot.as_mut() {
+            Some(animation) => match animation.run_frame(&mut vm) {
+                Ok(true) => Ok(()),
+                Ok(false) => boot.take().unwrap().finish(&mut vm),
+                Err(()) => Err(()),
+            },
+            None => vm.run(),
+        };
+        if result.is_err() {
+            break;
+        }
+        match audio.as_mut() {
+            Some(dump) => if let Err(e) = dump.write(&mut vm.cpu.bus.apu) {
+                error!("wav-out: {}", e);
+                audio = None;
+            },
+            None => match pipe.as_ref() {
+                Some(pipe) => pipe.queue().feed(&mut vm.cpu.bus.apu),
+                None => { vm.cpu.bus.apu.take_samples(); },
+            },
+        }
+        if let Some(found) = vm.take_lockup() {
+            lockup = Some((found.summary(), LOCKUP_OSD_FRAMES));
+        }
+        if let (Some(dir), Some(link)) = (printer.as_ref(), vm.serial_link_mut::<Printer>()) {
+            for printout in link.take_printouts() {
+                print_count += 1;
+                let path = dir.join(format!("print-{}.png", print_count));
+                match write_printout(&path, &printout) {
+                    Ok(()) => info!("printer: saved {}", path.display()),
+                    Err(e) => error!("printer: {}", e),
+                }
+            }
+        }
+        let mut frame = vm.buffer.clone();
+        if advance {
+            osd::draw_text(&mut frame, 1, 1, &format!("FRAME {}", vm.frame_count()));
+        }
+        if let Some((text, frames)) = lockup.as_mut() {
+            osd::draw_text(&mut frame, 1, HEIGHT - 8, text);
+            *frames -= 1;
+            if *frames == 0 {
+                lockup = None;
+            }
+        }
+        if frame_tx.send(frame).is_err() {
+            break;
+        }
+        frame_count += 1;
+        if frames.is_some_and(|frames| frame_count >= frames) {
+            break;
+        }
+    }
+    vm.dump();
+    if let Some(pc) = vm.detect_tight_loop(HISTORY_SIZE) {
+        info!("program is spinning in loop at {:04X}, maybe waiting on a register", pc);
+    }
+    if let (Some((path, symbols)), Some(clocks)) = (profile, vm.profile()) {
+        if let Err(e) = std::fs::write(&path, profile::report(clocks, &symbols)) {
+            error!("profile-game: {}", e);
+        }
+    }
+    if let (Some(path), Some(count)) = (heat_map, vm.access_count()) {
+        let result = if path.extension().is_some_and(|ext| ext == "png") {
+            heatmap::write_image(&path, count)
+        } else {
+            std::fs::write(&path, heatmap::report(count))
+        };
+        if let Err(e) = result {
+            error!("heat-map: {}", e);
+        }
+    }
+    if let Some(Err(e)) = audio.map(|dump| dump.finish()) {
+        error!("wav-out: {}", e);
+    }
+    vm
+}
+
+/// Load battery save into cartridge RAM, no file yet is a new game
+fn load_battery(vm: &mut Vm, path: &Path) {
+    let ram = match vm.cpu.bus.cartridge_ram_mut() {
+        Some(ram) => ram,
+        None => return,
+    };
+    match std::fs::read(path) {
+        Ok(data) if data.len() == ram.len() => {
+            ram.copy_from_slice(&data);
+            info!("battery: loaded {}", path.display());
+        },
+        Ok(data) => error!("battery: {} has {} bytes, cartridge RAM has {}, not loaded",
+                           path.display(), data.len(), ram.len()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+        Err(e) => error!("battery: {}: {}", path.display(), e),
+    }
+}
+
+/// Write cartridge RAM to battery save
+fn save_battery(vm: &Vm, path: &Path) {
+    if let Some(ram) = vm.cpu.bus.cartridge_ram() {
+        match std::fs::write(path, ram) {
+            Ok(()) => info!("battery: saved {}", path.display()),
+            Err(e) => error!("battery: {}: {}", path.display(), e),
+        }
+    }
+}
+
+fn write_printout(path: &Path, printout: &Printout) -> io::Result<()> {
+    let pixels: Vec<u32> = printout.pixels.iter().map(|&shade| GRAYSCALE[shade as usize]).collect();
+    png::write_rgb(path, printout.width, printout.height, &pixels)
+}
+
+/// Write tile sheet and both background maps to dir as PNG,
+/// colored by the current background palette
+fn dump_graphics(vm: &Vm, dir: &Path) -> io::Result<()> {
+    let gpu = &vm.cpu.bus.gpu;
+    let colors = gpu.palette_colors(Palette::Bg);
+    // draw tile at (x, y) of image with given width
+    let draw_tile = |image: &mut Vec<u32>, width: usize, x: usize, y: usize, index: usize| {
+        let pixels = gpu.tile_pixels(index, 0).unwrap_or_default();
+        for (line_idx, line) in pixels.iter().enumerate() {
+            for (col_idx, &pixel) in line.iter().enumerate() {
+                image[(y + line_idx) * width + x + col_idx] = colors[pixel as usize];
+            }
+        }
+    };
+
+    let width = TILE_SHEET_COLUMNS * 8;
+    let height = TILE_COUNT / TILE_SHEET_COLUMNS * 8;
+    let mut sheet = vec![0; width * height];
+    for index in 0..TILE_COUNT {
+        draw_tile(&mut sheet, width, index % TILE_SHEET_COLUMNS * 8, index / TILE_SHEET_COLUMNS * 8, index);
+    }
+    png::write_rgb(dir.join("tiles.png"), width, height, &sheet)?;
+
+    for &(map_select, name) in [(false, "bgmap_9800.png"), (true, "bgmap_9c00.png")].iter() {
+        let mut map = vec![0; 256 * 256];
+        for (row, tiles) in gpu.bg_map(map_select).iter().enumerate() {
+            for (col, &entry) in tiles.iter().enumerate() {
+                draw_tile(&mut map, 256, col * 8, row * 8, gpu.bg_tile_index(entry));
+            }
+        }
+        png::write_rgb(dir.join(name), 256, 256, &map)?;
+    }
+    info!("graphics written to {}", dir.display());
+    Ok(())
+}
+
+/// Window thread, forward input to emulation thread and show received frames.
+/// Below full speed, each frame is shown for several window updates,
+/// emulation thread waits on the frame channel in the meantime.
+fn present(frontend: &mut dyn Frontend, cmd_tx: &Sender<Command>, frame_rx: &Receiver<Vec<u32>>,
+           speed: f64) {
+    let mut buffer = vec![0; WIDTH * HEIGHT];
+    let mut turbo = 0;
+    let mut slow_motion = false;
+    let mut menu: Option<Menu> = None;
+    // emulated frames to take, one is taken when it reaches 1
+    let mut frame_credit = 1.0;
+    while !frontend.should_close() {
+        for event in frontend.poll_input() {
+            if let Some(current) = menu.as_mut() {
+                let picked = match event {
+                    InputEvent::Press(key) => current.press(key),
+                    InputEvent::Menu => Some(MenuItem::Resume),
+                    _ => None,
+                };
+                match picked {
+                    Some(MenuItem::Resume) => {},
+                    Some(MenuItem::Reset) => cmd_tx.send(Command::Reset).unwrap_or(()),
+                    Some(MenuItem::Turbo) => {
+                        let enable = turbo == 0;
+                        for &key in [JoypadKey::A, JoypadKey::B].iter() {
+                            turbo = if enable { turbo | key.mask() } else { turbo & !key.mask() };
+                            cmd_tx.send(Command::Turbo(key, enable)).unwrap_or(());
+                        }
+                        continue;
+                    },
+                    Some(MenuItem::Quit) => return,
+                    None => continue,
+                }
+                menu = None;
+                cmd_tx.send(Command::Menu(false)).unwrap_or(());
+                continue;
+            }
+            let cmd = match event {
+                InputEvent::Press(key)   => Command::PressKey(key),
+                InputEvent::Release(key) => Command::ReleaseKey(key),
+                InputEvent::Turbo(key, enable) => {
+                    turbo = if enable { turbo | key.mask() } else { turbo & !key.mask() };
+                    Command::Turbo(key, enable)
+                },
+                InputEvent::Pause        => Command::Pause,
+                InputEvent::FrameAdvance => Command::FrameAdvance,
+                InputEvent::SlowMotion(enable) => {
+                    slow_motion = enable;
+                    continue;
+                },
+                InputEvent::Menu => {
+                    menu = Some(Menu::new());
+                    Command::Menu(true)
+                },
+            };
+            cmd_tx.send(cmd).unwrap_or(());
+        }
+
+        frame_credit += if slow_motion { speed * SLOW_MOTION_SPEED } else { speed };
+        if frame_credit >= 1.0 {
+            // present the latest frame, keep the old one if worker is not ready
+            match frame_rx.try_recv() {
+                Ok(frame) => {
+                    buffer = frame;
+                    frame_credit -= 1.0;
+                },
+                Err(TryRecvError::Empty) => {},
+                Err(TryRecvError::Disconnected) => break,
+            }
+            frame_credit = frame_credit.min(1.0);
+        }
+        if turbo == 0 && menu.is_none() {
+            frontend.present_frame(&buffer);
+        } else {
+            // draw on a copy, the frame may be presented again after turbo is off
+            let mut screen = buffer.clone();
+            if turbo != 0 {
+                osd::draw_text(&mut screen, 1, 1, "TURBO");
+            }
+            if let Some(menu) = menu.as_ref() {
+                menu.draw(&mut screen, turbo != 0);
+            }
+            frontend.present_frame(&screen);
+        }
+    }
+}
+
 fn main() -> io::Result<()> {
     env_logger::init();
 
@@ -44,73 +432,469 @@ fn main() -> io::Result<()> {
                             .short("s")
                             .long("scale")
                             .default_value("1"))
+                    .arg(Arg::with_name("integer_scale")
+                            .help("Upscale by integer scale ourselves for crisp pixels, window can be resized")
+                            .short("i")
+                            .long("integer-scale"))
+                    .arg(Arg::with_name("fit")
+                            .help("Scale to fill resizable window keeping aspect ratio, scale may not be integer")
+                            .long("fit")
+                            .conflicts_with("integer_scale"))
+                    .arg(Arg::with_name("turbo_period")
+                            .help("Set frames turbo button is kept pressed or released in range [1-30]")
+                            .long("turbo-period")
+                            .default_value("2"))
+                    .arg(Arg::with_name("ram_init")
+                            .help("Set power-on RAM content: random[:seed], zero or ff")
+                            .long("ram-init")
+                            .default_value("zero"))
+                    .arg(Arg::with_name("allow_impossible_inputs")
+                            .help("Allow pressing opposite directions at the same time")
+                            .long("allow-impossible-inputs"))
+                    .arg(Arg::with_name("stack_check")
+                            .help("Stop on stack access outside of RAM or PUSH over a return address, for debugging homebrew")
+                            .long("stack-check"))
+                    .arg(Arg::with_name("no_crash_history")
+                            .help("Do not keep recent instructions for crash report")
+                            .long("no-crash-history"))
+                    .arg(Arg::with_name("frontend")
+                            .help("Set the frontend to display")
+                            .short("f")
+                            .long("frontend")
+                            .possible_values(&["window", "terminal"])
+                            .default_value("window"))
+                    .arg(Arg::with_name("speed")
+                            .help("Run at a fraction of real speed in range (0, 1], hold G for quarter of it")
+                            .long("speed")
+                            .default_value("1"))
+                    .arg(Arg::with_name("unknown_opcode")
+                            .help("Stop, run as NOP or lock up CPU on opcode not implemented")
+                            .long("unknown-opcode")
+                            .possible_values(&["error", "skip", "halt"])
+                            .default_value("error"))
+                    .arg(Arg::with_name("keyboard_layout")
+                            .help("Set keyboard layout, keys are bound by position on QWERTY keyboard")
+                            .long("keyboard-layout")
+                            .possible_values(&["qwerty", "azerty", "qwertz"])
+                            .default_value("qwerty"))
+                    .arg(Arg::with_name("trace")
+                            .help("Write instruction trace to file, in gameboy doctor format")
+                            .short("t")
+                            .long("trace")
+                            .takes_value(true))
+                    .arg(Arg::with_name("wav_out")
+                            .help("Write audio output to WAV file")
+                            .long("wav-out")
+                            .takes_value(true)
+                            .conflicts_with_all(&["tui_debug", "bench_cycles", "dump_graphics", "run_suite"]))
+                    .arg(Arg::with_name("audio_cmd")
+                            .help("Play audio by piping 16-bit little endian stereo at 44100 Hz to command, e.g. \"aplay -f cd\"")
+                            .long("audio-cmd")
+                            .value_name("CMD")
+                            .takes_value(true)
+                            .conflicts_with_all(&["wav_out", "tui_debug", "bench_cycles", "dump_graphics", "run_suite"]))
+                    .arg(Arg::with_name("wav_per_channel")
+                            .help("Also write each sound channel to its own WAV file")
+                            .long("wav-per-channel")
+                            .requires("wav_out"))
+                    .arg(Arg::with_name("profile_game")
+                            .help("Count clocks spent at each PC, write report by region and symbol of ROM .sym file at exit")
+                            .long("profile-game")
+                            .value_name("FILE")
+                            .takes_value(true)
+                            .conflicts_with_all(&["tui_debug", "bench_cycles", "dump_graphics", "run_suite"]))
+                    .arg(Arg::with_name("printer")
+                            .help("Connect Game Boy Printer to serial port, save printouts as PNG in save directory")
+                            .long("printer")
+                            .conflicts_with_all(&["tui_debug", "bench_cycles", "dump_graphics", "run_suite"]))
+                    .arg(Arg::with_name("heat_map")
+                            .help("Count memory access per 256 bytes page, write report at exit, as image if FILE ends with .png")
+                            .long("heat-map")
+                            .value_name("FILE")
+                            .takes_value(true)
+                            .conflicts_with_all(&["tui_debug", "bench_cycles", "dump_graphics", "run_suite"]))
+                    .arg(Arg::with_name("frames")
+                            .help("Run given number of frames without display, then exit")
+                            .long("frames")
+                            .takes_value(true))
+                    .arg(Arg::with_name("run_suite")
+                            .help("Run every test ROM in directory headlessly and print results, --frames limits each ROM")
+                            .long("run-suite")
+                            .takes_value(true)
+                            .conflicts_with_all(&["tui_debug", "bench_cycles", "dump_graphics"]))
+                    .arg(Arg::with_name("report")
+                            .help("Write results of --run-suite as JSON to this file")
+                            .long("report")
+                            .takes_value(true)
+                            .requires("run_suite"))
+                    .arg(Arg::with_name("paused")
+                            .help("Do not start execution until pause key is pressed, with --tui-debug start stopped at the first instruction. \
+                                   There is no GDB stub to wait for")
+                            .long("paused")
+                            .conflicts_with("frames"))
+                    .arg(Arg::with_name("skip_boot")
+                            .help("Start cartridge without boot logo animation")
+                            .long("skip-boot"))
+                    .arg(Arg::with_name("tui_debug")
+                            .help("Run debugger in terminal, game is shown in the window")
+                            .long("tui-debug")
+                            .conflicts_with("frames"))
+                    .arg(Arg::with_name("break_irq")
+                            .help("Debugger stops when interrupt of the source is serviced")
+                            .long("break-irq")
+                            .takes_value(true)
+                            .multiple(true)
+                            .number_of_values(1)
+                            .possible_values(&["vblank", "stat", "timer", "serial", "joypad", "any"])
+                            .requires("tui_debug"))
+                    .arg(Arg::with_name("break_bank")
+                            .help("Debugger stops when a write changes the ROM or RAM bank of MBC")
+                            .long("break-bank")
+                            .requires("tui_debug"))
+                    .arg(Arg::with_name("patch")
+                            .help("Apply IPS or BPS patch to the binary before running")
+                            .long("patch")
+                            .takes_value(true))
+                    .arg(Arg::with_name("patch_saves")
+                            .help("Name battery save and save directory folder after the patch too, so a hack keeps its own saves")
+                            .long("patch-saves")
+                            .requires("patch"))
+                    .arg(Arg::with_name("compat_db")
+                            .help("Add per-game settings from file, see src/compat.rs for format")
+                            .long("compat-db")
+                            .takes_value(true))
+                    .arg(Arg::with_name("no_compat")
+                            .help("Do not apply per-game settings")
+                            .long("no-compat")
+                            .conflicts_with("compat_db"))
+                    .arg(Arg::with_name("mode")
+                            .help("Run in DMG or CGB mode instead of the mode of cartridge header")
+                            .long("mode")
+                            .possible_values(&["dmg", "cgb"])
+                            .takes_value(true))
+                    .arg(Arg::with_name("accurate_timing")
+                            .help("Tick peripherals on every memory access, slower")
+                            .long("accurate-timing"))
+                    .arg(Arg::with_name("mapper")
+                            .help("Map cartridge as cartridge type byte, like 0x08, when the header is wrong")
+                            .long("mapper")
+                            .takes_value(true))
+                    .arg(Arg::with_name("palette")
+                            .help("Set screen colors of shade 0 to 3, like 0xffffff,0xaaaaaa,0x555555,0x000000")
+                            .long("palette")
+                            .takes_value(true))
+                    .arg(Arg::with_name("bench_cycles")
+                            .help("Run given number of clocks as fast as possible, print speed and exit")
+                            .long("bench-cycles")
+                            .takes_value(true)
+                            .conflicts_with_all(&["frames", "tui_debug"]))
+                    .arg(Arg::with_name("diff_against")
+                            .help("Compare every instruction with trace of another emulator in gameboy doctor format, stop at first divergence")
+                            .long("diff-against")
+                            .value_name("TRACE")
+                            .takes_value(true)
+                            .conflicts_with_all(&["frames", "tui_debug", "bench_cycles"]))
+                    .arg(Arg::with_name("dump_graphics")
+                            .help("Run frames without display, then write tiles and background maps as PNG to directory")
+                            .long("dump-graphics")
+                            .takes_value(true)
+                            .conflicts_with_all(&["tui_debug", "paused", "bench_cycles"]))
+                    .arg(Arg::with_name("save_dir")
+                            .help("Put output files with relative path in per-ROM folder of this directory")
+                            .long("save-dir")
+                            .takes_value(true))
                     .arg(Arg::with_name("binary")
-                            .help("Set the binary file to run")
-                            .required(true))
+                            .help("Set the binary file to run, recently played ROMs are listed if not given"))
                     .get_matches();
 
-    let bin_name = prog.value_of("binary").unwrap();
-
     let scale = prog.value_of("scale").unwrap();
     let scale = arg_check_range(scale, (1, MAX_ENLARGE_SCALE)).unwrap_or_else(|e| {
                     error!("scale: {}", e);
                     std::process::exit(1);
                 });
 
+    let turbo_period = prog.value_of("turbo_period").unwrap();
+    let turbo_period = arg_check_range(turbo_period, (1, MAX_TURBO_PERIOD)).unwrap_or_else(|e| {
+                    error!("turbo-period: {}", e);
+                    std::process::exit(1);
+                });
+
+
+    let ram_init = parse_ram_init(prog.value_of("ram_init").unwrap()).unwrap_or_else(|e| {
+                    error!("ram-init: {}", e);
+                    std::process::exit(1);
+                });
+    if let RamInit::Random(seed) = ram_init {
+        info!("ram-init: random seed {}", seed);
+    }
+
+    let speed = parse_speed(prog.value_of("speed").unwrap()).unwrap_or_else(|e| {
+                    error!("speed: {}", e);
+                    std::process::exit(1);
+                });
+
+    let paused = prog.is_present("paused");
+    let tui_debug = prog.is_present("tui_debug");
+    if tui_debug && prog.value_of("frontend") == Some("terminal") {
+        error!("tui-debug: terminal is used by debugger, use window frontend");
+        std::process::exit(1);
+    }
+    let break_bank = prog.is_present("break_bank");
+    let break_irq: Vec<Interrupt> = prog.values_of("break_irq")
+                                        .map_or(Vec::new(), |args| args.flat_map(parse_interrupts).collect());
+
+    let frames = prog.value_of("frames").map(|frames| {
+                    arg_check_range(frames, (1, u64::MAX)).unwrap_or_else(|e| {
+                        error!("frames: {}", e);
+                        std::process::exit(1);
+                    })
+                });
+    if let Some(dir) = prog.value_of("run_suite") {
+        let results = suite::run(Path::new(dir), frames.unwrap_or(SUITE_FRAMES), SUITE_TIMEOUT)?;
+        suite::print_table(&results);
+        if let Some(report) = prog.value_of("report") {
+            suite::write_report(Path::new(report), &results)?;
+        }
+        return Ok(());
+    }
+
+    // animation only makes sense with a display
+    let boot = !prog.is_present("skip_boot") && !tui_debug && frames.is_none();
+
+    let layout = match prog.value_of("keyboard_layout") {
+        Some("azerty") => KeyboardLayout::Azerty,
+        Some("qwertz") => KeyboardLayout::Qwertz,
+        _ => KeyboardLayout::Qwerty,
+    };
+    let scaling = if prog.is_present("fit") {
+        Scaling::Fit
+    } else if prog.is_present("integer_scale") {
+        Scaling::Integer
+    } else {
+        Scaling::Window
+    };
+    let mut recents = RecentRoms::load();
+    // launcher window is kept to run the picked ROM
+    let mut launcher_frontend: Option<Box<dyn Frontend>> = None;
+    let bin_name = match prog.value_of("binary") {
+        Some(name) => String::from(name),
+        None => {
+            let mut frontend = Box::new(MinifbFrontend::new(scale, scaling, layout));
+            match launcher::pick(frontend.as_mut(), &recents) {
+                Some(path) => {
+                    launcher_frontend = Some(frontend);
+                    path.to_string_lossy().into_owned()
+                },
+                None => return Ok(()),
+            }
+        },
+    };
+    let bin_name = bin_name.as_str();
+
     let mut file = File::open(bin_name)?;
     let mut binary = Vec::new();
     file.read_to_end(&mut binary)?;
+    if let Some(name) = prog.value_of("patch") {
+        let mut patch_data = Vec::new();
+        File::open(name)?.read_to_end(&mut patch_data)?;
+        binary = patch::apply(&binary, &patch_data).unwrap_or_else(|e| {
+                     error!("patch: {}: {}", name, e);
+                     std::process::exit(1);
+                 });
+        info!("{}: patched by {}", bin_name, name);
+    }
+    if !CartridgeHeader::verify_global_checksum(&binary) {
+        info!("{}: global checksum mismatch", bin_name);
+    }
+    if CartridgeHeader::new(&binary).is_ok_and(|header| header.cgb_mode()) {
+        info!("{}: run in CGB mode", bin_name);
+    }
 
-    let mut vm = Vm::new(binary);
-    let mut window = Window::new(
-        "rust Gameboy",
-        WIDTH * scale,
-        HEIGHT * scale,
-        WindowOptions::default(),
-    ).unwrap_or_else(|e| { panic!("{}", e); });
-    window.limit_update_rate(Some(std::time::Duration::from_micros(16600)));
-
-    while window.is_open() && !window.is_key_down(Key::Escape) {
-
-        // check key press
-        window.get_keys_pressed(KeyRepeat::No).map(|keys| {
-            for key in keys {
-                match key {
-                    Key::Up    => vm.cpu.bus.joypad.presskey(JoypadKey::UP),
-                    Key::Down  => vm.cpu.bus.joypad.presskey(JoypadKey::DOWN),
-                    Key::Left  => vm.cpu.bus.joypad.presskey(JoypadKey::LEFT),
-                    Key::Right => vm.cpu.bus.joypad.presskey(JoypadKey::RIGHT),
-                    Key::A     => vm.cpu.bus.joypad.presskey(JoypadKey::START),
-                    Key::S     => vm.cpu.bus.joypad.presskey(JoypadKey::SELECT),
-                    Key::Z     => vm.cpu.bus.joypad.presskey(JoypadKey::A),
-                    Key::X     => vm.cpu.bus.joypad.presskey(JoypadKey::B),
-                    _ => (),
-                }
+    // per-game settings replace defaults, then options given by user replace them
+    let mut user = CompatEntry {
+        cgb_mode: prog.value_of("mode").map(|mode| mode == "cgb"),
+        accurate_timing: if prog.is_present("accurate_timing") { Some(true) } else { None },
+        ..Default::default()
+    };
+    let user_options = [("mapper", prog.value_of("mapper").map(String::from)),
+                        ("palette", prog.value_of("palette").map(|colors| format!("[{}]", colors)))];
+    for (key, value) in user_options.iter() {
+        if let Some(value) = value {
+            user.set(key, value).unwrap_or_else(|e| {
+                error!("{}: {}", key, e);
+                std::process::exit(1);
+            });
+        }
+    }
+    let db = if prog.is_present("no_compat") {
+        None
+    } else {
+        let mut db = CompatDb::new();
+        if let Some(name) = prog.value_of("compat_db") {
+            let mut text = String::new();
+            File::open(name)?.read_to_string(&mut text)?;
+            db.load(&text).unwrap_or_else(|e| {
+                error!("compat-db: {}: {}", name, e);
+                std::process::exit(1);
+            });
+        }
+        Some(db)
+    };
+    let header = CartridgeHeader::new(&binary).ok();
+    let entry = db.as_ref().zip(header.as_ref()).and_then(|(db, header)| db.lookup(header));
+    if let Some(entry) = entry {
+        info!("{}: apply settings of {}", bin_name, entry.title);
+    }
+    let config = compat::merge(entry, &user);
+    let config = VmConfig {
+        ram_init,
+        stack_check: prog.is_present("stack_check"),
+        crash_history: !prog.is_present("no_crash_history"),
+        turbo_period,
+        allow_impossible_inputs: prog.is_present("allow_impossible_inputs"),
+        unknown_opcode: match prog.value_of("unknown_opcode") {
+            Some("skip") => UnknownOpcode::SkipAsNop,
+            Some("halt") => UnknownOpcode::Halt,
+            _ => UnknownOpcode::Error,
+        },
+        ..config
+    };
+
+    let header_checksum = CartridgeHeader::new(&binary).map_or(0, |header| header.header_checksum);
+    let title = CartridgeHeader::new(&binary).map_or(String::new(), |header| header.title);
+    recents.played(Path::new(bin_name), &title, Duration::from_secs(0));
+    if let Err(e) = recents.save() {
+        error!("recent ROMs: {}", e);
+    }
+    let patch_saves = prog.value_of("patch").filter(|_| prog.is_present("patch_saves"));
+    let save_dir = SaveDir::new(prog.value_of("save_dir"), bin_name, header_checksum, patch_saves)?;
+    let trace = match prog.value_of("trace") {
+        Some(name) => Some(File::create(save_dir.resolve(name))?),
+        None => None,
+    };
+    let heat_map = prog.value_of("heat_map").map(|name| save_dir.resolve(name));
+    let printer = if prog.is_present("printer") { Some(save_dir.resolve(".")) } else { None };
+    let audio = match prog.value_of("wav_out") {
+        Some(name) => Some(AudioDump::create(&save_dir.resolve(name), prog.is_present("wav_per_channel"))?),
+        None => None,
+    };
+    let pipe = match prog.value_of("audio_cmd") {
+        Some(command) => Some(AudioPipe::spawn(command)?),
+        None => None,
+    };
+    let battery = header.as_ref().filter(|header| header.has_battery()).map(|_| save_dir.battery(bin_name));
+    let profile = prog.value_of("profile_game").map(|name| {
+        let sym = Path::new(bin_name).with_extension("sym");
+        let symbols = if sym.exists() {
+            profile::load_symbols(&sym).unwrap_or_else(|e| {
+                error!("{}: {}", sym.display(), e);
+                Vec::new()
+            })
+        } else {
+            Vec::new()
+        };
+        (save_dir.resolve(name), symbols)
+    });
+
+    if let Some(cycles) = prog.value_of("bench_cycles") {
+        let cycles = arg_check_range(cycles, (1, u64::MAX)).unwrap_or_else(|e| {
+                         error!("bench-cycles: {}", e);
+                         std::process::exit(1);
+                     });
+        let mut vm = Vm::with_config(binary, config);
+        match vm.run_cycles(cycles) {
+            Ok(elapsed) => println!("{} clocks in {:.3}s, {:.2} times real speed",
+                                    vm.cpu.cycles(), elapsed.as_secs_f64(),
+                                    vm.cpu.cycles() as f64 / CLOCK_RATE as f64 / elapsed.as_secs_f64()),
+            Err(_) => error!("bench-cycles: emulation stopped with error"),
+        }
+        return Ok(());
+    }
+
+    if let Some(name) = prog.value_of("diff_against") {
+        let reference = io::BufReader::new(File::open(name)?);
+        let mut vm = Vm::with_config(binary, config);
+        match tracediff::run(&mut vm, reference)? {
+            Ok(count) => println!("{} instructions match the trace", count),
+            Err(report) => {
+                println!("{}", report);
+                std::process::exit(1);
             }
-        });
-
-        // check key release
-        window.get_keys_released().map(|keys| {
-            for key in keys {
-                match key {
-                    Key::Up    => vm.cpu.bus.joypad.releasekey(JoypadKey::UP),
-                    Key::Down  => vm.cpu.bus.joypad.releasekey(JoypadKey::DOWN),
-                    Key::Left  => vm.cpu.bus.joypad.releasekey(JoypadKey::LEFT),
-                    Key::Right => vm.cpu.bus.joypad.releasekey(JoypadKey::RIGHT),
-                    Key::A     => vm.cpu.bus.joypad.releasekey(JoypadKey::START),
-                    Key::S     => vm.cpu.bus.joypad.releasekey(JoypadKey::SELECT),
-                    Key::Z     => vm.cpu.bus.joypad.releasekey(JoypadKey::A),
-                    Key::X     => vm.cpu.bus.joypad.releasekey(JoypadKey::B),
-                    _ => (),
-                }
+        }
+        return Ok(());
+    }
+
+    if let Some(dir) = prog.value_of("dump_graphics") {
+        let dir = save_dir.resolve(dir);
+        std::fs::create_dir_all(&dir)?;
+        let mut vm = Vm::with_config(binary, config);
+        for _ in 0..frames.unwrap_or(DUMP_GRAPHICS_FRAMES) {
+            if vm.run().is_err() {
+                error!("dump-graphics: emulation stopped with error");
+                break;
             }
-        });
+            vm.cpu.bus.apu.take_samples();
+        }
+        return dump_graphics(&vm, &dir);
+    }
 
-        if vm.run().is_err() {
-            break;
+    let started = Instant::now();
+    let (frame_tx, frame_rx) = mpsc::sync_channel(1);
+    let (cmd_tx, cmd_rx) = mpsc::channel();
+    let worker = thread::spawn(move || {
+        let mut vm = Vm::with_config(binary, config);
+        if let Some(path) = battery.as_ref() {
+            load_battery(&mut vm, path);
+        }
+        if let Some(file) = trace {
+            vm.set_trace_log(Box::new(BufWriter::new(file)));
+        }
+        for &source in break_irq.iter() {
+            vm.set_interrupt_break(source, true);
         }
-        window.update_with_buffer(&vm.buffer, WIDTH, HEIGHT).unwrap();
+        vm.set_bank_switch_break(break_bank);
+        if tui_debug {
+            match Debugger::new(vm, paused) {
+                Ok(mut debugger) => {
+                    debugger.run(frame_tx, cmd_rx);
+                    if let Some(path) = battery.as_ref() {
+                        save_battery(debugger.vm(), path);
+                    }
+                },
+                Err(e) => error!("tui-debug: {}", e),
+            }
+        } else {
+            let vm = emulation(vm, frame_tx, cmd_rx, frames, audio, paused, boot, profile, heat_map, printer, pipe);
+            // flushed on quit and on emulation error alike
+            if let Some(path) = battery.as_ref() {
+                save_battery(&vm, path);
+            }
+        }
+    });
+
+    if frames.is_some() {
+        // headless, drain frames until worker is done
+        frame_rx.iter().for_each(drop);
+    } else {
+        let mut frontend: Box<dyn Frontend> = match (launcher_frontend, prog.value_of("frontend")) {
+            (Some(frontend), _) => frontend,
+            (None, Some("terminal")) => Box::new(TerminalFrontend::new(layout).unwrap_or_else(|e| {
+                                            error!("terminal: {}", e);
+                                            std::process::exit(1);
+                                        })),
+            (None, _) => Box::new(MinifbFrontend::new(scale, scaling, layout)),
+        };
+        present(frontend.as_mut(), &cmd_tx, &frame_rx, speed);
+    }
+
+    // worker may be blocked on a full frame channel, drop receiver to wake it
+    cmd_tx.send(Command::Quit).unwrap_or(());
+    drop(frame_rx);
+    worker.join().unwrap();
+
+    recents.played(Path::new(bin_name), &title, started.elapsed());
+    if let Err(e) = recents.save() {
+        error!("recent ROMs: {}", e);
     }
-    vm.dump();
     Ok(())
 }
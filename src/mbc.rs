@@ -0,0 +1,166 @@
+/*
+ * MBC1 memory bank controller, cartridge type 0x01-0x03
+ *
+ * 0x0000-0x1FFF write 0x0A in low 4 bits to enable RAM
+ * 0x2000-0x3FFF BANK1, low 5 bits of ROM bank at 0x4000, 0 selects 1
+ * 0x4000-0x5FFF BANK2, RAM bank or bit 5-6 of ROM bank
+ * 0x6000-0x7FFF mode 1 also applies BANK2 to 0x0000-0x3FFF and RAM
+ */
+use crate::bus::Device;
+use std::fmt;
+
+const ROM_BANK_SIZE: usize = 0x4000;
+const RAM_BANK_SIZE: usize = 0x2000;
+/// RAM of cartridge type 0x02 and 0x03, 4 banks
+const RAM_SIZE: usize = RAM_BANK_SIZE * 4;
+
+/// Banks mapped at 0x4000-0x7FFF and 0xA000-0xBFFF
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct Banks {
+    pub rom: usize,
+    pub ram: usize,
+}
+
+/// Write to MBC that changed the mapped banks
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct BankSwitch {
+    pub old: Banks,
+    pub new: Banks,
+}
+
+impl fmt::Display for BankSwitch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ROM bank {} -> {}, RAM bank {} -> {}",
+               self.old.rom, self.new.rom, self.old.ram, self.new.ram)
+    }
+}
+
+#[derive(Clone)]
+pub struct Mbc1 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    bank1: u8,
+    bank2: u8,
+    mode: bool,
+}
+
+impl Mbc1 {
+    pub fn new(rom: Vec<u8>, has_ram: bool) -> Self {
+        Self {
+            rom,
+            ram: vec![0; if has_ram { RAM_SIZE } else { 0 }],
+            ram_enabled: false,
+            bank1: 1,
+            bank2: 0,
+            mode: false,
+        }
+    }
+
+    /// Banks mapped now, wrapped to the size of ROM and RAM
+    pub fn banks(&self) -> Banks {
+        let rom_banks = (self.rom.len() / ROM_BANK_SIZE).max(1);
+        let ram_bank = if self.mode { self.bank2 as usize } else { 0 };
+        Banks {
+            rom: ((self.bank2 as usize) << 5 | self.bank1 as usize) % rom_banks,
+            ram: ram_bank % (self.ram.len() / RAM_BANK_SIZE).max(1),
+        }
+    }
+
+    /// All banks of cartridge RAM, empty if cartridge has none
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    pub fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    // bank mapped at 0x0000-0x3FFF
+    fn rom0_bank(&self) -> usize {
+        let rom_banks = (self.rom.len() / ROM_BANK_SIZE).max(1);
+        if self.mode { ((self.bank2 as usize) << 5) % rom_banks } else { 0 }
+    }
+}
+
+impl Device for Mbc1 {
+    fn load(&self, addr: u16) -> Result<u8, ()> {
+        let offset = match addr {
+            0x0000 ..= 0x3fff => self.rom0_bank() * ROM_BANK_SIZE + addr as usize,
+            0x4000 ..= 0x7fff => self.banks().rom * ROM_BANK_SIZE + (addr - 0x4000) as usize,
+            0xa000 ..= 0xbfff => {
+                if !self.ram_enabled || self.ram.is_empty() {
+                    return Ok(0xff);
+                }
+                return Ok(self.ram[self.banks().ram * RAM_BANK_SIZE + (addr - 0xa000) as usize]);
+            },
+            _ => return Err(()),
+        };
+        Ok(self.rom.get(offset).cloned().unwrap_or(0xff))
+    }
+
+    fn store(&mut self, addr: u16, value: u8) -> Result<(), ()> {
+        match addr {
+            0x0000 ..= 0x1fff => self.ram_enabled = value & 0x0f == 0x0a,
+            0x2000 ..= 0x3fff => self.bank1 = (value & 0x1f).max(1),
+            0x4000 ..= 0x5fff => self.bank2 = value & 0x03,
+            0x6000 ..= 0x7fff => self.mode = value & 0x01 != 0,
+            0xa000 ..= 0xbfff => {
+                if self.ram_enabled && !self.ram.is_empty() {
+                    let offset = self.banks().ram * RAM_BANK_SIZE + (addr - 0xa000) as usize;
+                    self.ram[offset] = value;
+                }
+            },
+            _ => return Err(()),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 8 banks ROM, first byte of each bank is its number
+    fn mbc1(has_ram: bool) -> Mbc1 {
+        let mut rom = vec![0; ROM_BANK_SIZE * 8];
+        for bank in 0..8 {
+            rom[bank * ROM_BANK_SIZE] = bank as u8;
+        }
+        Mbc1::new(rom, has_ram)
+    }
+
+    #[test]
+    fn rom_bank_zero_selects_one() {
+        let mut mbc = mbc1(false);
+        assert_eq!(mbc.load(0x4000), Ok(1));
+        mbc.store(0x2000, 5).unwrap();
+        assert_eq!(mbc.load(0x4000), Ok(5));
+        mbc.store(0x2000, 0).unwrap();
+        assert_eq!(mbc.load(0x4000), Ok(1));
+        assert_eq!(mbc.load(0x0000), Ok(0));
+    }
+
+    #[test]
+    fn rom_bank_wraps_to_rom_size() {
+        let mut mbc = mbc1(false);
+        mbc.store(0x2000, 0x0b).unwrap();
+        assert_eq!(mbc.banks(), Banks { rom: 3, ram: 0 });
+        assert_eq!(mbc.load(0x4000), Ok(3));
+    }
+
+    #[test]
+    fn ram_needs_enable() {
+        let mut mbc = mbc1(true);
+        mbc.store(0xa000, 0x12).unwrap();
+        assert_eq!(mbc.load(0xa000), Ok(0xff));
+        mbc.store(0x0000, 0x0a).unwrap();
+        mbc.store(0xa000, 0x12).unwrap();
+        assert_eq!(mbc.load(0xa000), Ok(0x12));
+        // bank 1 in mode 1 is another page
+        mbc.store(0x6000, 1).unwrap();
+        mbc.store(0x4000, 1).unwrap();
+        assert_eq!(mbc.banks().ram, 1);
+        assert_eq!(mbc.load(0xa000), Ok(0));
+    }
+}
@@ -1,36 +1,94 @@
 use crate::bus::Device;
 use log::info;
+use std::cell::RefCell;
+use std::collections::HashSet;
 
+#[derive(Clone)]
 pub enum Permission {
     Normal,
     ReadOnly,
     Invalid,
 }
 
+/// Power-on content of RAM.
+/// Real hardware starts with semi-random RAM, default is zero so the output
+/// of existing runs does not change.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum RamInit {
+    Zero,
+    Fill(u8),
+    /// pseudo random bytes from seed, same seed gives same content
+    Random(u64),
+}
+
+impl RamInit {
+    /// return generator of bytes to fill RAM
+    pub fn generator(self) -> impl FnMut() -> u8 {
+        // xorshift64, state cannot be zero
+        let mut state = match self {
+            RamInit::Random(seed) => seed | 1,
+            _ => 1,
+        };
+        move || match self {
+            RamInit::Zero => 0,
+            RamInit::Fill(value) => value,
+            RamInit::Random(_) => {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state >> 32) as u8
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Memory {
     base: usize,
     memory: Vec<u8>,
     permission: Permission,
+    /// invalid addresses already logged, each address is logged once
+    logged: RefCell<HashSet<u16>>,
 }
 
 impl Memory {
     pub fn new(base: usize, binary: Vec<u8>, perm: Permission) -> Self {
         Self {
-            base: base,
+            base,
             memory: binary.clone(),
             permission: perm,
+            logged: RefCell::new(HashSet::new()),
         }
     }
 
     pub fn new_empty(base: usize, size: usize, perm: Permission) -> Self {
         let memory = vec![0; size];
         Self {
-            base: base,
-            memory: memory,
+            base,
+            memory,
             permission: perm,
+            logged: RefCell::new(HashSet::new()),
         }
     }
 
+    // log access to invalid region only on first access of each address
+    fn log_invalid(&self, access: &str, addr: u16) {
+        if self.logged.borrow_mut().insert(addr) {
+            info!("Invalid {} address {:#X}, further access is not logged", access, addr);
+        }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.memory
+    }
+
+    pub fn bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.memory
+    }
+
+    pub fn fill_with(&mut self, next: &mut dyn FnMut() -> u8) {
+        self.memory.iter_mut().for_each(|byte| *byte = next());
+    }
 }
 
 impl Device for Memory {
@@ -44,7 +102,7 @@ impl Device for Memory {
                 }
             },
             Permission::Invalid => {
-                info!("Invalid load on address {:#X}", addr);
+                self.log_invalid("load on", addr);
                 Ok(0)
             },
         }
@@ -66,9 +124,28 @@ impl Device for Memory {
                 Ok(())
             },
             Permission::Invalid => {
-                info!("Invalid store to address {:#X}", addr);
+                self.log_invalid("store to", addr);
                 Ok(())
             },
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_access_logged_once_per_address() {
+        let mut memory = Memory::new_empty(0xfea0, 0x60, Permission::Invalid);
+        for _ in 0..3 {
+            assert_eq!(memory.load(0xfea0), Ok(0));
+            memory.store(0xfea0, 0x12).unwrap();
+        }
+        memory.store(0xfeff, 0x12).unwrap();
+        // an address is logged only when it is first added
+        let logged = memory.logged.borrow();
+        assert_eq!(logged.len(), 2);
+        assert!(logged.contains(&0xfea0) && logged.contains(&0xfeff));
+    }
+}
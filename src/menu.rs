@@ -0,0 +1,60 @@
+use crate::osd;
+use rugameboy::joypad::JoypadKey;
+
+/// Items of pause menu, in display order
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum MenuItem {
+    Resume,
+    Reset,
+    Turbo,
+    Quit,
+}
+
+const ITEMS: [(MenuItem, &str); 4] = [
+    (MenuItem::Resume, "RESUME"),
+    (MenuItem::Reset,  "RESET"),
+    (MenuItem::Turbo,  "TURBO"),
+    (MenuItem::Quit,   "QUIT"),
+];
+/// position of the first item on screen
+const MENU_X: usize = 8;
+const MENU_Y: usize = 8;
+/// rows between items, height of OSD text box
+const LINE_HEIGHT: usize = 7;
+
+/*
+ * Pause menu drawn over the frame, opened by Esc.
+ * Up and down select an item, A or Start picks it.
+ */
+pub struct Menu {
+    selected: usize,
+}
+
+impl Menu {
+    pub fn new() -> Self {
+        Self { selected: 0 }
+    }
+
+    /// Handle button pressed in menu, return item picked
+    pub fn press(&mut self, key: JoypadKey) -> Option<MenuItem> {
+        match key {
+            JoypadKey::UP => self.selected = (self.selected + ITEMS.len() - 1) % ITEMS.len(),
+            JoypadKey::DOWN => self.selected = (self.selected + 1) % ITEMS.len(),
+            JoypadKey::A | JoypadKey::START => return Some(ITEMS[self.selected].0),
+            _ => {},
+        }
+        None
+    }
+
+    /// Draw menu on buffer, turbo tells state shown next to turbo item
+    pub fn draw(&self, buffer: &mut [u32], turbo: bool) {
+        for (i, &(item, name)) in ITEMS.iter().enumerate() {
+            let mark = if i == self.selected { ">" } else { " " };
+            let text = match item {
+                MenuItem::Turbo => format!("{}{} {}", mark, name, if turbo { "ON" } else { "OFF" }),
+                _ => format!("{}{}", mark, name),
+            };
+            osd::draw_text(buffer, MENU_X, MENU_Y + i * LINE_HEIGHT, &text);
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use crate::frontend::{Frontend, InputEvent, KeyboardLayout};
+use rugameboy::joypad::JoypadKey;
+use rugameboy::vm::{WIDTH, HEIGHT, CLOCK_RATE, CYCLES_PER_FRAME};
+
+use minifb::{Key, Window, WindowOptions, KeyRepeat};
+
+/// letter keys from a to z
+const LETTER_KEYS: [Key; 26] = [
+    Key::A, Key::B, Key::C, Key::D, Key::E, Key::F, Key::G, Key::H, Key::I,
+    Key::J, Key::K, Key::L, Key::M, Key::N, Key::O, Key::P, Key::Q, Key::R,
+    Key::S, Key::T, Key::U, Key::V, Key::W, Key::X, Key::Y, Key::Z,
+];
+
+const BORDER: u32 = 0x00000000u32;
+
+/// How frame is scaled to window
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Scaling {
+    /// minifb stretches frame to window of fixed size
+    Window,
+    /// largest integer scale fits in window, for crisp pixels
+    Integer,
+    /// fill window as much as possible, scale may not be integer
+    Fit,
+}
+
+pub struct MinifbFrontend {
+    window: Window,
+    layout: KeyboardLayout,
+    scaling: Scaling,
+    /// window sized buffer when scaled by ourselves
+    scaled: Vec<u32>,
+}
+
+/// Size of frame scaled into window of width x height, keeping aspect ratio
+fn scaled_size(scaling: Scaling, width: usize, height: usize) -> (usize, usize) {
+    if scaling == Scaling::Integer {
+        let scale = (width / WIDTH).min(height / HEIGHT).max(1);
+        (WIDTH * scale, HEIGHT * scale)
+    } else if width * HEIGHT <= height * WIDTH {
+        (width, width * HEIGHT / WIDTH)
+    } else {
+        (height * WIDTH / HEIGHT, height)
+    }
+}
+
+/// Nearest neighbor scale WIDTH x HEIGHT src to scaled_width x scaled_height,
+/// centered in dst of width x height with black border, clipped if too large
+pub fn letterbox(src: &[u32], (scaled_width, scaled_height): (usize, usize),
+                 dst: &mut [u32], width: usize, height: usize) {
+    dst.iter_mut().for_each(|pixel| *pixel = BORDER);
+    let left = width.saturating_sub(scaled_width) / 2;
+    let top = height.saturating_sub(scaled_height) / 2;
+    for y in 0..scaled_height.min(height) {
+        let src_row = &src[y * HEIGHT / scaled_height * WIDTH..];
+        let dst_row = &mut dst[(top + y) * width + left..(top + y + 1) * width];
+        for (x, pixel) in dst_row.iter_mut().take(scaled_width).enumerate() {
+            *pixel = src_row[x * WIDTH / scaled_width];
+        }
+    }
+}
+
+impl MinifbFrontend {
+    pub fn new(scale: usize, scaling: Scaling, layout: KeyboardLayout) -> Self {
+        let options = WindowOptions {
+            resize: scaling != Scaling::Window,
+            ..WindowOptions::default()
+        };
+        let mut window = Window::new(
+            "rust Gameboy",
+            WIDTH * scale,
+            HEIGHT * scale,
+            options,
+        ).unwrap_or_else(|e| { panic!("{}", e); });
+        let frame_time = CYCLES_PER_FRAME * 1_000_000_000 / CLOCK_RATE;
+        window.limit_update_rate(Some(std::time::Duration::from_nanos(frame_time)));
+        Self {
+            window,
+            layout,
+            scaling,
+            scaled: Vec::new(),
+        }
+    }
+
+    /// Key at the same position on QWERTY keyboard
+    fn to_qwerty(&self, key: Key) -> Key {
+        match LETTER_KEYS.iter().position(|&letter| letter == key) {
+            Some(idx) => {
+                let letter = self.layout.to_qwerty((b'a' + idx as u8) as char);
+                LETTER_KEYS[(letter as u8 - b'a') as usize]
+            },
+            None => key,
+        }
+    }
+
+    fn map_key(key: Key) -> Option<JoypadKey> {
+        match key {
+            Key::Up    => Some(JoypadKey::UP),
+            Key::Down  => Some(JoypadKey::DOWN),
+            Key::Left  => Some(JoypadKey::LEFT),
+            Key::Right => Some(JoypadKey::RIGHT),
+            Key::A     => Some(JoypadKey::START),
+            Key::S     => Some(JoypadKey::SELECT),
+            Key::Z     => Some(JoypadKey::A),
+            Key::X     => Some(JoypadKey::B),
+            _ => None,
+        }
+    }
+}
+
+impl Frontend for MinifbFrontend {
+    fn present_frame(&mut self, buffer: &[u32]) {
+        // window may be resized by user, follow its size every frame
+        let (width, height) = self.window.get_size();
+        if self.scaling == Scaling::Window || width == 0 || height == 0 {
+            self.window.update_with_buffer(buffer, WIDTH, HEIGHT).unwrap();
+            return;
+        }
+        self.scaled.resize(width * height, BORDER);
+        let size = scaled_size(self.scaling, width, height);
+        letterbox(buffer, size, &mut self.scaled, width, height);
+        self.window.update_with_buffer(&self.scaled, width, height).unwrap();
+    }
+
+    fn poll_input(&mut self) -> Vec<InputEvent> {
+        let mut events = Vec::new();
+
+        // check key press
+        if let Some(keys) = self.window.get_keys_pressed(KeyRepeat::No) {
+            for key in keys {
+                match self.to_qwerty(key) {
+                    Key::Escape => events.push(InputEvent::Menu),
+                    Key::P => events.push(InputEvent::Pause),
+                    Key::Period => events.push(InputEvent::FrameAdvance),
+                    Key::G => events.push(InputEvent::SlowMotion(true)),
+                    Key::Q => events.push(InputEvent::Turbo(JoypadKey::A, true)),
+                    Key::W => events.push(InputEvent::Turbo(JoypadKey::B, true)),
+                    _ => if let Some(key) = Self::map_key(key) {
+                        events.push(InputEvent::Press(key));
+                    },
+                }
+            }
+        }
+
+        // check key release
+        if let Some(keys) = self.window.get_keys_released() {
+            for key in keys {
+                match self.to_qwerty(key) {
+                    Key::Q => events.push(InputEvent::Turbo(JoypadKey::A, false)),
+                    Key::W => events.push(InputEvent::Turbo(JoypadKey::B, false)),
+                    Key::G => events.push(InputEvent::SlowMotion(false)),
+                    _ => if let Some(key) = Self::map_key(key) {
+                        events.push(InputEvent::Release(key));
+                    },
+                }
+            }
+        }
+        events
+    }
+
+    fn should_close(&self) -> bool {
+        !self.window.is_open()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn integer_scale_makes_blocks() {
+        // checkerboard, every pixel differs from its neighbors
+        let src: Vec<u32> = (0..WIDTH * HEIGHT)
+            .map(|i| if (i % WIDTH + i / WIDTH).is_multiple_of(2) { 0xffffff } else { 0 })
+            .collect();
+        let (width, height) = (WIDTH * 3, HEIGHT * 3);
+        let size = scaled_size(Scaling::Integer, width + 2, height + 2);
+        assert_eq!(size, (width, height));
+        let mut dst = vec![0; width * height];
+        letterbox(&src, size, &mut dst, width, height);
+        for y in 0..height {
+            for x in 0..width {
+                assert_eq!(dst[y * width + x], src[y / 3 * WIDTH + x / 3], "at {},{}", x, y);
+            }
+        }
+    }
+}
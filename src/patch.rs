@@ -0,0 +1,319 @@
+/*
+ * ROM patches distributed by ROM hacks, applied to binary before running.
+ * Format is detected by header: "PATCH" for IPS, "BPS1" for BPS.
+ */
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const BPS_MAGIC: &[u8] = b"BPS1";
+/// source, target and patch CRC32 at the end of BPS
+const BPS_FOOTER_SIZE: usize = 12;
+/// largest target of BPS, 8 MiB of the biggest cartridge ROM
+const BPS_MAX_TARGET_SIZE: usize = 0x80_0000;
+
+/// Apply IPS or BPS patch to rom, return the patched rom
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    if patch.starts_with(IPS_MAGIC) {
+        apply_ips(rom, patch)
+    } else if patch.starts_with(BPS_MAGIC) {
+        apply_bps(rom, patch)
+    } else {
+        Err(String::from("unknown patch format, expect IPS or BPS"))
+    }
+}
+
+/// Read patch bytes one by one, error on truncated patch
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Self {
+        Self { data, pos }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let bytes = self.data.get(self.pos..self.pos + len)
+                        .ok_or_else(|| format!("patch truncated at offset {:#X}", self.pos))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    /// big endian number of len bytes, used by IPS
+    fn number(&mut self, len: usize) -> Result<usize, String> {
+        Ok(self.bytes(len)?.iter().fold(0, |value, &byte| value << 8 | byte as usize))
+    }
+
+    /// variable length number of BPS, 7 bits per byte and the last one has bit 7 set
+    fn varint(&mut self) -> Result<usize, String> {
+        let overflow = || String::from("number overflow in BPS patch");
+        let mut value = 0usize;
+        let mut shift = 1usize;
+        loop {
+            let byte = self.bytes(1)?[0];
+            value = ((byte & 0x7f) as usize).checked_mul(shift)
+                        .and_then(|bits| value.checked_add(bits))
+                        .ok_or_else(overflow)?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_mul(0x80).ok_or_else(overflow)?;
+            value = value.checked_add(shift).ok_or_else(overflow)?;
+        }
+    }
+}
+
+/*
+ * IPS: records of 3-byte offset and 2-byte size followed by data,
+ * size 0 is a run of 2-byte length filled by 1 byte value.
+ * Records end with "EOF", optionally followed by 3-byte size to truncate to.
+ */
+fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let mut output = rom.to_vec();
+    let mut reader = Reader::new(patch, IPS_MAGIC.len());
+    loop {
+        if reader.bytes(IPS_EOF.len())? == IPS_EOF {
+            if let Ok(size) = reader.number(3) {
+                output.truncate(size);
+            }
+            return Ok(output);
+        }
+        reader.pos -= IPS_EOF.len();
+
+        let offset = reader.number(3)?;
+        let size = reader.number(2)?;
+        let (data, len) = if size == 0 {
+            let len = reader.number(2)?;
+            (None, len)
+        } else {
+            (Some(reader.bytes(size)?), size)
+        };
+        if output.len() < offset + len {
+            output.resize(offset + len, 0);
+        }
+        match data {
+            Some(data) => output[offset..offset + len].copy_from_slice(data),
+            None => {
+                let value = reader.bytes(1)?[0];
+                output[offset..offset + len].iter_mut().for_each(|byte| *byte = value);
+            }
+        }
+    }
+}
+
+/*
+ * BPS: sizes and metadata, then actions building target from start to end.
+ * Each action copies length bytes from source at the same offset, from the patch,
+ * or from source or target at an offset relative to the previous copy.
+ */
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    if patch.len() < BPS_MAGIC.len() + BPS_FOOTER_SIZE {
+        return Err(String::from("BPS patch too short"));
+    }
+    let footer = patch.len() - BPS_FOOTER_SIZE;
+    let crc = |offset: usize| u32::from_le_bytes([patch[offset], patch[offset + 1],
+                                                  patch[offset + 2], patch[offset + 3]]);
+    let (source_crc, target_crc, patch_crc) = (crc(footer), crc(footer + 4), crc(footer + 8));
+    if crc32(&patch[..footer + 8]) != patch_crc {
+        return Err(String::from("BPS patch is corrupted, patch checksum mismatch"));
+    }
+    if crc32(rom) != source_crc {
+        return Err(format!("ROM does not match BPS patch, expect CRC32 {:08X} but ROM is {:08X}",
+                           source_crc, crc32(rom)));
+    }
+
+    let mut reader = Reader::new(&patch[..footer], BPS_MAGIC.len());
+    let source_size = reader.varint()?;
+    let target_size = reader.varint()?;
+    let metadata_size = reader.varint()?;
+    reader.bytes(metadata_size)?;
+    if source_size != rom.len() {
+        return Err(format!("ROM size {} does not match BPS patch size {}", rom.len(), source_size));
+    }
+    if target_size > BPS_MAX_TARGET_SIZE {
+        return Err(format!("BPS patch target size {} is larger than any cartridge", target_size));
+    }
+
+    let mut output: Vec<u8> = Vec::with_capacity(target_size);
+    let mut source_offset: usize = 0;
+    let mut target_offset: usize = 0;
+    let out_of_range = || String::from("BPS patch reads out of range");
+    while reader.pos < footer {
+        let data = reader.varint()?;
+        let len = (data >> 2) + 1;
+        if len > target_size - output.len() {
+            return Err(format!("BPS patch produces more than {} bytes", target_size));
+        }
+        match data & 0x3 {
+            // SourceRead
+            0 => {
+                let start = output.len();
+                output.extend_from_slice(rom.get(start..start + len).ok_or_else(out_of_range)?);
+            },
+            // TargetRead
+            1 => output.extend_from_slice(reader.bytes(len)?),
+            // SourceCopy, TargetCopy
+            action => {
+                let data = reader.varint()?;
+                let relative = data >> 1;
+                let offset = if action == 2 { &mut source_offset } else { &mut target_offset };
+                *offset = if data & 1 != 0 { offset.checked_sub(relative) } else { offset.checked_add(relative) }
+                              .ok_or_else(out_of_range)?;
+                let end = offset.checked_add(len).ok_or_else(out_of_range)?;
+                if action == 2 {
+                    output.extend_from_slice(rom.get(*offset..end).ok_or_else(out_of_range)?);
+                } else {
+                    // copied byte by byte, source may overlap bytes being written
+                    for i in *offset..end {
+                        let byte = *output.get(i).ok_or_else(out_of_range)?;
+                        output.push(byte);
+                    }
+                }
+                *offset = end;
+            },
+        }
+    }
+
+    if output.len() != target_size {
+        return Err(format!("BPS patch produces {} bytes, expect {}", output.len(), target_size));
+    }
+    if crc32(&output) != target_crc {
+        return Err(String::from("patched ROM checksum mismatch"));
+    }
+    Ok(output)
+}
+
+/// CRC-32 used by BPS, the same as zip and PNG
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| {
+            if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // IPS record of offset and data, or RLE record if data has one byte
+    fn ips_record(offset: usize, data: &[u8], rle_len: Option<u16>) -> Vec<u8> {
+        let mut record = offset.to_be_bytes()[5..].to_vec();
+        match rle_len {
+            Some(len) => {
+                record.extend_from_slice(&[0, 0]);
+                record.extend_from_slice(&len.to_be_bytes());
+            },
+            None => record.extend_from_slice(&(data.len() as u16).to_be_bytes()),
+        }
+        record.extend_from_slice(data);
+        record
+    }
+
+    fn ips(records: &[Vec<u8>], eof: &[u8]) -> Vec<u8> {
+        [IPS_MAGIC, &records.concat(), eof].concat()
+    }
+
+    #[test]
+    fn ips_rle_record() {
+        let patch = ips(&[ips_record(2, &[0xaa], Some(3)), ips_record(6, &[1, 2, 3], None)], IPS_EOF);
+        assert_eq!(apply(&[0; 8], &patch), Ok(vec![0, 0, 0xaa, 0xaa, 0xaa, 0, 1, 2, 3]));
+    }
+
+    #[test]
+    fn ips_eof_truncates() {
+        let patch = ips(&[ips_record(0, &[7], None)], &[IPS_EOF, &[0, 0, 4]].concat());
+        assert_eq!(apply(&[0; 8], &patch), Ok(vec![7, 0, 0, 0]));
+        // incomplete size after EOF is ignored
+        let patch = ips(&[], &[IPS_EOF, &[0, 4]].concat());
+        assert_eq!(apply(&[0; 8], &patch), Ok(vec![0; 8]));
+    }
+
+    #[test]
+    fn ips_truncated_record() {
+        let mut patch = ips(&[ips_record(0, &[1, 2, 3, 4], None)], &[]);
+        patch.truncate(patch.len() - 2);
+        assert_eq!(apply(&[0; 8], &patch), Err(String::from("patch truncated at offset 0xA")));
+        // records without EOF
+        let patch = ips(&[ips_record(0, &[1], None)], &[]);
+        assert!(apply(&[0; 8], &patch).unwrap_err().contains("truncated"));
+    }
+
+    fn encode_varint(mut value: usize) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        loop {
+            let low = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                bytes.push(low | 0x80);
+                return bytes;
+            }
+            bytes.push(low);
+            value -= 1;
+        }
+    }
+
+    #[test]
+    fn bps_varint_decoding() {
+        for &value in &[0, 1, 0x7f, 0x80, 0x407f, 0x4080, 0x12_3456, usize::MAX] {
+            let bytes = encode_varint(value);
+            assert_eq!(Reader::new(&bytes, 0).varint(), Ok(value), "{:?}", bytes);
+        }
+        assert_eq!(encode_varint(0x80), [0x00, 0x80]);
+        // more bits than usize has
+        let mut bytes = vec![0x7f; 10];
+        bytes.push(0x81);
+        assert_eq!(Reader::new(&bytes, 0).varint(), Err(String::from("number overflow in BPS patch")));
+        assert!(Reader::new(&[0x00], 0).varint().unwrap_err().contains("truncated"));
+    }
+
+    // BPS patch from source to target of actions, with given CRC of source and target
+    fn bps(source_size: usize, target_size: usize, actions: &[u8], source_crc: u32, target_crc: u32) -> Vec<u8> {
+        let mut patch = BPS_MAGIC.to_vec();
+        for &value in &[source_size, target_size, 0] {
+            patch.extend(encode_varint(value));
+        }
+        patch.extend_from_slice(actions);
+        patch.extend_from_slice(&source_crc.to_le_bytes());
+        patch.extend_from_slice(&target_crc.to_le_bytes());
+        let patch_crc = crc32(&patch);
+        patch.extend_from_slice(&patch_crc.to_le_bytes());
+        patch
+    }
+
+    // "hello" to "help, hello": SourceRead 3, TargetRead "p, ", SourceCopy 5 from 0
+    fn hello_actions() -> Vec<u8> {
+        [encode_varint(2 << 2), encode_varint((2 << 2) | 1), b"p, ".to_vec(),
+         encode_varint((4 << 2) | 2), encode_varint(0)].concat()
+    }
+
+    #[test]
+    fn bps_builds_target() {
+        let patch = bps(5, 11, &hello_actions(), crc32(b"hello"), crc32(b"help, hello"));
+        assert_eq!(apply(b"hello", &patch), Ok(b"help, hello".to_vec()));
+    }
+
+    #[test]
+    fn bps_crc_mismatch() {
+        let patch = bps(5, 11, &hello_actions(), crc32(b"hello"), crc32(b"help, hello"));
+        assert_eq!(apply(b"jello", &patch),
+                   Err(format!("ROM does not match BPS patch, expect CRC32 {:08X} but ROM is {:08X}",
+                               crc32(b"hello"), crc32(b"jello"))));
+        let patch = bps(5, 11, &hello_actions(), crc32(b"hello"), crc32(b"hello, help"));
+        assert_eq!(apply(b"hello", &patch), Err(String::from("patched ROM checksum mismatch")));
+        let mut patch = bps(5, 11, &hello_actions(), crc32(b"hello"), crc32(b"help, hello"));
+        patch[6] ^= 1;
+        assert_eq!(apply(b"hello", &patch), Err(String::from("BPS patch is corrupted, patch checksum mismatch")));
+    }
+
+    #[test]
+    fn bps_target_size_is_checked() {
+        let patch = bps(5, usize::MAX, &hello_actions(), crc32(b"hello"), 0);
+        assert!(apply(b"hello", &patch).unwrap_err().contains("larger than any cartridge"));
+        // TargetCopy repeating itself past target size
+        let actions = [encode_varint(0 << 2), encode_varint((1000 << 2) | 3), encode_varint(0)].concat();
+        let patch = bps(5, 16, &actions, crc32(b"hello"), 0);
+        assert_eq!(apply(b"hello", &patch), Err(String::from("BPS patch produces more than 16 bytes")));
+    }
+}
@@ -0,0 +1,92 @@
+use std::cell::Cell;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::info;
+
+/*
+ * Where files written for a ROM go, used by every output file option.
+ * With a save directory, each ROM gets its own folder named by ROM file stem
+ * and header checksum, so different versions of a game do not share files.
+ * Without it, paths are used as given.
+ * A patch stem is added to the ROM stem like "game+hack", so a ROM hack does
+ * not overwrite the battery save of the original game.
+ */
+pub struct SaveDir {
+    /// per-ROM folder, None if no save directory is given
+    dir: Option<PathBuf>,
+    /// file stem of patch to name saves after, if any
+    patch: Option<String>,
+    /// migration message is printed once
+    migration_noted: Cell<bool>,
+}
+
+impl SaveDir {
+    /// Create per-ROM folder under base if base is given,
+    /// named after patch too if given
+    pub fn new(base: Option<&str>, rom: &str, header_checksum: u8, patch: Option<&str>) -> io::Result<Self> {
+        let patch = patch.map(|patch| Path::new(patch).file_stem().unwrap_or_default().to_string_lossy().into_owned());
+        let stem = save_stem(rom, patch.as_deref());
+        let dir = match base {
+            Some(base) => {
+                let dir = Path::new(base).join(format!("{}-{:02x}", stem, header_checksum));
+                fs::create_dir_all(&dir)?;
+                info!("save directory: {}", dir.display());
+                Some(dir)
+            },
+            None => None,
+        };
+        Ok(Self { dir, patch, migration_noted: Cell::new(false) })
+    }
+
+    /// Battery save of ROM, in the save directory if given, otherwise next to the ROM
+    pub fn battery(&self, rom: &str) -> PathBuf {
+        let name = format!("{}.sav", save_stem(rom, self.patch.as_deref()));
+        match self.dir.as_ref() {
+            Some(dir) => dir.join(name),
+            None => Path::new(rom).with_file_name(name),
+        }
+    }
+
+    /// Path of output file given by user. Relative path is put in the save directory,
+    /// but an existing file at the given path that is not there yet is still used.
+    pub fn resolve(&self, path: &str) -> PathBuf {
+        let given = PathBuf::from(path);
+        let dir = match self.dir.as_ref() {
+            Some(dir) if given.is_relative() => dir,
+            _ => return given,
+        };
+        let saved = dir.join(&given);
+        if !saved.exists() && given.exists() {
+            if !self.migration_noted.replace(true) {
+                info!("{} is outside of save directory, move it to {} to keep files together",
+                      given.display(), dir.display());
+            }
+            return given;
+        }
+        saved
+    }
+}
+
+/// File stem of ROM, followed by "+" and patch stem if given
+fn save_stem(rom: &str, patch: Option<&str>) -> String {
+    let stem = Path::new(rom).file_stem().unwrap_or_default().to_string_lossy();
+    match patch {
+        Some(patch) => format!("{}+{}", stem, patch),
+        None => stem.into_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn battery_named_after_patch() {
+        let save_dir = SaveDir::new(None, "roms/game.gb", 0, None).unwrap();
+        assert_eq!(save_dir.battery("roms/game.gb"), Path::new("roms/game.sav"));
+        let save_dir = SaveDir::new(None, "roms/game.gb", 0, Some("hacks/hard.ips")).unwrap();
+        assert_eq!(save_dir.battery("roms/game.gb"), Path::new("roms/game+hard.sav"));
+    }
+}
@@ -0,0 +1,310 @@
+/*
+ * Game Boy Printer on the serial port. Game sends packets of
+ *
+ * 0x88 0x33 command compression length(2, LE) data checksum(2, LE) 0x00 0x00
+ *
+ * and printer answers 0x81 to the first trailing 0x00 and its status to the
+ * second, 0x00 to every other byte. Checksum is the sum of bytes from command
+ * to the end of data. Image data are 2bpp tiles, 20 tiles a row, printed with
+ * the palette of print command. Printing finishes at once, but status keeps
+ * busy for a few queries as games wait for it.
+ */
+use log::{info, error};
+
+use crate::bus::SerialLink;
+
+const SYNC1: u8 = 0x88;
+const SYNC2: u8 = 0x33;
+const ALIVE: u8 = 0x81;
+
+const COMMAND_INIT: u8 = 0x01;
+const COMMAND_PRINT: u8 = 0x02;
+const COMMAND_DATA: u8 = 0x04;
+const COMMAND_STATUS: u8 = 0x0f;
+
+const STATUS_CHECKSUM_ERROR: u8 = 0x01;
+const STATUS_PRINTING: u8 = 0x02;
+const STATUS_FULL: u8 = 0x04;
+const STATUS_UNPROCESSED: u8 = 0x08;
+
+/// printer memory, 9 data packets of 2 tile rows
+const BUFFER_SIZE: usize = 0x2000;
+/// width of printout in pixels and tiles
+pub const PRINT_WIDTH: usize = 160;
+const TILES_PER_ROW: usize = PRINT_WIDTH / 8;
+/// status queries answered busy after print
+const PRINT_BUSY_QUERIES: u8 = 4;
+/// palette 0 prints like the usual 0xE4
+const DEFAULT_PALETTE: u8 = 0xe4;
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum State {
+    Sync1,
+    Sync2,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Alive,
+    Status,
+}
+
+/// Finished printout, shade 0 to 3 per pixel, lightest first
+#[derive(Debug,Clone,PartialEq)]
+pub struct Printout {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+#[derive(Debug,Clone)]
+pub struct Printer {
+    state: State,
+    command: u8,
+    compressed: bool,
+    length: usize,
+    data: Vec<u8>,
+    sum: u16,
+    checksum: u16,
+    status: u8,
+    busy: u8,
+    /// image data received and not printed yet
+    buffer: Vec<u8>,
+    /// rows printed since the last feed, kept until a bottom margin
+    paper: Vec<u8>,
+    printouts: Vec<Printout>,
+}
+
+// RLE: 0x80 | n repeats next byte n + 2 times, n < 0x80 copies n + 1 bytes
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut iter = data.iter();
+    while let Some(&control) = iter.next() {
+        if control & 0x80 != 0 {
+            let byte = iter.next().cloned().unwrap_or(0);
+            output.extend(std::iter::repeat_n(byte, (control & 0x7f) as usize + 2));
+        } else {
+            output.extend(iter.by_ref().take(control as usize + 1));
+        }
+    }
+    output
+}
+
+/// Shades of 2bpp tiles laid out TILES_PER_ROW a row
+fn decode_tiles(data: &[u8], palette: u8) -> Vec<u8> {
+    let tile_rows = data.len() / 16 / TILES_PER_ROW;
+    let mut pixels = vec![0; PRINT_WIDTH * tile_rows * 8];
+    for (tile, bytes) in data.chunks_exact(16).take(tile_rows * TILES_PER_ROW).enumerate() {
+        let (tile_x, tile_y) = (tile % TILES_PER_ROW * 8, tile / TILES_PER_ROW * 8);
+        for line in 0..8 {
+            let (low, high) = (bytes[line * 2], bytes[line * 2 + 1]);
+            for x in 0..8 {
+                let color = (low >> (7 - x)) & 0x1 | ((high >> (7 - x)) & 0x1) << 1;
+                pixels[(tile_y + line) * PRINT_WIDTH + tile_x + x] = (palette >> (color * 2)) & 0x3;
+            }
+        }
+    }
+    pixels
+}
+
+impl Default for Printer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Printer {
+    pub fn new() -> Self {
+        Self {
+            state: State::Sync1,
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: Vec::new(),
+            sum: 0,
+            checksum: 0,
+            status: 0,
+            busy: 0,
+            buffer: Vec::new(),
+            paper: Vec::new(),
+            printouts: Vec::new(),
+        }
+    }
+
+    /// Printouts finished since last call, oldest first
+    pub fn take_printouts(&mut self) -> Vec<Printout> {
+        std::mem::take(&mut self.printouts)
+    }
+
+    fn add_sum(&mut self, byte: u8, next: State) {
+        self.sum = self.sum.wrapping_add(byte as u16);
+        self.state = next;
+    }
+
+    fn run_command(&mut self) {
+        if self.sum != self.checksum {
+            error!("printer: checksum {:04X} of command {:#04x}, expected {:04X}",
+                   self.checksum, self.command, self.sum);
+            self.status |= STATUS_CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !STATUS_CHECKSUM_ERROR;
+        match self.command {
+            COMMAND_INIT => {
+                self.buffer.clear();
+                self.status = 0;
+                self.busy = 0;
+            },
+            COMMAND_DATA => {
+                let data = if self.compressed { decompress(&self.data) } else { self.data.clone() };
+                let space = BUFFER_SIZE - self.buffer.len();
+                self.buffer.extend(data.into_iter().take(space));
+                if !self.buffer.is_empty() {
+                    self.status |= STATUS_UNPROCESSED;
+                }
+                if self.buffer.len() == BUFFER_SIZE {
+                    self.status |= STATUS_FULL;
+                }
+            },
+            COMMAND_PRINT => self.print(),
+            COMMAND_STATUS => if self.busy > 0 {
+                self.busy -= 1;
+                if self.busy == 0 {
+                    self.status &= !(STATUS_PRINTING | STATUS_FULL);
+                }
+            },
+            command => info!("printer: unknown command {:#04x}", command),
+        }
+    }
+
+    // print buffer, printout ends when the paper is fed by a bottom margin
+    fn print(&mut self) {
+        let sheets = self.data.first().cloned().unwrap_or(1);
+        let margins = self.data.get(1).cloned().unwrap_or(0);
+        let palette = match self.data.get(2).cloned().unwrap_or(0) {
+            0 => DEFAULT_PALETTE,
+            palette => palette,
+        };
+        let band = decode_tiles(&std::mem::take(&mut self.buffer), palette);
+        for _ in 0..sheets.max(1) {
+            self.paper.extend_from_slice(&band);
+        }
+        self.status = (self.status & !STATUS_UNPROCESSED) | STATUS_PRINTING;
+        self.busy = PRINT_BUSY_QUERIES;
+        if margins & 0x0f != 0 && !self.paper.is_empty() {
+            let pixels = std::mem::take(&mut self.paper);
+            info!("printer: printed {} lines", pixels.len() / PRINT_WIDTH);
+            self.printouts.push(Printout { width: PRINT_WIDTH, height: pixels.len() / PRINT_WIDTH, pixels });
+        }
+    }
+}
+
+impl SerialLink for Printer {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        match self.state {
+            State::Sync1 => if byte == SYNC1 {
+                self.state = State::Sync2;
+            },
+            State::Sync2 => self.state = match byte {
+                SYNC2 => State::Command,
+                SYNC1 => State::Sync2,
+                _ => State::Sync1,
+            },
+            State::Command => {
+                self.command = byte;
+                self.sum = 0;
+                self.add_sum(byte, State::Compression);
+            },
+            State::Compression => {
+                self.compressed = byte & 0x1 != 0;
+                self.add_sum(byte, State::LengthLow);
+            },
+            State::LengthLow => {
+                self.length = byte as usize;
+                self.add_sum(byte, State::LengthHigh);
+            },
+            State::LengthHigh => {
+                self.length |= (byte as usize) << 8;
+                self.data.clear();
+                let next = if self.length == 0 { State::ChecksumLow } else { State::Data };
+                self.add_sum(byte, next);
+            },
+            State::Data => {
+                self.data.push(byte);
+                let next = if self.data.len() == self.length { State::ChecksumLow } else { State::Data };
+                self.add_sum(byte, next);
+            },
+            State::ChecksumLow => {
+                self.checksum = byte as u16;
+                self.state = State::ChecksumHigh;
+            },
+            State::ChecksumHigh => {
+                self.checksum |= (byte as u16) << 8;
+                self.state = State::Alive;
+            },
+            State::Alive => {
+                self.run_command();
+                self.state = State::Status;
+                return ALIVE;
+            },
+            State::Status => {
+                self.state = State::Sync1;
+                return self.status;
+            },
+        }
+        0x00
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // send packet, return the alive and status bytes answered
+    fn send(printer: &mut Printer, command: u8, compressed: bool, data: &[u8]) -> (u8, u8) {
+        let mut packet = vec![command, compressed as u8, data.len() as u8, (data.len() >> 8) as u8];
+        packet.extend_from_slice(data);
+        let sum = packet.iter().fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16));
+        packet.extend_from_slice(&sum.to_le_bytes());
+        for &byte in [SYNC1, SYNC2].iter().chain(packet.iter()) {
+            assert_eq!(printer.transfer(byte), 0x00);
+        }
+        (printer.transfer(0x00), printer.transfer(0x00))
+    }
+
+    #[test]
+    fn captured_stream_prints_image() {
+        let mut printer = Printer::new();
+        assert_eq!(send(&mut printer, COMMAND_INIT, false, &[]), (ALIVE, 0x00));
+        // one row of 20 tiles, the first one black: 16 x 0xff then 304 x 0x00
+        let data = [0x8e, 0xff, 0xff, 0x00, 0xff, 0x00, 0xac, 0x00];
+        assert_eq!(send(&mut printer, COMMAND_DATA, true, &data), (ALIVE, STATUS_UNPROCESSED));
+        // empty data packet ends data
+        send(&mut printer, COMMAND_DATA, false, &[]);
+        // 1 sheet, bottom margin, default palette
+        assert_eq!(send(&mut printer, COMMAND_PRINT, false, &[0x01, 0x03, 0xe4, 0x40]).0, ALIVE);
+        assert_eq!(send(&mut printer, COMMAND_STATUS, false, &[]).1, STATUS_PRINTING);
+
+        let printouts = printer.take_printouts();
+        assert_eq!(printouts.len(), 1);
+        let printout = &printouts[0];
+        assert_eq!((printout.width, printout.height), (PRINT_WIDTH, 8));
+        for (i, &pixel) in printout.pixels.iter().enumerate() {
+            let expected = if i % PRINT_WIDTH < 8 { 3 } else { 0 };
+            assert_eq!(pixel, expected, "pixel {}", i);
+        }
+    }
+
+    #[test]
+    fn bad_checksum_sets_status() {
+        let mut printer = Printer::new();
+        for &byte in &[SYNC1, SYNC2, COMMAND_INIT, 0x00, 0x00, 0x00, 0x02, 0x00] {
+            printer.transfer(byte);
+        }
+        assert_eq!(printer.transfer(0x00), ALIVE);
+        assert_eq!(printer.transfer(0x00), STATUS_CHECKSUM_ERROR);
+    }
+}
@@ -0,0 +1,88 @@
+/*
+ * Report of clocks spent by pc, to find hot loops of a game. Clocks are
+ * summed by 256 bytes region and, if a .sym file is next to the ROM, by
+ * symbol. Without MBC, 0x4000-0x7fff is always bank 1, so only symbols of
+ * bank 0 and 1 are used for ROM.
+ */
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// lines of each part of the report
+const REPORT_LINES: usize = 32;
+
+/// Symbol of rgbds/bgb .sym file, lines like "01:4000 Main"
+pub struct Symbol {
+    pub bank: u8,
+    pub addr: u16,
+    pub name: String,
+}
+
+pub fn load_symbols(path: &Path) -> io::Result<Vec<Symbol>> {
+    let text = fs::read_to_string(path)?;
+    let mut symbols: Vec<Symbol> = text.lines().filter_map(|line| {
+        let line = line.split(';').next()?.trim();
+        let mut parts = line.split_whitespace();
+        let mut location = parts.next()?.splitn(2, ':');
+        let bank = u8::from_str_radix(location.next()?, 16).ok()?;
+        let addr = u16::from_str_radix(location.next()?, 16).ok()?;
+        Some(Symbol { bank, addr, name: parts.next()?.to_string() })
+    }).filter(|symbol| match symbol.addr {
+        0x0000..=0x3fff => symbol.bank == 0,
+        0x4000..=0x7fff => symbol.bank == 1,
+        _ => true,
+    }).collect();
+    symbols.sort_by_key(|symbol| symbol.addr);
+    Ok(symbols)
+}
+
+fn region_name(start: u16) -> &'static str {
+    match start {
+        0x0000..=0x3fff => "ROM0",
+        0x4000..=0x7fff => "ROM1",
+        0x8000..=0x9fff => "VRAM",
+        0xa000..=0xbfff => "SRAM",
+        0xc000..=0xfdff => "WRAM",
+        _ => "HRAM",
+    }
+}
+
+fn percent(clocks: u64, total: u64) -> f64 {
+    clocks as f64 * 100.0 / total.max(1) as f64
+}
+
+/// Report clocks by region and symbol, most clocks first
+pub fn report(profile: &[u64], symbols: &[Symbol]) -> String {
+    let total: u64 = profile.iter().sum();
+    let mut report = format!("{} clocks profiled\n\nBy region:\n", total);
+    let mut regions: Vec<(u16, u64)> = profile.chunks(0x100).enumerate()
+        .map(|(page, clocks)| ((page << 8) as u16, clocks.iter().sum()))
+        .filter(|&(_, clocks)| clocks > 0)
+        .collect();
+    regions.sort_by_key(|&(_, clocks)| std::cmp::Reverse(clocks));
+    for &(start, clocks) in regions.iter().take(REPORT_LINES) {
+        report.push_str(&format!("  {} {:04X}-{:04X} {:6.2}% {}\n", region_name(start), start,
+                                 start | 0xff, percent(clocks, total), clocks));
+    }
+
+    if symbols.is_empty() {
+        return report;
+    }
+    // pc belongs to the last symbol at or before it
+    let mut by_symbol: HashMap<&str, u64> = HashMap::new();
+    for (pc, &clocks) in profile.iter().enumerate().filter(|&(_, &clocks)| clocks > 0) {
+        let name = match symbols.iter().rposition(|symbol| symbol.addr as usize <= pc) {
+            Some(idx) => symbols[idx].name.as_str(),
+            None => "(none)",
+        };
+        *by_symbol.entry(name).or_insert(0) += clocks;
+    }
+    let mut by_symbol: Vec<(&str, u64)> = by_symbol.into_iter().collect();
+    by_symbol.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    report.push_str("\nBy symbol:\n");
+    for &(name, clocks) in by_symbol.iter().take(REPORT_LINES) {
+        report.push_str(&format!("  {:6.2}% {} {}\n", percent(clocks, total), clocks, name));
+    }
+    report
+}
@@ -0,0 +1,96 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// ROMs kept in the list, the least recently played is dropped
+const MAX_RECENT: usize = 10;
+const RECENT_FILE: &str = "recent.txt";
+
+/// ROM played before, shown by launcher
+#[derive(Debug,Clone)]
+pub struct RecentRom {
+    pub path: PathBuf,
+    pub title: String,
+    /// total seconds played
+    pub play_time: u64,
+    /// seconds since unix epoch of the last load
+    pub last_played: u64,
+}
+
+/*
+ * Recently played ROMs, most recent first, saved in the config directory
+ * ($XDG_CONFIG_HOME/rugameboy or ~/.config/rugameboy) one ROM per line:
+ * last played, play time, title and path separated by tab.
+ */
+pub struct RecentRoms {
+    /// file to save, None if no config directory is found
+    file: Option<PathBuf>,
+    entries: Vec<RecentRom>,
+}
+
+fn config_dir() -> Option<PathBuf> {
+    match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => Some(PathBuf::from(dir)),
+        None => env::var_os("HOME").map(|home| Path::new(&home).join(".config")),
+    }.map(|dir| dir.join("rugameboy"))
+}
+
+fn parse_line(line: &str) -> Option<RecentRom> {
+    let mut fields = line.splitn(4, '\t');
+    let last_played = fields.next()?.parse().ok()?;
+    let play_time = fields.next()?.parse().ok()?;
+    let title = String::from(fields.next()?);
+    let path = PathBuf::from(fields.next()?);
+    Some(RecentRom { path, title, play_time, last_played })
+}
+
+impl RecentRoms {
+    /// Load list from config directory, ROMs no longer existing are pruned
+    pub fn load() -> Self {
+        let file = config_dir().map(|dir| dir.join(RECENT_FILE));
+        let entries = file.as_ref()
+                          .and_then(|file| fs::read_to_string(file).ok())
+                          .map_or(Vec::new(), |text| {
+                              text.lines()
+                                  .filter_map(parse_line)
+                                  .filter(|rom| rom.path.exists())
+                                  .collect()
+                          });
+        Self { file, entries }
+    }
+
+    pub fn entries(&self) -> &[RecentRom] {
+        &self.entries
+    }
+
+    /// Move ROM to the top of list and add play time
+    pub fn played(&mut self, path: &Path, title: &str, play_time: Duration) {
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        let mut rom = match self.entries.iter().position(|rom| rom.path == path) {
+            Some(idx) => self.entries.remove(idx),
+            None => RecentRom { path, title: String::new(), play_time: 0, last_played: 0 },
+        };
+        rom.title = String::from(title);
+        rom.play_time += play_time.as_secs();
+        rom.last_played = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |t| t.as_secs());
+        self.entries.insert(0, rom);
+        self.entries.truncate(MAX_RECENT);
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let file = match self.file.as_ref() {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        if let Some(dir) = file.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let text: String = self.entries.iter()
+            .map(|rom| format!("{}\t{}\t{}\t{}\n", rom.last_played, rom.play_time,
+                               rom.title.replace('\t', " "), rom.path.display()))
+            .collect();
+        fs::write(file, text)
+    }
+}
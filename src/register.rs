@@ -1,6 +1,6 @@
 use std::fmt;
 
-#[derive(Debug,Default)]
+#[derive(Debug,Default,Clone)]
 pub struct FlagRegister {
     pub zero: bool,
     pub subtract: bool,
@@ -33,7 +33,7 @@ impl std::convert::From<u8> for FlagRegister {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug,Clone)]
 pub struct Register {
     pub a: u8,
     pub b: u8,
@@ -109,12 +109,8 @@ impl Register {
 
 impl fmt::Display for Register {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut output = String::new();
-        output = format!("{} AF:{:02X}{:02X}", output, self.a, u8::from(&self.f));
-        output = format!("{} BC:{:02X}{:02X}", output, self.b, self.c);
-        output = format!("{} DE:{:02X}{:02X}", output, self.d, self.e);
-        output = format!("{} HL:{:02x}{:02X}", output, self.h, self.l);
-        write!(f, "{}", output)
+        write!(f, " AF:{:02X}{:02X} BC:{:02X}{:02X} DE:{:02X}{:02X} HL:{:02x}{:02X}",
+               self.a, u8::from(&self.f), self.b, self.c, self.d, self.e, self.h, self.l)
     }
 }
 
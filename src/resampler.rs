@@ -0,0 +1,156 @@
+/*
+ * Band-limited resampler, same idea as blip_buf.
+ *
+ * The APU output only changes at square wave edges, so instead of picking
+ * the nearest sample, every amplitude change is added as a delta at its exact
+ * time. The delta is spread over KERNEL_WIDTH output samples by a windowed
+ * sinc kernel, and the output sample is the running sum of deltas.
+ * This removes the aliasing of point sampling.
+ */
+use std::f64::consts::PI;
+
+/// fraction positions of a delta between two output samples
+const PHASES: usize = 32;
+/// output samples touched by one delta
+const KERNEL_WIDTH: usize = 16;
+/// cutoff frequency relative to nyquist, leave room for the transition band
+const CUTOFF: f64 = 0.9;
+/// maximum adjust of output rate by buffer fill level, 0.5%
+pub const MAX_RATE_ADJUST: f64 = 0.005;
+
+/// Output rate adjust for buffer fill level, keeping the buffer half full.
+/// Empty buffer speeds output rate up by MAX_RATE_ADJUST, full buffer slows it down.
+pub fn rate_adjust(fill: usize, capacity: usize) -> f64 {
+    let fill = fill.min(capacity) as f64 / capacity.max(1) as f64;
+    1.0 + MAX_RATE_ADJUST * (1.0 - 2.0 * fill)
+}
+
+// windowed sinc kernel for each phase, every phase sums to 1
+fn build_kernel() -> Vec<[f32; KERNEL_WIDTH]> {
+    (0..=PHASES).map(|phase| {
+        let center = (KERNEL_WIDTH / 2) as f64 - 1.0 + phase as f64 / PHASES as f64;
+        let mut taps = [0.0; KERNEL_WIDTH];
+        for (i, tap) in taps.iter_mut().enumerate() {
+            let x = i as f64 - center;
+            let sinc = if x == 0.0 { 1.0 } else { (PI * x * CUTOFF).sin() / (PI * x * CUTOFF) };
+            // blackman window over the kernel width
+            let n = (x + KERNEL_WIDTH as f64 / 2.0) / KERNEL_WIDTH as f64;
+            let window = 0.42 - 0.5 * (2.0 * PI * n).cos() + 0.08 * (4.0 * PI * n).cos();
+            *tap = (sinc * window) as f32;
+        }
+        let sum: f32 = taps.iter().sum();
+        taps.iter_mut().for_each(|tap| *tap /= sum);
+        taps
+    }).collect()
+}
+
+#[derive(Clone)]
+pub struct Resampler {
+    /// input clocks per output sample
+    base_ratio: f64,
+    ratio: f64,
+    /// current time in output samples from the start of buffer
+    time: f64,
+    /// deltas of output samples not read yet
+    buffer: Vec<f32>,
+    /// running sum of deltas already read
+    sum: f32,
+    kernel: Vec<[f32; KERNEL_WIDTH]>,
+}
+
+impl Resampler {
+    pub fn new(clock_rate: u64, sample_rate: u64) -> Self {
+        let ratio = clock_rate as f64 / sample_rate as f64;
+        Self {
+            base_ratio: ratio,
+            ratio,
+            time: 0.0,
+            buffer: vec![0.0; KERNEL_WIDTH],
+            sum: 0.0,
+            kernel: build_kernel(),
+        }
+    }
+
+    /// Scale output rate, clamped to MAX_RATE_ADJUST, see rate_adjust
+    pub fn set_rate_adjust(&mut self, adjust: f64) {
+        let adjust = adjust.clamp(1.0 - MAX_RATE_ADJUST, 1.0 + MAX_RATE_ADJUST);
+        self.ratio = self.base_ratio / adjust;
+    }
+
+    /// Advance time by clock of input
+    pub fn advance(&mut self, clock: u64) {
+        self.time += clock as f64 / self.ratio;
+        let needed = self.time as usize + KERNEL_WIDTH + 1;
+        if self.buffer.len() < needed {
+            self.buffer.resize(needed, 0.0);
+        }
+    }
+
+    /// Change output amplitude by delta at current time
+    pub fn add_delta(&mut self, delta: f32) {
+        let pos = self.time as usize;
+        let phase = ((self.time - pos as f64) * PHASES as f64) as usize;
+        for (sample, tap) in self.buffer[pos..].iter_mut().zip(self.kernel[phase].iter()) {
+            *sample += delta * tap;
+        }
+    }
+
+    /// Number of output samples completed
+    pub fn available(&self) -> usize {
+        self.time as usize
+    }
+
+    /// Read completed output samples
+    pub fn read(&mut self) -> Vec<f32> {
+        let count = self.available();
+        let mut samples = Vec::with_capacity(count);
+        for delta in self.buffer.drain(..count) {
+            self.sum += delta;
+            samples.push(self.sum);
+        }
+        self.time -= count as f64;
+        samples
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_adjust_by_fill_level() {
+        assert_eq!(rate_adjust(50, 100), 1.0);
+        assert_eq!(rate_adjust(0, 100), 1.0 + MAX_RATE_ADJUST);
+        assert_eq!(rate_adjust(100, 100), 1.0 - MAX_RATE_ADJUST);
+        // overfull queue and empty capacity stay in range
+        assert_eq!(rate_adjust(300, 100), 1.0 - MAX_RATE_ADJUST);
+        assert_eq!(rate_adjust(0, 0), 1.0 + MAX_RATE_ADJUST);
+    }
+
+    // output samples of one second of input
+    fn samples_per_second(adjust: f64) -> usize {
+        let mut resampler = Resampler::new(1_000_000, 10_000);
+        resampler.set_rate_adjust(adjust);
+        resampler.advance(1_000_000);
+        resampler.available()
+    }
+
+    #[test]
+    fn rate_adjust_is_clamped() {
+        assert_eq!(samples_per_second(1.0), 10_000);
+        assert_eq!(samples_per_second(1.002), 10_020);
+        assert_eq!(samples_per_second(2.0), 10_050);
+        assert_eq!(samples_per_second(0.0), 9_950);
+    }
+
+    #[test]
+    fn step_is_band_limited() {
+        let mut resampler = Resampler::new(100, 1);
+        resampler.add_delta(1.0);
+        resampler.advance(100 * KERNEL_WIDTH as u64);
+        let samples = resampler.read();
+        // rises over the kernel and settles at the step
+        assert!(samples.windows(2).any(|pair| pair[0] > 0.0 && pair[0] < 0.9 && pair[1] > pair[0]));
+        assert!((samples.last().unwrap() - 1.0).abs() < 0.01);
+    }
+}
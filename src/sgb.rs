@@ -0,0 +1,172 @@
+/*
+ * Super Game Boy packets. Games talk to SGB by pulsing P14 and P15: both low
+ * resets and starts a packet, P14 low sends bit 0, P15 low sends bit 1, both
+ * high between bits. A packet is 16 bytes LSB first and a stop bit 0. The
+ * first byte holds command and number of packets of the command.
+ *
+ * Only decoding is done, commands are logged and kept. No reply is given, so
+ * games see a plain DMG.
+ */
+use log::info;
+use std::collections::VecDeque;
+
+/// bytes of one packet
+pub const PACKET_SIZE: usize = 16;
+const PACKET_BITS: usize = PACKET_SIZE * 8;
+/// commands kept, the oldest one is dropped
+const MAX_COMMANDS: usize = 64;
+const MLT_REQ: u8 = 0x11;
+
+const COMMAND_NAMES: [&str; 0x1a] = [
+    "PAL01", "PAL23", "PAL03", "PAL12", "ATTR_BLK", "ATTR_LIN", "ATTR_DIV", "ATTR_CHR",
+    "SOUND", "SOU_TRN", "PAL_SET", "PAL_TRN", "ATRC_EN", "TEST_EN", "ICON_EN", "DATA_SND",
+    "DATA_TRN", "MLT_REQ", "JUMP", "CHR_TRN", "PCT_TRN", "ATTR_TRN", "ATTR_SET", "MASK_EN",
+    "OBJ_TRN", "PAL_PRI",
+];
+
+/// Command with data of all its packets, header byte included
+#[derive(Debug,Clone,PartialEq)]
+pub struct SgbCommand {
+    pub command: u8,
+    pub data: Vec<u8>,
+}
+
+impl SgbCommand {
+    pub fn name(&self) -> &'static str {
+        COMMAND_NAMES.get(self.command as usize).cloned().unwrap_or("unknown")
+    }
+
+    /// Number of players requested by MLT_REQ, None for other commands
+    pub fn players(&self) -> Option<u8> {
+        if self.command != MLT_REQ {
+            return None;
+        }
+        match self.data[1] & 0x03 {
+            1 => Some(2),
+            3 => Some(4),
+            _ => Some(1),
+        }
+    }
+}
+
+#[derive(Debug,Default,Clone)]
+pub struct SgbReceiver {
+    /// P14 and P15 as last written
+    select: u8,
+    /// bits received of current packet, None outside of packet
+    bit: Option<usize>,
+    packet: [u8; PACKET_SIZE],
+    /// data of command with more packets to come
+    pending: Vec<u8>,
+    remaining: u8,
+    commands: VecDeque<SgbCommand>,
+}
+
+impl SgbReceiver {
+    pub fn new() -> Self {
+        // nothing selected at power on
+        Self { select: 0x30, ..Self::default() }
+    }
+
+    /// Feed P1 written by game, only bit 4 and 5 are used
+    pub fn write(&mut self, value: u8) {
+        let select = value & 0x30;
+        if select == self.select {
+            return;
+        }
+        self.select = select;
+        match (select, self.bit) {
+            (0x00, _) => {
+                self.bit = Some(0);
+                self.packet = [0; PACKET_SIZE];
+            },
+            (0x30, _) | (_, None) => {},
+            (pulse, Some(PACKET_BITS)) => {
+                self.bit = None;
+                // stop bit must be 0
+                if pulse == 0x20 {
+                    self.receive_packet();
+                }
+            },
+            (pulse, Some(bit)) => {
+                if pulse == 0x10 {
+                    self.packet[bit / 8] |= 1 << (bit % 8);
+                }
+                self.bit = Some(bit + 1);
+            },
+        }
+    }
+
+    fn receive_packet(&mut self) {
+        if self.remaining == 0 {
+            self.pending.clear();
+            self.remaining = (self.packet[0] & 0x7).max(1);
+        }
+        self.pending.extend_from_slice(&self.packet);
+        self.remaining -= 1;
+        if self.remaining != 0 {
+            return;
+        }
+        let command = SgbCommand {
+            command: self.pending[0] >> 3,
+            data: std::mem::take(&mut self.pending),
+        };
+        info!("SGB command {} ({} packets)", command.name(), command.data.len() / PACKET_SIZE);
+        if self.commands.len() >= MAX_COMMANDS {
+            self.commands.pop_front();
+        }
+        self.commands.push_back(command);
+    }
+
+    /// Commands received, oldest first
+    pub fn commands(&self) -> &VecDeque<SgbCommand> {
+        &self.commands
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Device;
+    use crate::joypad::Joypad;
+
+    // write packet to P1 like a game, stop bit as given
+    fn send_packet(joypad: &mut Joypad, packet: &[u8; PACKET_SIZE], stop: u8) {
+        joypad.store(0xff00, 0x00).unwrap();
+        joypad.store(0xff00, 0x30).unwrap();
+        for bit in 0..PACKET_BITS {
+            let one = packet[bit / 8] & (1 << (bit % 8)) != 0;
+            joypad.store(0xff00, if one { 0x10 } else { 0x20 }).unwrap();
+            joypad.store(0xff00, 0x30).unwrap();
+        }
+        joypad.store(0xff00, stop).unwrap();
+        joypad.store(0xff00, 0x30).unwrap();
+    }
+
+    fn mlt_req(players: u8) -> [u8; PACKET_SIZE] {
+        let mut packet = [0; PACKET_SIZE];
+        packet[0] = MLT_REQ << 3 | 1;
+        packet[1] = players;
+        packet
+    }
+
+    #[test]
+    fn mlt_req_through_p1() {
+        let mut joypad = Joypad::new();
+        send_packet(&mut joypad, &mlt_req(0x01), 0x20);
+        let commands = joypad.sgb.commands();
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0].name(), "MLT_REQ");
+        assert_eq!(commands[0].data, mlt_req(0x01).to_vec());
+        assert_eq!(commands[0].players(), Some(2));
+    }
+
+    #[test]
+    fn packet_without_stop_bit_is_dropped() {
+        let mut joypad = Joypad::new();
+        send_packet(&mut joypad, &mlt_req(0x03), 0x10);
+        assert!(joypad.sgb.commands().is_empty());
+        send_packet(&mut joypad, &mlt_req(0x03), 0x20);
+        assert_eq!(joypad.sgb.commands()[0].players(), Some(4));
+    }
+}
@@ -0,0 +1,246 @@
+/*
+ * Run a directory of test ROMs headlessly and report the result of each.
+ * A ROM runs until a detector decides pass or fail, or it runs out of
+ * frames or real time. Every ROM runs in its own thread, so a panic only
+ * fails that ROM.
+ */
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::patch::crc32;
+use rugameboy::vm::Vm;
+
+/// mooneye tests run LD B,B with fibonacci numbers in registers on pass
+const MOONEYE_PASS: (u16, u16, u16) = (0x0305, 0x080d, 0x1522);
+const MOONEYE_FAIL: (u16, u16, u16) = (0x4242, 0x4242, 0x4242);
+/// file next to ROM with expected frame CRC in hex, named <rom>.crc
+const FRAME_CRC_EXTENSION: &str = "crc";
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum Outcome {
+    Passed,
+    Failed,
+    /// no detector decided before frames or time ran out
+    Timeout,
+    /// emulation stopped with error or panicked
+    Error(String),
+}
+
+impl Outcome {
+    fn name(&self) -> &str {
+        match self {
+            Outcome::Passed => "passed",
+            Outcome::Failed => "failed",
+            Outcome::Timeout => "timeout",
+            Outcome::Error(_) => "error",
+        }
+    }
+}
+
+/// Decide result of test ROM from the Vm, checked after every frame
+trait Detector {
+    fn name(&self) -> &'static str;
+    fn check(&mut self, vm: &mut Vm) -> Option<Outcome>;
+}
+
+/// blargg tests print Passed or Failed to serial port
+struct SerialText {
+    text: String,
+}
+
+impl Detector for SerialText {
+    fn name(&self) -> &'static str { "serial" }
+
+    fn check(&mut self, vm: &mut Vm) -> Option<Outcome> {
+        self.text.extend(vm.take_serial_output().into_iter().map(|byte| byte as char));
+        if self.text.contains("Passed") {
+            Some(Outcome::Passed)
+        } else if self.text.contains("Failed") {
+            Some(Outcome::Failed)
+        } else {
+            None
+        }
+    }
+}
+
+/// mooneye tests leave a register fingerprint and loop forever
+struct Registers;
+
+impl Detector for Registers {
+    fn name(&self) -> &'static str { "registers" }
+
+    fn check(&mut self, vm: &mut Vm) -> Option<Outcome> {
+        let entry = vm.cpu.trace_entry();
+        match (entry.bc, entry.de, entry.hl) {
+            MOONEYE_PASS => Some(Outcome::Passed),
+            MOONEYE_FAIL => Some(Outcome::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// screen matches a known good frame
+struct FrameCrc {
+    expected: u32,
+}
+
+fn frame_crc(buffer: &[u32]) -> u32 {
+    let bytes: Vec<u8> = buffer.iter().flat_map(|pixel| pixel.to_le_bytes().to_vec()).collect();
+    crc32(&bytes)
+}
+
+impl Detector for FrameCrc {
+    fn name(&self) -> &'static str { "frame-crc" }
+
+    fn check(&mut self, vm: &mut Vm) -> Option<Outcome> {
+        if frame_crc(&vm.buffer) == self.expected {
+            Some(Outcome::Passed)
+        } else {
+            None
+        }
+    }
+}
+
+fn detectors(rom: &Path) -> Vec<Box<dyn Detector>> {
+    let mut detectors: Vec<Box<dyn Detector>> = vec![
+        Box::new(SerialText { text: String::new() }),
+        Box::new(Registers),
+    ];
+    let expected = fs::read_to_string(rom.with_extension(FRAME_CRC_EXTENSION)).ok()
+                      .and_then(|text| u32::from_str_radix(text.trim(), 16).ok());
+    if let Some(expected) = expected {
+        detectors.push(Box::new(FrameCrc { expected }));
+    }
+    detectors
+}
+
+pub struct RomResult {
+    pub path: PathBuf,
+    pub outcome: Outcome,
+    /// detector deciding the outcome
+    pub detector: Option<&'static str>,
+    pub frames: u64,
+    pub elapsed: Duration,
+    /// CRC of the last frame, to be saved as expected frame
+    pub frame_crc: u32,
+}
+
+fn run_rom(path: &Path, frames: u64, timeout: Duration) -> RomResult {
+    let start = Instant::now();
+    let mut result = RomResult {
+        path: path.to_path_buf(),
+        outcome: Outcome::Timeout,
+        detector: None,
+        frames: 0,
+        elapsed: Duration::default(),
+        frame_crc: 0,
+    };
+    let binary = match fs::read(path) {
+        Ok(binary) => binary,
+        Err(e) => {
+            result.outcome = Outcome::Error(e.to_string());
+            return result;
+        }
+    };
+    let mut detectors = detectors(path);
+    let mut vm = Vm::new(binary);
+    vm.set_serial_capture(true);
+    'frames: while result.frames < frames && start.elapsed() < timeout {
+        if vm.run().is_err() {
+            result.outcome = Outcome::Error(String::from("emulation stopped"));
+            break;
+        }
+        vm.cpu.bus.apu.take_samples();
+        result.frames += 1;
+        for detector in detectors.iter_mut() {
+            if let Some(outcome) = detector.check(&mut vm) {
+                result.outcome = outcome;
+                result.detector = Some(detector.name());
+                break 'frames;
+            }
+        }
+    }
+    result.frame_crc = frame_crc(&vm.buffer);
+    result.elapsed = start.elapsed();
+    result
+}
+
+// ROM files under dir and its subdirectories
+fn find_roms(dir: &Path, roms: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_roms(&path, roms)?;
+        } else if path.extension().is_some_and(|ext| ext == "gb" || ext == "gbc") {
+            roms.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Run every ROM in dir for at most frames frames and timeout real time
+pub fn run(dir: &Path, frames: u64, timeout: Duration) -> io::Result<Vec<RomResult>> {
+    let mut roms = Vec::new();
+    find_roms(dir, &mut roms)?;
+    roms.sort();
+    let results = roms.into_iter().map(|path| {
+        let rom = path.clone();
+        thread::spawn(move || run_rom(&rom, frames, timeout)).join().unwrap_or_else(|_| RomResult {
+            path,
+            outcome: Outcome::Error(String::from("panic")),
+            detector: None,
+            frames: 0,
+            elapsed: Duration::default(),
+            frame_crc: 0,
+        })
+    }).collect();
+    Ok(results)
+}
+
+/// Human readable table, with a summary line
+pub fn print_table(results: &[RomResult]) {
+    for result in results {
+        let outcome = match &result.outcome {
+            Outcome::Error(e) => format!("error ({})", e),
+            outcome => String::from(outcome.name()),
+        };
+        println!("{:<8} {:<16} {:>6} frames {:>7.2}s {:08x}  {}",
+                 outcome, result.detector.unwrap_or("-"), result.frames,
+                 result.elapsed.as_secs_f64(), result.frame_crc, result.path.display());
+    }
+    let passed = results.iter().filter(|result| result.outcome == Outcome::Passed).count();
+    println!("{}/{} passed", passed, results.len());
+}
+
+fn json_string(text: &str) -> String {
+    let mut output = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            c if (c as u32) < 0x20 => output.push_str(&format!("\\u{:04x}", c as u32)),
+            c => output.push(c),
+        }
+    }
+    output.push('"');
+    output
+}
+
+/// Write results as JSON array, one object per ROM
+pub fn write_report(path: &Path, results: &[RomResult]) -> io::Result<()> {
+    let entries: Vec<String> = results.iter().map(|result| {
+        let error = match &result.outcome {
+            Outcome::Error(e) => json_string(e),
+            _ => String::from("null"),
+        };
+        format!("  {{\"rom\": {}, \"result\": \"{}\", \"error\": {}, \"detector\": {}, \
+                 \"frames\": {}, \"seconds\": {:.3}, \"frame_crc\": \"{:08x}\"}}",
+                json_string(&result.path.to_string_lossy()), result.outcome.name(), error,
+                result.detector.map_or(String::from("null"), json_string),
+                result.frames, result.elapsed.as_secs_f64(), result.frame_crc)
+    }).collect();
+    fs::write(path, format!("[\n{}\n]\n", entries.join(",\n")))
+}
@@ -0,0 +1,165 @@
+use crate::frontend::{Frontend, InputEvent, KeyboardLayout};
+use rugameboy::joypad::JoypadKey;
+use rugameboy::vm::{WIDTH, HEIGHT};
+
+use std::io::{stdout, Stdout, Write};
+use std::time::{Duration, Instant};
+
+use crossterm::{execute, queue};
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyModifiers};
+use crossterm::style::{Color, Print, ResetColor, SetBackgroundColor, SetForegroundColor};
+use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+
+/// terminal is slow, only draw a frame every FRAME_INTERVAL
+const FRAME_INTERVAL: Duration = Duration::from_millis(100);
+/// terminal has no key release event, release key not repeated in KEY_HOLD
+const KEY_HOLD: Duration = Duration::from_millis(150);
+
+/*
+ * Render framebuffer with ANSI half block characters,
+ * each character cell shows 2 pixels: upper one by foreground color
+ * and lower one by background color.
+ * Screen is downsampled when terminal is too small to hold it.
+ */
+pub struct TerminalFrontend {
+    stdout: Stdout,
+    last_frame: Instant,
+    /// key pressed and the last time it is reported
+    held: Vec<(KeyCode, Instant)>,
+    layout: KeyboardLayout,
+    close: bool,
+}
+
+impl TerminalFrontend {
+    pub fn new(layout: KeyboardLayout) -> crossterm::Result<Self> {
+        let mut stdout = stdout();
+        terminal::enable_raw_mode()?;
+        execute!(stdout, EnterAlternateScreen, Hide, Clear(ClearType::All))?;
+        Ok(Self {
+            stdout,
+            last_frame: Instant::now() - FRAME_INTERVAL,
+            held: Vec::new(),
+            layout,
+            close: false,
+        })
+    }
+
+    fn map_key(key: KeyCode) -> Option<JoypadKey> {
+        match key {
+            KeyCode::Up    | KeyCode::Char('w') => Some(JoypadKey::UP),
+            KeyCode::Down  | KeyCode::Char('s') => Some(JoypadKey::DOWN),
+            KeyCode::Left  | KeyCode::Char('a') => Some(JoypadKey::LEFT),
+            KeyCode::Right | KeyCode::Char('d') => Some(JoypadKey::RIGHT),
+            KeyCode::Enter     => Some(JoypadKey::START),
+            KeyCode::Char(' ') => Some(JoypadKey::SELECT),
+            KeyCode::Char('z') => Some(JoypadKey::A),
+            KeyCode::Char('x') => Some(JoypadKey::B),
+            _ => None,
+        }
+    }
+
+    fn rgb(pixel: u32) -> Color {
+        Color::Rgb {
+            r: (pixel >> 16) as u8,
+            g: (pixel >> 8) as u8,
+            b: pixel as u8,
+        }
+    }
+
+    fn draw(&mut self, buffer: &[u32]) -> crossterm::Result<()> {
+        let (cols, rows) = terminal::size()?;
+        let (cols, rows) = (cols.max(1) as usize, rows.max(1) as usize);
+        // smallest downsample factor that fits the terminal
+        let factor = WIDTH.div_ceil(cols).max((HEIGHT / 2).div_ceil(rows)).max(1);
+
+        for row in 0..(HEIGHT / 2 / factor) {
+            queue!(self.stdout, MoveTo(0, row as u16))?;
+            let upper = row * 2 * factor;
+            let lower = upper + factor;
+            for col in 0..(WIDTH / factor) {
+                let x = col * factor;
+                queue!(self.stdout,
+                       SetForegroundColor(Self::rgb(buffer[upper * WIDTH + x])),
+                       SetBackgroundColor(Self::rgb(buffer[lower * WIDTH + x])),
+                       Print('▀'))?;
+            }
+            queue!(self.stdout, ResetColor)?;
+        }
+        self.stdout.flush()?;
+        Ok(())
+    }
+}
+
+impl Frontend for TerminalFrontend {
+    fn present_frame(&mut self, buffer: &[u32]) {
+        if self.last_frame.elapsed() < FRAME_INTERVAL {
+            return;
+        }
+        self.last_frame = Instant::now();
+        if self.draw(buffer).is_err() {
+            self.close = true;
+        }
+    }
+
+    fn poll_input(&mut self) -> Vec<InputEvent> {
+        let mut events = Vec::new();
+
+        while let Ok(true) = event::poll(Duration::from_millis(0)) {
+            let code = match event::read() {
+                Ok(Event::Key(KeyEvent { code, modifiers })) => {
+                    if code == KeyCode::Char('c') && modifiers.contains(KeyModifiers::CONTROL) {
+                        self.close = true;
+                        continue;
+                    }
+                    match code {
+                        KeyCode::Char(letter) => KeyCode::Char(self.layout.to_qwerty(letter)),
+                        _ => code,
+                    }
+                }
+                Ok(_) => continue,
+                Err(_) => {
+                    self.close = true;
+                    break;
+                }
+            };
+            match code {
+                KeyCode::Char('q') => self.close = true,
+                KeyCode::Esc => events.push(InputEvent::Menu),
+                KeyCode::Char('p') => events.push(InputEvent::Pause),
+                KeyCode::Char('.') => events.push(InputEvent::FrameAdvance),
+                _ => if let Some(key) = Self::map_key(code) {
+                    match self.held.iter_mut().find(|(held, _)| *held == code) {
+                        Some((_, time)) => *time = Instant::now(),
+                        None => {
+                            self.held.push((code, Instant::now()));
+                            events.push(InputEvent::Press(key));
+                        }
+                    }
+                },
+            }
+        }
+
+        // release keys not repeated by terminal recently
+        let (released, held) = self.held.drain(..)
+                                   .partition(|(_, time)| time.elapsed() >= KEY_HOLD);
+        self.held = held;
+        for (code, _) in released.into_iter() {
+            if let Some(key) = Self::map_key(code) {
+                events.push(InputEvent::Release(key));
+            }
+        }
+        events
+    }
+
+    fn should_close(&self) -> bool {
+        self.close
+    }
+}
+
+impl Drop for TerminalFrontend {
+    fn drop(&mut self) {
+        execute!(self.stdout, ResetColor, Show, LeaveAlternateScreen).unwrap_or(());
+        terminal::disable_raw_mode().unwrap_or(());
+    }
+}
@@ -0,0 +1,219 @@
+/*
+ * Set up GPU state directly for rendering tests, without a ROM drawing
+ * the scene. Tiles, maps and sprites are written to a fresh Gpu, then one
+ * frame is run through the GPU alone and the framebuffer is returned.
+ *
+ * A smoke test ROM is built in, so gross regressions of CPU, bus, GPU and
+ * timer show up without any external ROM file.
+ */
+use crate::asm::Asm;
+use crate::bus::Device;
+use crate::gpu::{Gpu, GpuMode, OAM_START, VRAM_START};
+use crate::instruction::{Condition, Instruction, Target};
+use crate::vm::{WIDTH, HEIGHT};
+
+/// clocks GPU is run at a time, one machine cycle
+const STEP_CLOCK: u64 = 4;
+
+/// HRAM counters of VBlank and timer interrupts of smoke ROM
+const SMOKE_VBLANK_COUNT: u16 = 0xff80;
+const SMOKE_TIMER_COUNT: u16 = 0xff81;
+/// WRAM byte holding 0x12 + 0x34 computed by smoke ROM
+const SMOKE_SUM: u16 = 0xc000;
+
+pub struct Scene {
+    /// registers like lcdc, scx and palettes are set on gpu directly
+    pub gpu: Gpu,
+}
+
+impl Default for Scene {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scene {
+    /// Gpu after power on: LCD, background and tile data 0x8000 enabled,
+    /// VRAM and OAM cleared
+    pub fn new() -> Self {
+        Self { gpu: Gpu::new() }
+    }
+
+    /// Write bytes to VRAM from addr, 0x8000-0x9FFF
+    pub fn set_vram(&mut self, addr: u16, bytes: &[u8]) {
+        for (offset, &byte) in bytes.iter().enumerate() {
+            let addr = addr + offset as u16;
+            assert!(self.gpu.store(addr, byte).is_ok(), "{:#X} is not VRAM", addr);
+        }
+    }
+
+    /// Encode tile of 8x8 color indices at 0x8000 + index * 16
+    pub fn set_tile(&mut self, index: usize, pixels: [[u8; 8]; 8]) {
+        let mut bytes = [0; 16];
+        for (line, row) in pixels.iter().enumerate() {
+            for (x, &pixel) in row.iter().enumerate() {
+                bytes[line * 2] |= (pixel & 0x1) << (7 - x);
+                bytes[line * 2 + 1] |= ((pixel >> 1) & 0x1) << (7 - x);
+            }
+        }
+        self.set_vram(VRAM_START + index as u16 * 16, &bytes);
+    }
+
+    /// Write OAM entry idx with screen position as stored in OAM,
+    /// x is offset by 8 and y by 16
+    pub fn set_sprite(&mut self, idx: usize, y: u8, x: u8, tile: u8, flags: u8) {
+        let addr = OAM_START + idx as u16 * 4;
+        for (offset, &byte) in [y, x, tile, flags].iter().enumerate() {
+            assert!(self.gpu.store(addr + offset as u16, byte).is_ok(), "sprite {} out of OAM", idx);
+        }
+    }
+
+    /// Run GPU from line 0 to VBlank and build the frame, WIDTH x HEIGHT
+    pub fn render(&mut self) -> Vec<u32> {
+        while self.gpu.mode != GpuMode::VBlank {
+            self.gpu.update(STEP_CLOCK);
+        }
+        let mut buffer = vec![0; WIDTH * HEIGHT];
+        self.gpu.build_screen(&mut buffer);
+        buffer
+    }
+}
+
+// handler counting interrupts in HRAM byte at 0xFF00 + counter
+fn count_handler(counter: u16) -> Asm {
+    Asm::new()
+        .inst(Instruction::PUSH(Target::AF), &[])
+        .ldh_a_a8(counter as u8)
+        .inst(Instruction::INC8(Target::A), &[])
+        .ldh_a8_a(counter as u8)
+        .inst(Instruction::POP(Target::AF), &[])
+        .inst(Instruction::RETI, &[])
+}
+
+/// ROM drawing a tile on background and as sprite, counting VBlank and
+/// timer interrupts, with a little arithmetic and a call
+pub fn smoke_rom() -> Vec<u8> {
+    let main = Asm::new()
+        .di()
+        .ld_d16(Target::SP, 0xfffe)
+        // tile 1 is solid black
+        .ld_d16(Target::HL, VRAM_START + 16)
+        .ld_d8(Target::B, 16)
+        .ld_a_d8(0xff)
+        .inst(Instruction::LDRR(Target::A, Target::HLINC), &[])
+        .inst(Instruction::DEC8(Target::B), &[])
+        .jr_if(Condition::NotZero, -4)
+        // background tile at (3, 2), sprite 0 at (60, 40)
+        .ld_d16(Target::HL, 0x9800 + 2 * 32 + 3)
+        .ld_d8(Target::HL, 1)
+        .ld_d16(Target::HL, OAM_START)
+        .ld_a_d8(16 + 40)
+        .inst(Instruction::LDRR(Target::A, Target::HLINC), &[])
+        .ld_a_d8(8 + 60)
+        .inst(Instruction::LDRR(Target::A, Target::HLINC), &[])
+        .ld_a_d8(1)
+        .inst(Instruction::LDRR(Target::A, Target::HLINC), &[])
+        .inst(Instruction::XOR(Target::A), &[])
+        .inst(Instruction::LDRR(Target::A, Target::HL), &[])
+        // LCD, background and sprites on
+        .ld_a_d8(0x93)
+        .ldh_a8_a(0x40)
+        // 0x12 + 0x34 to WRAM, B set by subroutine
+        .ld_a_d8(0x12)
+        .inst(Instruction::ADD(Target::D8), &[0x34])
+        .inst(Instruction::LD16A, &SMOKE_SUM.to_le_bytes())
+        .call(0x0200)
+        // timer at 262144 Hz, VBlank and timer interrupts
+        .ld_a_d8(0x05)
+        .ldh_a8_a(0x07)
+        .ldh_a8_a(0xff)
+        .inst(Instruction::XOR(Target::A), &[])
+        .ldh_a8_a(0x0f)
+        .ldh_a8_a(SMOKE_VBLANK_COUNT as u8)
+        .ldh_a8_a(SMOKE_TIMER_COUNT as u8)
+        .ei()
+        .halt()
+        .jr(-3)
+        .build();
+    let subroutine = Asm::new().ld_d8(Target::B, 0x99).ret().build();
+
+    let mut rom = vec![0; 0x8000];
+    let mut place = |addr: usize, bytes: &[u8]| rom[addr..addr + bytes.len()].copy_from_slice(bytes);
+    place(0x40, &count_handler(SMOKE_VBLANK_COUNT).build());
+    place(0x50, &count_handler(SMOKE_TIMER_COUNT).build());
+    place(0x100, &Asm::new().nop().jp(0x150).build());
+    place(0x150, &main);
+    place(0x200, &subroutine);
+    rom
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::Vm;
+
+    /// frames smoke_test runs
+    const SMOKE_FRAMES: u64 = 10;
+    /// hash of the last frame, a block of background and a sprite
+    const SMOKE_FRAME_HASH: u32 = 0xb04501c5;
+
+    // FNV-1a of pixels, stable across platforms
+    fn frame_hash(buffer: &[u32]) -> u32 {
+        buffer.iter().flat_map(|pixel| pixel.to_le_bytes()).fold(0x811c9dc5, |hash, byte| {
+            (hash ^ byte as u32).wrapping_mul(0x01000193)
+        })
+    }
+
+    /// Run smoke_rom for a few frames and check the state it leaves,
+    /// return the first mismatch
+    fn smoke_test() -> Result<(), String> {
+        let mut vm = Vm::new(smoke_rom());
+        for frame in 0..SMOKE_FRAMES {
+            vm.run().map_err(|_| format!("emulation stopped in frame {}", frame))?;
+        }
+        let check = |name: &str, actual: u32, expected: u32| {
+            if actual == expected {
+                Ok(())
+            } else {
+                Err(format!("{}: {:#x}, expected {:#x}", name, actual, expected))
+            }
+        };
+        check("VBlank interrupts", vm.peek(SMOKE_VBLANK_COUNT) as u32, SMOKE_FRAMES as u32)?;
+        if vm.peek(SMOKE_TIMER_COUNT) == 0 {
+            return Err(String::from("timer interrupt never serviced"));
+        }
+        check("sum", vm.peek(SMOKE_SUM) as u32, 0x46)?;
+        check("B", (vm.cpu.trace_entry().bc >> 8) as u32, 0x99)?;
+        check("frame hash", frame_hash(&vm.buffer), SMOKE_FRAME_HASH)
+    }
+
+    #[test]
+    fn smoke_rom_passes() {
+        assert_eq!(smoke_test(), Ok(()));
+    }
+
+    #[test]
+    fn scene_composes_tile_and_sprite() {
+        use crate::gpu::{Palette, LCDC};
+        let mut scene = Scene::new();
+        scene.gpu.lcdc = LCDC::from_u8(0x93);
+        scene.gpu.set_palette(Palette::Obj0, 0xe4);
+        // background tile 1 solid color 1 at map (1, 1)
+        scene.set_tile(1, [[1; 8]; 8]);
+        scene.set_vram(0x9800 + 32 + 1, &[1]);
+        // sprite tile 2 color 3 on the left half, transparent on the right
+        scene.set_tile(2, [[3, 3, 3, 3, 0, 0, 0, 0]; 8]);
+        scene.set_sprite(0, 16 + 10, 8 + 12, 2, 0);
+        let buffer = scene.render();
+        let bg = scene.gpu.palette_colors(Palette::Bg);
+        let obj = scene.gpu.palette_colors(Palette::Obj0);
+        let pixel = |x: usize, y: usize| buffer[y * WIDTH + x];
+        assert_eq!(pixel(0, 0), bg[0]);
+        assert_eq!(pixel(8, 8), bg[1]);
+        assert_eq!(pixel(12, 10), obj[3]);
+        assert_eq!(pixel(15, 17), obj[3]);
+        // transparent sprite pixels show background
+        assert_eq!(pixel(16, 10), bg[0]);
+        assert_eq!(pixel(12, 18), bg[0]);
+    }
+}
@@ -4,24 +4,35 @@ use std::default::Default;
 pub const TIMER_START: u16 = 0xff04;
 pub const TIMER_END: u16 = 0xff07;
 
+#[derive(Clone,Default)]
 enum TimerScale {
+    #[default]
     X1  = 0b00, // freq 4096
     X4  = 0b11, // freq 16384
     X16 = 0b10, // freq 65536
     X64 = 0b01, // freq 262144
 }
 
-impl Default for TimerScale {
-    fn default() -> Self { TimerScale::X1 }
-}
-
-#[derive(Default)]
+#[derive(Default,Clone)]
 pub struct TimerControl {
     scale: TimerScale,
     running: bool,
 }
 
-#[derive(Default)]
+/// Registers and internal counters of timer, to start tests from a known state
+#[derive(Debug,Default,Clone,Copy,PartialEq)]
+pub struct TimerState {
+    pub div: u8,
+    pub tima: u8,
+    pub tma: u8,
+    pub tac: u8,
+    /// clocks since div was incremented, less than 256
+    pub div_counter: u64,
+    /// clocks since tima was incremented, less than period set by tac
+    pub timer_counter: u64,
+}
+
+#[derive(Default,Clone)]
 pub struct Timer {
     /// ff04 div, incremented 16384 times a second
     div: u8,
@@ -44,6 +55,8 @@ pub struct Timer {
     div_counter: u64,
     timer_counter: u64,
     roundvalue: u64,
+    /// tima overflowed in last machine cycle, tma is loaded in this cycle
+    reload_pending: bool,
     pub is_interrupt: bool,
 }
 
@@ -56,6 +69,28 @@ impl Timer {
         self.is_interrupt
     }
 
+    pub fn state(&self) -> TimerState {
+        TimerState {
+            div: self.div,
+            tima: self.tima,
+            tma: self.tma,
+            tac: self.load(0xFF07).unwrap_or(0),
+            div_counter: self.div_counter,
+            timer_counter: self.timer_counter,
+        }
+    }
+
+    /// Replace timer state, pending reload of tima is dropped
+    pub fn set_state(&mut self, state: TimerState) {
+        self.store(0xFF07, state.tac).unwrap_or(());
+        self.div = state.div;
+        self.tima = state.tima;
+        self.tma = state.tma;
+        self.div_counter = state.div_counter;
+        self.timer_counter = state.timer_counter;
+        self.reload_pending = false;
+    }
+
     pub fn update(&mut self, clock: u64) {
         // handle div
         // div has a constant update rate: 16384 Hz
@@ -66,16 +101,27 @@ impl Timer {
             self.div = self.div.wrapping_add(1);
         }
 
-        // handle tac
-        if self.tac.running {
-            self.timer_counter += clock;
-            if self.timer_counter >= self.roundvalue {
-                self.timer_counter -= self.roundvalue;
+        // handle tac, one machine cycle (4 clocks) at a time
+        // since overflow is handled in the cycle after it
+        let mut clock = clock;
+        while clock > 0 {
+            let cycle = clock.min(4);
+            clock -= cycle;
+
+            // tima reads 0 for one cycle after overflow, then loads tma
+            if self.reload_pending {
+                self.reload_pending = false;
+                self.tima = self.tma;
+                self.is_interrupt = true;
+            }
 
-                if self.tma == 0xff {
-                    self.tma = self.tima;
-                } else {
-                    self.tma = self.tma.wrapping_add(1);
+            if self.tac.running {
+                self.timer_counter += cycle;
+                if self.timer_counter >= self.roundvalue {
+                    self.timer_counter -= self.roundvalue;
+                    let (tima, overflow) = self.tima.overflowing_add(1);
+                    self.tima = tima;
+                    self.reload_pending = overflow;
                 }
             }
         }
@@ -89,6 +135,8 @@ impl Device for Timer {
             0xFF05 => Ok(self.tima),
             0xFF06 => Ok(self.tma),
             0xFF07 => Ok({
+                // upper 5 bits are unused and read 1
+                0xf8 |
                 ( if self.tac.running { 1 << 2 } else { 0 } ) |
                 ( match self.tac.scale {
                     TimerScale::X1  => 0b00,
@@ -104,7 +152,11 @@ impl Device for Timer {
     fn store(&mut self, addr: u16, value: u8) -> Result<(), ()> {
         match addr {
             0xFF04 => self.div = 0,
-            0xFF05 => self.tima = value,
+            0xFF05 => {
+                // write in the cycle after overflow cancels reload and interrupt
+                self.tima = value;
+                self.reload_pending = false;
+            },
             0xFF06 => self.tma = value,
             0xFF07 => {
                 self.tac.running = (value & 0x4) != 0;
@@ -129,3 +181,36 @@ impl Device for Timer {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // timer running at 16 clocks per tick, one tick before overflow
+    fn overflowing_timer() -> Timer {
+        let mut timer = Timer::new();
+        timer.set_state(TimerState { tima: 0xff, tma: 0x42, tac: 0x05, ..Default::default() });
+        timer
+    }
+
+    #[test]
+    fn tima_reads_zero_one_cycle_before_reload() {
+        let mut timer = overflowing_timer();
+        timer.update(16);
+        assert_eq!(timer.load(0xFF05), Ok(0x00));
+        assert!(!timer.is_interrupt());
+        timer.update(4);
+        assert_eq!(timer.load(0xFF05), Ok(0x42));
+        assert!(timer.is_interrupt());
+    }
+
+    #[test]
+    fn tima_write_in_delay_cancels_reload() {
+        let mut timer = overflowing_timer();
+        timer.update(16);
+        timer.store(0xFF05, 0x10).unwrap();
+        timer.update(4);
+        assert_eq!(timer.load(0xFF05), Ok(0x10));
+        assert!(!timer.is_interrupt());
+    }
+}
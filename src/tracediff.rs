@@ -0,0 +1,78 @@
+/*
+ * Differential testing against another emulator: its per-instruction trace
+ * in gameboy doctor format, the same as --trace writes, is compared with our
+ * state before every instruction. The first divergence stops the run with
+ * both states, recent instructions and memory around the pointers. The
+ * trace is streamed line by line, so traces of gigabytes are fine.
+ */
+use std::io::{self, BufRead};
+
+use rugameboy::vm::Vm;
+
+/// instructions shown before the divergence
+const DIFF_HISTORY: usize = 32;
+/// bytes of memory shown from each pointer
+const DIFF_MEMORY: u16 = 8;
+
+// fields of trace line, like A:01 or PC:0100
+fn fields(line: &str) -> Vec<(&str, &str)> {
+    line.split_whitespace().filter_map(|field| {
+        let mut parts = field.splitn(2, ':');
+        Some((parts.next()?, parts.next()?))
+    }).collect()
+}
+
+fn report(vm: &Vm, count: u64, ours: &str, theirs: &str) -> String {
+    let mut report = format!("diverged before instruction {} (line {} of trace)\n  ours:   {}\n  theirs: {}\n",
+                             count, count + 1, ours, theirs);
+    let theirs_fields = fields(theirs);
+    let differ: Vec<String> = fields(ours).into_iter()
+        .filter_map(|(name, value)| {
+            let other = theirs_fields.iter().find(|&&(other, _)| other == name).map_or("-", |&(_, value)| value);
+            if value != other { Some(format!("{} {} != {}", name, value, other)) } else { None }
+        })
+        .collect();
+    report.push_str(&format!("  differ: {}\n", differ.join(", ")));
+
+    report.push_str("Recent instructions, oldest first:\n");
+    for entry in vm.recent_instructions(DIFF_HISTORY) {
+        report.push_str(&format!("  {}\n", entry));
+    }
+
+    report.push_str("Memory:\n");
+    let entry = vm.cpu.trace_entry();
+    for &(name, addr) in [("PC", entry.pc), ("SP", entry.sp), ("HL", entry.hl)].iter() {
+        let bytes: Vec<String> = (0..DIFF_MEMORY)
+            .map(|offset| format!("{:02X}", vm.peek(addr.wrapping_add(offset))))
+            .collect();
+        report.push_str(&format!("  {} {:04X}: {}\n", name, addr, bytes.join(" ")));
+    }
+    report
+}
+
+/// Step vm along reference trace. Return number of instructions matched when
+/// the trace ends, or report of the first divergence.
+pub fn run<R: BufRead>(vm: &mut Vm, reference: R) -> io::Result<Result<u64, String>> {
+    // snapshots for reverse step cost time and are not needed here
+    vm.set_snapshot_interval(u64::MAX);
+    vm.set_crash_history(true);
+    let mut ours = String::new();
+    let mut count = 0;
+    for line in reference.lines() {
+        let line = line?;
+        let theirs = line.trim();
+        if theirs.is_empty() {
+            continue;
+        }
+        ours.clear();
+        vm.cpu.write_trace(&mut ours);
+        if ours != theirs {
+            return Ok(Err(report(vm, count, &ours, theirs)));
+        }
+        if vm.step().is_err() {
+            return Ok(Err(format!("emulation stopped at instruction {}\n{}", count, vm.crash_report())));
+        }
+        count += 1;
+    }
+    Ok(Ok(count))
+}
@@ -1,32 +1,788 @@
-use crate::cpu::Cpu;
-use crate::gpu::GpuMode;
-use log::{debug};
+use crate::cpu::{Cpu, TraceEntry, UnknownOpcode};
+use crate::bus::{AccessCount, Interrupt, InterruptFlag, SerialLink};
+use crate::cartridge::Mapper;
+use crate::mbc::BankSwitch;
+use crate::gpu::{GpuMode, GRAYSCALE, OAM_SIZE};
+use crate::instruction::{Instruction, CBInstruction};
+use crate::joypad::{JoypadKey, JOYPAD_KEYS};
+use crate::lockup::{Lockup, LockupWatch};
+use crate::memory::RamInit;
+use crate::timer::TimerState;
+use log::{debug, error, info};
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::io::Write;
+use std::time::{Duration, Instant};
 
 pub const WIDTH: usize = 160;
 pub const HEIGHT: usize = 144;
+/// clock rate of the CPU in Hz
+pub const CLOCK_RATE: u64 = 4_194_304;
+/// clock of one frame, 154 lines of 456 clocks
+pub const CYCLES_PER_FRAME: u64 = 70224;
+
+/// default number of instructions between two snapshots for reverse step
+const SNAPSHOT_INTERVAL: u64 = 1000;
+/// maximum snapshots kept, the oldest one is dropped
+const MAX_SNAPSHOTS: usize = 64;
+/// default frames of turbo button pressed or released
+const TURBO_PERIOD: u64 = 2;
+/// instructions kept for crash report
+pub const HISTORY_SIZE: usize = 64;
+/// PCs of a spin loop lie within this many bytes
+const TIGHT_LOOP_SPAN: u16 = 16;
+/// IO registers compared by diff, LCD, timer and interrupt state
+const DIFF_IO: [(&str, u16); 17] = [
+    ("P1", 0xff00), ("DIV", 0xff04), ("TIMA", 0xff05), ("TMA", 0xff06), ("TAC", 0xff07),
+    ("IF", 0xff0f), ("LCDC", 0xff40), ("STAT", 0xff41), ("SCY", 0xff42), ("SCX", 0xff43),
+    ("LY", 0xff44), ("LYC", 0xff45), ("BGP", 0xff47), ("OBP0", 0xff48), ("OBP1", 0xff49),
+    ("WY", 0xff4a), ("IE", 0xffff),
+];
+/// memory regions compared by diff, only the first differing byte is reported
+const DIFF_REGIONS: [(&str, u16, u16); 5] = [
+    ("VRAM", 0x8000, 0x9fff),
+    ("cartridge RAM", 0xa000, 0xbfff),
+    ("WRAM", 0xc000, 0xdfff),
+    ("OAM", 0xfe00, 0xfe9f),
+    ("HRAM", 0xff80, 0xfffe),
+];
+
+/// Callback given the framebuffer at VBlank, None for a skipped frame
+pub type FrameCallback = Box<dyn FnMut(Option<&[u32]>)>;
+
+/// Why run_until_break stops before the end of frame
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum StopReason {
+    /// pc reaches a breakpoint
+    Breakpoint(u16),
+    /// interrupt is serviced, pc is at its handler, pc is the interrupted one
+    Interrupt { source: Interrupt, pc: u16 },
+    /// write of instruction at pc changed the banks of MBC
+    BankSwitch { switch: BankSwitch, pc: u16 },
+}
+
+/// Work done to emulate one frame
+#[derive(Debug,Default,Clone,Copy,PartialEq)]
+pub struct FrameStats {
+    /// instructions executed, a machine cycle in HALT counts as one
+    pub instructions: u64,
+    pub cycles: u64,
+    /// real time spent in emulation, not including time stopped by breakpoint
+    pub elapsed: Duration,
+}
+
+/// Difference between two Vms, values of self first
+#[derive(Debug,Clone,PartialEq)]
+pub enum StateDiff {
+    /// CPU register or IME, named as in trace
+    Register { name: &'static str, left: u16, right: u16 },
+    /// cycles run since power on
+    Cycles { left: u64, right: u64 },
+    Io { name: &'static str, left: u8, right: u8 },
+    /// first differing byte of a memory region
+    Memory { region: &'static str, addr: u16, left: u8, right: u8 },
+}
+
+impl fmt::Display for StateDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateDiff::Register { name, left, right } =>
+                write!(f, "{}: {:04X} != {:04X}", name, left, right),
+            StateDiff::Cycles { left, right } =>
+                write!(f, "cycles: {} != {}", left, right),
+            StateDiff::Io { name, left, right } =>
+                write!(f, "{}: {:02X} != {:02X}", name, left, right),
+            StateDiff::Memory { region, addr, left, right } =>
+                write!(f, "{} at {:04X}: {:02X} != {:02X}", region, addr, left, right),
+        }
+    }
+}
+
+/// Options of Vm applied at construction
+#[derive(Debug,Clone)]
+pub struct VmConfig {
+    /// power-on content of RAM
+    pub ram_init: RamInit,
+    /// force CGB mode on or off, None follows cartridge header
+    pub cgb_mode: Option<bool>,
+    /// force mapper, None follows cartridge header
+    pub mapper: Option<Mapper>,
+    /// tick peripherals on every memory access
+    pub accurate_timing: bool,
+    /// stop on stack access outside of RAM
+    pub stack_check: bool,
+    /// what to do on opcode not implemented
+    pub unknown_opcode: UnknownOpcode,
+    /// frames turbo button is kept pressed or released
+    pub turbo_period: u64,
+    /// allow pressing opposite directions together
+    pub allow_impossible_inputs: bool,
+    /// instructions between snapshots for reverse step
+    pub snapshot_interval: u64,
+    /// keep recent instructions for crash report, costs a little speed
+    pub crash_history: bool,
+    /// screen colors of shade 0 to 3, lightest first
+    pub colors: [u32; 4],
+    /// frames not built after each built frame, to save time
+    pub frame_skip: u64,
+}
+
+impl Default for VmConfig {
+    fn default() -> Self {
+        Self {
+            ram_init: RamInit::Zero,
+            cgb_mode: None,
+            mapper: None,
+            accurate_timing: false,
+            stack_check: false,
+            unknown_opcode: UnknownOpcode::Error,
+            turbo_period: TURBO_PERIOD,
+            allow_impossible_inputs: false,
+            snapshot_interval: SNAPSHOT_INTERVAL,
+            crash_history: true,
+            colors: GRAYSCALE,
+            frame_skip: 0,
+        }
+    }
+}
 
 pub struct Vm {
     pub cpu: Cpu,
     pub buffer: Vec<u32>,
+    /// button bitmask set by frontend, applied at start of frame
+    buttons: u8,
+    /// button bitmask applied to joypad
+    applied_buttons: u8,
+    /// button bitmask in turbo mode, toggled every turbo_period frames
+    turbo: u8,
+    turbo_period: u64,
+    /// number of frames run
+    frame_count: u64,
+    /// cpu cycles at which current frame ends
+    frame_end: u64,
+    /// stats of current frame being collected, cycles counted from its start
+    frame_stats: FrameStats,
+    last_frame_stats: FrameStats,
+    /// called once per frame with the completed framebuffer
+    frame_callback: Option<FrameCallback>,
+    frame_skip: u64,
+    /// frames still to skip before the next one is built
+    skip_left: u64,
+    /// number of instructions executed through step
+    step_count: u64,
+    /// keep snapshots for rstep, off unless a debugger uses it
+    reverse_step: bool,
+    /// take a snapshot every snapshot_interval instructions
+    snapshot_interval: u64,
+    /// snapshots of cpu taken while stepping, with its step_count
+    snapshots: VecDeque<(u64, Cpu)>,
+    /// write one line per instruction for log comparison
+    trace_log: Option<Box<dyn Write>>,
+    /// recently executed instructions, None if disabled
+    history: Option<VecDeque<TraceEntry>>,
+    /// addresses run_until_break stops at
+    breakpoints: HashSet<u16>,
+    /// mask of interrupt sources run_until_break stops at, bits as IF
+    interrupt_breaks: u8,
+    /// run_until_break stops after MBC bank switch
+    bank_break: bool,
+    /// bank switch by the last instruction
+    bank_switch: Option<BankSwitch>,
+    /// clocks spent in instructions at each pc, None if not profiling
+    profile: Option<Vec<u64>>,
+    /// cpu right after construction, restored by reset
+    power_on: Option<Box<Cpu>>,
+    lockup_watch: LockupWatch,
+    /// lock-up found and not yet taken
+    lockup: Option<Lockup>,
 }
 
 impl Vm {
     pub fn new(binary: Vec<u8>) -> Self {
-        Self {
+        Vm::with_config(binary, VmConfig::default())
+    }
+
+    pub fn with_config(binary: Vec<u8>, config: VmConfig) -> Self {
+        let mut vm = Self {
             cpu: Cpu::new(binary),
             buffer: vec![0; WIDTH * HEIGHT],
+            buttons: 0,
+            applied_buttons: 0,
+            turbo: 0,
+            turbo_period: TURBO_PERIOD,
+            frame_count: 0,
+            frame_end: 0,
+            frame_stats: Default::default(),
+            last_frame_stats: Default::default(),
+            frame_callback: None,
+            frame_skip: 0,
+            skip_left: 0,
+            step_count: 0,
+            reverse_step: false,
+            snapshot_interval: SNAPSHOT_INTERVAL,
+            snapshots: VecDeque::new(),
+            trace_log: None,
+            history: None,
+            breakpoints: HashSet::new(),
+            interrupt_breaks: 0,
+            bank_break: false,
+            bank_switch: None,
+            profile: None,
+            power_on: None,
+            lockup_watch: Default::default(),
+            lockup: None,
+        };
+        if let Some(cgb_mode) = config.cgb_mode {
+            vm.cpu.set_cgb_mode(cgb_mode);
+        }
+        if let Some(mapper) = config.mapper {
+            vm.cpu.bus.set_mapper(mapper);
+        }
+        vm.cpu.bus.init_ram(config.ram_init);
+        vm.set_accurate_timing(config.accurate_timing);
+        vm.set_stack_check(config.stack_check);
+        vm.set_unknown_opcode(config.unknown_opcode);
+        vm.set_turbo_period(config.turbo_period);
+        vm.set_allow_impossible_inputs(config.allow_impossible_inputs);
+        vm.set_snapshot_interval(config.snapshot_interval);
+        vm.set_crash_history(config.crash_history);
+        vm.set_frame_skip(config.frame_skip);
+        vm.cpu.bus.gpu.set_colors(config.colors);
+        vm.power_on = Some(Box::new(vm.cpu.clone()));
+        vm
+    }
+
+    /// Restart cartridge from power on, settings of Vm are kept
+    pub fn reset(&mut self) {
+        if let Some(cpu) = self.power_on.as_ref() {
+            // diagnostics enabled after construction are kept
+            let access_count = self.cpu.bus.access_count.take();
+            let capture_serial = self.cpu.bus.capture_serial;
+            let serial_link = self.cpu.bus.serial_link.take();
+            // cartridge RAM is on the cartridge, reset does not clear it
+            let cartridge_ram = self.cpu.bus.cartridge_ram().map(|ram| ram.to_vec());
+            self.cpu = (**cpu).clone();
+            if let (Some(saved), Some(ram)) = (cartridge_ram, self.cpu.bus.cartridge_ram_mut()) {
+                ram.copy_from_slice(&saved);
+            }
+            self.cpu.bus.access_count = access_count;
+            self.cpu.bus.capture_serial = capture_serial;
+            self.cpu.bus.serial_link = serial_link;
+        }
+        self.frame_count = 0;
+        self.frame_end = self.cpu.cycles();
+        self.frame_stats = Default::default();
+        self.step_count = 0;
+        self.snapshots.clear();
+        if let Some(history) = self.history.as_mut() {
+            history.clear();
+        }
+        self.lockup_watch = Default::default();
+        self.lockup = None;
+    }
+
+    /// Press or release single button, applied at start of next frame
+    pub fn set_button(&mut self, key: JoypadKey, pressed: bool) {
+        if pressed {
+            self.buttons |= key.mask();
+        } else {
+            self.buttons &= !key.mask();
         }
     }
 
+    /// Set all buttons by bitmask, bit 0 to 7 are
+    /// Right, Left, Up, Down, A, B, Select, Start. 1 means pressed.
+    pub fn set_buttons(&mut self, buttons: u8) {
+        self.buttons = buttons;
+    }
+
+    /// Turn on or off turbo of button, which is pressed and released alternately
+    pub fn set_turbo(&mut self, key: JoypadKey, enable: bool) {
+        if enable {
+            self.turbo |= key.mask();
+        } else {
+            self.turbo &= !key.mask();
+        }
+    }
+
+    /// Set number of frames turbo button is kept pressed or released
+    pub fn set_turbo_period(&mut self, frames: u64) {
+        self.turbo_period = frames.max(1);
+    }
+
+    pub fn is_turbo_active(&self) -> bool {
+        self.turbo != 0
+    }
+
+    fn apply_buttons(&mut self) {
+        let turbo = if (self.frame_count / self.turbo_period).is_multiple_of(2) { self.turbo } else { 0 };
+        let buttons = self.buttons | turbo;
+        let changed = buttons ^ self.applied_buttons;
+        for key in JOYPAD_KEYS.iter().filter(|key| changed & key.mask() != 0) {
+            if buttons & key.mask() != 0 {
+                self.cpu.bus.joypad.presskey(*key);
+            } else {
+                self.cpu.bus.joypad.releasekey(*key);
+            }
+        }
+        self.applied_buttons = buttons;
+    }
+
+    /// Allow pressing opposite directions together, which hardware cannot do
+    pub fn set_allow_impossible_inputs(&mut self, allow: bool) {
+        self.cpu.bus.joypad.set_allow_opposite(allow);
+    }
+
+    /// Interrupts raised (IF), an interrupt is serviced only if it is
+    /// also enabled and IME is on
+    pub fn pending_interrupts(&self) -> InterruptFlag {
+        self.cpu.bus.pending_interrupts()
+    }
+
+    /// Interrupts enabled (IE)
+    pub fn enabled_interrupts(&self) -> InterruptFlag {
+        self.cpu.bus.interruptenb.clone()
+    }
+
+    pub fn timer_state(&self) -> TimerState {
+        self.cpu.bus.timer.state()
+    }
+
+    /// Set timer registers and counters directly, for tests starting near overflow
+    pub fn set_timer_state(&mut self, state: TimerState) {
+        self.cpu.bus.timer.set_state(state);
+    }
+
+    /// Interrupt master enable (IME)
+    pub fn interrupt_master_enabled(&self) -> bool {
+        self.cpu.interrupt_master_enabled()
+    }
+
+    /// Register a callback invoked at VBlank entry of every emulated frame,
+    /// with None for a frame not built because of frame skip
+    pub fn set_frame_callback(&mut self, callback: FrameCallback) {
+        self.frame_callback = Some(callback);
+    }
+
+    /// Build the screen of one frame, then skip the next skip frames
+    pub fn set_frame_skip(&mut self, skip: u64) {
+        self.frame_skip = skip;
+        self.skip_left = 0;
+    }
+
+    /// Stop with EmuError::StackCorruption on stack access outside of RAM
+    /// or PUSH over the return address of the last call
+    pub fn set_stack_check(&mut self, enable: bool) {
+        self.cpu.set_stack_check(enable);
+    }
+
+    /// Stop, skip as NOP or lock up CPU on opcode not implemented
+    pub fn set_unknown_opcode(&mut self, policy: UnknownOpcode) {
+        self.cpu.set_unknown_opcode(policy);
+    }
+
+    /// Tick GPU and timer on every memory access and copy OAM DMA over 160
+    /// machine cycles, slower but more accurate
+    pub fn set_accurate_timing(&mut self, enable: bool) {
+        self.cpu.set_accurate_timing(enable);
+        self.cpu.bus.timed_dma = enable;
+    }
+
+    /// Trace every executed instruction to writer, in gameboy doctor format
+    pub fn set_trace_log(&mut self, writer: Box<dyn Write>) {
+        self.trace_log = Some(writer);
+    }
+
+    /// Keep recent instructions to report when emulation stops with error
+    pub fn set_crash_history(&mut self, enable: bool) {
+        self.history = if enable { Some(VecDeque::with_capacity(HISTORY_SIZE)) } else { None };
+    }
+
+    /// Count clocks spent at each pc, costs a little speed
+    pub fn set_profile(&mut self, enable: bool) {
+        self.profile = if enable { Some(vec![0; 0x10000]) } else { None };
+    }
+
+    /// Clocks spent in instructions at each pc, None if not profiling
+    pub fn profile(&self) -> Option<&[u64]> {
+        self.profile.as_deref()
+    }
+
+    /// Last count instructions executed, oldest first, empty without crash history
+    pub fn recent_instructions(&self, count: usize) -> Vec<TraceEntry> {
+        self.history.as_ref().map_or(Vec::new(), |history| {
+            history.iter().skip(history.len().saturating_sub(count)).cloned().collect()
+        })
+    }
+
+    /// Recent instructions, interrupt and GPU state, for error report
+    pub fn crash_report(&self) -> String {
+        let mut report = String::new();
+        if let Some(err) = self.cpu.last_error() {
+            report.push_str(&format!("{}\n", err));
+        }
+        report.push_str(&format!("IF: {:?}\nIE: {:?}\nIME: {}\nGPU mode: {:?} line: {}\n",
+                                 self.pending_interrupts(), self.enabled_interrupts(),
+                                 self.interrupt_master_enabled(),
+                                 self.cpu.bus.gpu.mode, self.cpu.bus.gpu.line));
+        if let Some(history) = self.history.as_ref() {
+            report.push_str("Recent instructions, last one failed:\n");
+            for entry in history.iter() {
+                report.push_str(&format!("  {}\n", entry));
+            }
+        }
+        report
+    }
+
+    /// Start of spin loop if the last window instructions all lie in a few bytes,
+    /// like polling an IO register that never changes.
+    /// Uses crash history, so window is at most HISTORY_SIZE.
+    pub fn detect_tight_loop(&self, window: usize) -> Option<u16> {
+        let history = self.history.as_ref()?;
+        if window == 0 || history.len() < window {
+            return None;
+        }
+        let pcs = history.iter().rev().take(window).map(|entry| entry.pc);
+        let (low, high) = pcs.fold((u16::MAX, 0), |(low, high), pc| (low.min(pc), high.max(pc)));
+        if high - low < TIGHT_LOOP_SPAN { Some(low) } else { None }
+    }
+
+    fn cpu_step(&mut self) -> Result<(), ()> {
+        if let Some(history) = self.history.as_mut() {
+            if history.len() >= HISTORY_SIZE {
+                history.pop_front();
+            }
+            history.push_back(self.cpu.trace_entry());
+        }
+        let (pc, cycles) = (self.cpu.pc, self.cpu.cycles());
+        let result = self.cpu.step_with_trace(&mut self.trace_log);
+        if let Some(profile) = self.profile.as_mut() {
+            profile[pc as usize] += self.cpu.cycles() - cycles;
+        }
+        self.bank_switch = None;
+        for switch in self.cpu.bus.take_bank_switches() {
+            debug!("PC {:#06x}: {}", pc, switch);
+            self.bank_switch = Some(switch);
+        }
+        if result.is_err() {
+            error!("Emulation stopped\n{}", self.crash_report());
+        }
+        result
+    }
+
+    /// Run one frame of CYCLES_PER_FRAME clocks, the screen is built at VBlank entry.
+    /// Clock overrun by the last instruction is taken from the next frame,
+    /// so frame boundaries do not depend on the instructions executed.
     pub fn run(&mut self) -> Result<(), ()> {
-        // TODO: better way to control this
-        while self.cpu.bus.gpu.mode != GpuMode::VBlank {
-            self.cpu.step()?;
+        while self.run_until_break()?.is_some() {}
+        Ok(())
+    }
+
+    /// Run to the end of frame like run, but stop before instruction at a breakpoint
+    /// or after an interrupt set by set_interrupt_break is serviced.
+    /// Return the reason if stopped, next call continues the same frame
+    /// and executes the instruction at breakpoint.
+    pub fn run_until_break(&mut self) -> Result<Option<StopReason>, ()> {
+        self.begin_frame();
+        self.run_to(self.frame_end)
+    }
+
+    /// Run at least cycles clocks as fast as possible, frames are built and
+    /// breakpoints are ignored. Return the wall-clock time taken, for benchmark.
+    pub fn run_cycles(&mut self, cycles: u64) -> Result<Duration, ()> {
+        let start = Instant::now();
+        let target = self.cpu.cycles() + cycles;
+        while self.cpu.cycles() < target {
+            self.begin_frame();
+            while self.run_to(self.frame_end.min(target))?.is_some() {}
+            // nothing plays the samples, do not let them pile up in snapshots
+            self.cpu.bus.apu.take_samples();
+        }
+        Ok(start.elapsed())
+    }
+
+    // start next frame if the current one is done
+    fn begin_frame(&mut self) {
+        if self.cpu.cycles() >= self.frame_end {
+            if let Some(lockup) = self.lockup_watch.end_frame(&self.cpu) {
+                info!("{}", lockup);
+                self.lockup = Some(lockup);
+            }
+            self.apply_buttons();
+            // after buttons, replay from here sees the same joypad
+            self.take_snapshot();
+            self.frame_count += 1;
+            self.frame_stats = FrameStats { cycles: self.cpu.cycles(), ..Default::default() };
+            self.frame_end += CYCLES_PER_FRAME;
         }
-        self.cpu.bus.gpu.build_screen(&mut self.buffer);
-        while self.cpu.bus.gpu.mode == GpuMode::VBlank {
+    }
+
+    /// Game stuck in a loop for many frames, reported once until it leaves the loop
+    pub fn take_lockup(&mut self) -> Option<Lockup> {
+        self.lockup.take()
+    }
+
+    /// Stats of the last completed frame
+    pub fn last_frame_stats(&self) -> FrameStats {
+        self.last_frame_stats
+    }
+
+    // run until cycles reach end or stop by breakpoint, collect frame stats
+    fn run_to(&mut self, end: u64) -> Result<Option<StopReason>, ()> {
+        let start = Instant::now();
+        let result = self.run_until(end);
+        self.frame_stats.elapsed += start.elapsed();
+        if self.cpu.cycles() >= self.frame_end {
+            self.last_frame_stats = FrameStats {
+                cycles: self.cpu.cycles() - self.frame_stats.cycles,
+                ..self.frame_stats
+            };
+        }
+        result
+    }
+
+    fn run_until(&mut self, end: u64) -> Result<Option<StopReason>, ()> {
+        let mut resume = true;
+        while self.cpu.cycles() < end {
+            if !resume && self.breakpoints.contains(&self.cpu.pc) {
+                return Ok(Some(StopReason::Breakpoint(self.cpu.pc)));
+            }
+            resume = false;
+            let was_vblank = self.cpu.bus.gpu.mode == GpuMode::VBlank;
+            let pc = self.cpu.pc;
+            self.cpu_step()?;
+            // counted like step, rstep replays from the snapshot of frame start
+            self.step_count += 1;
+            self.frame_stats.instructions += 1;
+            self.lockup_watch.step(pc, self.cpu.serviced_interrupt().is_some(),
+                                   self.cpu.bus.requested_interrupts());
+            if !was_vblank && self.cpu.bus.gpu.mode == GpuMode::VBlank {
+                let build = self.skip_left == 0;
+                if build {
+                    self.cpu.bus.gpu.build_screen(&mut self.buffer);
+                    self.skip_left = self.frame_skip;
+                } else {
+                    self.skip_left -= 1;
+                }
+                if let Some(callback) = self.frame_callback.as_mut() {
+                    callback(if build { Some(&self.buffer) } else { None });
+                }
+            }
+            if let Some((source, pc)) = self.cpu.serviced_interrupt() {
+                if self.interrupt_breaks & source.mask() != 0 {
+                    return Ok(Some(StopReason::Interrupt { source, pc }));
+                }
+            }
+            if let Some(switch) = self.bank_switch.filter(|_| self.bank_break) {
+                return Ok(Some(StopReason::BankSwitch { switch, pc }));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Stop run_until_break when interrupt of source is serviced
+    pub fn set_interrupt_break(&mut self, source: Interrupt, enable: bool) {
+        if enable {
+            self.interrupt_breaks |= source.mask();
+        } else {
+            self.interrupt_breaks &= !source.mask();
+        }
+    }
+
+    /// Stop run_until_break after a write changes the banks of MBC
+    pub fn set_bank_switch_break(&mut self, enable: bool) {
+        self.bank_break = enable;
+    }
+
+    /// Number of frames started by run
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    /// Set or clear breakpoint at addr, return whether it is set now
+    pub fn toggle_breakpoint(&mut self, addr: u16) -> bool {
+        if !self.breakpoints.remove(&addr) {
+            self.breakpoints.insert(addr);
+        }
+        self.breakpoints.contains(&addr)
+    }
+
+    pub fn has_breakpoint(&self, addr: u16) -> bool {
+        self.breakpoints.contains(&addr)
+    }
+
+    /// Read memory for debugger, invalid address reads 0xff
+    pub fn peek(&self, addr: u16) -> u8 {
+        self.cpu.bus.load8(addr).unwrap_or(0xff)
+    }
+
+    /// Keep bytes sent through serial port, test ROMs print results there
+    pub fn set_serial_capture(&mut self, enable: bool) {
+        self.cpu.bus.capture_serial = enable;
+    }
+
+    /// Bytes sent through serial port since last call
+    pub fn take_serial_output(&mut self) -> Vec<u8> {
+        self.cpu.bus.take_serial_output()
+    }
+
+    /// Plug device into serial port, like a printer
+    pub fn connect_serial(&mut self, link: Box<dyn SerialLink>) {
+        self.cpu.bus.serial_link = Some(link);
+    }
+
+    /// Device plugged into serial port, if it is of type T
+    pub fn serial_link_mut<T: SerialLink + 'static>(&mut self) -> Option<&mut T> {
+        self.cpu.bus.serial_link.as_mut()?.as_any_mut().downcast_mut::<T>()
+    }
+
+    /// Count CPU reads and writes per 256 bytes page, counts restart when enabled
+    pub fn set_access_count(&mut self, enable: bool) {
+        self.cpu.bus.access_count = if enable { Some(Box::new(AccessCount::new())) } else { None };
+    }
+
+    /// Reads and writes per page since set_access_count
+    pub fn access_count(&self) -> Option<&AccessCount> {
+        self.cpu.bus.access_count.as_deref()
+    }
+
+    /// Move GPU to line and mode, clock into mode, to test raster effects
+    /// without running to the line
+    pub fn force_gpu_position(&mut self, line: u8, mode: GpuMode, clock: u64) {
+        self.cpu.bus.gpu.force_position(line, mode, clock);
+    }
+
+    /// Background map address and tile index under screen pixel (x, y),
+    /// to find the tile of something seen on screen
+    pub fn tile_at_screen(&self, x: u8, y: u8) -> (u16, u8) {
+        self.cpu.bus.gpu.bg_tile_at(x, y)
+    }
+
+    /// Read whole OAM at once, for tools and tests
+    pub fn read_oam(&self) -> [u8; OAM_SIZE] {
+        self.cpu.bus.gpu.oam()
+    }
+
+    /// Write whole OAM at once, bypass OAM DMA
+    pub fn write_oam(&mut self, oam: &[u8; OAM_SIZE]) {
+        self.cpu.bus.gpu.set_oam(oam);
+    }
+
+    /// Compare state with other Vm, to prove two code paths behave the same.
+    /// Empty when registers, key IO registers and RAM are equal.
+    pub fn diff(&self, other: &Vm) -> Vec<StateDiff> {
+        let mut diffs = Vec::new();
+        let (left, right) = (self.cpu.trace_entry(), other.cpu.trace_entry());
+        let registers = [
+            ("PC", left.pc, right.pc), ("SP", left.sp, right.sp),
+            ("AF", left.af, right.af), ("BC", left.bc, right.bc),
+            ("DE", left.de, right.de), ("HL", left.hl, right.hl),
+            ("IME", self.interrupt_master_enabled() as u16, other.interrupt_master_enabled() as u16),
+        ];
+        diffs.extend(registers.iter()
+            .filter(|(_, left, right)| left != right)
+            .map(|&(name, left, right)| StateDiff::Register { name, left, right }));
+        if self.cpu.cycles() != other.cpu.cycles() {
+            diffs.push(StateDiff::Cycles { left: self.cpu.cycles(), right: other.cpu.cycles() });
+        }
+        diffs.extend(DIFF_IO.iter()
+            .map(|&(name, addr)| (name, self.peek(addr), other.peek(addr)))
+            .filter(|(_, left, right)| left != right)
+            .map(|(name, left, right)| StateDiff::Io { name, left, right }));
+        for &(region, start, end) in DIFF_REGIONS.iter() {
+            let differ = (start..=end).find(|&addr| self.peek(addr) != other.peek(addr));
+            if let Some(addr) = differ {
+                diffs.push(StateDiff::Memory { region, addr, left: self.peek(addr), right: other.peek(addr) });
+            }
+        }
+        diffs
+    }
+
+    /// Disassemble instruction at addr, return text with its bytes and length
+    pub fn disassemble(&self, addr: u16) -> (String, u16) {
+        let opcode = self.peek(addr);
+        let (name, len) = if opcode == 0xcb {
+            let inst = CBInstruction::from_byte(self.peek(addr.wrapping_add(1)));
+            (format!("{:?}", inst), 2)
+        } else {
+            match Instruction::from_byte(opcode) {
+                Some(inst) => (format!("{:?}", inst), inst.len() + 1),
+                None => (String::from("unknown"), 1),
+            }
+        };
+        let bytes: Vec<String> = (0..len)
+            .map(|i| format!("{:02X}", self.peek(addr.wrapping_add(i))))
+            .collect();
+        (format!("{:<8} {}", bytes.join(" "), name), len)
+    }
+
+    /// Set the instruction interval between snapshots for reverse step.
+    /// Each snapshot is a full copy of cpu and bus (~50KB for a 32KB ROM),
+    /// smaller interval costs more memory but rstep re-executes fewer instructions.
+    pub fn set_snapshot_interval(&mut self, interval: u64) {
+        self.snapshot_interval = interval.max(1);
+        self.snapshots.clear();
+    }
+
+    /// Keep snapshots for rstep, each costs a copy of cpu and bus
+    pub fn set_reverse_step(&mut self, enable: bool) {
+        self.reverse_step = enable;
+        self.snapshots.clear();
+    }
+
+    /// Execute single machine cycle (4 clocks)
+    pub fn tick(&mut self) -> Result<(), ()> {
+        self.cpu.tick()
+    }
+
+    // snapshot for reverse step, once per step_count
+    fn take_snapshot(&mut self) {
+        if !self.reverse_step {
+            return;
+        }
+        if self.snapshots.back().is_some_and(|(count, _)| *count == self.step_count) {
+            return;
+        }
+        if self.snapshots.len() >= MAX_SNAPSHOTS {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back((self.step_count, self.cpu.clone()));
+    }
+
+    /// Execute single instruction, keeping snapshots for reverse step
+    pub fn step(&mut self) -> Result<(), ()> {
+        if self.step_count.is_multiple_of(self.snapshot_interval) {
+            self.take_snapshot();
+        }
+        self.cpu_step()?;
+        self.step_count += 1;
+        Ok(())
+    }
+
+    /// Step back one instruction: restore the nearest earlier snapshot
+    /// then execute forward until one instruction before current state
+    pub fn rstep(&mut self) -> Result<(), ()> {
+        if self.step_count == 0 {
+            return Err(());
+        }
+        let target = self.step_count - 1;
+        while let Some((count, _)) = self.snapshots.back() {
+            if *count <= target {
+                break;
+            }
+            self.snapshots.pop_back();
+        }
+        let (count, cpu) = match self.snapshots.back() {
+            Some((count, cpu)) => (*count, cpu.clone()),
+            None => return Err(()),
+        };
+        self.cpu = cpu;
+        // replay without trace, history, profile and bank switch bookkeeping,
+        // those already saw these instructions
+        for _ in count..target {
             self.cpu.step()?;
         }
+        self.step_count = target;
         Ok(())
     }
 
@@ -34,3 +790,372 @@ impl Vm {
         debug!("{}", self.cpu.dump());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use crate::mbc::Banks;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    fn vm_with(program: &[u8]) -> Vm {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + program.len()].copy_from_slice(program);
+        Vm::new(rom)
+    }
+
+    // registers keep changing, so every instruction leaves a different state
+    fn counter() -> Vec<u8> {
+        asm![INC B; INC C; ADD A, B; JR -5]
+    }
+
+    #[test]
+    fn rstep_after_run_goes_back_one_instruction() {
+        let mut vm = vm_with(&counter());
+        vm.set_reverse_step(true);
+        for _ in 0..5 {
+            vm.step().unwrap();
+        }
+        for _ in 0..3 {
+            vm.run().unwrap();
+        }
+        let steps = vm.step_count;
+        vm.rstep().unwrap();
+
+        let mut fresh = vm_with(&counter());
+        for _ in 0..steps - 1 {
+            fresh.step().unwrap();
+        }
+        assert_eq!(vm.cpu.trace_entry(), fresh.cpu.trace_entry());
+        assert_eq!(vm.cpu.cycles(), fresh.cpu.cycles());
+    }
+
+    #[test]
+    fn frame_callback_fires_every_frame_with_skip() {
+        let mut vm = vm_with(&counter());
+        vm.set_frame_skip(2);
+        let built = Rc::new(Cell::new((0, 0)));
+        let counts = built.clone();
+        vm.set_frame_callback(Box::new(move |pixels| {
+            let (frames, built) = counts.get();
+            counts.set((frames + 1, built + pixels.is_some() as u32));
+        }));
+        for _ in 0..9 {
+            vm.run().unwrap();
+        }
+        assert_eq!(built.get(), (9, 3));
+    }
+
+    #[test]
+    fn bank_switch_stops_run() {
+        // MBC1 ROM of 4 banks: LD A, 2; LD (0x2000), A; JR -2
+        let mut rom = vec![0; 0x10000];
+        rom[0x147] = 0x01;
+        rom[0x100..0x107].copy_from_slice(&[0x3e, 0x02, 0xea, 0x00, 0x20, 0x18, 0xfe]);
+        rom[0x8000] = 0x42;
+        let mut vm = Vm::new(rom);
+        vm.set_bank_switch_break(true);
+        let old = Banks { rom: 1, ram: 0 };
+        let new = Banks { rom: 2, ram: 0 };
+        assert_eq!(vm.run_until_break(), Ok(Some(StopReason::BankSwitch {
+            switch: BankSwitch { old, new }, pc: 0x102,
+        })));
+        assert_eq!(vm.cpu.bus.load8(0x4000), Ok(0x42));
+        // writing the same bank again is not a switch
+        assert_eq!(vm.run_until_break(), Ok(None));
+    }
+
+    #[test]
+    fn rstep_without_step_fails() {
+        let mut vm = vm_with(&counter());
+        assert!(vm.rstep().is_err());
+    }
+
+    // writer keeping the trace for the test to read
+    struct SharedLog(Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl Write for SharedLog {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn trace_log_is_gameboy_doctor_format() {
+        // LD A,0x42; NOP; HALT
+        let mut vm = vm_with(&[0x3e, 0x42, 0x00, 0x76]);
+        let log = Rc::new(std::cell::RefCell::new(Vec::new()));
+        vm.set_trace_log(Box::new(SharedLog(log.clone())));
+        vm.step().unwrap();
+        vm.step().unwrap();
+        let log = String::from_utf8(log.borrow().clone()).unwrap();
+        assert_eq!(log,
+                   "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:3E,42,00,76\n\
+                    A:42 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0102 PCMEM:00,76,00,00\n");
+    }
+
+    struct BrokenLog;
+
+    impl Write for BrokenLog {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("disk full"))
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn trace_log_dropped_on_write_error() {
+        let mut vm = vm_with(&counter());
+        vm.set_trace_log(Box::new(BrokenLog));
+        vm.step().unwrap();
+        assert!(vm.trace_log.is_none());
+        vm.step().unwrap();
+    }
+
+    #[test]
+    fn run_takes_cycles_of_one_frame() {
+        let mut vm = vm_with(&counter());
+        for frame in 1..=5 {
+            vm.run().unwrap();
+            // overshoot of the last instruction does not add up over frames
+            let cycles = vm.cpu.cycles();
+            assert!(cycles >= frame * CYCLES_PER_FRAME, "frame {} at {}", frame, cycles);
+            assert!(cycles < frame * CYCLES_PER_FRAME + 16, "frame {} at {}", frame, cycles);
+        }
+    }
+
+    #[test]
+    fn with_config_applies_options() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + counter().len()].copy_from_slice(&counter());
+        let config = VmConfig {
+            ram_init: RamInit::Fill(0x5a),
+            cgb_mode: Some(true),
+            turbo_period: 3,
+            snapshot_interval: 7,
+            crash_history: false,
+            ..Default::default()
+        };
+        let vm = Vm::with_config(rom, config);
+        assert!(vm.cpu.bus.cgb_mode);
+        assert_eq!(vm.cpu.bus.load8(0xc000), Ok(0x5a));
+        assert_eq!(vm.turbo_period, 3);
+        assert_eq!(vm.snapshot_interval, 7);
+        assert!(vm.history.is_none());
+        // Vm::new is the default config
+        let vm = vm_with(&counter());
+        assert!(!vm.cpu.bus.cgb_mode);
+        assert_eq!(vm.cpu.bus.load8(0xc000), Ok(0));
+        assert!(vm.history.is_some());
+    }
+
+    #[test]
+    fn masked_timer_is_pending_but_not_enabled() {
+        // EI; NOP; NOP
+        let mut vm = vm_with(&[0xfb, 0x00, 0x00]);
+        vm.cpu.bus.store8(0xffff, 0x01).unwrap();
+        vm.cpu.bus.store8(0xff0f, 0x00).unwrap();
+        vm.cpu.bus.request_interrupt(Interrupt::Timer);
+        vm.step().unwrap();
+        vm.step().unwrap();
+        assert!(vm.interrupt_master_enabled());
+        assert!(vm.pending_interrupts().timer);
+        assert!(!vm.enabled_interrupts().timer);
+        // raised but masked, so never serviced
+        assert_eq!(vm.cpu.pc, 0x0102);
+        assert!(vm.pending_interrupts().timer);
+    }
+
+    #[test]
+    fn run_cycles_runs_at_least_cycles() {
+        let mut vm = vm_with(&counter());
+        vm.run_cycles(1000).unwrap();
+        let cycles = vm.cpu.cycles();
+        assert!((1000..1016).contains(&cycles), "ran {}", cycles);
+        // spans several frames, breakpoints are ignored
+        vm.toggle_breakpoint(0x0100);
+        vm.run_cycles(3 * CYCLES_PER_FRAME).unwrap();
+        assert!(vm.cpu.cycles() >= cycles + 3 * CYCLES_PER_FRAME);
+        assert!(vm.frame_count >= 3);
+    }
+
+    #[test]
+    fn tight_loop_reports_loop_pc() {
+        // NOP; NOP; JR -2
+        let mut vm = vm_with(&[0x00, 0x00, 0x18, 0xfe]);
+        vm.step().unwrap();
+        vm.step().unwrap();
+        assert_eq!(vm.detect_tight_loop(8), None);
+        for _ in 0..8 {
+            vm.step().unwrap();
+        }
+        assert_eq!(vm.detect_tight_loop(8), Some(0x0102));
+        // counter loop spans more instructions, still a few bytes
+        let mut vm = vm_with(&counter());
+        vm.run().unwrap();
+        assert_eq!(vm.detect_tight_loop(16), Some(0x0100));
+    }
+
+    #[test]
+    fn timer_state_one_cycle_from_overflow() {
+        let mut vm = vm_with(&[0x00; 4]);
+        vm.set_timer_state(TimerState {
+            tima: 0xff, tma: 0x30, tac: 0x05, timer_counter: 12, ..Default::default()
+        });
+        assert_eq!(vm.timer_state().tima, 0xff);
+        vm.step().unwrap();
+        assert_eq!(vm.timer_state().tima, 0x00);
+        assert!(!vm.pending_interrupts().timer);
+        vm.step().unwrap();
+        assert_eq!(vm.timer_state().tima, 0x30);
+        assert!(vm.pending_interrupts().timer);
+    }
+
+    #[test]
+    fn frame_stats_of_last_frame() {
+        let mut vm = vm_with(&counter());
+        assert_eq!(vm.last_frame_stats().instructions, 0);
+        vm.run().unwrap();
+        vm.run().unwrap();
+        let stats = vm.last_frame_stats();
+        assert!((CYCLES_PER_FRAME - 24..CYCLES_PER_FRAME + 24).contains(&stats.cycles),
+                "cycles {}", stats.cycles);
+        // loop of 4 instructions takes 24 clocks
+        assert!((stats.cycles / 6).abs_diff(stats.instructions) <= 4,
+                "instructions {}", stats.instructions);
+        assert!(stats.elapsed > Duration::from_secs(0));
+    }
+
+    #[test]
+    fn write_oam_decodes_sprites() {
+        let mut vm = vm_with(&counter());
+        let mut oam = [0; OAM_SIZE];
+        for (i, sprite) in oam.chunks_mut(4).enumerate() {
+            sprite.copy_from_slice(&[i as u8 + 16, i as u8 * 2 + 8, i as u8, 0xa0]);
+        }
+        vm.write_oam(&oam);
+        assert_eq!(vm.read_oam()[..], oam[..]);
+        for (i, sprite) in vm.cpu.bus.gpu.sprites().iter().enumerate() {
+            assert_eq!((sprite.y, sprite.x), (i as isize, i as isize * 2), "sprite {}", i);
+            assert_eq!(sprite.tile_idx, i as u8);
+            assert!(sprite.priority && sprite.flip_x && !sprite.flip_y);
+        }
+    }
+
+    #[test]
+    fn unknown_opcode_policies() {
+        // undefined 0xD3; INC B
+        let program = [0xd3, 0x04];
+        let mut vm = vm_with(&program);
+        assert!(vm.step().is_err());
+        let mut vm = vm_with(&program);
+        vm.set_unknown_opcode(UnknownOpcode::SkipAsNop);
+        let b = vm.cpu.trace_entry().bc >> 8;
+        vm.step().unwrap();
+        assert_eq!(vm.cpu.pc, 0x0101);
+        vm.step().unwrap();
+        assert_eq!(vm.cpu.trace_entry().bc >> 8, b + 1);
+        // locked cpu stays, frames keep running
+        let mut vm = vm_with(&program);
+        vm.set_unknown_opcode(UnknownOpcode::Halt);
+        vm.run().unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.cpu.pc, 0x0101);
+        assert_eq!(vm.cpu.trace_entry().bc >> 8, b);
+    }
+
+    #[test]
+    fn diff_of_lockstep_vms_is_empty() {
+        let mut left = vm_with(&counter());
+        let mut right = vm_with(&counter());
+        for _ in 0..3 {
+            left.run().unwrap();
+            right.run().unwrap();
+            assert_eq!(left.diff(&right), []);
+        }
+        right.cpu.bus.store8(0xc123, 0x42).unwrap();
+        assert_eq!(left.diff(&right), [StateDiff::Memory { region: "WRAM", addr: 0xc123, left: 0, right: 0x42 }]);
+        // one more instruction changes registers and cycles
+        right.step().unwrap();
+        let diffs = left.diff(&right);
+        assert!(diffs.iter().any(|diff| matches!(diff, StateDiff::Register { name: "PC", .. })));
+        assert!(diffs.iter().any(|diff| matches!(diff, StateDiff::Cycles { .. })));
+    }
+
+    #[test]
+    fn forced_gpu_position_shows_in_stat() {
+        let mut vm = vm_with(&counter());
+        vm.cpu.bus.store8(0xff45, 80).unwrap();
+        let positions = vec![
+            (80, GpuMode::HBlank, 0x04), (3, GpuMode::ScanlineOAM, 0x02),
+            (100, GpuMode::ScanlineVRAM, 0x03), (150, GpuMode::VBlank, 0x01),
+        ];
+        for (line, mode, stat) in positions {
+            vm.force_gpu_position(line, mode, 8);
+            assert_eq!(vm.peek(0xff44), line);
+            // mode and LY = LYC bits
+            assert_eq!(vm.peek(0xff41) & 0x07, stat, "line {}", line);
+        }
+    }
+
+    #[test]
+    fn tile_at_screen_applies_scroll_and_map() {
+        let mut vm = vm_with(&counter());
+        vm.cpu.bus.store8(0x9803, 0x55).unwrap();
+        vm.cpu.bus.store8(0x9c22, 0x66).unwrap();
+        vm.cpu.bus.store8(0xff43, 20).unwrap();
+        vm.cpu.bus.store8(0xff42, 250).unwrap();
+        // (30, 4) after scroll, wrapped at the bottom of map
+        assert_eq!(vm.tile_at_screen(10, 10), (0x9803, 0x55));
+        // map at 0x9C00, (20, 14) after scroll
+        vm.cpu.bus.store8(0xff40, 0x99).unwrap();
+        assert_eq!(vm.tile_at_screen(0, 20), (0x9c22, 0x66));
+    }
+
+    #[test]
+    fn reset_keeps_cartridge_ram() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0147] = 0x09;
+        let mut vm = Vm::new(rom);
+        vm.cpu.bus.store8(0xa000, 0x12).unwrap();
+        vm.cpu.bus.store8(0xc000, 0x34).unwrap();
+        vm.reset();
+        assert_eq!(vm.peek(0xa000), 0x12);
+        assert_eq!(vm.peek(0xc000), 0);
+    }
+
+    #[test]
+    fn snapshots_only_with_reverse_step() {
+        let mut vm = vm_with(&counter());
+        vm.run().unwrap();
+        vm.step().unwrap();
+        assert!(vm.snapshots.is_empty());
+        assert!(vm.rstep().is_err());
+    }
+
+    #[test]
+    fn rstep_replay_leaves_trace_and_history() {
+        let mut vm = vm_with(&counter());
+        vm.set_reverse_step(true);
+        vm.set_snapshot_interval(4);
+        let log = Rc::new(std::cell::RefCell::new(Vec::new()));
+        vm.set_trace_log(Box::new(SharedLog(log.clone())));
+        for _ in 0..7 {
+            vm.step().unwrap();
+        }
+        let history = vm.history.as_ref().unwrap().len();
+        vm.rstep().unwrap();
+        vm.rstep().unwrap();
+        assert_eq!(log.borrow().iter().filter(|&&byte| byte == b'\n').count(), 7);
+        assert_eq!(vm.history.as_ref().unwrap().len(), history);
+        assert_eq!(vm.step_count, 5);
+    }
+}
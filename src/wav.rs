@@ -0,0 +1,104 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use rugameboy::apu::{Apu, SAMPLE_RATE};
+
+const HEADER_SIZE: u32 = 44;
+
+/// 16-bit PCM WAV file, sizes in header are patched by finish
+pub struct WavWriter {
+    writer: BufWriter<File>,
+    channels: u16,
+    data_size: u32,
+}
+
+impl WavWriter {
+    pub fn create<P: AsRef<Path>>(path: P, channels: u16) -> io::Result<Self> {
+        let mut wav = Self {
+            writer: BufWriter::new(File::create(path)?),
+            channels,
+            data_size: 0,
+        };
+        wav.write_header()?;
+        Ok(wav)
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let block_align = self.channels * 2;
+        let w = &mut self.writer;
+        w.write_all(b"RIFF")?;
+        w.write_all(&(HEADER_SIZE - 8 + self.data_size).to_le_bytes())?;
+        w.write_all(b"WAVEfmt ")?;
+        w.write_all(&16u32.to_le_bytes())?;
+        w.write_all(&1u16.to_le_bytes())?; // PCM
+        w.write_all(&self.channels.to_le_bytes())?;
+        w.write_all(&(SAMPLE_RATE as u32).to_le_bytes())?;
+        w.write_all(&(SAMPLE_RATE as u32 * block_align as u32).to_le_bytes())?;
+        w.write_all(&block_align.to_le_bytes())?;
+        w.write_all(&16u16.to_le_bytes())?;
+        w.write_all(b"data")?;
+        w.write_all(&self.data_size.to_le_bytes())
+    }
+
+    pub fn write_samples(&mut self, samples: &[i16]) -> io::Result<()> {
+        for sample in samples {
+            self.writer.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_size += samples.len() as u32 * 2;
+        Ok(())
+    }
+
+    /// Rewrite header with the final data size
+    pub fn finish(mut self) -> io::Result<()> {
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.writer.flush()
+    }
+}
+
+/// Dump mixed stereo output, and each channel when per_channel is set.
+/// Channel files are named after path with .ch1 to .ch4 before extension.
+pub struct AudioDump {
+    mixed: WavWriter,
+    channels: Vec<WavWriter>,
+}
+
+impl AudioDump {
+    pub fn create(path: &Path, per_channel: bool) -> io::Result<Self> {
+        let mixed = WavWriter::create(path, 2)?;
+        let mut channels = Vec::new();
+        if per_channel {
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            for ch in 1..=4 {
+                let name = path.with_file_name(format!("{}.ch{}.wav", stem, ch));
+                channels.push(WavWriter::create(name, 1)?);
+            }
+        }
+        Ok(Self { mixed, channels })
+    }
+
+    /// Enable channel capture of apu if needed
+    pub fn attach(&self, apu: &mut Apu) {
+        apu.set_channel_capture(!self.channels.is_empty());
+    }
+
+    /// Write samples produced by apu since last call
+    pub fn write(&mut self, apu: &mut Apu) -> io::Result<()> {
+        self.mixed.write_samples(&apu.take_samples())?;
+        if let Some(samples) = apu.take_channel_samples() {
+            for (wav, samples) in self.channels.iter_mut().zip(samples.iter()) {
+                wav.write_samples(samples)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        self.mixed.finish()?;
+        for wav in self.channels {
+            wav.finish()?;
+        }
+        Ok(())
+    }
+}